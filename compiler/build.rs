@@ -0,0 +1,16 @@
+// Hash the compiler's sources, see `src/build_id.rs`.
+
+use std::path::Path;
+
+#[path = "src/build_id.rs"]
+mod build_id;
+
+fn main() {
+    println!("cargo:rerun-if-changed=src");
+    println!("cargo:rerun-if-changed=Cargo.toml");
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR"));
+    println!(
+        "cargo:rustc-env=COMPILER_SOURCES={}",
+        build_id::sources_hash(dir)
+    );
+}
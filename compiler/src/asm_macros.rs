@@ -0,0 +1,401 @@
+// extended assembly: constants, macros, includes and pseudo-instructions
+//
+// `asm --extended` expands these into plain Hack before assembling:
+//
+//     .define WIDTH 32           @WIDTH means @32 from here on
+//     .macro COPY from, to       a macro with parameters, up to .endm
+//     COPY R0, R1                a use of it
+//     .include "stack.asm"       another file, relative to this one
+//     PUSH_D, POP_D, GOTO label  built-in macros
+//
+// A label a macro declares is local to each use of it. Every line out
+// keeps the file and line it was written at and the macro uses that led to
+// it, so errors point at the source.
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::assembler::{
+    code, is_symbol, is_symbol_char, lines, AsmError, Expansion, Origin, SourceLine,
+};
+
+/// The built-in macros, for a stack whose pointer is at SP and which grows
+/// up like the VM's.
+const BUILTINS: &str = "\
+.macro PUSH_D
+@SP
+A=M
+M=D
+@SP
+M=M+1
+.endm
+.macro POP_D
+@SP
+AM=M-1
+D=M
+.endm
+.macro GOTO label
+@label
+0;JMP
+.endm
+";
+
+/// How deep macro uses may nest.
+const MAX_DEPTH: usize = 32;
+
+#[derive(Debug)]
+struct Macro {
+    params: Vec<String>,
+    body: Vec<SourceLine>,
+    origin: Origin,
+}
+
+#[derive(Debug, Default)]
+struct Expander {
+    defines: HashMap<String, (String, Origin)>,
+    macros: HashMap<String, Macro>,
+    /// The files being read, innermost last.
+    including: Vec<PathBuf>,
+    /// Uses of macros so far, numbering their local labels.
+    uses: usize,
+    out: Vec<SourceLine>,
+    errors: Vec<AsmError>,
+}
+
+/// The lines of the `.asm` file at `path` in plain Hack.
+pub fn expand(path: &Path) -> Result<Vec<SourceLine>, Vec<AsmError>> {
+    let mut expander = Expander::default();
+    expander.lines(&lines("<built-in>", BUILTINS), 0);
+    expander.file(path, None);
+    if expander.errors.is_empty() {
+        Ok(expander.out)
+    } else {
+        Err(expander.errors)
+    }
+}
+
+impl Expander {
+    /// Read the file at `path`, included from `from`.
+    fn file(&mut self, path: &Path, from: Option<&Origin>) {
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        let origin = from.cloned().unwrap_or(Origin {
+            file: name.to_string(),
+            line: 0,
+            expansions: vec![],
+        });
+        let canonical = path.canonicalize().unwrap_or(path.to_path_buf());
+        if self.including.contains(&canonical) {
+            let message = format!("{} includes itself", name);
+            self.errors.push(AsmError::new(&origin, message));
+            return;
+        }
+        match fs::read_to_string(path) {
+            Ok(text) => {
+                self.including.push(canonical);
+                self.lines(&lines(&name, &text), 0);
+                self.including.pop();
+            }
+            Err(error) => {
+                let message = format!("{}: {}", path.display(), error);
+                self.errors.push(AsmError::new(&origin, message));
+            }
+        }
+    }
+
+    /// Expand `lines`, `depth` macro uses deep.
+    fn lines(&mut self, lines: &[SourceLine], depth: usize) {
+        let mut index = 0;
+        while index < lines.len() {
+            let line = &lines[index];
+            index += 1;
+            let text = code(&line.text);
+            let (word, rest) = text.split_once(char::is_whitespace).unwrap_or((text, ""));
+            let rest = rest.trim();
+            match word {
+                ".define" => self.define(line, rest),
+                ".macro" => {
+                    let end = lines[index..]
+                        .iter()
+                        .position(|line| code(&line.text) == ".endm");
+                    match end {
+                        Some(end) => {
+                            self.define_macro(line, rest, &lines[index..index + end]);
+                            index += end + 1;
+                        }
+                        None => self.error(line, ".macro without .endm".to_string()),
+                    }
+                }
+                ".endm" => self.error(line, ".endm without .macro".to_string()),
+                ".include" => self.include(line, rest, depth),
+                _ if word.starts_with('.') => {
+                    self.error(line, format!("{} is no directive", word));
+                }
+                _ if self.macros.contains_key(word) => self.use_macro(line, word, rest, depth),
+                _ => self.out.push(self.substitute_defines(line)),
+            }
+        }
+    }
+
+    fn error(&mut self, line: &SourceLine, message: String) {
+        self.errors.push(AsmError::new(&line.origin, message));
+    }
+
+    /// `.define NAME value`
+    fn define(&mut self, line: &SourceLine, rest: &str) {
+        let mut words = rest.split_whitespace();
+        let (Some(name), Some(value), None) = (words.next(), words.next(), words.next()) else {
+            return self.error(line, ".define takes a name and a value".to_string());
+        };
+        if !is_symbol(name) {
+            return self.error(line, format!("{} is no symbol", name));
+        }
+        if let Some((_, origin)) = self.defines.get(name) {
+            let message = format!("{} is already defined at {}", name, origin);
+            return self.error(line, message);
+        }
+        self.defines
+            .insert(name.to_string(), (value.to_string(), line.origin.clone()));
+    }
+
+    /// `.macro NAME param, ...` with `body` up to its `.endm`.
+    fn define_macro(&mut self, line: &SourceLine, rest: &str, body: &[SourceLine]) {
+        let (name, params) = rest.split_once(char::is_whitespace).unwrap_or((rest, ""));
+        let params = arguments(params);
+        for name in params.iter().chain([&name.to_string()]) {
+            if !is_symbol(name) {
+                return self.error(line, format!("{} is no symbol", name));
+            }
+        }
+        if let Some(nested) = body
+            .iter()
+            .find(|line| code(&line.text).starts_with(".macro"))
+        {
+            return self.error(nested, "a macro can't define macros".to_string());
+        }
+        if let Some(defined) = self.macros.get(name) {
+            let message = format!("macro {} is already defined at {}", name, defined.origin);
+            return self.error(line, message);
+        }
+        let definition = Macro {
+            params,
+            body: body.to_vec(),
+            origin: line.origin.clone(),
+        };
+        self.macros.insert(name.to_string(), definition);
+    }
+
+    /// `.include "file.asm"`, relative to the file being read.
+    fn include(&mut self, line: &SourceLine, rest: &str, depth: usize) {
+        if depth > 0 {
+            return self.error(line, "a macro can't include files".to_string());
+        }
+        let Some(name) = rest
+            .strip_prefix('"')
+            .and_then(|rest| rest.strip_suffix('"'))
+        else {
+            return self.error(line, ".include takes a quoted file name".to_string());
+        };
+        let dir = self
+            .including
+            .last()
+            .and_then(|file| file.parent())
+            .unwrap_or(Path::new(""));
+        self.file(&dir.join(name), Some(&line.origin));
+    }
+
+    /// The lines of a use of macro `name`.
+    fn use_macro(&mut self, line: &SourceLine, name: &str, rest: &str, depth: usize) {
+        if depth == MAX_DEPTH {
+            // at the outermost use, not through every level
+            let outermost = line
+                .origin
+                .expansions
+                .last()
+                .map_or(line.origin.clone(), |use_| Origin {
+                    file: use_.file.clone(),
+                    line: use_.line,
+                    expansions: vec![],
+                });
+            let message = format!("macros nest over {} deep, does {} use itself?", depth, name);
+            return self.errors.push(AsmError::new(&outermost, message));
+        }
+        let definition = &self.macros[name];
+        let args = arguments(rest);
+        if args.len() != definition.params.len() {
+            let message = match definition.params.len() {
+                1 => format!("{} takes 1 argument, not {}", name, args.len()),
+                n => format!("{} takes {} arguments, not {}", name, n, args.len()),
+            };
+            return self.error(line, message);
+        }
+        self.uses += 1;
+        let mut names: HashMap<&str, String> = definition
+            .body
+            .iter()
+            .filter_map(|line| code(&line.text).strip_prefix('(')?.strip_suffix(')'))
+            .map(|label| (label, format!("{}${}", label, self.uses)))
+            .collect();
+        names.extend(definition.params.iter().map(String::as_str).zip(args));
+        let expansion = Expansion {
+            name: name.to_string(),
+            file: line.origin.file.clone(),
+            line: line.origin.line,
+        };
+        let body: Vec<SourceLine> = definition
+            .body
+            .iter()
+            .map(|body_line| {
+                let mut origin = body_line.origin.clone();
+                origin.expansions.push(expansion.clone());
+                origin
+                    .expansions
+                    .extend(line.origin.expansions.iter().cloned());
+                SourceLine {
+                    text: substitute(code(&body_line.text), |word| names.get(word).cloned()),
+                    origin,
+                }
+            })
+            .collect();
+        self.lines(&body, depth + 1);
+    }
+
+    /// `line` with `@NAME` replaced by the value `NAME` is defined as.
+    fn substitute_defines(&self, line: &SourceLine) -> SourceLine {
+        let text = match code(&line.text).strip_prefix('@') {
+            Some(name) => match self.defines.get(name) {
+                Some((value, _)) => format!("@{}", value),
+                None => line.text.clone(),
+            },
+            None => line.text.clone(),
+        };
+        SourceLine {
+            text,
+            origin: line.origin.clone(),
+        }
+    }
+}
+
+/// Comma separated arguments.
+fn arguments(text: &str) -> Vec<String> {
+    text.split(',')
+        .map(|arg| arg.trim().to_string())
+        .filter(|arg| !arg.is_empty())
+        .collect()
+}
+
+/// `text` with each symbol `replace` has a replacement for replaced.
+fn substitute(text: &str, replace: impl Fn(&str) -> Option<String>) -> String {
+    let mut out = String::new();
+    let mut rest = text;
+    while let Some(start) = rest.find(is_symbol_char) {
+        out += &rest[..start];
+        rest = &rest[start..];
+        let end = rest.find(|c| !is_symbol_char(c)).unwrap_or(rest.len());
+        let word = &rest[..end];
+        out += &replace(word).unwrap_or(word.to_string());
+        rest = &rest[end..];
+    }
+    out + rest
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::assemble;
+
+    #[test]
+    fn expands_defines_macros_includes_and_pseudo_ops() {
+        let dir = std::env::temp_dir().join(format!("asm-macros-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(
+            dir.join("lib.asm"),
+            "// counts down from `count` to 0
+.macro COUNTDOWN count
+    @count
+    D=A
+(LOOP)
+    D=D-1
+    @LOOP
+    D;JGT
+.endm
+",
+        )
+        .unwrap();
+        fs::write(
+            dir.join("Main.asm"),
+            ".include \"lib.asm\"
+.define ROWS 8
+    COUNTDOWN ROWS
+    COUNTDOWN 2
+    PUSH_D
+    POP_D
+    GOTO END
+(END)
+",
+        )
+        .unwrap();
+        let expanded = expand(&dir.join("Main.asm")).unwrap();
+        let text: Vec<&str> = expanded
+            .iter()
+            .map(|line| code(&line.text))
+            .filter(|text| !text.is_empty())
+            .collect();
+        assert_eq!(
+            text,
+            [
+                "@8", "D=A", "(LOOP$1)", "D=D-1", "@LOOP$1", "D;JGT", "@2", "D=A", "(LOOP$2)",
+                "D=D-1", "@LOOP$2", "D;JGT", "@SP", "A=M", "M=D", "@SP", "M=M+1", "@SP", "AM=M-1",
+                "D=M", "@END", "0;JMP", "(END)"
+            ]
+        );
+        assert_eq!(
+            expanded
+                .iter()
+                .find(|line| line.text.contains("D=D-1"))
+                .unwrap()
+                .origin
+                .to_string(),
+            "lib.asm:6 (in COUNTDOWN at Main.asm:3)"
+        );
+        assert_eq!(assemble(&expanded).unwrap().words[3], 2);
+
+        // an error in a macro is traced through each use
+        fs::write(
+            dir.join("Main.asm"),
+            ".macro STORE to\n@to\nM=D+2\n.endm\n\
+             .macro TWICE to\nSTORE to\nSTORE to\n.endm\nTWICE R1\n",
+        )
+        .unwrap();
+        let errors = assemble(&expand(&dir.join("Main.asm")).unwrap()).unwrap_err();
+        assert_eq!(
+            errors[1].to_string(),
+            "Main.asm:3 (in STORE at Main.asm:7) (in TWICE at Main.asm:9): D+2 is no computation, \
+             did you mean D+1?"
+        );
+
+        fs::write(
+            dir.join("Main.asm"),
+            ".define X 1\n.define X 2\nPUSH_D 1\n.macro SELF\nSELF\n.endm\nSELF\n\
+             .include \"Main.asm\"\n.endm\n.macro A\n",
+        )
+        .unwrap();
+        let errors: Vec<String> = expand(&dir.join("Main.asm"))
+            .unwrap_err()
+            .iter()
+            .map(AsmError::to_string)
+            .collect();
+        assert_eq!(
+            errors,
+            [
+                "Main.asm:2: X is already defined at Main.asm:1",
+                "Main.asm:3: PUSH_D takes 0 arguments, not 1",
+                "Main.asm:7: macros nest over 32 deep, does SELF use itself?",
+                "Main.asm:8: Main.asm includes itself",
+                "Main.asm:9: .endm without .macro",
+                "Main.asm:10: .macro without .endm",
+            ]
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,523 @@
+// Hack assembler
+//
+// The two passes the book describes: the first gives every `(LABEL)` the
+// ROM address of the instruction after it, the second encodes the
+// instructions, giving each new `@variable` the next RAM word from 16.
+// Lines carry where they were written, which for the extended syntax of
+// `asm_macros` includes the macro uses that produced them, so every error
+// points at a source line.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::{self, Display};
+use std::fs;
+use std::path::Path;
+
+use crate::asm_macros;
+use crate::cpu_emulator::ROM_SIZE;
+use crate::disassembler::{COMPS, DESTS, JUMPS};
+
+/// The symbols every program starts with.
+const PREDEFINED: [(&str, u16); 7] = [
+    ("SP", 0),
+    ("LCL", 1),
+    ("ARG", 2),
+    ("THIS", 3),
+    ("THAT", 4),
+    ("SCREEN", 16384),
+    ("KBD", 24576),
+];
+
+/// Where a line was written, and the macro uses that put it in the
+/// program, innermost first.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Origin {
+    pub file: String,
+    pub line: usize,
+    pub expansions: Vec<Expansion>,
+}
+
+/// A use of the macro `name` at `file:line`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Expansion {
+    pub name: String,
+    pub file: String,
+    pub line: usize,
+}
+
+impl Display for Origin {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.file, self.line)?;
+        for expansion in &self.expansions {
+            write!(
+                f,
+                " (in {} at {}:{})",
+                expansion.name, expansion.file, expansion.line
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct AsmError {
+    pub origin: Origin,
+    pub message: String,
+}
+
+impl AsmError {
+    pub fn new(origin: &Origin, message: String) -> AsmError {
+        AsmError {
+            origin: origin.clone(),
+            message,
+        }
+    }
+}
+
+impl Display for AsmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.origin, self.message)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SourceLine {
+    pub text: String,
+    pub origin: Origin,
+}
+
+/// The lines of `text`, numbered from 1.
+pub fn lines(file: &str, text: &str) -> Vec<SourceLine> {
+    text.lines()
+        .enumerate()
+        .map(|(index, line)| SourceLine {
+            text: line.to_string(),
+            origin: Origin {
+                file: file.to_string(),
+                line: index + 1,
+                expansions: vec![],
+            },
+        })
+        .collect()
+}
+
+/// The lines of the `.asm` file at `path`, with the extended syntax
+/// expanded when `extended` is set.
+pub fn read(path: &Path, extended: bool) -> Result<Vec<SourceLine>, Vec<AsmError>> {
+    if extended {
+        return asm_macros::expand(path);
+    }
+    let file = path.file_name().unwrap_or_default().to_string_lossy();
+    let text = fs::read_to_string(path).map_err(|error| {
+        let origin = Origin {
+            file: file.to_string(),
+            line: 0,
+            expansions: vec![],
+        };
+        vec![AsmError::new(&origin, error.to_string())]
+    })?;
+    Ok(lines(&file, &text))
+}
+
+/// A line without its comment and surrounding blanks.
+pub fn code(line: &str) -> &str {
+    line.split_once("//").map_or(line, |(code, _)| code).trim()
+}
+
+/// Whether `name` can be a label or variable: letters, digits, `_`, `.`,
+/// `$` and `:`, not starting with a digit.
+pub fn is_symbol(name: &str) -> bool {
+    name.chars().next().is_some_and(|c| !c.is_ascii_digit()) && name.chars().all(is_symbol_char)
+}
+
+pub fn is_symbol_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || "_.$:".contains(c)
+}
+
+/// What a symbol is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SymbolKind {
+    Label,
+    Variable,
+}
+
+/// An assembled program.
+#[derive(Debug, PartialEq)]
+pub struct Assembly {
+    pub words: Vec<u16>,
+    /// The address each source line assembled to, when it is an
+    /// instruction.
+    pub addresses: Vec<Option<usize>>,
+    /// The labels and variables, without the predefined symbols.
+    pub symbols: BTreeMap<String, (u16, SymbolKind)>,
+    /// Variables that look like a misspelled label.
+    pub warnings: Vec<AsmError>,
+}
+
+/// Where a symbol came from.
+enum Defined<'a> {
+    Predefined,
+    Label(&'a Origin),
+    /// A variable and its first use.
+    Variable(&'a Origin),
+}
+
+/// Assemble a program into its words.
+pub fn assemble(lines: &[SourceLine]) -> Result<Assembly, Vec<AsmError>> {
+    let mut errors = vec![];
+    let mut symbols: HashMap<String, (u16, Defined)> = PREDEFINED
+        .iter()
+        .map(|(name, value)| (name.to_string(), (*value, Defined::Predefined)))
+        .chain((0..16).map(|n| (format!("R{}", n), (n, Defined::Predefined))))
+        .collect();
+    let mut address: usize = 0;
+    for line in lines {
+        let code = code(&line.text);
+        if let Some(label) = code.strip_prefix('(') {
+            let label = label.strip_suffix(')').unwrap_or_default();
+            if !is_symbol(label) {
+                errors.push(AsmError::new(&line.origin, format!("{} is no label", code)));
+            } else if let Some((_, defined)) = symbols.get(label) {
+                let message = match defined {
+                    Defined::Label(origin) => {
+                        format!("{} is already defined at {}", label, origin)
+                    }
+                    _ => format!("{} is a predefined symbol", label),
+                };
+                errors.push(AsmError::new(&line.origin, message));
+            } else {
+                // past the ROM, which is an error below, the value sticks
+                let value = u16::try_from(address).unwrap_or(u16::MAX);
+                symbols.insert(label.to_string(), (value, Defined::Label(&line.origin)));
+            }
+        } else if !code.is_empty() {
+            address += 1;
+        }
+    }
+    if address > ROM_SIZE {
+        let origin = &lines.last().unwrap().origin;
+        errors.push(AsmError::new(
+            origin,
+            format!("{} instructions don't fit in the ROM", address),
+        ));
+    }
+    let mut variables = 16;
+    let mut words = vec![];
+    let mut addresses = vec![];
+    for line in lines {
+        let code = code(&line.text);
+        if code.is_empty() || code.starts_with('(') {
+            addresses.push(None);
+            continue;
+        }
+        addresses.push(Some(words.len()));
+        match encode(code, &mut |name| {
+            symbols
+                .entry(name.to_string())
+                .or_insert_with(|| {
+                    variables += 1;
+                    (variables - 1, Defined::Variable(&line.origin))
+                })
+                .0
+        }) {
+            Ok(word) => words.push(word),
+            Err(message) => {
+                words.push(0);
+                errors.push(AsmError::new(&line.origin, message));
+            }
+        }
+    }
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+    let labels: Vec<&str> = symbols
+        .iter()
+        .filter(|(_, (_, defined))| matches!(defined, Defined::Label(_)))
+        .map(|(name, _)| name.as_str())
+        .collect();
+    let mut warnings: Vec<AsmError> = symbols
+        .iter()
+        .filter_map(|(name, (_, defined))| {
+            let Defined::Variable(origin) = defined else {
+                return None;
+            };
+            let label = closest(name, labels.iter().copied())?;
+            let message = format!(
+                "@{} makes a new variable, did you mean the label {}?",
+                name, label
+            );
+            Some(AsmError::new(origin, message))
+        })
+        .collect();
+    warnings.sort_by_key(|warning| warning.origin.line);
+    let symbols = symbols
+        .into_iter()
+        .filter_map(|(name, (value, defined))| match defined {
+            Defined::Predefined => None,
+            Defined::Label(_) => Some((name, (value, SymbolKind::Label))),
+            Defined::Variable(_) => Some((name, (value, SymbolKind::Variable))),
+        })
+        .collect();
+    Ok(Assembly {
+        words,
+        addresses,
+        symbols,
+        warnings,
+    })
+}
+
+impl Assembly {
+    /// Each source line with its address and word, then the symbol table.
+    pub fn listing(&self, lines: &[SourceLine]) -> String {
+        let origins: Vec<String> = lines.iter().map(|line| line.origin.to_string()).collect();
+        let width = origins.iter().map(String::len).max().unwrap_or(0);
+        let mut text = String::new();
+        for ((line, origin), address) in lines.iter().zip(&origins).zip(&self.addresses) {
+            let (address, word) = match address {
+                Some(address) => (
+                    address.to_string(),
+                    format!("{:016b}", self.words[*address]),
+                ),
+                None => (String::new(), String::new()),
+            };
+            let row = format!(
+                "{:>5}  {:16}  {:width$}  {}",
+                address,
+                word,
+                origin,
+                line.text.trim_end(),
+                width = width
+            );
+            text += row.trim_end();
+            text.push('\n');
+        }
+        let width = self.symbols.keys().map(String::len).max().unwrap_or(0);
+        text += "\nsymbols:\n";
+        for (name, (value, kind)) in &self.symbols {
+            let kind = match kind {
+                SymbolKind::Label => "label",
+                SymbolKind::Variable => "variable",
+            };
+            text += &format!("  {:width$}  {:>5}  {}\n", name, value, kind, width = width);
+        }
+        text
+    }
+}
+
+/// Encode an instruction, looking symbols up with `symbol`.
+fn encode(code: &str, symbol: &mut impl FnMut(&str) -> u16) -> Result<u16, String> {
+    if let Some(value) = code.strip_prefix('@') {
+        if value.starts_with(|c: char| c.is_ascii_digit() || c == '-') {
+            return match value.parse::<u16>() {
+                Ok(value) if value < 0x8000 => Ok(value),
+                _ => Err(format!("@{} isn't a number from 0 to 32767", value)),
+            };
+        }
+        if !is_symbol(value) {
+            return Err(format!("@{} is no symbol", value));
+        }
+        // a label after the last word of a full ROM
+        return match symbol(value) {
+            word if word < 0x8000 => Ok(word),
+            word => Err(format!("@{} is {}, past 32767", value, word)),
+        };
+    }
+    if code.starts_with('.') {
+        let directive = code.split_whitespace().next().unwrap();
+        return Err(format!("{} needs the extended syntax", directive));
+    }
+    let code: String = code.chars().filter(|c| !c.is_whitespace()).collect();
+    let (dest, rest) = code.split_once('=').unwrap_or(("", &code));
+    let (comp, jump) = rest.split_once(';').unwrap_or((rest, ""));
+    let mut dest_bits = 0;
+    for register in dest.chars() {
+        let bit = match register {
+            'A' => 4,
+            'D' => 2,
+            'M' => 1,
+            _ => 8,
+        };
+        if dest_bits & bit != 0 || bit == 8 {
+            return Err(unknown("destination", dest, DESTS.into_iter()));
+        }
+        dest_bits |= bit;
+    }
+    // `M+D` is `D+M` the other way round
+    let swapped = comp
+        .split_once(['+', '&', '|'])
+        .map(|(x, y)| format!("{}{}{}", y, &comp[x.len()..x.len() + 1], x));
+    let Some((_, comp_bits)) = COMPS
+        .iter()
+        .find(|(name, _)| *name == comp || Some(*name) == swapped.as_deref())
+    else {
+        return Err(unknown(
+            "computation",
+            comp,
+            COMPS.iter().map(|(name, _)| *name),
+        ));
+    };
+    let jump_bits = match jump {
+        "" => 0,
+        jump => JUMPS
+            .iter()
+            .position(|name| *name == jump)
+            .ok_or_else(|| unknown("jump", jump, JUMPS.into_iter()))?,
+    };
+    Ok(0xe000 | comp_bits << 6 | dest_bits << 3 | jump_bits as u16)
+}
+
+/// `M+2 is no computation, did you mean M+1?`
+fn unknown<'a>(what: &str, name: &str, known: impl Iterator<Item = &'a str>) -> String {
+    match closest(name, known) {
+        Some(known) => format!("{} is no {}, did you mean {}?", name, what, known),
+        None => format!("{} is no {}", name, what),
+    }
+}
+
+/// The name in `known` a typo away from `name`, when there is one: at most
+/// two letters changed, added, dropped or swapped, and fewer than `name`
+/// has.
+fn closest<'a>(name: &str, known: impl Iterator<Item = &'a str>) -> Option<&'a str> {
+    let limit = 2.min(name.chars().count().saturating_sub(1));
+    known
+        .filter(|known| !known.is_empty())
+        .map(|known| (distance(name, known), known))
+        .filter(|(distance, _)| *distance <= limit)
+        .min()
+        .map(|(_, known)| known)
+}
+
+/// The edits from `a` to `b`, a swap of neighbours counting as one.
+fn distance(a: &str, b: &str) -> usize {
+    let a: Vec<char> = a.chars().collect();
+    let b: Vec<char> = b.chars().collect();
+    // rows i - 2, i - 1 and i of the table
+    let mut before: Vec<usize> = vec![0; b.len() + 1];
+    let mut last: Vec<usize> = (0..=b.len()).collect();
+    for i in 1..=a.len() {
+        let mut row = vec![i; b.len() + 1];
+        for j in 1..=b.len() {
+            let change = last[j - 1] + usize::from(a[i - 1] != b[j - 1]);
+            row[j] = change.min(last[j] + 1).min(row[j - 1] + 1);
+            if i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+                row[j] = row[j].min(before[j - 2] + 1);
+            }
+        }
+        before = std::mem::replace(&mut last, row);
+    }
+    last[b.len()]
+}
+
+/// The words as a `.hack` file.
+pub fn to_hack(words: &[u16]) -> String {
+    words
+        .iter()
+        .map(|word| format!("{:016b}\n", word))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu_emulator::parse_hack;
+    use crate::disassembler::disassemble;
+
+    #[test]
+    fn assembles_symbols_and_reassembles_disassembly() {
+        let source = "// Max
+            @R0
+            D=M              // D = first number
+            @R1
+            D = D - M
+            @OUTPUT_FIRST
+            D; JGT
+            @R1
+            D=M
+            @OUTPUT_D
+            0;JMP
+            (OUTPUT_FIRST)
+            @R0
+            D=M
+            (OUTPUT_D)
+            @max
+            M=D
+            (INFINITE_LOOP)
+            @INFINITE_LOOP
+            0;JMP
+            @SCREEN
+            DM=-1
+            M=M+D";
+        let words = assemble(&lines("Max.asm", source)).unwrap().words;
+        assert_eq!(&words[..5], [0, 0xfc10, 1, 0xf4d0, 10]);
+        // @max is the first variable
+        assert_eq!(words[12], 16);
+        assert_eq!(&words[16..], [16384, 0xee98, 0xf088]);
+        let text = disassemble(&words, true).text;
+        assert_eq!(assemble(&lines("", &text)).unwrap().words, words);
+        assert_eq!(parse_hack(&to_hack(&words)).unwrap(), words);
+
+        let errors = assemble(&lines(
+            "Bad.asm",
+            "(LOOP)\n@40000\nD=M+2\nAA=1\n(LOOP)\n0;JPM\n.define X 1\n(R1)\n@-1\nD=Q",
+        ))
+        .unwrap_err();
+        let errors: Vec<String> = errors.iter().map(AsmError::to_string).collect();
+        assert_eq!(
+            errors,
+            [
+                "Bad.asm:5: LOOP is already defined at Bad.asm:1",
+                "Bad.asm:8: R1 is a predefined symbol",
+                "Bad.asm:2: @40000 isn't a number from 0 to 32767",
+                "Bad.asm:3: M+2 is no computation, did you mean M+1?",
+                "Bad.asm:4: AA is no destination, did you mean A?",
+                "Bad.asm:6: JPM is no jump, did you mean JMP?",
+                "Bad.asm:7: .define needs the extended syntax",
+                "Bad.asm:9: @-1 isn't a number from 0 to 32767",
+                "Bad.asm:10: Q is no computation",
+            ]
+        );
+
+        // a full ROM, then one instruction too many
+        let full = "@END\n0;JMP\n".repeat(ROM_SIZE / 2) + "(END)\n";
+        let errors = assemble(&lines("Full.asm", &full)).unwrap_err();
+        let errors: Vec<String> = errors.iter().map(AsmError::to_string).collect();
+        assert_eq!(errors[0], "Full.asm:1: @END is 32768, past 32767");
+        assert_eq!(errors.len(), ROM_SIZE / 2);
+        let errors = assemble(&lines("Big.asm", &"D=0\n".repeat(65540))).unwrap_err();
+        assert_eq!(
+            errors[0].to_string(),
+            "Big.asm:65540: 65540 instructions don't fit in the ROM"
+        );
+    }
+
+    #[test]
+    fn lists_addresses_and_symbols_and_warns_of_typos() {
+        let source = lines(
+            "Loop.asm",
+            "// count down\n@10\nD=A\n(LOOP)\n  D=D-1  // again\n@count\nM=D\n@LOPP\nD;JGT",
+        );
+        let assembly = assemble(&source).unwrap();
+        let listing = [
+            "                         Loop.asm:1  // count down",
+            "    0  0000000000001010  Loop.asm:2  @10",
+            "    1  1110110000010000  Loop.asm:3  D=A",
+            "                         Loop.asm:4  (LOOP)",
+            "    2  1110001110010000  Loop.asm:5    D=D-1  // again",
+            "    3  0000000000010000  Loop.asm:6  @count",
+            "    4  1110001100001000  Loop.asm:7  M=D",
+            "    5  0000000000010001  Loop.asm:8  @LOPP",
+            "    6  1110001100000001  Loop.asm:9  D;JGT",
+            "",
+            "symbols:",
+            "  LOOP       2  label",
+            "  LOPP      17  variable",
+            "  count     16  variable",
+            "",
+        ];
+        assert_eq!(assembly.listing(&source), listing.join("\n"));
+        let warnings: Vec<String> = assembly.warnings.iter().map(AsmError::to_string).collect();
+        assert_eq!(
+            warnings,
+            ["Loop.asm:8: @LOPP makes a new variable, did you mean the label LOOP?"]
+        );
+    }
+}
@@ -0,0 +1,35 @@
+// common interface shared by every parse tree emitter
+
+use crate::{
+    parser::{Node, Span},
+    tokenizer::TokenSpan,
+};
+
+pub trait Backend {
+    /// Serialise `node` and everything below it.
+    fn convert_node(&self, node: &Node) -> String;
+}
+
+/// Map a token index range back to the source text it was parsed from.
+pub fn resolve_span(spans: &[TokenSpan], span: &Span) -> Option<TokenSpan> {
+    let first = spans.get(span.start)?;
+    if span.end <= span.start {
+        return Some(TokenSpan {
+            start: first.start,
+            end: first.start,
+        });
+    }
+    let last = spans.get(span.end - 1)?;
+    Some(TokenSpan {
+        start: first.start,
+        end: last.end,
+    })
+}
+
+/// Read back a `Span` that went through serde, as done by the serde based backends.
+pub fn span_from_value(value: &serde_json::Value) -> Option<Span> {
+    Some(Span {
+        start: value.get("start")?.as_u64()? as usize,
+        end: value.get("end")?.as_u64()? as usize,
+    })
+}
@@ -0,0 +1,74 @@
+// which compiler a build is
+//
+// `build.rs` includes this file to hash the compiler's own sources, and
+// passes the hash on to the crate as `COMPILER_SOURCES`. The `--cache`
+// entries are keyed on it, so any edit to the tokenizer, parser, code
+// generator or optimiser makes every entry stale, where the package version
+// would stay the same.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// 64-bit FNV-1a, stable across runs and toolchains unlike `DefaultHasher`.
+pub fn hash(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+/// `Cargo.toml` and every file under `src`, in path order.
+fn source_files(dir: &Path) -> Vec<PathBuf> {
+    fn walk(dir: &Path, files: &mut Vec<PathBuf>) {
+        for entry in fs::read_dir(dir).unwrap() {
+            let path = entry.unwrap().path();
+            if path.is_dir() {
+                walk(&path, files);
+            } else {
+                files.push(path);
+            }
+        }
+    }
+    let mut files = vec![dir.join("Cargo.toml")];
+    walk(&dir.join("src"), &mut files);
+    files.sort();
+    files
+}
+
+/// The hash of the names and contents of the sources of the crate in `dir`,
+/// as 16 hex digits. Only `build.rs` and the tests call it.
+#[cfg_attr(not(test), allow(dead_code))]
+pub fn sources_hash(dir: &Path) -> String {
+    let mut bytes = vec![];
+    for file in source_files(dir) {
+        let name = file.strip_prefix(dir).unwrap().to_string_lossy();
+        bytes.extend(name.as_bytes());
+        bytes.push(0);
+        bytes.extend(fs::read(&file).unwrap());
+        bytes.push(0);
+    }
+    format!("{:016x}", hash(&bytes))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hashes_change_with_any_source() {
+        let dir = std::env::temp_dir().join(format!("build-id-{}", std::process::id()));
+        fs::create_dir_all(dir.join("src/nested")).unwrap();
+        fs::write(dir.join("Cargo.toml"), "[package]\n").unwrap();
+        fs::write(dir.join("src/opt.rs"), "fn a() {}\n").unwrap();
+        fs::write(dir.join("src/nested/vm.rs"), "fn b() {}\n").unwrap();
+        let first = sources_hash(&dir);
+        assert_eq!(sources_hash(&dir), first);
+        fs::write(dir.join("src/opt.rs"), "fn a() { }\n").unwrap();
+        let edited = sources_hash(&dir);
+        assert_ne!(edited, first);
+        fs::write(dir.join("src/nested/vm.rs"), "fn c() {}\n").unwrap();
+        assert_ne!(sources_hash(&dir), edited);
+        fs::rename(dir.join("src/opt.rs"), dir.join("src/lower.rs")).unwrap();
+        assert_ne!(sources_hash(&dir), edited);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
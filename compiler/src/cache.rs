@@ -0,0 +1,170 @@
+// on-disk cache of compiled classes
+//
+// With `--cache`, each class is kept in `.jack-cache/<Class>.json` next to
+// its source: the parse tree, token spans, class symbol table and generated
+// VM code. An entry is only used for the same source text, the same
+// compiler, as told by the hash of its sources in `build_id`, and the same
+// `-O` setting. Nothing else can make it stale: a class is compiled on its
+// own, a call to another class becoming `call Class.name n` from the call
+// site alone, so the VM code never depends on what other classes declare.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    build_id::hash, parser::structures::Class, symbol_table::SymbolTable, tokenizer::TokenSpan,
+    vm::MappedCommand,
+};
+
+const DIR: &str = ".jack-cache";
+
+/// The compiler this is, changing with any edit to its sources.
+const VERSION: &str = env!("COMPILER_SOURCES");
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Entry {
+    version: String,
+    source_hash: u64,
+    optimize: bool,
+    pub class: Class,
+    pub token_spans: Vec<TokenSpan>,
+    pub symbols: SymbolTable,
+    pub commands: Vec<MappedCommand>,
+    /// VM instructions before optimisation, for the `-O` report.
+    pub unoptimized: usize,
+}
+
+impl Entry {
+    pub fn new(
+        source: &str,
+        optimize: bool,
+        class: Class,
+        token_spans: Vec<TokenSpan>,
+        commands: Vec<MappedCommand>,
+        unoptimized: usize,
+    ) -> Entry {
+        Entry {
+            version: VERSION.to_string(),
+            source_hash: hash(source.as_bytes()),
+            optimize,
+            symbols: SymbolTable::for_class(&class),
+            class,
+            token_spans,
+            commands,
+            unoptimized,
+        }
+    }
+}
+
+pub struct Cache {
+    optimize: bool,
+}
+
+impl Cache {
+    pub fn new(optimize: bool) -> Cache {
+        Cache { optimize }
+    }
+
+    fn path(file: &Path) -> PathBuf {
+        let dir = file.parent().unwrap_or(Path::new(""));
+        let name = file.file_stem().unwrap().to_string_lossy();
+        dir.join(DIR).join(format!("{}.json", name))
+    }
+
+    /// The entry for `file` if it was made from `source` by this compiler
+    /// with the same settings. Unreadable entries count as missing.
+    pub fn load(&self, file: &Path, source: &str) -> Option<Entry> {
+        let text = fs::read_to_string(Cache::path(file)).ok()?;
+        let entry: Entry = serde_json::from_str(&text).ok()?;
+        (entry.version == VERSION
+            && entry.source_hash == hash(source.as_bytes())
+            && entry.optimize == self.optimize)
+            .then_some(entry)
+    }
+
+    pub fn store(&self, file: &Path, entry: &Entry) {
+        let path = Cache::path(file);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, serde_json::to_string(entry).unwrap()).unwrap();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::{Parsable, TokenReader};
+    use crate::tokenizer::tokenize_str;
+
+    fn parse(source: &str) -> Class {
+        let tokens = tokenize_str(source);
+        Class::try_parse(&TokenReader { tokens }, 0).unwrap().0
+    }
+
+    #[test]
+    fn round_trips_entries() {
+        let dir = std::env::temp_dir().join(format!("jack-cache-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let file = dir.join("B.jack");
+        let source = "class B { function void g() { do A.f(1); return; } }";
+        let cache = Cache::new(false);
+        let commands = vec![("function B.g 0".to_string(), 1)];
+        let entry = Entry::new(source, false, parse(source), vec![], commands.clone(), 1);
+        cache.store(&file, &entry);
+        assert!(cache.load(&file, "class B {}").is_none());
+        assert!(Cache::new(true).load(&file, source).is_none());
+        let loaded = cache.load(&file, source).unwrap();
+        assert_eq!(loaded.commands, commands);
+        assert_eq!(
+            serde_json::to_value(&loaded.class).unwrap(),
+            serde_json::to_value(parse(source)).unwrap()
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn callers_stay_right_when_a_callee_changes_signature() {
+        let dir = std::env::temp_dir().join(format!("jack-signature-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let (main, b) = (dir.join("Main.jack"), dir.join("B.jack"));
+        fs::write(
+            &main,
+            "class Main { function void main() { var int x;
+             let x = B.g(); do B.f(x, 2); return; } }",
+        )
+        .unwrap();
+        fs::write(
+            &b,
+            "class B { function int g() { return 1; }
+             function void f(int a, int b) { return; } }",
+        )
+        .unwrap();
+        let files = [b.clone(), main.clone()];
+        crate::compile_cached(&files, false, false).unwrap();
+        fs::write(
+            &b,
+            "class B { function boolean g() { return true; }
+             method int f(boolean a) { return 0; } }",
+        )
+        .unwrap();
+        let source = fs::read_to_string(&main).unwrap();
+        assert!(Cache::new(false).load(&main, &source).is_some());
+        let entries = crate::compile_cached(&files, false, false).unwrap();
+        // the reused VM code is what compiling the whole program anew gives
+        for (file, entry) in entries {
+            let source = fs::read_to_string(&file).unwrap();
+            let (_, commands, _) = crate::compile_file(&file, &source, false).unwrap();
+            assert_eq!(entry.commands, commands, "{}", file.display());
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn keys_entries_on_the_compiler_sources() {
+        // stale if the key outlived an edit to the compiler
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR"));
+        assert_eq!(VERSION, crate::build_id::sources_hash(dir));
+        assert_ne!(VERSION, env!("CARGO_PKG_VERSION"));
+    }
+}
@@ -0,0 +1,589 @@
+// Hack CPU emulator
+//
+// Runs `.hack` machine code an instruction a cycle, the way the Computer
+// chip does: the A, D and PC registers, 32K words of ROM and the data memory
+// with the screen at 16384 and the keyboard at 24576. The ALU is the one the
+// HDL simulator's built-in CPU uses, so both agree on what an instruction
+// does. A program is done when it jumps to the `@n` that loads its own
+// address, the `(END) @END 0;JMP` loop Hack programs end with.
+//
+// `step` decodes every instruction as it runs it and reports what it wrote,
+// for tracing. To run whole games, the ROM is also decoded once up front
+// and split into basic blocks, runs of instructions ending at a jump, which
+// `run` executes with the registers in locals, checking for the end of the
+// program and for keys only between blocks.
+
+use std::collections::HashMap;
+
+use crate::hdl_builtin::alu;
+use crate::keyboard::{KeyPlayer, KEYBOARD};
+use crate::vcd::Vcd;
+use crate::vm_emulator::{State, RAM_SIZE};
+
+pub const ROM_SIZE: usize = 32768;
+
+/// The words of a `.hack` file, one line of 16 binary digits each.
+pub fn parse_hack(text: &str) -> Result<Vec<u16>, String> {
+    let words = text
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .enumerate()
+        .map(|(index, line)| {
+            u16::from_str_radix(line, 2)
+                .ok()
+                .filter(|_| line.len() == 16)
+                .ok_or_else(|| format!("word {} is not 16 binary digits", index))
+        })
+        .collect::<Result<Vec<u16>, String>>()?;
+    if words.len() > ROM_SIZE {
+        return Err(format!("{} words don't fit in the ROM", words.len()));
+    }
+    Ok(words)
+}
+
+/// How `run_mode` runs instructions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    /// Decoding each as it runs, with `step`.
+    Step,
+    /// One predecoded instruction at a time.
+    Decoded,
+    /// A predecoded basic block at a time.
+    Blocks,
+}
+
+/// A C-instruction's computation, `Other` being the ALU control bits and
+/// `a` bit of one outside the Hack table.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Comp {
+    Zero,
+    One,
+    MinusOne,
+    D,
+    A,
+    M,
+    NotD,
+    NotA,
+    NotM,
+    NegD,
+    NegA,
+    NegM,
+    IncD,
+    IncA,
+    IncM,
+    DecD,
+    DecA,
+    DecM,
+    DPlusA,
+    DPlusM,
+    DMinusA,
+    DMinusM,
+    AMinusD,
+    MMinusD,
+    DAndA,
+    DAndM,
+    DOrA,
+    DOrM,
+    Other(u16),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Decoded {
+    /// `@value`
+    Load(u16),
+    Compute {
+        comp: Comp,
+        dest: u16,
+        jump: u16,
+    },
+}
+
+impl Decoded {
+    fn new(word: u16) -> Decoded {
+        if word & 0x8000 == 0 {
+            return Decoded::Load(word);
+        }
+        // the `a c1..c6` bits, as in `disassembler::COMPS`
+        let bits = word >> 6 & 0x7f;
+        let comp = match bits {
+            0b0101010 => Comp::Zero,
+            0b0111111 => Comp::One,
+            0b0111010 => Comp::MinusOne,
+            0b0001100 => Comp::D,
+            0b0110000 => Comp::A,
+            0b1110000 => Comp::M,
+            0b0001101 => Comp::NotD,
+            0b0110001 => Comp::NotA,
+            0b1110001 => Comp::NotM,
+            0b0001111 => Comp::NegD,
+            0b0110011 => Comp::NegA,
+            0b1110011 => Comp::NegM,
+            0b0011111 => Comp::IncD,
+            0b0110111 => Comp::IncA,
+            0b1110111 => Comp::IncM,
+            0b0001110 => Comp::DecD,
+            0b0110010 => Comp::DecA,
+            0b1110010 => Comp::DecM,
+            0b0000010 => Comp::DPlusA,
+            0b1000010 => Comp::DPlusM,
+            0b0010011 => Comp::DMinusA,
+            0b1010011 => Comp::DMinusM,
+            0b0000111 => Comp::AMinusD,
+            0b1000111 => Comp::MMinusD,
+            0b0000000 => Comp::DAndA,
+            0b1000000 => Comp::DAndM,
+            0b0010101 => Comp::DOrA,
+            0b1010101 => Comp::DOrM,
+            _ => Comp::Other(bits),
+        };
+        Decoded::Compute {
+            comp,
+            dest: word >> 3 & 7,
+            jump: word & 7,
+        }
+    }
+
+    fn jumps(&self) -> bool {
+        matches!(self, Decoded::Compute { jump, .. } if *jump != 0)
+    }
+}
+
+fn compute(comp: Comp, a: u16, d: u16, m: u16) -> u16 {
+    match comp {
+        Comp::Zero => 0,
+        Comp::One => 1,
+        Comp::MinusOne => 0xffff,
+        Comp::D => d,
+        Comp::A => a,
+        Comp::M => m,
+        Comp::NotD => !d,
+        Comp::NotA => !a,
+        Comp::NotM => !m,
+        Comp::NegD => d.wrapping_neg(),
+        Comp::NegA => a.wrapping_neg(),
+        Comp::NegM => m.wrapping_neg(),
+        Comp::IncD => d.wrapping_add(1),
+        Comp::IncA => a.wrapping_add(1),
+        Comp::IncM => m.wrapping_add(1),
+        Comp::DecD => d.wrapping_sub(1),
+        Comp::DecA => a.wrapping_sub(1),
+        Comp::DecM => m.wrapping_sub(1),
+        Comp::DPlusA => d.wrapping_add(a),
+        Comp::DPlusM => d.wrapping_add(m),
+        Comp::DMinusA => d.wrapping_sub(a),
+        Comp::DMinusM => d.wrapping_sub(m),
+        Comp::AMinusD => a.wrapping_sub(d),
+        Comp::MMinusD => m.wrapping_sub(d),
+        Comp::DAndA => d & a,
+        Comp::DAndM => d & m,
+        Comp::DOrA => d | a,
+        Comp::DOrM => d | m,
+        Comp::Other(bits) => {
+            let y = if bits & 0x40 != 0 { m } else { a };
+            alu(d, y, (bits & 0x3f) as u64)
+        }
+    }
+}
+
+/// Whether the jump bits `jump` jump on `out`.
+fn jumps(jump: u16, out: u16) -> bool {
+    let out = out as i16;
+    (jump & 4 != 0 && out < 0) || (jump & 2 != 0 && out == 0) || (jump & 1 != 0 && out > 0)
+}
+
+/// The registers as `run_mode` keeps them.
+struct Registers {
+    a: u16,
+    d: u16,
+}
+
+impl Registers {
+    /// Run a C-instruction, returning its output.
+    #[inline(always)]
+    fn compute(&mut self, ram: &mut [i16], comp: Comp, dest: u16) -> u16 {
+        let address = self.a as usize & (RAM_SIZE - 1);
+        let out = compute(comp, self.a, self.d, ram[address] as u16);
+        if dest & 1 != 0 {
+            ram[address] = out as i16;
+        }
+        if dest & 2 != 0 {
+            self.d = out;
+        }
+        if dest & 4 != 0 {
+            self.a = out;
+        }
+        out
+    }
+}
+
+pub struct CpuEmulator {
+    /// The program, which must not change once the emulator is made.
+    pub rom: Vec<u16>,
+    pub ram: Vec<i16>,
+    pub a: u16,
+    pub d: u16,
+    pub pc: u16,
+    pub cycles: u64,
+    pub state: State,
+    /// Drives the keyboard register, counting cycles in instructions.
+    pub keys: KeyPlayer,
+    decoded: Vec<Decoded>,
+    /// The address of the jump, or the last instruction of the ROM, that
+    /// ends the basic block each address is in.
+    block_ends: Vec<usize>,
+}
+
+/// What one instruction did, for tracing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Step {
+    pub instruction: u16,
+    /// `(address, value)` when it wrote to memory.
+    pub write: Option<(usize, i16)>,
+}
+
+impl CpuEmulator {
+    pub fn new(program: &[u16]) -> CpuEmulator {
+        let mut rom = program.to_vec();
+        rom.resize(ROM_SIZE, 0);
+        let decoded: Vec<Decoded> = rom.iter().map(|word| Decoded::new(*word)).collect();
+        let mut block_ends = vec![ROM_SIZE - 1; ROM_SIZE];
+        for address in (0..ROM_SIZE - 1).rev() {
+            block_ends[address] = if decoded[address].jumps() {
+                address
+            } else {
+                block_ends[address + 1]
+            };
+        }
+        CpuEmulator {
+            rom,
+            ram: vec![0; RAM_SIZE],
+            a: 0,
+            d: 0,
+            pc: 0,
+            cycles: 0,
+            state: State::Running,
+            keys: KeyPlayer::default(),
+            decoded,
+            block_ends,
+        }
+    }
+
+    /// Run one instruction.
+    pub fn step(&mut self) -> Step {
+        if let Some(key) = self.keys.poll(self.cycles) {
+            self.ram[KEYBOARD] = key;
+        }
+        self.cycles += 1;
+        let pc = self.pc;
+        let instruction = self.rom[pc as usize & (ROM_SIZE - 1)];
+        let mut step = Step {
+            instruction,
+            write: None,
+        };
+        if instruction & 0x8000 == 0 {
+            self.a = instruction;
+            self.pc = pc.wrapping_add(1);
+            return step;
+        }
+        let bit = |n: u16| instruction >> n & 1 == 1;
+        // M is the word at A, out of the 32K address space's top bit
+        let address = self.a as usize & (RAM_SIZE - 1);
+        let y = if bit(12) {
+            self.ram[address] as u16
+        } else {
+            self.a
+        };
+        let out = alu(self.d, y, (instruction >> 6 & 0x3f) as u64);
+        if bit(3) {
+            self.ram[address] = out as i16;
+            step.write = Some((address, out as i16));
+        }
+        let (zr, ng) = (out == 0, out >> 15 == 1);
+        let jump = (bit(2) && ng) || (bit(1) && zr) || (bit(0) && !ng && !zr);
+        // the jump goes to A as it was before this instruction
+        let target = self.a;
+        if bit(5) {
+            self.a = out;
+        }
+        if bit(4) {
+            self.d = out;
+        }
+        if jump {
+            self.pc = target;
+            if target == pc.wrapping_sub(1) && self.rom[target as usize & (ROM_SIZE - 1)] == target
+            {
+                self.state = State::Halted;
+            }
+        } else {
+            self.pc = pc.wrapping_add(1);
+        }
+        step
+    }
+
+    /// Run until the program halts or `max_cycles` have run.
+    pub fn run(&mut self, max_cycles: u64) -> State {
+        self.run_mode(Mode::Blocks, max_cycles)
+    }
+
+    /// Run like `run`, the way `mode` says. Blocks read a key that comes in
+    /// the middle of one at its end.
+    pub fn run_mode(&mut self, mode: Mode, max_cycles: u64) -> State {
+        match mode {
+            Mode::Step => {
+                while self.state == State::Running && self.cycles < max_cycles {
+                    self.step();
+                }
+            }
+            Mode::Decoded => self.run_decoded(max_cycles, false),
+            Mode::Blocks => self.run_decoded(max_cycles, true),
+        }
+        self.state
+    }
+
+    fn run_decoded(&mut self, max_cycles: u64, blocks: bool) {
+        let mut registers = Registers {
+            a: self.a,
+            d: self.d,
+        };
+        let mut pc = self.pc;
+        while self.state == State::Running && self.cycles < max_cycles {
+            if let Some(key) = self.keys.poll(self.cycles) {
+                self.ram[KEYBOARD] = key;
+            }
+            let start = pc as usize & (ROM_SIZE - 1);
+            let end = if blocks {
+                self.block_ends[start].min(start + (max_cycles - self.cycles) as usize - 1)
+            } else {
+                start
+            };
+            let ram = &mut self.ram[..];
+            for decoded in &self.decoded[start..end] {
+                match *decoded {
+                    Decoded::Load(value) => registers.a = value,
+                    Decoded::Compute { comp, dest, .. } => {
+                        registers.compute(ram, comp, dest);
+                    }
+                }
+            }
+            let last = pc.wrapping_add((end - start) as u16);
+            self.cycles += (end - start + 1) as u64;
+            pc = last.wrapping_add(1);
+            match self.decoded[end] {
+                Decoded::Load(value) => registers.a = value,
+                Decoded::Compute { comp, dest, jump } => {
+                    // the jump goes to A as it was before this instruction
+                    let target = registers.a;
+                    let out = registers.compute(ram, comp, dest);
+                    if jumps(jump, out) {
+                        pc = target;
+                        let halts = self.rom[target as usize & (ROM_SIZE - 1)] == target;
+                        if target == last.wrapping_sub(1) && halts {
+                            self.state = State::Halted;
+                        }
+                    }
+                }
+            }
+        }
+        self.a = registers.a;
+        self.d = registers.d;
+        self.pc = pc;
+    }
+}
+
+/// A VCD waveform of a run, a time unit per instruction: the registers,
+/// the instruction, the memory write signals and `RAM_n` for each word
+/// from when it is first written.
+#[derive(Debug)]
+pub struct Trace {
+    vcd: Vcd,
+    /// pc, A, D, instruction, writeM, addressM and outM
+    registers: [Option<usize>; 7],
+    memory: HashMap<usize, Option<usize>>,
+}
+
+impl Trace {
+    pub fn new(cpu: &CpuEmulator, filter: Option<&str>) -> Trace {
+        let mut vcd = Vcd::new("CPU", filter);
+        let registers = [
+            ("pc", 15),
+            ("A", 16),
+            ("D", 16),
+            ("instruction", 16),
+            ("writeM", 1),
+            ("addressM", 15),
+            ("outM", 16),
+        ]
+        .map(|(name, width)| vcd.add(name, width));
+        let mut trace = Trace {
+            vcd,
+            registers,
+            memory: HashMap::new(),
+        };
+        let instruction = cpu.rom[cpu.pc as usize & (ROM_SIZE - 1)];
+        trace.sample(cpu, instruction, None);
+        trace
+    }
+
+    /// Record the state after `step`.
+    pub fn record(&mut self, cpu: &CpuEmulator, step: &Step) {
+        self.sample(cpu, step.instruction, step.write);
+    }
+
+    fn sample(&mut self, cpu: &CpuEmulator, instruction: u16, write: Option<(usize, i16)>) {
+        let (address, value) = write.unwrap_or((0, 0));
+        let values = [
+            cpu.pc as u64,
+            cpu.a as u64,
+            cpu.d as u64,
+            instruction as u64,
+            write.is_some() as u64,
+            address as u64,
+            value as u16 as u64,
+        ];
+        for (signal, value) in self.registers.into_iter().zip(values) {
+            if let Some(signal) = signal {
+                self.vcd.change(cpu.cycles, signal, value);
+            }
+        }
+        if let Some((address, value)) = write {
+            let vcd = &mut self.vcd;
+            let signal = *self
+                .memory
+                .entry(address)
+                .or_insert_with(|| vcd.add(&format!("RAM_{}", address), 16));
+            if let Some(signal) = signal {
+                self.vcd.change(cpu.cycles, signal, value as u16 as u64);
+            }
+        }
+    }
+
+    pub fn finish(&self) -> String {
+        self.vcd.finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `06/max/Max.asm`: RAM[2] = max(RAM[0], RAM[1])
+    const MAX: &str = "0000000000000000\n1111110000010000\n0000000000000001\n\
+                       1111010011010000\n0000000000001010\n1110001100000001\n\
+                       0000000000000001\n1111110000010000\n0000000000001100\n\
+                       1110101010000111\n0000000000000000\n1111110000010000\n\
+                       0000000000000010\n1110001100001000\n0000000000001110\n\
+                       1110101010000111\n";
+
+    #[test]
+    fn runs_programs_until_they_loop_on_themselves() {
+        for (x, y) in [(3, 7), (-2, -9)] {
+            let mut cpu = CpuEmulator::new(&parse_hack(MAX).unwrap());
+            cpu.ram[0] = x;
+            cpu.ram[1] = y;
+            assert_eq!(cpu.run(1000), State::Halted);
+            assert_eq!(cpu.ram[2], x.max(y));
+            assert_eq!(cpu.pc, 14);
+        }
+        // A=-1, 0;JMP runs on from ROM[32767], the top bit being ignored
+        let mut cpu = CpuEmulator::new(&[0xeea0, 0xea87]);
+        assert_eq!(cpu.run(10), State::Running);
+        assert_eq!(cpu.a, 0xffff);
+        assert_eq!(
+            parse_hack("0101\n").unwrap_err(),
+            "word 0 is not 16 binary digits"
+        );
+    }
+
+    #[test]
+    fn reports_writes_and_reads_the_keyboard() {
+        // D=M at the keyboard, then M=D at RAM[0]
+        let program = [0x6000, 0xfc10, 0x0000, 0xe308];
+        let mut cpu = CpuEmulator::new(&program);
+        cpu.keys = KeyPlayer::new(crate::keyboard::KeyScript::parse("press q at 1").unwrap());
+        for _ in 0..3 {
+            assert_eq!(cpu.step().write, None);
+        }
+        assert_eq!(cpu.step().write, Some((0, 113)));
+        assert_eq!(cpu.state, State::Running);
+    }
+
+    #[test]
+    fn decodes_computations_like_the_alu() {
+        for bits in 0..0x80 {
+            let Decoded::Compute { comp, .. } = Decoded::new(0xe000 | bits << 6) else {
+                unreachable!()
+            };
+            let named = crate::disassembler::COMPS
+                .iter()
+                .any(|(_, comp)| *comp == bits);
+            assert_eq!(comp == Comp::Other(bits), !named, "{:07b}", bits);
+            for (a, d, m) in [(0, 0, 0), (1234, 0xfff0, 7), (0x8000, 1, 0xffff)] {
+                let y = if bits & 0x40 != 0 { m } else { a };
+                let expected = alu(d, y, (bits & 0x3f) as u64);
+                assert_eq!(compute(comp, a, d, m), expected, "{:07b}", bits);
+            }
+        }
+    }
+
+    #[test]
+    fn runs_the_same_in_every_mode() {
+        // RAM[1] = RAM[0] * 3 by repeated addition, drawing each partial
+        // sum on the screen, the first D=0 swapped for an ALU control no
+        // assembler makes
+        let source = "D=0\n@R1\nM=0\n@R2\nM=0\n(LOOP)\n@R2\nD=M\n@3\nD=D-A\n@END\nD;JGE\n\
+                      @R0\nD=M\n@R1\nM=D+M\nD=M\n@SCREEN\nA=A+1\nM=D\n@R2\nM=M+1\n\
+                      @LOOP\n0;JMP\n(END)\n@END\n0;JMP";
+        let lines = crate::assembler::lines("Triple.asm", source);
+        let mut program = crate::assembler::assemble(&lines).unwrap().words;
+        program[0] = 0xe000 | 0b0000101 << 6 | 0b010 << 3;
+        let reference = {
+            let mut cpu = CpuEmulator::new(&program);
+            cpu.ram[0] = 12345;
+            cpu.run_mode(Mode::Step, 10_000);
+            cpu
+        };
+        assert_eq!(reference.state, State::Halted);
+        assert_eq!(reference.ram[1], 12345_i16.wrapping_mul(3));
+        for mode in [Mode::Decoded, Mode::Blocks] {
+            for max_cycles in [1, 7, 40, 10_000] {
+                let mut cpu = CpuEmulator::new(&program);
+                cpu.ram[0] = 12345;
+                cpu.run_mode(mode, max_cycles);
+                let mut stepped = CpuEmulator::new(&program);
+                stepped.ram[0] = 12345;
+                stepped.run_mode(Mode::Step, max_cycles);
+                assert_eq!(
+                    (cpu.a, cpu.d, cpu.pc, cpu.cycles, cpu.state, &cpu.ram),
+                    (
+                        stepped.a,
+                        stepped.d,
+                        stepped.pc,
+                        stepped.cycles,
+                        stepped.state,
+                        &stepped.ram
+                    ),
+                    "{:?} for {} cycles",
+                    mode,
+                    max_cycles
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn traces_registers_and_written_words() {
+        let mut cpu = CpuEmulator::new(&parse_hack(MAX).unwrap());
+        cpu.ram[0] = 3;
+        cpu.ram[1] = 7;
+        let mut trace = Trace::new(&cpu, Some("D,RAM_*"));
+        while cpu.state == State::Running {
+            let step = cpu.step();
+            trace.record(&cpu, &step);
+        }
+        let vcd = trace.finish();
+        assert!(vcd.contains("$var wire 16 ! D $end\n$var wire 16 \" RAM_2 $end\n"));
+        assert!(vcd.contains("#0\nb0 !\n#2\nb11 !\n"));
+        assert!(vcd.ends_with("b111 \"\n"));
+    }
+}
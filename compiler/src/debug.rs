@@ -0,0 +1,583 @@
+// source-level debugger for Jack programs running on the VM emulator
+//
+// Commands are read one per line, so a session can be scripted by piping a
+// file into `compiler debug`. Jack locations come from the same source maps
+// that `--source-map` writes, variable names from the symbol table.
+
+use std::collections::HashMap;
+use std::io::{BufRead, Write};
+
+use crate::ir::{Function, Ty};
+use crate::keyboard::{KeyPlayer, KeyScript};
+use crate::os::NativeOs;
+use crate::parser::structures::Class;
+use crate::sourcemap::SourceMap;
+use crate::symbol_table::{Kind, SymbolTable};
+use crate::vm;
+use crate::vm_emulator::{Command, Program, State, VmEmulator, THIS};
+
+const HELP: &str = "\
+break <Class.subroutine | File.jack:line>   set a breakpoint (b)
+delete <n>                                   remove breakpoint n
+breakpoints                                  list breakpoints and watches
+watch <address | variable>                   stop when a RAM word changes
+unwatch <address>                            remove a watch
+continue                                     run to the next stop (c, run)
+step / next                                  next Jack statement, into / over calls (s, n)
+stepi / nexti                                next VM command, into / over calls (si, ni)
+finish                                       run until the current subroutine returns
+print <variable>                             show a local, argument, field or static (p)
+locals / fields / statics                    show every variable of that kind
+backtrace                                    show the call stack (bt)
+x <address> [count]                          show RAM words
+where                                        show the current position
+quit                                         leave the debugger (q)";
+
+type Location = Option<(String, u32)>;
+
+struct Breakpoint {
+    spec: String,
+    pcs: Vec<usize>,
+}
+
+/// Why a run stopped.
+enum Stop {
+    Done,
+    Breakpoint(usize),
+    Watch(usize, i16, i16),
+    Halted,
+    Limit,
+    Error(String),
+}
+
+pub struct Debugger {
+    emulator: VmEmulator,
+    /// Text of every VM command, for showing where execution is.
+    texts: Vec<String>,
+    /// Jack location of every VM command.
+    locations: Vec<Location>,
+    /// Symbols of every function by VM name, e.g. `Main.main`.
+    symbols: HashMap<String, SymbolTable>,
+    breakpoints: Vec<Option<Breakpoint>>,
+    watches: Vec<(usize, i16)>,
+    /// Commands a single run may execute before giving up.
+    pub max_steps: u64,
+}
+
+impl Debugger {
+    /// Debug classes compiled from Jack, `file_name` being the `.jack` file
+    /// of each. `native` picks the native OS classes, see
+    /// `NativeOs::for_program`.
+    pub fn from_classes(
+        classes: &[(String, &Class, Vec<Function>)],
+        native: Option<&str>,
+    ) -> Result<Debugger, String> {
+        let mut sources = vec![];
+        let mut maps = vec![];
+        let mut symbols = HashMap::new();
+        for (file_name, class, functions) in classes {
+            let commands = vm::class_mapped(functions);
+            let texts: Vec<String> = commands.iter().map(|(text, _)| text.clone()).collect();
+            sources.push((class.class_name.0.clone(), vm::join_lines(&texts)));
+            maps.push(Some(SourceMap::from_commands(file_name, &commands)));
+            let mut table = SymbolTable::for_class(class);
+            for subroutine_dec in &class.subroutine_dec {
+                table.start_subroutine(&class.class_name.0, subroutine_dec);
+                let name = format!(
+                    "{}.{}",
+                    class.class_name.0, subroutine_dec.subroutine_name.0
+                );
+                symbols.insert(name, table.clone());
+            }
+        }
+        Debugger::new(&sources, &maps, symbols, native)
+    }
+
+    /// Debug `.vm` files given as `(file stem, text, source map)`. Without
+    /// Jack sources variables can't be shown by name.
+    pub fn from_vm(
+        files: &[(String, String, Option<SourceMap>)],
+        native: Option<&str>,
+    ) -> Result<Debugger, String> {
+        let sources: Vec<(String, String)> = files
+            .iter()
+            .map(|(name, text, _)| (name.clone(), text.clone()))
+            .collect();
+        let maps: Vec<Option<SourceMap>> = files.iter().map(|(_, _, map)| map.clone()).collect();
+        Debugger::new(&sources, &maps, HashMap::new(), native)
+    }
+
+    fn new(
+        sources: &[(String, String)],
+        maps: &[Option<SourceMap>],
+        symbols: HashMap<String, SymbolTable>,
+        native: Option<&str>,
+    ) -> Result<Debugger, String> {
+        let program = Program::load(sources).map_err(|error| error.to_string())?;
+        let mut texts = vec![];
+        let mut locations = vec![];
+        for origin in &program.origins {
+            let text = sources[origin.file]
+                .1
+                .lines()
+                .nth(origin.line - 1)
+                .unwrap_or("");
+            texts.push(text.split("//").next().unwrap_or("").trim().to_string());
+            let location = maps[origin.file]
+                .as_ref()
+                .and_then(|map| map.lookup(origin.line))
+                .map(|(file, line)| (file.to_string(), line));
+            locations.push(location);
+        }
+        let os = NativeOs::for_program(&program, native)?;
+        let emulator = VmEmulator::with_os(program, os).map_err(|error| error.message)?;
+        Ok(Debugger {
+            emulator,
+            texts,
+            locations,
+            symbols,
+            breakpoints: vec![],
+            watches: vec![],
+            max_steps: 100_000_000,
+        })
+    }
+
+    /// Feed the keyboard from `script` as the program runs.
+    pub fn set_keys(&mut self, script: KeyScript) {
+        self.emulator.keys = KeyPlayer::new(script);
+    }
+
+    /// Read commands from `input` until it ends or `quit`, answering on
+    /// `output`. The prompt is only shown when `prompt` is set.
+    pub fn repl(&mut self, input: impl BufRead, output: &mut impl Write, prompt: bool) {
+        let mut lines = input.lines();
+        loop {
+            if prompt {
+                write!(output, "(debug) ").unwrap();
+                output.flush().unwrap();
+            }
+            let Some(Ok(line)) = lines.next() else {
+                break;
+            };
+            let words: Vec<&str> = line.split_whitespace().collect();
+            if words
+                .first()
+                .is_some_and(|word| matches!(*word, "quit" | "q"))
+            {
+                break;
+            }
+            let reply = self.command(&words);
+            if !reply.is_empty() {
+                writeln!(output, "{}", reply).unwrap();
+            }
+        }
+    }
+
+    /// Run one command and return what it prints.
+    pub fn command(&mut self, words: &[&str]) -> String {
+        let result = match words {
+            [] => Ok(String::new()),
+            ["help" | "h"] => Ok(HELP.to_string()),
+            ["break" | "b", spec] => self.add_breakpoint(spec),
+            ["delete", n] => self.delete_breakpoint(n),
+            ["breakpoints"] => Ok(self.list_breakpoints()),
+            ["watch", target] => self.watch(target),
+            ["unwatch", address] => self.unwatch(address),
+            ["continue" | "c" | "run"] => Ok(self.run(|_| false)),
+            ["stepi" | "si"] => Ok(self.run(|_| true)),
+            ["nexti" | "ni"] => {
+                let depth = self.emulator.frames.len();
+                Ok(self.run(move |debugger| debugger.emulator.frames.len() <= depth))
+            }
+            ["step" | "s"] => Ok(self.step_statement(false)),
+            ["next" | "n"] => Ok(self.step_statement(true)),
+            ["finish"] => {
+                let depth = self.emulator.frames.len();
+                Ok(self.run(move |debugger| debugger.emulator.frames.len() < depth))
+            }
+            ["print" | "p", name] => self.format_variable(name),
+            ["locals"] => Ok(self.print_kinds(&[Kind::Argument, Kind::Local])),
+            ["fields"] => Ok(self.print_kinds(&[Kind::Field])),
+            ["statics"] => Ok(self.print_kinds(&[Kind::Static])),
+            ["backtrace" | "bt"] => Ok(self.backtrace()),
+            ["x", address] => self.examine(address, "1"),
+            ["x", address, count] => self.examine(address, count),
+            ["where"] => Ok(self.position()),
+            _ => Err(format!("unknown command {:?}, try help", words.join(" "))),
+        };
+        result.unwrap_or_else(|error| format!("error: {}", error))
+    }
+
+    /// A statement starts where its Jack line differs from the previous
+    /// command's, not counting `function` commands themselves.
+    fn is_statement_start(&self, pc: usize) -> bool {
+        let commands = &self.emulator.program.commands;
+        if self.locations[pc].is_none() || matches!(commands[pc], Command::Function { .. }) {
+            return false;
+        }
+        pc == 0
+            || self.locations[pc - 1] != self.locations[pc]
+            || matches!(commands[pc - 1], Command::Function { .. })
+    }
+
+    fn add_breakpoint(&mut self, spec: &str) -> Result<String, String> {
+        let program = &self.emulator.program;
+        let pcs: Vec<usize> = if let Some((file, line)) = spec.rsplit_once(':') {
+            let line: u32 = line
+                .parse()
+                .map_err(|_| format!("bad line number {:?}", line))?;
+            let location = Some((file.to_string(), line));
+            (0..self.locations.len())
+                .filter(|&pc| self.locations[pc] == location && self.is_statement_start(pc))
+                .collect()
+        } else {
+            let start = *program
+                .functions
+                .get(spec)
+                .ok_or_else(|| format!("no function {}", spec))?;
+            vec![start + 1]
+        };
+        if pcs.is_empty() {
+            return Err(format!("no code at {}", spec));
+        }
+        let at = self.describe(pcs[0]);
+        self.breakpoints.push(Some(Breakpoint {
+            spec: spec.to_string(),
+            pcs,
+        }));
+        Ok(format!("breakpoint {} at {}", self.breakpoints.len(), at))
+    }
+
+    fn delete_breakpoint(&mut self, n: &str) -> Result<String, String> {
+        let slot = n
+            .parse::<usize>()
+            .ok()
+            .and_then(|n| self.breakpoints.get_mut(n.checked_sub(1)?))
+            .filter(|slot| slot.is_some())
+            .ok_or_else(|| format!("no breakpoint {}", n))?;
+        *slot = None;
+        Ok(format!("deleted breakpoint {}", n))
+    }
+
+    fn list_breakpoints(&self) -> String {
+        let mut lines = vec![];
+        for (i, breakpoint) in self.breakpoints.iter().enumerate() {
+            if let Some(breakpoint) = breakpoint {
+                lines.push(format!("breakpoint {}: {}", i + 1, breakpoint.spec));
+            }
+        }
+        for (address, value) in &self.watches {
+            lines.push(format!("watch RAM[{}] = {}", address, value));
+        }
+        lines.join("\n")
+    }
+
+    fn address(&self, text: &str) -> Result<usize, String> {
+        match text.parse::<usize>() {
+            Ok(address) if address < self.emulator.ram.len() => Ok(address),
+            Ok(address) => Err(format!("address {} out of range", address)),
+            Err(_) => self.variable(text).map(|(address, _)| address),
+        }
+    }
+
+    fn watch(&mut self, target: &str) -> Result<String, String> {
+        let address = self.address(target)?;
+        let value = self.emulator.read(address);
+        self.watches.retain(|(watched, _)| *watched != address);
+        self.watches.push((address, value));
+        Ok(format!("watching RAM[{}] = {}", address, value))
+    }
+
+    fn unwatch(&mut self, address: &str) -> Result<String, String> {
+        let address = self.address(address)?;
+        let count = self.watches.len();
+        self.watches.retain(|(watched, _)| *watched != address);
+        if self.watches.len() == count {
+            return Err(format!("RAM[{}] is not watched", address));
+        }
+        Ok(format!("stopped watching RAM[{}]", address))
+    }
+
+    /// Step until `done` says so, a breakpoint or watch triggers, or the
+    /// program ends.
+    fn run(&mut self, done: impl Fn(&Debugger) -> bool) -> String {
+        if self.emulator.state == State::Halted {
+            return self.position();
+        }
+        let mut stop = Stop::Limit;
+        self.emulator.step_limit = self.emulator.steps.saturating_add(self.max_steps);
+        for _ in 0..self.max_steps {
+            match self.emulator.step() {
+                Err(error) => {
+                    stop = Stop::Error(error.to_string());
+                    break;
+                }
+                Ok(State::Halted) => {
+                    stop = Stop::Halted;
+                    break;
+                }
+                Ok(State::Running) => {}
+            }
+            if let Some(changed) = self.changed_watch() {
+                stop = changed;
+                break;
+            }
+            let pc = self.emulator.pc;
+            let hit = self.breakpoints.iter().position(|breakpoint| {
+                breakpoint
+                    .as_ref()
+                    .is_some_and(|breakpoint| breakpoint.pcs.contains(&pc))
+            });
+            if let Some(n) = hit {
+                stop = Stop::Breakpoint(n + 1);
+                break;
+            }
+            if done(self) {
+                stop = Stop::Done;
+                break;
+            }
+        }
+        let position = self.position();
+        match stop {
+            Stop::Done => position,
+            Stop::Breakpoint(n) => format!("breakpoint {}, {}", n, position),
+            Stop::Watch(address, old, new) => {
+                format!(
+                    "RAM[{}] changed from {} to {}, {}",
+                    address, old, new, position
+                )
+            }
+            Stop::Halted => format!("program halted after {} steps", self.emulator.steps),
+            Stop::Limit => format!("stopped after {} steps, {}", self.max_steps, position),
+            Stop::Error(error) => format!("error: {}, {}", error, position),
+        }
+    }
+
+    fn changed_watch(&mut self) -> Option<Stop> {
+        for (address, value) in &mut self.watches {
+            let new = self.emulator.ram[*address];
+            if new != *value {
+                let old = std::mem::replace(value, new);
+                return Some(Stop::Watch(*address, old, new));
+            }
+        }
+        None
+    }
+
+    /// Run to the start of the next Jack statement. Calls are stepped over
+    /// when `over` is set.
+    fn step_statement(&mut self, over: bool) -> String {
+        let depth = self.emulator.frames.len();
+        self.run(move |debugger| {
+            let pc = debugger.emulator.pc;
+            debugger.is_statement_start(pc) && (!over || debugger.emulator.frames.len() <= depth)
+        })
+    }
+
+    /// Address and type of a variable of the current subroutine.
+    fn variable(&self, name: &str) -> Result<(usize, Ty), String> {
+        let frame = self.emulator.frames.last().ok_or("the program has ended")?;
+        let symbol = self
+            .symbols
+            .get(&frame.function)
+            .and_then(|table| table.lookup(name))
+            .ok_or_else(|| format!("no variable {} in {}", name, frame.function))?;
+        let index = symbol.index as usize;
+        let address = match symbol.kind {
+            Kind::Local => frame.lcl + index,
+            Kind::Argument => frame.arg + index,
+            Kind::Field => self.emulator.read(THIS) as u16 as usize + index,
+            Kind::Static => {
+                let class = frame.function.split('.').next().unwrap_or("");
+                let file = self
+                    .emulator
+                    .program
+                    .files
+                    .iter()
+                    .find(|file| file.name == class);
+                file.ok_or_else(|| format!("no statics for {}", class))?
+                    .static_base
+                    + index
+            }
+        };
+        if address >= self.emulator.ram.len() {
+            return Err(format!("{} is at {}, outside RAM", name, address));
+        }
+        Ok((address, symbol.var_type.clone()))
+    }
+
+    fn format_variable(&self, name: &str) -> Result<String, String> {
+        let (address, var_type) = self.variable(name)?;
+        let value = self.emulator.read(address);
+        let shown = match (&var_type, value) {
+            (Ty::Boolean, 0) => "false".to_string(),
+            (Ty::Boolean, -1) => "true".to_string(),
+            (Ty::Char, 32..=126) => format!("{} '{}'", value, value as u8 as char),
+            (Ty::Class(_), _) => format!("@{}", value as u16),
+            _ => value.to_string(),
+        };
+        Ok(format!("{}: {} = {}", name, var_type, shown))
+    }
+
+    fn print_kinds(&self, kinds: &[Kind]) -> String {
+        let Some(table) = self
+            .emulator
+            .frames
+            .last()
+            .and_then(|frame| self.symbols.get(&frame.function))
+        else {
+            return "no symbols for this function".to_string();
+        };
+        let lines: Vec<String> = table
+            .subroutine
+            .iter()
+            .chain(&table.class)
+            .filter(|symbol| kinds.contains(&symbol.kind))
+            .map(|symbol| {
+                self.format_variable(&symbol.name)
+                    .unwrap_or_else(|error| format!("{}: {}", symbol.name, error))
+            })
+            .collect();
+        if lines.is_empty() {
+            return "none".to_string();
+        }
+        lines.join("\n")
+    }
+
+    fn backtrace(&self) -> String {
+        let frames = &self.emulator.frames;
+        let mut lines = vec![];
+        for (depth, frame) in frames.iter().enumerate().rev() {
+            // an outer frame is paused at the call into the next one
+            let pc = frames
+                .get(depth + 1)
+                .map_or(self.emulator.pc, |inner| inner.call_pc);
+            let location = match &self.locations[pc] {
+                Some((file, line)) => format!(" at {}:{}", file, line),
+                None => String::new(),
+            };
+            lines.push(format!(
+                "#{} {}{} (ARG {}, LCL {})",
+                frames.len() - 1 - depth,
+                frame.function,
+                location,
+                frame.arg,
+                frame.lcl
+            ));
+        }
+        lines.join("\n")
+    }
+
+    fn examine(&self, address: &str, count: &str) -> Result<String, String> {
+        let address = self.address(address)?;
+        let count: usize = count
+            .parse()
+            .map_err(|_| format!("bad count {:?}", count))?;
+        let end = (address + count).min(self.emulator.ram.len());
+        let lines: Vec<String> = (address..end)
+            .map(|address| format!("RAM[{}] = {}", address, self.emulator.read(address)))
+            .collect();
+        Ok(lines.join("\n"))
+    }
+
+    /// `function at file:line`, plus the VM command at `pc`.
+    fn describe(&self, pc: usize) -> String {
+        let program = &self.emulator.program;
+        let function = program.function_at(pc).unwrap_or("?");
+        let origin = program.origins[pc];
+        let location = match &self.locations[pc] {
+            Some((file, line)) => format!(" at {}:{}", file, line),
+            None => String::new(),
+        };
+        let vm_file = &program.files[origin.file].name;
+        format!(
+            "{}{} ({}.vm:{}: {})",
+            function, location, vm_file, origin.line, self.texts[pc]
+        )
+    }
+
+    fn position(&self) -> String {
+        if self.emulator.state == State::Halted {
+            return "the program has ended".to_string();
+        }
+        self.describe(self.emulator.pc)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lower::lower_class;
+    use crate::parser::{Parsable, TokenReader};
+    use crate::tokenizer::tokenize_str_spanned;
+
+    const MAIN: &str = "class Main {
+    static int total;
+    function void main() {
+        var int i;
+        let i = 3;
+        while (i > 0) {
+            do Main.add(i);
+            let i = i - 1;
+        }
+        return;
+    }
+    function void add(int n) {
+        let total = total + n;
+        return;
+    }
+}
+";
+
+    fn debugger() -> Debugger {
+        let (tokens, token_spans) = tokenize_str_spanned(MAIN);
+        let (class, _) = Class::try_parse(&TokenReader { tokens }, 0).unwrap();
+        let functions = lower_class(&class, &token_spans).unwrap();
+        Debugger::from_classes(&[("Main.jack".to_string(), &class, functions)], None).unwrap()
+    }
+
+    fn session(script: &str) -> String {
+        let mut output = vec![];
+        debugger().repl(script.as_bytes(), &mut output, false);
+        String::from_utf8(output).unwrap()
+    }
+
+    #[test]
+    fn stops_at_breakpoints_and_prints_variables() {
+        let output = session("break Main.add\nc\nprint n\nbt\nc\nlocals\nstatics\n");
+        let lines: Vec<&str> = output.lines().collect();
+        assert!(lines[0].starts_with("breakpoint 1 at Main.add at Main.jack:13"));
+        assert!(lines[1].starts_with("breakpoint 1, Main.add at Main.jack:13"));
+        assert_eq!(lines[2], "n: int = 3");
+        assert!(lines[3].starts_with("#0 Main.add at Main.jack:13"));
+        assert!(lines[4].starts_with("#1 Main.main at Main.jack:7"));
+        assert!(lines[5].starts_with("breakpoint 1, Main.add"));
+        assert_eq!(lines[6], "n: int = 2");
+        assert_eq!(lines[7], "total: int = 3");
+    }
+
+    #[test]
+    fn steps_by_statement() {
+        let output = session("break Main.jack:7\nc\nnext\nstep\nstep\ndelete 1\nfinish\nnext\n");
+        let lines: Vec<&str> = output.lines().collect();
+        assert!(lines[1].starts_with("breakpoint 1, Main.main at Main.jack:7"));
+        assert!(lines[2].starts_with("Main.main at Main.jack:8"));
+        assert!(lines[3].starts_with("Main.main at Main.jack:6"));
+        assert!(lines[4].starts_with("breakpoint 1, Main.main at Main.jack:7"));
+        // finish from main's loop runs the program to its end
+        assert_eq!(lines[5], "deleted breakpoint 1");
+        assert!(lines[6].starts_with("program halted after "));
+        assert_eq!(lines[7], "the program has ended");
+    }
+
+    #[test]
+    fn watches_memory_and_reports_errors() {
+        let output = session("watch total\nc\nc\nbreak Main.jack:2\nfrobnicate\n");
+        let lines: Vec<&str> = output.lines().collect();
+        assert_eq!(lines[0], "watching RAM[16] = 0");
+        assert!(lines[1].starts_with("RAM[16] changed from 0 to 3, Main.add at Main.jack:14"));
+        assert!(lines[2].starts_with("RAM[16] changed from 3 to 5"));
+        assert_eq!(lines[3], "error: no code at Main.jack:2");
+        assert_eq!(lines[4], "error: unknown command \"frobnicate\", try help");
+    }
+}
@@ -0,0 +1,187 @@
+// Hack disassembler
+//
+// Turns `.hack` machine code back into assembly, an instruction a line:
+// `@value` for an A-instruction and `dest=comp;jump` for a C-instruction.
+// Words that no assembler would produce, a computation outside the Hack
+// table or a C-instruction whose bits 13 and 14 aren't set, are flagged and
+// written as the nearest legal instruction followed by `// was <bits>`, so
+// every word still takes one address. With labels, every `@n` that feeds a jump becomes `@Ln` with `(Ln)` put
+// before instruction n, so the text reassembles to the same words.
+
+use std::collections::BTreeSet;
+
+/// The computations, by their `a c1..c6` bits.
+pub const COMPS: [(&str, u16); 28] = [
+    ("0", 0b0101010),
+    ("1", 0b0111111),
+    ("-1", 0b0111010),
+    ("D", 0b0001100),
+    ("A", 0b0110000),
+    ("!D", 0b0001101),
+    ("!A", 0b0110001),
+    ("-D", 0b0001111),
+    ("-A", 0b0110011),
+    ("D+1", 0b0011111),
+    ("A+1", 0b0110111),
+    ("D-1", 0b0001110),
+    ("A-1", 0b0110010),
+    ("D+A", 0b0000010),
+    ("D-A", 0b0010011),
+    ("A-D", 0b0000111),
+    ("D&A", 0b0000000),
+    ("D|A", 0b0010101),
+    ("M", 0b1110000),
+    ("!M", 0b1110001),
+    ("-M", 0b1110011),
+    ("M+1", 0b1110111),
+    ("M-1", 0b1110010),
+    ("D+M", 0b1000010),
+    ("D-M", 0b1010011),
+    ("M-D", 0b1000111),
+    ("D&M", 0b1000000),
+    ("D|M", 0b1010101),
+];
+
+/// The destinations, by their `d1 d2 d3` bits.
+pub const DESTS: [&str; 8] = ["", "M", "D", "MD", "A", "AM", "AD", "AMD"];
+
+/// The jumps, by their `j1 j2 j3` bits.
+pub const JUMPS: [&str; 8] = ["", "JGT", "JEQ", "JGE", "JLT", "JNE", "JLE", "JMP"];
+
+#[derive(Debug, PartialEq)]
+pub struct Disassembly {
+    /// The assembly, a line per instruction and label.
+    pub text: String,
+    /// Words that aren't legal Hack, by ROM address.
+    pub problems: Vec<(usize, String)>,
+}
+
+/// An instruction as assembly, and what is wrong with its encoding.
+pub fn decode(word: u16) -> (String, Option<String>) {
+    if word & 0x8000 == 0 {
+        return (format!("@{}", word), None);
+    }
+    let bits = word >> 6 & 0x7f;
+    let dest = DESTS[(word >> 3 & 7) as usize];
+    let jump = JUMPS[(word & 7) as usize];
+    // the computation differing in the fewest bits, the first one of those
+    let (comp, nearest) = COMPS
+        .iter()
+        .min_by_key(|(_, comp)| (comp ^ bits).count_ones())
+        .unwrap();
+    let problem = if *nearest != bits {
+        Some(format!(
+            "{:016b}: a={} c={:06b} is no Hack computation",
+            word,
+            bits >> 6,
+            bits & 0x3f
+        ))
+    } else if word & 0x6000 != 0x6000 {
+        Some(format!(
+            "{:016b}: bits 13 and 14 of a C-instruction aren't set",
+            word
+        ))
+    } else {
+        None
+    };
+    let mut text = String::new();
+    if !dest.is_empty() {
+        text += &format!("{}=", dest);
+    }
+    text += comp;
+    if !jump.is_empty() {
+        text += &format!(";{}", jump);
+    }
+    if problem.is_some() {
+        text += &format!(" // was {:016b}", word);
+    }
+    (text, problem)
+}
+
+/// Disassemble a program, naming jump targets when `labels` is set.
+pub fn disassemble(words: &[u16], labels: bool) -> Disassembly {
+    let decoded: Vec<(String, Option<String>)> = words.iter().map(|word| decode(*word)).collect();
+    // the @n right before a jump, when n is in the program
+    let feeds_jump = |address: usize| {
+        labels
+            && words[address] & 0x8000 == 0
+            && (words[address] as usize) <= words.len()
+            && words
+                .get(address + 1)
+                .is_some_and(|next| next & 0x8000 != 0 && next & 7 != 0)
+    };
+    let targets: BTreeSet<usize> = (0..words.len())
+        .filter(|address| feeds_jump(*address))
+        .map(|address| words[address] as usize)
+        .collect();
+    let mut lines = vec![];
+    let mut problems = vec![];
+    for (address, (text, problem)) in decoded.into_iter().enumerate() {
+        if targets.contains(&address) {
+            lines.push(format!("(L{})", address));
+        }
+        if feeds_jump(address) {
+            lines.push(format!("    @L{}", words[address]));
+        } else {
+            lines.push(format!("    {}", text));
+        }
+        problems.extend(problem.map(|problem| (address, problem)));
+    }
+    if targets.contains(&words.len()) {
+        lines.push(format!("(L{})", words.len()));
+    }
+    let mut text = lines.join("\n");
+    text.push('\n');
+    Disassembly { text, problems }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu_emulator::parse_hack;
+
+    /// `06/max/Max.asm`
+    const MAX: &str = "0000000000000000\n1111110000010000\n0000000000000001\n\
+                       1111010011010000\n0000000000001010\n1110001100000001\n\
+                       0000000000000001\n1111110000010000\n0000000000001100\n\
+                       1110101010000111\n0000000000000000\n1111110000010000\n\
+                       0000000000000010\n1110001100001000\n0000000000001110\n\
+                       1110101010000111\n";
+
+    #[test]
+    fn decodes_instructions_and_labels_jump_targets() {
+        let max = disassemble(&parse_hack(MAX).unwrap(), true);
+        assert_eq!(
+            max.text,
+            "    @0\n    D=M\n    @1\n    D=D-M\n    @L10\n    D;JGT\n    \
+             @1\n    D=M\n    @L12\n    0;JMP\n\
+             (L10)\n    @0\n    D=M\n\
+             (L12)\n    @2\n    M=D\n\
+             (L14)\n    @L14\n    0;JMP\n"
+        );
+        assert!(max.problems.is_empty());
+        assert_eq!(decode(0xfc10), ("D=M".to_string(), None));
+        assert_eq!(decode(0xeeba).0, "AMD=-1;JEQ");
+        assert_eq!(
+            decode(0x9c10),
+            (
+                "D=M // was 1001110000010000".to_string(),
+                Some("1001110000010000: bits 13 and 14 of a C-instruction aren't set".to_string())
+            )
+        );
+        // 101011 is a bit off 101010, 0; the jump after it keeps its address
+        let bad = disassemble(&[0x0001, 0xeac7, 0x0001, 0xea87], true);
+        assert_eq!(
+            bad.text,
+            "    @L1\n\
+             (L1)\n    0;JMP // was 1110101011000111\n    @L1\n    0;JMP\n"
+        );
+        assert_eq!(
+            bad.problems,
+            [(
+                1,
+                "1110101011000111: a=0 c=101011 is no Hack computation".to_string()
+            )]
+        );
+    }
+}
@@ -0,0 +1,671 @@
+// graphviz backend for Jack language
+//
+// Besides the plain parse tree, this renders the control-flow graph of a
+// subroutine and the call graph of a whole program.
+
+use std::collections::BTreeSet;
+
+use crate::parser::{
+    expressions::{self, Expression, SubroutineCall, Term},
+    statements::{LetLHS, Statement, Statements},
+    structures::{Class, ReturnType, SubroutineBody, SubroutineDec, SubroutineType, VarTypeEnum},
+};
+
+/// Parse tree of a whole class.
+pub fn class_tree(class: &Class) -> String {
+    let mut graph = Graph::new(&class.class_name.0);
+    let id = graph.node("class", ", shape=box");
+    graph.class(id, class);
+    graph.finish()
+}
+
+struct Graph {
+    out: String,
+    next_id: usize,
+}
+
+impl Graph {
+    fn new(name: &str) -> Graph {
+        let mut out = format!("digraph \"{}\" {{\n", escape(name));
+        out.push_str("  node [fontname=\"monospace\"];\n");
+        Graph { out, next_id: 0 }
+    }
+
+    fn finish(mut self) -> String {
+        self.out.push_str("}\n");
+        self.out
+    }
+
+    fn node(&mut self, label: &str, attrs: &str) -> usize {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.out.push_str(&format!(
+            "  n{} [label=\"{}\"{}];\n",
+            id,
+            escape(label),
+            attrs
+        ));
+        id
+    }
+
+    fn edge(&mut self, from: usize, to: usize, label: Option<&str>) {
+        match label {
+            Some(label) => self.out.push_str(&format!(
+                "  n{} -> n{} [label=\"{}\"];\n",
+                from,
+                to,
+                escape(label)
+            )),
+            None => self.out.push_str(&format!("  n{} -> n{};\n", from, to)),
+        }
+    }
+
+    fn leaf(&mut self, parent: usize, label: &str) {
+        let id = self.node(label, ", shape=plaintext");
+        self.edge(parent, id, None);
+    }
+
+    fn inner(&mut self, parent: usize, label: &str) -> usize {
+        let id = self.node(label, ", shape=box");
+        self.edge(parent, id, None);
+        id
+    }
+
+    fn class(&mut self, id: usize, class: &Class) {
+        self.leaf(id, &class.class_name.0);
+        for var_dec in &class.class_var_dec {
+            let var_id = self.inner(id, "classVarDec");
+            let kind = match var_dec.var_dec_type {
+                crate::parser::structures::ClassVarDecType::STATIC => "static",
+                crate::parser::structures::ClassVarDecType::FIELD => "field",
+            };
+            let names: Vec<&str> = var_dec.var_names.iter().map(|n| n.0.as_str()).collect();
+            self.leaf(
+                var_id,
+                &format!(
+                    "{} {} {}",
+                    kind,
+                    type_text(&var_dec.var_type.0),
+                    names.join(", ")
+                ),
+            );
+        }
+        for subroutine_dec in &class.subroutine_dec {
+            let sub_id = self.inner(id, "subroutineDec");
+            self.subroutine_dec(sub_id, subroutine_dec);
+        }
+    }
+
+    fn subroutine_dec(&mut self, id: usize, subroutine_dec: &SubroutineDec) {
+        self.leaf(id, &signature_text(subroutine_dec));
+        let body_id = self.inner(id, "subroutineBody");
+        self.subroutine_body(body_id, &subroutine_dec.subroutine_body);
+    }
+
+    fn subroutine_body(&mut self, id: usize, body: &SubroutineBody) {
+        for var_dec in &body.var_decs {
+            let names: Vec<&str> = var_dec.var_names.iter().map(|n| n.0.as_str()).collect();
+            let var_id = self.inner(id, "varDec");
+            self.leaf(
+                var_id,
+                &format!(
+                    "var {} {}",
+                    type_text(&var_dec.var_type.0),
+                    names.join(", ")
+                ),
+            );
+        }
+        let statements_id = self.inner(id, "statements");
+        self.statements(statements_id, &body.statements);
+    }
+
+    fn statements(&mut self, id: usize, statements: &Statements) {
+        for statement in &statements.0 {
+            match statement {
+                Statement::LetStatement(s) => {
+                    let s_id = self.inner(id, "letStatement");
+                    self.let_statement(s_id, s);
+                }
+                Statement::IfStatement(s) => {
+                    let s_id = self.inner(id, "ifStatement");
+                    self.if_statement(s_id, s);
+                }
+                Statement::WhileStatement(s) => {
+                    let s_id = self.inner(id, "whileStatement");
+                    self.while_statement(s_id, s);
+                }
+                Statement::DoStatement(s) => {
+                    let s_id = self.inner(id, "doStatement");
+                    self.subroutine_call(s_id, &s.subroutine_call);
+                }
+                Statement::ReturnStatement(s) => {
+                    let s_id = self.inner(id, "returnStatement");
+                    self.return_statement(s_id, s);
+                }
+            }
+        }
+    }
+
+    fn let_statement(&mut self, id: usize, s: &crate::parser::statements::LetStatement) {
+        match &s.let_lhs {
+            LetLHS::VarName(name) => self.leaf(id, &name.0),
+            LetLHS::ArrayTerm(array_term) => {
+                let array_id = self.inner(id, &format!("{}[]", array_term.var_name.0));
+                let exp_id = self.inner(array_id, "expression");
+                self.expression(exp_id, &array_term.expression);
+            }
+        }
+        let exp_id = self.inner(id, "expression");
+        self.expression(exp_id, &s.let_rhs);
+    }
+
+    fn if_statement(&mut self, id: usize, s: &crate::parser::statements::IfStatement) {
+        let cond_id = self.inner(id, "expression");
+        self.expression(cond_id, &s.condition);
+        let true_id = self.inner(id, "statements");
+        self.statements(true_id, &s.true_statements);
+        if let Some(false_statements) = &s.false_statements {
+            let false_id = self.inner(id, "else statements");
+            self.statements(false_id, false_statements);
+        }
+    }
+
+    fn while_statement(&mut self, id: usize, s: &crate::parser::statements::WhileStatement) {
+        let cond_id = self.inner(id, "expression");
+        self.expression(cond_id, &s.condition);
+        let body_id = self.inner(id, "statements");
+        self.statements(body_id, &s.statements);
+    }
+
+    fn return_statement(&mut self, id: usize, s: &crate::parser::statements::ReturnStatement) {
+        if let Some(exp) = &s.expression {
+            let exp_id = self.inner(id, "expression");
+            self.expression(exp_id, exp);
+        }
+    }
+
+    fn expression(&mut self, id: usize, exp: &Expression) {
+        let term_id = self.inner(id, "term");
+        self.term(term_id, &exp.term);
+        for (op, term) in &exp.op_term {
+            self.leaf(id, &op.0 .0.to_string());
+            let term_id = self.inner(id, "term");
+            self.term(term_id, term);
+        }
+    }
+
+    fn term(&mut self, id: usize, term: &Term) {
+        match term {
+            Term::ArrayTerm(array_term) => {
+                self.leaf(id, &format!("{}[]", array_term.var_name.0));
+                let exp_id = self.inner(id, "expression");
+                self.expression(exp_id, &array_term.expression);
+            }
+            Term::UnaryTerm(uterm) => {
+                self.leaf(id, &uterm.unary_op.0 .0.to_string());
+                let term_id = self.inner(id, "term");
+                self.term(term_id, &uterm.term);
+            }
+            Term::WrappedExpression(exp) => {
+                let exp_id = self.inner(id, "expression");
+                self.expression(exp_id, &exp.0);
+            }
+            Term::SubroutineCall(call) => {
+                let call_id = self.inner(id, "subroutineCall");
+                self.subroutine_call(call_id, call);
+            }
+            _ => self.leaf(id, &term_text(term)),
+        }
+    }
+
+    fn subroutine_call(&mut self, id: usize, call: &SubroutineCall) {
+        self.leaf(id, &call_name(call));
+        for exp in &call.expression_list.0 {
+            let exp_id = self.inner(id, "expression");
+            self.expression(exp_id, exp);
+        }
+    }
+}
+
+#[derive(Default)]
+struct Block {
+    lines: Vec<String>,
+    succ: Vec<(usize, Option<&'static str>)>,
+}
+
+/// Basic blocks of a subroutine, block 0 is the entry and block 1 the exit.
+#[derive(Default)]
+struct Cfg {
+    blocks: Vec<Block>,
+}
+
+impl Cfg {
+    fn block(&mut self) -> usize {
+        self.blocks.push(Block::default());
+        self.blocks.len() - 1
+    }
+
+    fn edge(&mut self, from: usize, to: usize, label: Option<&'static str>) {
+        self.blocks[from].succ.push((to, label));
+    }
+
+    /// Append `statements` to `current`, returning the block control falls
+    /// out of, or `None` when every path has returned.
+    fn statements(
+        &mut self,
+        statements: &Statements,
+        current: usize,
+        exit: usize,
+    ) -> Option<usize> {
+        let mut current = Some(current);
+        for statement in &statements.0 {
+            // code after a return still gets a block, it just has no predecessor
+            let block = current.unwrap_or_else(|| self.block());
+            current = self.statement(statement, block, exit);
+        }
+        current
+    }
+
+    fn statement(&mut self, statement: &Statement, block: usize, exit: usize) -> Option<usize> {
+        match statement {
+            Statement::LetStatement(s) => {
+                let lhs = match &s.let_lhs {
+                    LetLHS::VarName(name) => name.0.clone(),
+                    LetLHS::ArrayTerm(array_term) => format!(
+                        "{}[{}]",
+                        array_term.var_name.0,
+                        expression_text(&array_term.expression)
+                    ),
+                };
+                self.blocks[block].lines.push(format!(
+                    "let {} = {}",
+                    lhs,
+                    expression_text(&s.let_rhs)
+                ));
+                Some(block)
+            }
+            Statement::DoStatement(s) => {
+                self.blocks[block]
+                    .lines
+                    .push(format!("do {}", call_text(&s.subroutine_call)));
+                Some(block)
+            }
+            Statement::ReturnStatement(s) => {
+                let line = match &s.expression {
+                    Some(exp) => format!("return {}", expression_text(exp)),
+                    None => "return".to_string(),
+                };
+                self.blocks[block].lines.push(line);
+                self.edge(block, exit, None);
+                None
+            }
+            Statement::IfStatement(s) => {
+                self.blocks[block]
+                    .lines
+                    .push(format!("if ({})", expression_text(&s.condition)));
+                let true_block = self.block();
+                self.edge(block, true_block, Some("true"));
+                let true_end = self.statements(&s.true_statements, true_block, exit);
+                let false_end = match &s.false_statements {
+                    Some(false_statements) => {
+                        let false_block = self.block();
+                        self.edge(block, false_block, Some("false"));
+                        self.statements(false_statements, false_block, exit)
+                    }
+                    None => Some(block),
+                };
+                if true_end.is_none() && false_end.is_none() {
+                    return None;
+                }
+                let join = self.block();
+                if let Some(true_end) = true_end {
+                    self.edge(true_end, join, None);
+                }
+                if let Some(false_end) = false_end {
+                    let label = if false_end == block {
+                        Some("false")
+                    } else {
+                        None
+                    };
+                    self.edge(false_end, join, label);
+                }
+                Some(join)
+            }
+            Statement::WhileStatement(s) => {
+                let cond = self.block();
+                self.edge(block, cond, None);
+                self.blocks[cond]
+                    .lines
+                    .push(format!("while ({})", expression_text(&s.condition)));
+                let body = self.block();
+                self.edge(cond, body, Some("true"));
+                if let Some(body_end) = self.statements(&s.statements, body, exit) {
+                    self.edge(body_end, cond, None);
+                }
+                let after = self.block();
+                self.edge(cond, after, Some("false"));
+                Some(after)
+            }
+        }
+    }
+}
+
+/// Control-flow graph of a single subroutine, one node per basic block.
+pub fn subroutine_cfg(class_name: &str, subroutine_dec: &SubroutineDec) -> String {
+    let mut cfg = Cfg::default();
+    let entry = cfg.block();
+    let exit = cfg.block();
+    if let Some(end) = cfg.statements(&subroutine_dec.subroutine_body.statements, entry, exit) {
+        cfg.edge(end, exit, None);
+    }
+    let name = format!("{}.{}", class_name, subroutine_dec.subroutine_name.0);
+    let mut out = format!("digraph \"{}\" {{\n", escape(&name));
+    out.push_str("  node [shape=box, fontname=\"monospace\"];\n");
+    for (id, block) in cfg.blocks.iter().enumerate() {
+        let title = match id {
+            0 => format!("{} (entry)", escape(&signature_text(subroutine_dec))),
+            1 => "exit".to_string(),
+            _ => format!("B{}", id),
+        };
+        let mut label = title;
+        label.push_str("\\l");
+        for line in &block.lines {
+            label.push_str(&escape(line));
+            label.push_str("\\l");
+        }
+        let shape = if id == 1 { ", shape=oval" } else { "" };
+        out.push_str(&format!("  b{} [label=\"{}\"{}];\n", id, label, shape));
+    }
+    for (id, block) in cfg.blocks.iter().enumerate() {
+        for (to, label) in &block.succ {
+            match label {
+                Some(label) => {
+                    out.push_str(&format!("  b{} -> b{} [label=\"{}\"];\n", id, to, label))
+                }
+                None => out.push_str(&format!("  b{} -> b{};\n", id, to)),
+            }
+        }
+    }
+    out.push_str("}\n");
+    out
+}
+
+/// Every subroutine call made by `statements`, nested expressions included.
+pub fn collect_calls<'a>(statements: &'a Statements, calls: &mut Vec<&'a SubroutineCall>) {
+    for statement in &statements.0 {
+        match statement {
+            Statement::LetStatement(s) => {
+                if let LetLHS::ArrayTerm(array_term) = &s.let_lhs {
+                    collect_expression_calls(&array_term.expression, calls);
+                }
+                collect_expression_calls(&s.let_rhs, calls);
+            }
+            Statement::IfStatement(s) => {
+                collect_expression_calls(&s.condition, calls);
+                collect_calls(&s.true_statements, calls);
+                if let Some(false_statements) = &s.false_statements {
+                    collect_calls(false_statements, calls);
+                }
+            }
+            Statement::WhileStatement(s) => {
+                collect_expression_calls(&s.condition, calls);
+                collect_calls(&s.statements, calls);
+            }
+            Statement::DoStatement(s) => collect_call(&s.subroutine_call, calls),
+            Statement::ReturnStatement(s) => {
+                if let Some(exp) = &s.expression {
+                    collect_expression_calls(exp, calls);
+                }
+            }
+        }
+    }
+}
+
+fn collect_call<'a>(call: &'a SubroutineCall, calls: &mut Vec<&'a SubroutineCall>) {
+    calls.push(call);
+    for exp in &call.expression_list.0 {
+        collect_expression_calls(exp, calls);
+    }
+}
+
+fn collect_expression_calls<'a>(exp: &'a Expression, calls: &mut Vec<&'a SubroutineCall>) {
+    collect_term_calls(&exp.term, calls);
+    for (_, term) in &exp.op_term {
+        collect_term_calls(term, calls);
+    }
+}
+
+fn collect_term_calls<'a>(term: &'a Term, calls: &mut Vec<&'a SubroutineCall>) {
+    match term {
+        Term::ArrayTerm(array_term) => collect_expression_calls(&array_term.expression, calls),
+        Term::UnaryTerm(uterm) => collect_term_calls(&uterm.term, calls),
+        Term::WrappedExpression(exp) => collect_expression_calls(&exp.0, calls),
+        Term::SubroutineCall(call) => collect_call(call, calls),
+        _ => {}
+    }
+}
+
+/// Resolve the `Class.subroutine` a call made from `subroutine_dec` lands in.
+/// A call through a variable goes to the class of its declared type, any
+/// other qualifier is taken to be a class name already.
+pub fn call_target(class: &Class, subroutine_dec: &SubroutineDec, call: &SubroutineCall) -> String {
+    let Some(bind_this) = &call.bind_this else {
+        return format!("{}.{}", class.class_name.0, call.subroutine_name.0);
+    };
+    let body = &subroutine_dec.subroutine_body;
+    let declared = subroutine_dec
+        .parameter_list
+        .parameters
+        .iter()
+        .map(|(var_type, name)| (var_type, name))
+        .chain(body.var_decs.iter().flat_map(|var_dec| {
+            var_dec
+                .var_names
+                .iter()
+                .map(move |name| (&var_dec.var_type, name))
+        }))
+        .chain(class.class_var_dec.iter().flat_map(|var_dec| {
+            var_dec
+                .var_names
+                .iter()
+                .map(move |name| (&var_dec.var_type, name))
+        }))
+        .find(|(_, name)| name.0 == bind_this.0);
+    match declared {
+        Some((var_type, _)) => format!("{}.{}", type_text(&var_type.0), call.subroutine_name.0),
+        None => format!("{}.{}", bind_this.0, call.subroutine_name.0),
+    }
+}
+
+/// Program-wide call graph. Calls into subroutines that none of `classes`
+/// declare, the OS for instance, end in dashed nodes.
+pub fn call_graph(classes: &[Class]) -> String {
+    let mut declared = BTreeSet::new();
+    let mut edges = BTreeSet::new();
+    for class in classes {
+        for subroutine_dec in &class.subroutine_dec {
+            let caller = format!(
+                "{}.{}",
+                class.class_name.0, subroutine_dec.subroutine_name.0
+            );
+            let mut calls = vec![];
+            collect_calls(&subroutine_dec.subroutine_body.statements, &mut calls);
+            for call in calls {
+                edges.insert((caller.clone(), call_target(class, subroutine_dec, call)));
+            }
+            declared.insert(caller);
+        }
+    }
+    let mut out = String::from("digraph \"callgraph\" {\n");
+    out.push_str("  node [shape=box, fontname=\"monospace\"];\n");
+    for class in classes {
+        out.push_str(&format!(
+            "  subgraph \"cluster_{}\" {{\n    label=\"{}\";\n",
+            escape(&class.class_name.0),
+            escape(&class.class_name.0)
+        ));
+        for subroutine_dec in &class.subroutine_dec {
+            let name = format!(
+                "{}.{}",
+                class.class_name.0, subroutine_dec.subroutine_name.0
+            );
+            out.push_str(&format!("    \"{}\";\n", escape(&name)));
+        }
+        out.push_str("  }\n");
+    }
+    let external: BTreeSet<&String> = edges
+        .iter()
+        .map(|(_, callee)| callee)
+        .filter(|callee| !declared.contains(*callee))
+        .collect();
+    for callee in external {
+        out.push_str(&format!("  \"{}\" [style=dashed];\n", escape(callee)));
+    }
+    for (caller, callee) in &edges {
+        out.push_str(&format!(
+            "  \"{}\" -> \"{}\";\n",
+            escape(caller),
+            escape(callee)
+        ));
+    }
+    out.push_str("}\n");
+    out
+}
+
+/// Quote a label for use inside a double quoted dot string.
+fn escape(text: &str) -> String {
+    text.replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+fn type_text(var_type: &VarTypeEnum) -> String {
+    match var_type {
+        VarTypeEnum::INT => "int".to_string(),
+        VarTypeEnum::CHAR => "char".to_string(),
+        VarTypeEnum::BOOLEAN => "boolean".to_string(),
+        VarTypeEnum::CLASSNAME(name) => name.0.clone(),
+    }
+}
+
+pub fn signature_text(subroutine_dec: &SubroutineDec) -> String {
+    let kind = match subroutine_dec.subroutine_type {
+        SubroutineType::CONSTRUCTOR => "constructor",
+        SubroutineType::FUNCTION => "function",
+        SubroutineType::METHOD => "method",
+    };
+    let return_type = match &subroutine_dec.return_type {
+        ReturnType::VOID => "void".to_string(),
+        ReturnType::VARTYPE(var_type) => type_text(&var_type.0),
+    };
+    let params: Vec<String> = subroutine_dec
+        .parameter_list
+        .parameters
+        .iter()
+        .map(|(var_type, name)| format!("{} {}", type_text(&var_type.0), name.0))
+        .collect();
+    format!(
+        "{} {} {}({})",
+        kind,
+        return_type,
+        subroutine_dec.subroutine_name.0,
+        params.join(", ")
+    )
+}
+
+fn call_name(call: &SubroutineCall) -> String {
+    match &call.bind_this {
+        Some(bind_this) => format!("{}.{}", bind_this.0, call.subroutine_name.0),
+        None => call.subroutine_name.0.clone(),
+    }
+}
+
+/// Render an expression back to Jack source, used for graph labels.
+pub fn expression_text(exp: &Expression) -> String {
+    let mut text = term_text(&exp.term);
+    for (op, term) in &exp.op_term {
+        text.push_str(&format!(" {} {}", op.0 .0, term_text(term)));
+    }
+    text
+}
+
+fn term_text(term: &Term) -> String {
+    match term {
+        Term::IntegerConstant(int) => int.0.to_string(),
+        Term::StringConstant(string) => format!("\"{}\"", string.0),
+        Term::KeywordConstant(keyword) => match keyword {
+            expressions::KeywordConstant::TRUE => "true",
+            expressions::KeywordConstant::FALSE => "false",
+            expressions::KeywordConstant::NULL => "null",
+            expressions::KeywordConstant::THIS => "this",
+        }
+        .to_string(),
+        Term::VarName(name) => name.0.clone(),
+        Term::ArrayTerm(array_term) => format!(
+            "{}[{}]",
+            array_term.var_name.0,
+            expression_text(&array_term.expression)
+        ),
+        Term::UnaryTerm(uterm) => format!("{}{}", uterm.unary_op.0 .0, term_text(&uterm.term)),
+        Term::WrappedExpression(exp) => format!("({})", expression_text(&exp.0)),
+        Term::SubroutineCall(call) => call_text(call),
+    }
+}
+
+fn call_text(call: &SubroutineCall) -> String {
+    let args: Vec<String> = call.expression_list.0.iter().map(expression_text).collect();
+    format!("{}({})", call_name(call), args.join(", "))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        dot::{call_graph, subroutine_cfg},
+        parser::{structures, Parsable, TokenReader},
+        tokenizer::tokenize_str,
+    };
+
+    fn parse(source: &str) -> structures::Class {
+        let tokens = tokenize_str(source);
+        structures::Class::try_parse(&TokenReader { tokens }, 0)
+            .unwrap()
+            .0
+    }
+
+    #[test]
+    fn while_cfg() {
+        let class = parse(
+            "class A { function void f() { var int i; let i = 0; while (i < 3) { let i = i + 1; } return; } }",
+        );
+        let cfg = subroutine_cfg("A", &class.subroutine_dec[0]);
+        assert!(cfg.contains("b0 [label=\"function void f() (entry)\\llet i = 0\\l\"];"));
+        assert!(cfg.contains("b2 [label=\"B2\\lwhile (i < 3)\\l\"];"));
+        assert!(cfg.contains("b2 -> b3 [label=\"true\"];"));
+        assert!(cfg.contains("b3 -> b2;"));
+        assert!(cfg.contains("b2 -> b4 [label=\"false\"];"));
+        assert!(cfg.contains("b4 -> b1;"));
+    }
+
+    #[test]
+    fn if_else_both_return() {
+        let class =
+            parse("class A { function int f(int x) { if (x) { return 1; } else { return 2; } } }");
+        let cfg = subroutine_cfg("A", &class.subroutine_dec[0]);
+        assert!(cfg.contains("b2 -> b1;"));
+        assert!(cfg.contains("b3 -> b1;"));
+        assert!(!cfg.contains("b4"));
+    }
+
+    #[test]
+    fn call_targets() {
+        let class = parse(
+            "class A { field B b; method void f() { var C c; do b.g(); do c.h(); do f(); do Output.printInt(Math.abs(1)); return; } }",
+        );
+        let graph = call_graph(&[class]);
+        assert!(graph.contains("\"A.f\" -> \"B.g\";"));
+        assert!(graph.contains("\"A.f\" -> \"C.h\";"));
+        assert!(graph.contains("\"A.f\" -> \"A.f\";"));
+        assert!(graph.contains("\"A.f\" -> \"Math.abs\";"));
+        assert!(graph.contains("\"Output.printInt\" [style=dashed];"));
+    }
+}
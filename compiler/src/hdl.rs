@@ -0,0 +1,524 @@
+// parser for the nand2tetris hardware description language
+//
+//     CHIP Mux16 {
+//         IN a[16], b[16], sel;
+//         OUT out[16];
+//         PARTS:
+//         Mux(a=a[0], b=b[0], sel=sel, out=out[0]);
+//         ...
+//     }
+//
+// Built-in chips replace `PARTS:` with `BUILTIN Name;` and may list their
+// `CLOCKED` pins. Every node keeps the place it was read from, so errors
+// found later while wiring chips together can point back into the file.
+
+use std::fmt::{self, Display};
+
+/// Where something was read from, lines and columns counting from 1.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Location {
+    pub file: String,
+    pub line: usize,
+    pub column: usize,
+}
+
+impl Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}:{}", self.file, self.line, self.column)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct HdlError {
+    pub location: Location,
+    pub message: String,
+}
+
+impl HdlError {
+    pub fn new(location: &Location, message: String) -> HdlError {
+        HdlError {
+            location: location.clone(),
+            message,
+        }
+    }
+}
+
+impl Display for HdlError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.location.file.is_empty() {
+            return write!(f, "{}", self.message);
+        }
+        write!(f, "{}: {}", self.location, self.message)
+    }
+}
+
+/// A pin declared in `IN` or `OUT`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Pin {
+    pub name: String,
+    pub width: usize,
+    pub location: Location,
+}
+
+/// A pin or wire name with an optional `[i]` or `[i..j]` sub-bus.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PinRef {
+    pub name: String,
+    pub range: Option<(usize, usize)>,
+    pub location: Location,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Wire {
+    Pin(PinRef),
+    /// `true` or `false`, filling every bit it's connected to.
+    Const(bool),
+}
+
+/// `pin=wire` inside a part.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Connection {
+    pub pin: PinRef,
+    pub wire: Wire,
+    pub location: Location,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Part {
+    pub chip: String,
+    pub connections: Vec<Connection>,
+    pub location: Location,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Chip {
+    pub name: String,
+    pub inputs: Vec<Pin>,
+    pub outputs: Vec<Pin>,
+    pub parts: Vec<Part>,
+    /// Name after `BUILTIN`, when the chip isn't made of parts.
+    pub builtin: Option<String>,
+    pub clocked: Vec<String>,
+    pub location: Location,
+}
+
+impl Chip {
+    pub fn input(&self, name: &str) -> Option<&Pin> {
+        self.inputs.iter().find(|pin| pin.name == name)
+    }
+
+    pub fn output(&self, name: &str) -> Option<&Pin> {
+        self.outputs.iter().find(|pin| pin.name == name)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Word(String),
+    Number(usize),
+    Symbol(&'static str),
+}
+
+impl Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Token::Word(word) => write!(f, "{}", word),
+            Token::Number(n) => write!(f, "{}", n),
+            Token::Symbol(symbol) => write!(f, "{}", symbol),
+        }
+    }
+}
+
+const SYMBOLS: [&str; 11] = ["..", "{", "}", "(", ")", "[", "]", ",", ";", "=", ":"];
+
+fn tokenize(file: &str, text: &str) -> Result<Vec<(Token, Location)>, HdlError> {
+    let mut tokens = vec![];
+    let chars: Vec<char> = text.chars().collect();
+    let (mut i, mut line, mut column) = (0, 1, 1);
+    let location = |line, column| Location {
+        file: file.to_string(),
+        line,
+        column,
+    };
+    while i < chars.len() {
+        let start = location(line, column);
+        let rest: String = chars[i..chars.len().min(i + 2)].iter().collect();
+        let mut advance = |n: usize, i: &mut usize| {
+            for _ in 0..n {
+                if chars[*i] == '\n' {
+                    line += 1;
+                    column = 1;
+                } else {
+                    column += 1;
+                }
+                *i += 1;
+            }
+        };
+        if chars[i].is_whitespace() {
+            advance(1, &mut i);
+        } else if rest == "//" {
+            while i < chars.len() && chars[i] != '\n' {
+                advance(1, &mut i);
+            }
+        } else if rest == "/*" {
+            advance(2, &mut i);
+            while i < chars.len() && !(chars[i] == '*' && chars.get(i + 1) == Some(&'/')) {
+                advance(1, &mut i);
+            }
+            if i >= chars.len() {
+                return Err(HdlError::new(&start, "unterminated comment".to_string()));
+            }
+            advance(2, &mut i);
+        } else if chars[i].is_ascii_digit() {
+            let mut n = 0usize;
+            while i < chars.len() && chars[i].is_ascii_digit() {
+                n = n
+                    .saturating_mul(10)
+                    .saturating_add(chars[i] as usize - '0' as usize);
+                advance(1, &mut i);
+            }
+            tokens.push((Token::Number(n), start));
+        } else if chars[i].is_alphabetic() || chars[i] == '_' {
+            let mut word = String::new();
+            while i < chars.len() && (chars[i].is_alphanumeric() || chars[i] == '_') {
+                word.push(chars[i]);
+                advance(1, &mut i);
+            }
+            tokens.push((Token::Word(word), start));
+        } else if let Some(symbol) = SYMBOLS.iter().find(|symbol| rest.starts_with(**symbol)) {
+            advance(symbol.len(), &mut i);
+            tokens.push((Token::Symbol(symbol), start));
+        } else {
+            return Err(HdlError::new(
+                &start,
+                format!("unexpected character {:?}", chars[i]),
+            ));
+        }
+    }
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<(Token, Location)>,
+    index: usize,
+    end: Location,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.index).map(|(token, _)| token)
+    }
+
+    fn location(&self) -> Location {
+        self.tokens
+            .get(self.index)
+            .map_or(self.end.clone(), |(_, location)| location.clone())
+    }
+
+    fn error(&self, expected: &str) -> HdlError {
+        let found = match self.peek() {
+            Some(token) => format!("found {}", token),
+            None => "reached the end of the file".to_string(),
+        };
+        HdlError::new(
+            &self.location(),
+            format!("expected {}, {}", expected, found),
+        )
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.peek().cloned();
+        self.index += 1;
+        token
+    }
+
+    fn is_symbol(&self, symbol: &str) -> bool {
+        self.peek()
+            == Some(&Token::Symbol(
+                match SYMBOLS.iter().find(|s| **s == symbol) {
+                    Some(s) => s,
+                    None => return false,
+                },
+            ))
+    }
+
+    fn is_word(&self, word: &str) -> bool {
+        matches!(self.peek(), Some(Token::Word(w)) if w == word)
+    }
+
+    fn symbol(&mut self, symbol: &str) -> Result<(), HdlError> {
+        if !self.is_symbol(symbol) {
+            return Err(self.error(&format!("'{}'", symbol)));
+        }
+        self.index += 1;
+        Ok(())
+    }
+
+    fn keyword(&mut self, word: &str) -> Result<(), HdlError> {
+        if !self.is_word(word) {
+            return Err(self.error(word));
+        }
+        self.index += 1;
+        Ok(())
+    }
+
+    fn name(&mut self) -> Result<(String, Location), HdlError> {
+        let location = self.location();
+        match self.peek() {
+            Some(Token::Word(word)) => {
+                let word = word.clone();
+                self.index += 1;
+                Ok((word, location))
+            }
+            _ => Err(self.error("a name")),
+        }
+    }
+
+    fn number(&mut self) -> Result<usize, HdlError> {
+        match self.peek() {
+            Some(Token::Number(n)) => {
+                let n = *n;
+                self.index += 1;
+                Ok(n)
+            }
+            _ => Err(self.error("a number")),
+        }
+    }
+
+    /// `name` or `name[width]`, for `IN` and `OUT`.
+    fn pins(&mut self) -> Result<Vec<Pin>, HdlError> {
+        let mut pins = vec![];
+        loop {
+            let (name, location) = self.name()?;
+            let mut width = 1;
+            if self.is_symbol("[") {
+                self.symbol("[")?;
+                let at = self.location();
+                width = self.number()?;
+                if !(1..=64).contains(&width) {
+                    return Err(HdlError::new(&at, format!("bad bus width {}", width)));
+                }
+                self.symbol("]")?;
+            }
+            pins.push(Pin {
+                name,
+                width,
+                location,
+            });
+            if !self.is_symbol(",") {
+                break;
+            }
+            self.symbol(",")?;
+        }
+        self.symbol(";")?;
+        Ok(pins)
+    }
+
+    fn pin_ref(&mut self) -> Result<PinRef, HdlError> {
+        let (name, location) = self.name()?;
+        let mut range = None;
+        if self.is_symbol("[") {
+            self.symbol("[")?;
+            let at = self.location();
+            let from = self.number()?;
+            let mut to = from;
+            if self.is_symbol("..") {
+                self.symbol("..")?;
+                to = self.number()?;
+            }
+            if to < from {
+                return Err(HdlError::new(
+                    &at,
+                    format!("bad sub-bus [{}..{}]", from, to),
+                ));
+            }
+            self.symbol("]")?;
+            range = Some((from, to));
+        }
+        Ok(PinRef {
+            name,
+            range,
+            location,
+        })
+    }
+
+    fn part(&mut self) -> Result<Part, HdlError> {
+        let (chip, location) = self.name()?;
+        self.symbol("(")?;
+        let mut connections = vec![];
+        loop {
+            let at = self.location();
+            let pin = self.pin_ref()?;
+            self.symbol("=")?;
+            let wire = if self.is_word("true") || self.is_word("false") {
+                let value = self.is_word("true");
+                self.index += 1;
+                Wire::Const(value)
+            } else {
+                Wire::Pin(self.pin_ref()?)
+            };
+            connections.push(Connection {
+                pin,
+                wire,
+                location: at,
+            });
+            if !self.is_symbol(",") {
+                break;
+            }
+            self.symbol(",")?;
+        }
+        self.symbol(")")?;
+        self.symbol(";")?;
+        Ok(Part {
+            chip,
+            connections,
+            location,
+        })
+    }
+
+    fn chip(&mut self) -> Result<Chip, HdlError> {
+        let location = self.location();
+        self.keyword("CHIP")?;
+        let (name, _) = self.name()?;
+        self.symbol("{")?;
+        let mut chip = Chip {
+            name,
+            inputs: vec![],
+            outputs: vec![],
+            parts: vec![],
+            builtin: None,
+            clocked: vec![],
+            location,
+        };
+        loop {
+            if self.is_word("IN") {
+                self.next();
+                chip.inputs.extend(self.pins()?);
+            } else if self.is_word("OUT") {
+                self.next();
+                chip.outputs.extend(self.pins()?);
+            } else {
+                break;
+            }
+        }
+        if self.is_word("BUILTIN") {
+            self.next();
+            chip.builtin = Some(self.name()?.0);
+            self.symbol(";")?;
+            if self.is_word("CLOCKED") {
+                self.next();
+                loop {
+                    chip.clocked.push(self.name()?.0);
+                    if !self.is_symbol(",") {
+                        break;
+                    }
+                    self.symbol(",")?;
+                }
+                self.symbol(";")?;
+            }
+        } else {
+            self.keyword("PARTS")?;
+            self.symbol(":")?;
+            while !self.is_symbol("}") && self.peek().is_some() {
+                chip.parts.push(self.part()?);
+            }
+        }
+        self.symbol("}")?;
+        if self.peek().is_some() {
+            return Err(self.error("the end of the file"));
+        }
+        Ok(chip)
+    }
+}
+
+/// Parse the chip in `text`, read from `file`.
+pub fn parse(file: &str, text: &str) -> Result<Chip, HdlError> {
+    let tokens = tokenize(file, text)?;
+    let lines = text.lines().count().max(1);
+    let mut parser = Parser {
+        tokens,
+        index: 0,
+        end: Location {
+            file: file.to_string(),
+            line: lines,
+            column: text
+                .lines()
+                .last()
+                .map_or(1, |line| line.chars().count() + 1),
+        },
+    };
+    parser.chip()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_chips() {
+        let chip = parse(
+            "Mux16.hdl",
+            "/** doc */\nCHIP Mux16 {\n    IN a[16], b[16], sel; // select\n    OUT out[16];\n    PARTS:\n    Mux(a=a[0], b=b[3..4], sel=true, out=out[0], out=x);\n}\n",
+        )
+        .unwrap();
+        assert_eq!(chip.name, "Mux16");
+        assert_eq!(
+            chip.inputs
+                .iter()
+                .map(|pin| (pin.name.as_str(), pin.width))
+                .collect::<Vec<_>>(),
+            vec![("a", 16), ("b", 16), ("sel", 1)]
+        );
+        assert_eq!(chip.outputs[0].location.to_string(), "Mux16.hdl:4:9");
+        let part = &chip.parts[0];
+        assert_eq!(part.chip, "Mux");
+        assert_eq!(part.location.line, 6);
+        assert_eq!(part.connections.len(), 5);
+        let Wire::Pin(wire) = &part.connections[1].wire else {
+            panic!("expected a pin");
+        };
+        assert_eq!(wire.range, Some((3, 4)));
+        assert_eq!(part.connections[2].wire, Wire::Const(true));
+        let Wire::Pin(wire) = &part.connections[3].wire else {
+            panic!("expected a pin");
+        };
+        assert_eq!(wire.range, Some((0, 0)));
+    }
+
+    #[test]
+    fn parses_builtin_chips() {
+        let chip = parse(
+            "DFF.hdl",
+            "CHIP DFF { IN in; OUT out; BUILTIN DFF; CLOCKED in; }",
+        )
+        .unwrap();
+        assert_eq!(chip.builtin.as_deref(), Some("DFF"));
+        assert_eq!(chip.clocked, vec!["in"]);
+        assert!(chip.parts.is_empty());
+    }
+
+    #[test]
+    fn reports_located_errors() {
+        let error = |text| parse("X.hdl", text).unwrap_err().to_string();
+        assert_eq!(
+            error("CHIP X {\n  IN a;\n  OUT b\n  PARTS:\n}"),
+            "X.hdl:4:3: expected ';', found PARTS"
+        );
+        assert_eq!(
+            error("CHIP X { IN a[0]; PARTS: }"),
+            "X.hdl:1:15: bad bus width 0"
+        );
+        assert_eq!(
+            error("CHIP X { PARTS: Not(in=a[3..1], out=b); }"),
+            "X.hdl:1:26: bad sub-bus [3..1]"
+        );
+        assert_eq!(
+            error("CHIP X { PARTS: Not(in=a"),
+            "X.hdl:1:25: expected ')', reached the end of the file"
+        );
+        assert_eq!(
+            error("CHIP X { # }"),
+            "X.hdl:1:10: unexpected character '#'"
+        );
+    }
+}
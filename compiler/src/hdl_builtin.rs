@@ -0,0 +1,635 @@
+// the standard chips, simulated natively
+//
+// Each built-in chip works on whole buses instead of gates, so a chip can
+// be tested with the standard versions of its parts whatever state the
+// user's own versions are in. Clocked chips keep words of state (a
+// register, a memory) that `tick` computes writes to and `tock` applies,
+// like a DFF. Test scripts can read and set that state by name, such as
+// `RAM16K[2]` or `DRegister[]`. Memory and Computer keep the state of the
+// parts they are made of under the parts' names.
+
+use crate::keyboard::KEYBOARD;
+use crate::screen::SCREEN;
+
+/// Writes of `(word, value)` to a chip's state.
+pub type Writes = Vec<(usize, u16)>;
+
+/// The writes a clocked chip makes given its state and inputs.
+pub type Tick = fn(&[u16], &[u64]) -> Writes;
+
+/// A built-in chip: its interface, behaviour and state.
+#[derive(Debug)]
+pub struct Builtin {
+    pub name: &'static str,
+    /// `IN` and `OUT` declarations.
+    pub pins: &'static str,
+    /// Outputs with the inputs they follow within an eval. Empty when every
+    /// output depends on every input; outputs not listed only change on a
+    /// `tock`.
+    pub groups: &'static [Group],
+    /// Words of state.
+    pub words: usize,
+    /// Names scripts use for the state, as `(name, first word, words)`.
+    pub exposes: &'static [(&'static str, usize, usize)],
+    /// Outputs from the state and the inputs, in declaration order.
+    pub eval: fn(&[u16], &[u64]) -> Vec<u64>,
+    /// Writes to the state on the rising edge, applied on the falling one.
+    pub tick: Option<Tick>,
+}
+
+#[derive(Debug)]
+pub struct Group {
+    pub outputs: &'static [&'static str],
+    pub inputs: &'static [&'static str],
+}
+
+impl Builtin {
+    /// The interface as an HDL chip.
+    pub fn hdl(&self) -> String {
+        format!(
+            "CHIP {} {{ {} BUILTIN {}; }}",
+            self.name, self.pins, self.name
+        )
+    }
+
+    /// The state word a script means by `name[index]`, with `name[]` being
+    /// the first.
+    pub fn word(&self, name: &str, index: Option<usize>) -> Option<usize> {
+        let (first, words) = self.exposed(name)?;
+        let index = index.unwrap_or(0);
+        (index < words).then_some(first + index)
+    }
+
+    /// The first word and number of words of the state named `name`.
+    pub fn exposed(&self, name: &str) -> Option<(usize, usize)> {
+        self.exposes
+            .iter()
+            .find(|(exposed, _, _)| *exposed == name)
+            .map(|(_, first, words)| (*first, *words))
+    }
+}
+
+pub fn find(name: &str) -> Option<&'static Builtin> {
+    CHIPS.iter().find(|chip| chip.name == name)
+}
+
+/// The Hack ALU's output for the six control bits `zx nx zy ny f no`, `zx`
+/// the most significant, as in bits 11 to 6 of a C-instruction.
+pub fn alu(x: u16, y: u16, control: u64) -> u16 {
+    let x = if bit(control, 5) { 0 } else { x };
+    let x = if bit(control, 4) { !x } else { x };
+    let y = if bit(control, 3) { 0 } else { y };
+    let y = if bit(control, 2) { !y } else { y };
+    let out = if bit(control, 1) {
+        x.wrapping_add(y)
+    } else {
+        x & y
+    };
+    if bit(control, 0) {
+        !out
+    } else {
+        out
+    }
+}
+
+fn bit(value: u64, n: u32) -> bool {
+    (value >> n) & 1 == 1
+}
+
+fn mask(width: u32) -> u64 {
+    (1 << width) - 1
+}
+
+fn not(_: &[u16], i: &[u64]) -> Vec<u64> {
+    vec![!i[0] & 1]
+}
+
+fn and(_: &[u16], i: &[u64]) -> Vec<u64> {
+    vec![i[0] & i[1]]
+}
+
+fn or(_: &[u16], i: &[u64]) -> Vec<u64> {
+    vec![i[0] | i[1]]
+}
+
+fn xor(_: &[u16], i: &[u64]) -> Vec<u64> {
+    vec![i[0] ^ i[1]]
+}
+
+fn mux(_: &[u16], i: &[u64]) -> Vec<u64> {
+    vec![if i[2] & 1 == 1 { i[1] } else { i[0] }]
+}
+
+fn dmux(_: &[u16], i: &[u64]) -> Vec<u64> {
+    (0..2).map(|n| if i[1] == n { i[0] } else { 0 }).collect()
+}
+
+fn not16(_: &[u16], i: &[u64]) -> Vec<u64> {
+    vec![!i[0] & mask(16)]
+}
+
+fn or8way(_: &[u16], i: &[u64]) -> Vec<u64> {
+    vec![(i[0] != 0) as u64]
+}
+
+fn mux4way16(_: &[u16], i: &[u64]) -> Vec<u64> {
+    vec![i[i[4] as usize]]
+}
+
+fn mux8way16(_: &[u16], i: &[u64]) -> Vec<u64> {
+    vec![i[i[8] as usize]]
+}
+
+fn dmux4way(_: &[u16], i: &[u64]) -> Vec<u64> {
+    (0..4).map(|n| if i[1] == n { i[0] } else { 0 }).collect()
+}
+
+fn dmux8way(_: &[u16], i: &[u64]) -> Vec<u64> {
+    (0..8).map(|n| if i[1] == n { i[0] } else { 0 }).collect()
+}
+
+fn half_adder(_: &[u16], i: &[u64]) -> Vec<u64> {
+    let sum = i[0] + i[1];
+    vec![sum & 1, sum >> 1]
+}
+
+fn full_adder(_: &[u16], i: &[u64]) -> Vec<u64> {
+    let sum = i[0] + i[1] + i[2];
+    vec![sum & 1, sum >> 1]
+}
+
+fn add16(_: &[u16], i: &[u64]) -> Vec<u64> {
+    vec![(i[0] + i[1]) & mask(16)]
+}
+
+fn inc16(_: &[u16], i: &[u64]) -> Vec<u64> {
+    vec![(i[0] + 1) & mask(16)]
+}
+
+fn alu_chip(_: &[u16], i: &[u64]) -> Vec<u64> {
+    let control = i[2..].iter().fold(0, |control, bit| control << 1 | bit);
+    let out = alu(i[0] as u16, i[1] as u16, control);
+    vec![out as u64, (out == 0) as u64, (out >> 15) as u64]
+}
+
+/// Registers and memories: `out` is the addressed word.
+fn memory(state: &[u16], i: &[u64]) -> Vec<u64> {
+    let address = i.get(2).copied().unwrap_or(0) as usize;
+    vec![state[address] as u64]
+}
+
+/// `in` written to the addressed word when `load` is set.
+fn memory_write(_: &[u16], i: &[u64]) -> Writes {
+    if i[1] == 1 {
+        vec![(i.get(2).copied().unwrap_or(0) as usize, i[0] as u16)]
+    } else {
+        vec![]
+    }
+}
+
+fn rom(state: &[u16], i: &[u64]) -> Vec<u64> {
+    vec![state[i[0] as usize] as u64]
+}
+
+fn keyboard(state: &[u16], _: &[u64]) -> Vec<u64> {
+    vec![state[0] as u64]
+}
+
+fn pc(state: &[u16], _: &[u64]) -> Vec<u64> {
+    vec![state[0] as u64]
+}
+
+fn pc_write(state: &[u16], i: &[u64]) -> Writes {
+    let (input, load, inc, reset) = (i[0] as u16, i[1] == 1, i[2] == 1, i[3] == 1);
+    let next = if reset {
+        0
+    } else if load {
+        input
+    } else if inc {
+        state[0].wrapping_add(1)
+    } else {
+        state[0]
+    };
+    vec![(0, next)]
+}
+
+const A: usize = 0;
+const D: usize = 1;
+const PC: usize = 2;
+
+/// The ALU output for a C-instruction.
+fn cpu_alu(state: &[u16], in_m: u16, instruction: u64) -> u16 {
+    let y = if bit(instruction, 12) { in_m } else { state[A] };
+    alu(state[D], y, instruction >> 6 & 0x3f)
+}
+
+fn cpu(state: &[u16], i: &[u64]) -> Vec<u64> {
+    let (in_m, instruction) = (i[0] as u16, i[1]);
+    let c = bit(instruction, 15);
+    let out_m = if c {
+        cpu_alu(state, in_m, instruction)
+    } else {
+        0
+    };
+    vec![
+        out_m as u64,
+        (c && bit(instruction, 3)) as u64,
+        state[A] as u64 & mask(15),
+        state[PC] as u64 & mask(15),
+    ]
+}
+
+fn cpu_write(state: &[u16], i: &[u64]) -> Writes {
+    let (in_m, instruction, reset) = (i[0] as u16, i[1], i[2] == 1);
+    let mut writes = vec![];
+    let mut jump = false;
+    if bit(instruction, 15) {
+        let out = cpu_alu(state, in_m, instruction);
+        let (zr, ng) = (out == 0, out >> 15 == 1);
+        if bit(instruction, 5) {
+            writes.push((A, out));
+        }
+        if bit(instruction, 4) {
+            writes.push((D, out));
+        }
+        jump = (bit(instruction, 2) && ng)
+            || (bit(instruction, 1) && zr)
+            || (bit(instruction, 0) && !ng && !zr);
+    } else {
+        writes.push((A, instruction as u16));
+    }
+    let next = if reset {
+        0
+    } else if jump {
+        state[A]
+    } else {
+        state[PC].wrapping_add(1)
+    };
+    writes.push((PC, next));
+    writes
+}
+
+/// Data memory, a word of state per address up to the keyboard's, decoded
+/// like the CPU emulator's memory map: RAM16K, then Screen, then Keyboard.
+/// Addresses past the keyboard read 0.
+fn data_memory(state: &[u16], i: &[u64]) -> Vec<u64> {
+    vec![state.get(i[2] as usize).map_or(0, |word| *word as u64)]
+}
+
+/// `in` written to RAM16K or Screen; the keyboard and the addresses past it
+/// can't be written.
+fn data_memory_write(_: &[u16], i: &[u64]) -> Writes {
+    let address = i[2] as usize;
+    if i[1] == 1 && address < KEYBOARD {
+        vec![(address, i[0] as u16)]
+    } else {
+        vec![]
+    }
+}
+
+/// Where the Computer keeps the state of its ROM32K, CPU and Memory.
+const ROM: usize = 0;
+const CPU: usize = ROM + 32768;
+const DATA: usize = CPU + 3;
+
+/// The Computer has no outputs; it only runs its program on the clock.
+fn computer(_: &[u16], _: &[u64]) -> Vec<u64> {
+    vec![]
+}
+
+/// A cycle of the CPU running the instruction at PC, its `writeM` going to
+/// Memory.
+fn computer_write(state: &[u16], i: &[u64]) -> Writes {
+    let (cpu_state, memory) = (&state[CPU..DATA], &state[DATA..]);
+    let instruction = state[ROM + (cpu_state[PC] as usize & 0x7fff)] as u64;
+    let address = cpu_state[A] as u64 & mask(15);
+    let in_m = data_memory(memory, &[0, 0, address])[0];
+    let cpu_inputs = [in_m, instruction, i[0]];
+    let out = cpu(cpu_state, &cpu_inputs);
+    let cpu_writes = cpu_write(cpu_state, &cpu_inputs);
+    let memory_writes = data_memory_write(memory, &[out[0], out[1], address]);
+    cpu_writes
+        .into_iter()
+        .map(|(word, value)| (CPU + word, value))
+        .chain(
+            memory_writes
+                .into_iter()
+                .map(|(word, value)| (DATA + word, value)),
+        )
+        .collect()
+}
+
+const COMBINATIONAL: &[Group] = &[];
+
+const REGISTER: &[Group] = &[Group {
+    outputs: &["out"],
+    inputs: &[],
+}];
+
+const MEMORY: &[Group] = &[Group {
+    outputs: &["out"],
+    inputs: &["address"],
+}];
+
+const fn gates(
+    name: &'static str,
+    pins: &'static str,
+    eval: fn(&[u16], &[u64]) -> Vec<u64>,
+) -> Builtin {
+    Builtin {
+        name,
+        pins,
+        groups: COMBINATIONAL,
+        words: 0,
+        exposes: &[],
+        eval,
+        tick: None,
+    }
+}
+
+const fn register(
+    name: &'static str,
+    pins: &'static str,
+    exposes: &'static [(&'static str, usize, usize)],
+) -> Builtin {
+    Builtin {
+        name,
+        pins,
+        groups: REGISTER,
+        words: 1,
+        exposes,
+        eval: memory,
+        tick: Some(memory_write),
+    }
+}
+
+const fn ram(
+    name: &'static str,
+    pins: &'static str,
+    words: usize,
+    exposes: &'static [(&'static str, usize, usize)],
+) -> Builtin {
+    Builtin {
+        name,
+        pins,
+        groups: MEMORY,
+        words,
+        exposes,
+        eval: memory,
+        tick: Some(memory_write),
+    }
+}
+
+pub const CHIPS: [Builtin; 36] = [
+    gates("Not", "IN in; OUT out;", not),
+    gates("And", "IN a, b; OUT out;", and),
+    gates("Or", "IN a, b; OUT out;", or),
+    gates("Xor", "IN a, b; OUT out;", xor),
+    gates("Mux", "IN a, b, sel; OUT out;", mux),
+    gates("DMux", "IN in, sel; OUT a, b;", dmux),
+    gates("Not16", "IN in[16]; OUT out[16];", not16),
+    gates("And16", "IN a[16], b[16]; OUT out[16];", and),
+    gates("Or16", "IN a[16], b[16]; OUT out[16];", or),
+    gates("Mux16", "IN a[16], b[16], sel; OUT out[16];", mux),
+    gates("Or8Way", "IN in[8]; OUT out;", or8way),
+    gates(
+        "Mux4Way16",
+        "IN a[16], b[16], c[16], d[16], sel[2]; OUT out[16];",
+        mux4way16,
+    ),
+    gates(
+        "Mux8Way16",
+        "IN a[16], b[16], c[16], d[16], e[16], f[16], g[16], h[16], sel[3]; OUT out[16];",
+        mux8way16,
+    ),
+    gates("DMux4Way", "IN in, sel[2]; OUT a, b, c, d;", dmux4way),
+    gates(
+        "DMux8Way",
+        "IN in, sel[3]; OUT a, b, c, d, e, f, g, h;",
+        dmux8way,
+    ),
+    gates("HalfAdder", "IN a, b; OUT sum, carry;", half_adder),
+    gates("FullAdder", "IN a, b, c; OUT sum, carry;", full_adder),
+    gates("Add16", "IN a[16], b[16]; OUT out[16];", add16),
+    gates("Inc16", "IN in[16]; OUT out[16];", inc16),
+    gates(
+        "ALU",
+        "IN x[16], y[16], zx, nx, zy, ny, f, no; OUT out[16], zr, ng;",
+        alu_chip,
+    ),
+    register("Bit", "IN in, load; OUT out;", &[("Bit", 0, 1)]),
+    register(
+        "Register",
+        "IN in[16], load; OUT out[16];",
+        &[("Register", 0, 1)],
+    ),
+    register(
+        "ARegister",
+        "IN in[16], load; OUT out[16];",
+        &[("ARegister", 0, 1)],
+    ),
+    register(
+        "DRegister",
+        "IN in[16], load; OUT out[16];",
+        &[("DRegister", 0, 1)],
+    ),
+    Builtin {
+        name: "PC",
+        pins: "IN in[16], load, inc, reset; OUT out[16];",
+        groups: REGISTER,
+        words: 1,
+        exposes: &[("PC", 0, 1)],
+        eval: pc,
+        tick: Some(pc_write),
+    },
+    ram(
+        "RAM8",
+        "IN in[16], load, address[3]; OUT out[16];",
+        8,
+        &[("RAM8", 0, 8)],
+    ),
+    ram(
+        "RAM64",
+        "IN in[16], load, address[6]; OUT out[16];",
+        64,
+        &[("RAM64", 0, 64)],
+    ),
+    ram(
+        "RAM512",
+        "IN in[16], load, address[9]; OUT out[16];",
+        512,
+        &[("RAM512", 0, 512)],
+    ),
+    ram(
+        "RAM4K",
+        "IN in[16], load, address[12]; OUT out[16];",
+        4096,
+        &[("RAM4K", 0, 4096)],
+    ),
+    ram(
+        "RAM16K",
+        "IN in[16], load, address[14]; OUT out[16];",
+        16384,
+        &[("RAM16K", 0, 16384)],
+    ),
+    ram(
+        "Screen",
+        "IN in[16], load, address[13]; OUT out[16];",
+        8192,
+        &[("Screen", 0, 8192)],
+    ),
+    Builtin {
+        name: "ROM32K",
+        pins: "IN address[15]; OUT out[16];",
+        groups: MEMORY,
+        words: 32768,
+        exposes: &[("ROM32K", 0, 32768)],
+        eval: rom,
+        tick: None,
+    },
+    Builtin {
+        name: "Keyboard",
+        pins: "OUT out[16];",
+        groups: REGISTER,
+        words: 1,
+        exposes: &[("Keyboard", 0, 1)],
+        eval: keyboard,
+        tick: None,
+    },
+    Builtin {
+        name: "CPU",
+        pins: "IN inM[16], instruction[16], reset; \
+               OUT outM[16], writeM, addressM[15], pc[15];",
+        groups: &[
+            Group {
+                outputs: &["outM", "writeM"],
+                inputs: &["inM", "instruction"],
+            },
+            Group {
+                outputs: &["addressM", "pc"],
+                inputs: &[],
+            },
+        ],
+        words: 3,
+        exposes: &[("ARegister", A, 1), ("DRegister", D, 1), ("PC", PC, 1)],
+        eval: cpu,
+        tick: Some(cpu_write),
+    },
+    Builtin {
+        name: "Memory",
+        pins: "IN in[16], load, address[15]; OUT out[16];",
+        groups: MEMORY,
+        words: KEYBOARD + 1,
+        exposes: &[
+            ("RAM16K", 0, SCREEN),
+            ("Screen", SCREEN, KEYBOARD - SCREEN),
+            ("Keyboard", KEYBOARD, 1),
+            ("Memory", 0, KEYBOARD + 1),
+        ],
+        eval: data_memory,
+        tick: Some(data_memory_write),
+    },
+    Builtin {
+        name: "Computer",
+        pins: "IN reset;",
+        groups: COMBINATIONAL,
+        words: DATA + KEYBOARD + 1,
+        exposes: &[
+            ("ROM32K", ROM, 32768),
+            ("ARegister", CPU + A, 1),
+            ("DRegister", CPU + D, 1),
+            ("PC", CPU + PC, 1),
+            ("RAM16K", DATA, SCREEN),
+            ("Screen", DATA + SCREEN, KEYBOARD - SCREEN),
+            ("Keyboard", DATA + KEYBOARD, 1),
+            ("Memory", DATA, KEYBOARD + 1),
+        ],
+        eval: computer,
+        tick: Some(computer_write),
+    },
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hdl;
+
+    #[test]
+    fn interfaces_parse() {
+        for chip in &CHIPS {
+            let parsed = hdl::parse(chip.name, &chip.hdl()).unwrap();
+            assert_eq!(parsed.builtin.as_deref(), Some(chip.name));
+            for group in chip.groups {
+                for pin in group.outputs {
+                    assert!(parsed.output(pin).is_some(), "{}.{}", chip.name, pin);
+                }
+                for pin in group.inputs {
+                    assert!(parsed.input(pin).is_some(), "{}.{}", chip.name, pin);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn runs_cpu_instructions() {
+        let eval = |state: &[u16], instruction: u64| cpu(state, &[7, instruction, 0]);
+        let tick = |state: &mut [u16], instruction: u64| {
+            for (word, value) in cpu_write(state, &[7, instruction, 0]) {
+                state[word] = value;
+            }
+        };
+        let mut state = [0u16; 3];
+        tick(&mut state, 12345); // @12345
+        assert_eq!(state, [12345, 0, 1]);
+        tick(&mut state, 0b1110110000010000); // D=A
+        assert_eq!(state, [12345, 12345, 2]);
+        // M=D+M writes 12352
+        assert_eq!(eval(&state, 0b1111000010001000), vec![12352, 1, 12345, 2]);
+        tick(&mut state, 0b1110001100000101); // D;JNE
+        assert_eq!(state[PC], 12345);
+        assert_eq!(alu(5, 3, 0b010011), 2); // x-y
+    }
+
+    #[test]
+    fn decodes_memory_like_the_emulator() {
+        let mut state = vec![0u16; KEYBOARD + 1];
+        let mut write = |value: u64, address: usize| {
+            for (word, value) in data_memory_write(&state, &[value, 1, address as u64]) {
+                state[word] = value;
+            }
+            data_memory(&state, &[0, 0, address as u64])[0]
+        };
+        assert_eq!(write(7, 100), 7);
+        assert_eq!(write(9, SCREEN + 5), 9);
+        // the keyboard and the addresses past it are read-only
+        assert_eq!(write(3, KEYBOARD), 0);
+        assert_eq!(write(3, 0x7fff), 0);
+        let chip = find("Memory").unwrap();
+        assert_eq!(chip.word("Screen", Some(5)), Some(SCREEN + 5));
+        assert_eq!(chip.word("Keyboard", None), Some(KEYBOARD));
+        assert_eq!(chip.word("RAM16K", Some(SCREEN)), None);
+    }
+
+    #[test]
+    fn runs_programs_on_the_computer() {
+        let chip = find("Computer").unwrap();
+        let mut state = vec![0u16; chip.words];
+        // RAM[0] = 2 + 3, then M=D to the keyboard, which keeps its key
+        let program = [2, 0xec10, 3, 0xe090, 0, 0xe308, 24576, 0xe308];
+        state[..program.len()].copy_from_slice(&program);
+        state[chip.word("Keyboard", None).unwrap()] = 75;
+        for _ in 0..program.len() / 2 * 2 {
+            for (word, value) in computer_write(&state, &[0]) {
+                state[word] = value;
+            }
+        }
+        let word = |name, index| state[chip.word(name, index).unwrap()];
+        assert_eq!(word("RAM16K", Some(0)), 5);
+        assert_eq!(word("Keyboard", None), 75);
+        assert_eq!((word("ARegister", None), word("PC", None)), (24576, 8));
+        for (word, value) in computer_write(&state, &[1]) {
+            state[word] = value;
+        }
+        assert_eq!(state[CPU + PC], 0);
+    }
+}
@@ -0,0 +1,245 @@
+// lockstep differential testing of an HDL Computer against the CPU emulator
+//
+// `hdl-diff Computer.hdl Max.hack` loads a program into the ROM32K of a
+// Computer chip and into the CPU emulator, then clocks the chip a cycle for
+// each instruction the emulator runs. After every cycle it compares the A, D
+// and PC registers, as the chip's ARegister, DRegister and PC parts hold
+// them, and every data memory word either side has written so far, as the
+// chip's RAM16K and Screen parts hold it. It stops at the first cycle where
+// they disagree.
+
+use std::collections::BTreeSet;
+
+use crate::cpu_emulator::CpuEmulator;
+use crate::hdl_sim::Simulator;
+use crate::keyboard::KEYBOARD;
+use crate::screen::SCREEN;
+use crate::vm_emulator::State;
+
+/// The registers compared and the parts holding them, in the order of
+/// `registers`.
+const REGISTERS: [(&str, &str); 3] = [("A", "ARegister"), ("D", "DRegister"), ("PC", "PC")];
+
+fn registers(cpu: &CpuEmulator) -> [u16; 3] {
+    [cpu.a, cpu.d, cpu.pc]
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Outcome {
+    /// Both sides agreed on every cycle, and the program halted or ran out
+    /// of cycles.
+    Agreed {
+        cycles: u64,
+        state: State,
+    },
+    Mismatch(Mismatch),
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Mismatch {
+    pub cycle: u64,
+    /// The address and instruction the cycle ran.
+    pub pc: u16,
+    pub instruction: u16,
+    pub differences: Vec<Difference>,
+}
+
+/// A register or memory word that differs: `what` is `D` or `RAM[16]`.
+#[derive(Debug, PartialEq)]
+pub struct Difference {
+    pub what: String,
+    pub chip: i16,
+    pub emulator: i16,
+}
+
+impl Mismatch {
+    pub fn text(&self) -> String {
+        let mut lines = vec![format!(
+            "cycle {}, running {:016b} at ROM[{}]:",
+            self.cycle, self.instruction, self.pc
+        )];
+        for difference in &self.differences {
+            lines.push(format!(
+                "  {} is {} in the chip but {} in the emulator",
+                difference.what, difference.chip, difference.emulator
+            ));
+        }
+        lines.join("\n")
+    }
+}
+
+/// The state word of the chip that holds data memory `address`.
+fn memory_word(address: usize) -> String {
+    match address {
+        _ if address < SCREEN => format!("RAM16K[{}]", address),
+        _ if address < KEYBOARD => format!("Screen[{}]", address - SCREEN),
+        KEYBOARD => "Keyboard[]".to_string(),
+        _ => format!("RAM[{}]", address),
+    }
+}
+
+/// The data memory address of a state word the chip writes, if it is one.
+fn memory_address(word: (&str, usize)) -> Option<usize> {
+    match word {
+        ("RAM16K", index) => Some(index),
+        ("Screen", index) => Some(SCREEN + index),
+        _ => None,
+    }
+}
+
+/// Set data memory `address` to `value` on both sides.
+pub fn poke(
+    simulator: &mut Simulator,
+    cpu: &mut CpuEmulator,
+    address: usize,
+    value: i16,
+) -> Result<(), String> {
+    simulator.set(&memory_word(address), value as i64)?;
+    cpu.ram[address] = value;
+    Ok(())
+}
+
+/// Run `cpu`'s program on both sides, with the chip's ROM32K loaded from
+/// the emulator's ROM, for at most `max_cycles` instructions.
+pub fn lockstep(
+    simulator: &mut Simulator,
+    cpu: &mut CpuEmulator,
+    max_cycles: u64,
+) -> Result<Outcome, String> {
+    let chip = simulator.chip.name.clone();
+    for (_, part) in REGISTERS {
+        if simulator.get(&format!("{}[]", part)).is_none() {
+            return Err(format!(
+                "{} has no {} to compare, use the built-in CPU or one made of \
+                 ARegister, DRegister and PC",
+                chip, part
+            ));
+        }
+    }
+    simulator.load_words("ROM32K", &cpu.rom)?;
+    simulator.eval();
+    let mut written = BTreeSet::new();
+    while cpu.state == State::Running && cpu.cycles < max_cycles {
+        let pc = cpu.pc;
+        let step = cpu.step();
+        written.extend(step.write.map(|(address, _)| address));
+        simulator.tick();
+        written.extend(
+            simulator
+                .pending_writes()
+                .into_iter()
+                .filter_map(memory_address),
+        );
+        simulator.tock();
+        let registers = REGISTERS
+            .iter()
+            .zip(registers(cpu))
+            .map(|((register, part), value)| (register.to_string(), format!("{}[]", part), value));
+        let memory = written.iter().map(|address| {
+            (
+                format!("RAM[{}]", address),
+                memory_word(*address),
+                cpu.ram[*address] as u16,
+            )
+        });
+        let mut differences = vec![];
+        for (what, word, emulator) in registers.chain(memory) {
+            let chip = simulator
+                .get(&word)
+                .ok_or_else(|| format!("{} has no {} to compare", chip, word))?;
+            if chip as u16 != emulator {
+                differences.push(Difference {
+                    what,
+                    chip: chip as i16,
+                    emulator: emulator as i16,
+                });
+            }
+        }
+        if !differences.is_empty() {
+            return Ok(Outcome::Mismatch(Mismatch {
+                cycle: cpu.cycles,
+                pc,
+                instruction: step.instruction,
+                differences,
+            }));
+        }
+    }
+    Ok(Outcome::Agreed {
+        cycles: cpu.cycles,
+        state: cpu.state,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu_emulator::parse_hack;
+    use crate::hdl_sim::Library;
+    use std::fs;
+
+    fn memory(ram_address: &str) -> String {
+        format!(
+            "CHIP Memory {{ IN in[16], load, address[15]; OUT out[16]; PARTS:
+             DMux4Way(in=load, sel=address[13..14], a=ram0, b=ram1, c=screen, d=keyboard);
+             Or(a=ram0, b=ram1, out=ram);
+             RAM16K(in=in, load=ram, address={}, out=ramOut);
+             Screen(in=in, load=screen, address=address[0..12], out=screenOut);
+             Keyboard(out=keyboardOut);
+             Mux4Way16(a=ramOut, b=ramOut, c=screenOut, d=keyboardOut, sel=address[13..14],
+                       out=out); }}",
+            ram_address
+        )
+    }
+
+    #[test]
+    fn finds_the_first_cycle_the_chip_goes_wrong() {
+        let dir = std::env::temp_dir().join(format!("hdl-diff-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(
+            dir.join("Computer.hdl"),
+            "CHIP Computer { IN reset; PARTS:
+             ROM32K(address=pc, out=instruction);
+             CPU(inM=inM, instruction=instruction, reset=reset, outM=outM, writeM=writeM,
+                 addressM=address, pc=pc);
+             Memory(in=outM, load=writeM, address=address, out=inM); }",
+        )
+        .unwrap();
+        // RAM[1] = RAM[0] + 3, then the screen's first word = -1
+        let program = parse_hack(
+            "0000000000000000\n1111110000010000\n0000000000000011\n\
+             1110000010010000\n0000000000000001\n1110001100001000\n\
+             0100000000000000\n1110111010001000\n0000000000001000\n\
+             1110101010000111\n",
+        )
+        .unwrap();
+        let run = |dir: &std::path::Path| {
+            let mut library = Library::new(dir, None).unwrap();
+            let mut simulator = Simulator::build(&mut library, "Computer").unwrap();
+            let mut cpu = CpuEmulator::new(&program);
+            poke(&mut simulator, &mut cpu, 0, 4).unwrap();
+            lockstep(&mut simulator, &mut cpu, 100).unwrap()
+        };
+
+        fs::write(dir.join("Memory.hdl"), memory("address[0..13]")).unwrap();
+        assert_eq!(
+            run(&dir),
+            Outcome::Agreed {
+                cycles: 10,
+                state: State::Halted
+            }
+        );
+
+        // a Memory that drops the low address bit writes RAM[0] instead
+        fs::write(dir.join("Memory.hdl"), memory("address[1..14]")).unwrap();
+        let Outcome::Mismatch(mismatch) = run(&dir) else {
+            panic!("no mismatch");
+        };
+        assert_eq!(
+            mismatch.text(),
+            "cycle 6, running 1110001100001000 at ROM[5]:\n  \
+             RAM[0] is 7 in the chip but 4 in the emulator\n  \
+             RAM[1] is 0 in the chip but 7 in the emulator"
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
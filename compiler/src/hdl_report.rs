@@ -0,0 +1,341 @@
+// gate counts and critical paths of HDL chips
+//
+// `hdl-report ALU.hdl` flattens a chip to Nands and DFFs like the simulator
+// does, then counts the Nands in total and per part and finds its longest
+// combinational path: the most Nands a change has to ripple through, from
+// an input pin or a DFF to an output pin or a DFF. Parts simulated natively
+// aren't made of Nands, so they are listed apart and take no time; a path
+// can also start at the state of one, such as the A register of a built-in
+// CPU.
+
+use std::collections::HashMap;
+
+use serde::Serialize;
+
+use crate::{
+    hdl::Part,
+    hdl_sim::{Count, Flattened, Net},
+};
+
+#[derive(Debug, PartialEq, Serialize)]
+pub struct Report {
+    pub chip: String,
+    #[serde(flatten)]
+    pub total: Count,
+    pub parts: Vec<PartReport>,
+    /// `None` when no path goes through a Nand.
+    pub critical_path: Option<CriticalPath>,
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+pub struct PartReport {
+    pub chip: String,
+    pub line: usize,
+    pub column: usize,
+    #[serde(flatten)]
+    pub count: Count,
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+pub struct CriticalPath {
+    /// Nands along the path.
+    pub delays: usize,
+    pub from: String,
+    pub to: String,
+    /// The parts it goes through, as `Chip (line:column)`, each once, in the
+    /// order the path first reaches them.
+    pub through: Vec<String>,
+}
+
+impl Report {
+    pub fn new(flattened: &Flattened) -> Report {
+        let parts = flattened
+            .chip
+            .parts
+            .iter()
+            .zip(&flattened.parts)
+            .map(|(part, count)| PartReport {
+                chip: part.chip.clone(),
+                line: part.location.line,
+                column: part.location.column,
+                count: count.clone(),
+            })
+            .collect();
+        Report {
+            chip: flattened.chip.name.clone(),
+            total: flattened.total.clone(),
+            parts,
+            critical_path: critical_path(flattened),
+        }
+    }
+
+    pub fn text(&self) -> String {
+        let mut lines = vec![format!("{}: {}", self.chip, count(&self.total))];
+        for part in &self.parts {
+            lines.push(format!(
+                "  {}: {}",
+                label(&part.chip, part.line, part.column),
+                count(&part.count)
+            ));
+        }
+        if let Some(path) = &self.critical_path {
+            let mut line = format!(
+                "critical path: {}, from {} to {}",
+                plural(path.delays, "gate delay"),
+                path.from,
+                path.to
+            );
+            if !path.through.is_empty() {
+                line += &format!(" through {}", path.through.join(", "));
+            }
+            lines.push(line);
+        }
+        if !self.total.builtins.is_empty() {
+            lines.push("built-in parts count as no Nands and no delay".to_string());
+        }
+        lines.join("\n")
+    }
+
+    pub fn json(&self) -> String {
+        serde_json::to_string_pretty(self).unwrap()
+    }
+}
+
+fn plural(n: usize, what: &str) -> String {
+    match n {
+        1 => format!("1 {}", what),
+        n => format!("{} {}s", n, what),
+    }
+}
+
+/// `And (3:14)`, a part by where it is in its chip's file.
+fn label(chip: &str, line: usize, column: usize) -> String {
+    format!("{} ({}:{})", chip, line, column)
+}
+
+/// `12 Nands, 2 DFFs, built in: RAM8`
+fn count(count: &Count) -> String {
+    let mut text = plural(count.nands, "Nand");
+    if count.dffs > 0 {
+        text += &format!(", {}", plural(count.dffs, "DFF"));
+    }
+    if !count.builtins.is_empty() {
+        let builtins: Vec<String> = count
+            .builtins
+            .iter()
+            .map(|(chip, n)| match n {
+                1 => chip.clone(),
+                n => format!("{} x{}", chip, n),
+            })
+            .collect();
+        text += &format!(", built in: {}", builtins.join(", "));
+    }
+    text
+}
+
+/// The deepest net in Nands, traced back to where its path starts.
+fn critical_path(flattened: &Flattened) -> Option<CriticalPath> {
+    let gates = &flattened.gates;
+    let nets = gates
+        .iter()
+        .flat_map(|gate| gate.inputs.iter().chain(&gate.outputs))
+        .max()?
+        + 1;
+    let mut depth = vec![0; nets];
+    // the gate driving each net and the input its longest path comes from
+    let mut via: Vec<Option<(usize, Option<Net>)>> = vec![None; nets];
+    for (index, gate) in gates.iter().enumerate() {
+        let mut from = None;
+        for net in &gate.inputs {
+            if from.is_none_or(|from| depth[*net] > depth[from]) {
+                from = Some(*net);
+            }
+        }
+        let reached = from.map_or(0, |net| depth[net]) + gate.delay;
+        for net in &gate.outputs {
+            depth[*net] = reached;
+            via[*net] = Some((index, from));
+        }
+    }
+    let mut end = 0;
+    for net in 0..nets {
+        if depth[net] > depth[end] {
+            end = net;
+        }
+    }
+    if depth[end] == 0 {
+        return None;
+    }
+    let mut through: Vec<usize> = vec![];
+    let mut net = end;
+    let mut first = None;
+    // a built-in part whose outputs only follow its state, like a DFF's
+    let mut state = None;
+    while let Some((gate, from)) = via[net] {
+        let gate = &gates[gate];
+        match from {
+            Some(from) => {
+                first = Some(gate);
+                through.extend(gate.part);
+                net = from;
+            }
+            None => {
+                state = gate.builtin.map(|builtin| (builtin, gate.part));
+                break;
+            }
+        }
+    }
+    through.reverse();
+    let mut seen = vec![];
+    through.retain(|part| {
+        let first = !seen.contains(part);
+        seen.push(*part);
+        first
+    });
+    let names = Names::new(flattened);
+    let parts = &flattened.chip.parts;
+    let from = match state {
+        Some((builtin, part)) => names.state(builtin, part),
+        None => names.describe(net, first.and_then(|gate| gate.part)),
+    };
+    Some(CriticalPath {
+        delays: depth[end],
+        from,
+        to: names.describe(end, via[end].and_then(|(gate, _)| gates[gate].part)),
+        through: through
+            .into_iter()
+            .map(|part| names.part(&parts[part]))
+            .collect(),
+    })
+}
+
+/// What the nets of a flattened chip are called.
+struct Names<'f> {
+    flattened: &'f Flattened,
+    names: HashMap<Net, String>,
+}
+
+impl Names<'_> {
+    fn new(flattened: &Flattened) -> Names<'_> {
+        let mut names = HashMap::new();
+        // pins first, then the wires joined to them
+        let chip = &flattened.chip;
+        let pins = chip.inputs.iter().chain(&chip.outputs).map(|pin| &pin.name);
+        for name in pins.chain(flattened.names.keys()) {
+            let nets = &flattened.names[name];
+            for (bit, net) in nets.iter().enumerate() {
+                names.entry(*net).or_insert_with(|| match nets.len() {
+                    1 => name.clone(),
+                    _ => format!("{}[{}]", name, bit),
+                });
+            }
+        }
+        Names { flattened, names }
+    }
+
+    /// A built-in chip in `part`, as `PC in CPU (5:5)`.
+    fn state(&self, builtin: &str, part: Option<usize>) -> String {
+        match part.map(|part| &self.flattened.chip.parts[part]) {
+            Some(part) if part.chip == builtin => self.part(part),
+            Some(part) => format!("{} in {}", builtin, self.part(part)),
+            None => builtin.to_string(),
+        }
+    }
+
+    fn part(&self, part: &Part) -> String {
+        label(&part.chip, part.location.line, part.location.column)
+    }
+
+    /// The name of `net`, or what it is in `part` when it has none.
+    fn describe(&self, net: Net, part: Option<usize>) -> String {
+        if let Some(name) = self.names.get(&net) {
+            return name.clone();
+        }
+        let dffs = &self.flattened.dffs;
+        let what = match net {
+            0 => return "false".to_string(),
+            1 => return "true".to_string(),
+            _ if dffs.iter().any(|(input, out)| *input == net || *out == net) => "a DFF",
+            _ => "a wire",
+        };
+        match part {
+            Some(part) => {
+                let part = &self.flattened.chip.parts[part];
+                format!("{} in {}", what, self.part(part))
+            }
+            None => what.to_string(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hdl_sim::{flatten, Library};
+    use std::fs;
+
+    #[test]
+    fn counts_nands_and_finds_the_critical_path() {
+        let dir = std::env::temp_dir().join(format!("hdl-report-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let chips = [
+            "CHIP Not { IN in; OUT out; PARTS: Nand(a=in, b=in, out=out); }",
+            "CHIP And { IN a, b; OUT out; PARTS: Nand(a=a, b=b, out=x); Not(in=x, out=out); }",
+            "CHIP Or { IN a, b; OUT out; PARTS:
+             Not(in=a, out=na); Not(in=b, out=nb); Nand(a=na, b=nb, out=out); }",
+            "CHIP Top { IN a, b, c; OUT out, count[16];
+             PARTS:
+             And(a=a, b=b, out=ab);
+             Or(a=ab, b=c, out=out);
+             Register(in=false, load=c, out=count); }",
+        ];
+        for chip in chips {
+            let name = chip.split_whitespace().nth(1).unwrap();
+            fs::write(dir.join(format!("{}.hdl", name)), chip).unwrap();
+        }
+        let mut library = Library::new(&dir, None).unwrap();
+        let report = Report::new(&flatten(&mut library, "Top").unwrap());
+        assert_eq!(
+            report.text(),
+            "Top: 5 Nands, built in: Register\n  \
+             And (3:14): 2 Nands\n  \
+             Or (4:14): 3 Nands\n  \
+             Register (5:14): 0 Nands, built in: Register\n\
+             critical path: 4 gate delays, from a to out through And (3:14), Or (4:14)\n\
+             built-in parts count as no Nands and no delay"
+        );
+        let json: serde_json::Value = serde_json::from_str(&report.json()).unwrap();
+        assert_eq!(json["nands"], 5);
+        assert_eq!(json["parts"][1]["chip"], "Or");
+        assert_eq!(json["parts"][1]["column"], 14);
+        assert_eq!(json["parts"][2]["builtins"]["Register"], 1);
+        assert_eq!(json["critical_path"]["delays"], 4);
+        // from a register, into Twice, out of it and back through two Nots
+        fs::write(
+            dir.join("Twice.hdl"),
+            "CHIP Twice { IN a, b; OUT x, y; PARTS: Not(in=a, out=x); Not(in=b, out=y); }",
+        )
+        .unwrap();
+        fs::write(
+            dir.join("Top.hdl"),
+            "CHIP Top { IN load; OUT out;
+             PARTS:
+             Register(in=false, load=load, out[0]=r);
+             Twice(a=r, b=back, x=there, y=out);
+             Not(in=there, out=not); Not(in=not, out=back); }",
+        )
+        .unwrap();
+        let mut library = Library::new(&dir, None).unwrap();
+        let report = Report::new(&flatten(&mut library, "Top").unwrap());
+        let path = report.critical_path.unwrap();
+        assert_eq!(
+            (path.delays, path.from.as_str(), path.to.as_str()),
+            (4, "Register (3:14)", "out")
+        );
+        assert_eq!(
+            path.through,
+            vec!["Twice (4:14)", "Not (5:14)", "Not (5:38)"]
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
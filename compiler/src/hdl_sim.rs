@@ -0,0 +1,1530 @@
+// HDL chips flattened to gates and simulated
+//
+// Every bit of every pin and wire of a chip gets a net, and the nets joined
+// by a connection are merged with union-find, so once a chip is flattened a
+// net is one bit of state with a single driver. Each chip definition is
+// flattened once, into a template of gates over its own nets, and copied
+// into every chip that uses it as a part. Part chips come from the `.hdl`
+// files next to the chip under test, falling back on the built-in ones.
+// Mistakes in the wiring (unknown parts or pins, buses of different
+// widths, inputs and outputs left unconnected) are reported at the
+// connection that caused them.
+//
+// The standard chips can also be simulated natively, see `hdl_builtin`: a
+// native chip is a gate per group of outputs, taking whole buses in.
+//
+// `DFF`s and native chips are the only state. The clock runs in two phases
+// like the reference simulator's: `tick` latches every DFF's input, `tock`
+// moves the latched values to their outputs, and the gates are evaluated
+// after each. A DFF's output doesn't depend on its input until the next
+// `tock`, so the gates between DFFs can be run in a fixed order, and a path
+// of gates that feeds back into itself without a DFF is an error, reported
+// in the innermost chip holding the whole loop.
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+use serde::Serialize;
+
+use crate::hdl::{self, Chip, Connection, HdlError, Location, PinRef, Wire};
+use crate::hdl_builtin::{self, Builtin};
+
+pub type Net = usize;
+
+const FALSE: Net = 0;
+const TRUE: Net = 1;
+
+/// Interfaces of the gates everything else is made of.
+const PRIMITIVES: [(&str, &str); 2] = [
+    ("Nand", "CHIP Nand { IN a, b; OUT out; BUILTIN Nand; }"),
+    (
+        "DFF",
+        "CHIP DFF { IN in; OUT out; BUILTIN DFF; CLOCKED in; }",
+    ),
+];
+
+#[derive(Debug, Clone, PartialEq)]
+enum Gate {
+    Nand {
+        a: Net,
+        b: Net,
+        out: Net,
+    },
+    /// One group of outputs of a native chip.
+    Native {
+        native: usize,
+        group: usize,
+    },
+}
+
+impl Gate {
+    fn inputs(&self, natives: &[Native]) -> Vec<Net> {
+        match self {
+            Gate::Nand { a, b, .. } => vec![*a, *b],
+            Gate::Native { native, group } => {
+                let native = &natives[*native];
+                native.groups[*group]
+                    .0
+                    .iter()
+                    .flat_map(|pin| native.inputs[*pin].iter().copied())
+                    .collect()
+            }
+        }
+    }
+
+    fn outputs(&self, natives: &[Native]) -> Vec<Net> {
+        match self {
+            Gate::Nand { out, .. } => vec![*out],
+            Gate::Native { native, group } => {
+                let native = &natives[*native];
+                native.groups[*group]
+                    .1
+                    .iter()
+                    .flat_map(|pin| native.outputs[*pin].iter().copied())
+                    .collect()
+            }
+        }
+    }
+
+    fn map_nets(&mut self, mut f: impl FnMut(Net) -> Net) {
+        match self {
+            Gate::Nand { a, b, out } => {
+                *a = f(*a);
+                *b = f(*b);
+                *out = f(*out);
+            }
+            // the native chip holds the nets
+            Gate::Native { .. } => {}
+        }
+    }
+}
+
+/// A chip simulated natively, on whole buses.
+#[derive(Debug, Clone)]
+struct Native {
+    chip: &'static Builtin,
+    /// Nets of its input and output pins, in declaration order.
+    inputs: Vec<Vec<Net>>,
+    outputs: Vec<Vec<Net>>,
+    /// The inputs each group of outputs depends on, by index.
+    groups: Vec<(Vec<usize>, Vec<usize>)>,
+    state: Vec<u16>,
+    /// Writes to the state computed by the last `tick`.
+    pending: Vec<(usize, u16)>,
+}
+
+impl Native {
+    fn map_nets(&mut self, mut f: impl FnMut(Net) -> Net) {
+        for nets in self.inputs.iter_mut().chain(&mut self.outputs) {
+            for net in nets {
+                *net = f(*net);
+            }
+        }
+    }
+}
+
+/// Which chips are simulated natively instead of from their `.hdl` files.
+#[derive(Debug, PartialEq)]
+enum Builtins {
+    /// Those without a file.
+    Missing,
+    /// All but the chip under test.
+    All,
+    /// None but the primitives.
+    Never,
+    /// These, and those without a file.
+    Listed(Vec<String>),
+}
+
+/// Where part chips are looked up: parsed chips by name, the `.hdl` files
+/// of a directory, then the built-in chips.
+pub struct Library {
+    dir: Option<PathBuf>,
+    chips: HashMap<String, Rc<Chip>>,
+    builtins: Builtins,
+    /// The chip under test.
+    top: Option<String>,
+}
+
+impl Library {
+    /// The chips of `dir`, with built-in ones picked by a `--builtin`
+    /// value: `all`, `none` or a comma separated list. Without one, only
+    /// chips without a `.hdl` file are built in.
+    pub fn new(dir: &Path, builtins: Option<&str>) -> Result<Library, String> {
+        let builtins = match builtins {
+            None => Builtins::Missing,
+            Some("all") => Builtins::All,
+            Some("none") => Builtins::Never,
+            Some(list) => {
+                let names: Vec<String> = list
+                    .split(',')
+                    .map(|name| name.trim().to_string())
+                    .collect();
+                if let Some(unknown) = names.iter().find(|name| hdl_builtin::find(name).is_none()) {
+                    return Err(format!("{} has no built-in version", unknown));
+                }
+                Builtins::Listed(names)
+            }
+        };
+        Ok(Library {
+            dir: Some(dir.to_path_buf()),
+            chips: HashMap::new(),
+            builtins,
+            top: None,
+        })
+    }
+
+    /// The interface of the built-in version of `name`, if it may be used.
+    fn builtin(&self, name: &str) -> Option<String> {
+        if let Some((_, text)) = PRIMITIVES.iter().find(|(primitive, _)| *primitive == name) {
+            return Some(text.to_string());
+        }
+        let builtin = hdl_builtin::find(name).filter(|_| self.builtins != Builtins::Never)?;
+        Some(builtin.hdl())
+    }
+
+    fn prefers_builtin(&self, name: &str) -> bool {
+        match &self.builtins {
+            Builtins::All => self.top.as_deref() != Some(name),
+            Builtins::Listed(names) => names.iter().any(|listed| listed == name),
+            Builtins::Missing | Builtins::Never => false,
+        }
+    }
+
+    fn load(&mut self, name: &str) -> Result<Option<Rc<Chip>>, HdlError> {
+        if let Some(chip) = self.chips.get(name) {
+            return Ok(Some(chip.clone()));
+        }
+        let builtin = self.builtin(name);
+        let file = self
+            .dir
+            .as_ref()
+            .map(|dir| dir.join(format!("{}.hdl", name)))
+            .filter(|file| file.exists())
+            .filter(|_| builtin.is_none() || !self.prefers_builtin(name));
+        let chip = match file {
+            Some(file) => {
+                let text = fs::read_to_string(&file).map_err(|error| {
+                    HdlError::new(
+                        &Location::default(),
+                        format!("{}: {}", file.display(), error),
+                    )
+                })?;
+                let chip = hdl::parse(&file.display().to_string(), &text)?;
+                if chip.name != name {
+                    return Err(HdlError::new(
+                        &chip.location,
+                        format!("expected chip {}, found {}", name, chip.name),
+                    ));
+                }
+                chip
+            }
+            None => match builtin {
+                Some(text) => hdl::parse(&format!("<built-in {}>", name), &text)?,
+                None => return Ok(None),
+            },
+        };
+        let chip = Rc::new(chip);
+        self.chips.insert(name.to_string(), chip.clone());
+        Ok(Some(chip))
+    }
+}
+
+/// A chip flattened once and copied into every chip using it: its gates
+/// and DFFs over nets numbered from 0, the constants first.
+#[derive(Debug)]
+struct Template {
+    nets: usize,
+    pins: HashMap<String, Vec<Net>>,
+    internal: BTreeMap<String, Vec<Net>>,
+    /// Gates in evaluation order.
+    gates: Vec<Gate>,
+    /// `(in, out)` of every DFF.
+    dffs: Vec<(Net, Net)>,
+    natives: Vec<Native>,
+}
+
+impl Template {
+    fn builtin(
+        pins: &[(&str, usize)],
+        gates: Vec<Gate>,
+        dffs: Vec<(Net, Net)>,
+        natives: Vec<Native>,
+    ) -> Template {
+        let mut nets = TRUE + 1;
+        let pins = pins
+            .iter()
+            .map(|(name, width)| {
+                nets += width;
+                (name.to_string(), (nets - width..nets).collect())
+            })
+            .collect();
+        Template {
+            nets,
+            pins,
+            internal: BTreeMap::new(),
+            gates,
+            dffs,
+            natives,
+        }
+    }
+}
+
+/// The nets of a chip being wired up, joined with union-find, and the
+/// gates copied in from its parts.
+struct Wiring<'c> {
+    chip: &'c Chip,
+    parent: Vec<Net>,
+    pins: HashMap<String, Vec<Net>>,
+    internal: BTreeMap<String, Vec<Net>>,
+    /// Bits of each output pin some part drives.
+    driven_outputs: HashMap<&'c str, Vec<bool>>,
+    /// Wires of parts that couldn't be loaded.
+    unresolved: HashSet<&'c str>,
+    gates: Vec<Gate>,
+    /// The part each gate was copied from.
+    origins: Vec<usize>,
+    dffs: Vec<(Net, Net)>,
+    natives: Vec<Native>,
+}
+
+impl Wiring<'_> {
+    fn nets(&mut self, width: usize) -> Vec<Net> {
+        let start = self.parent.len();
+        self.parent.extend(start..start + width);
+        (start..start + width).collect()
+    }
+
+    fn find(&mut self, mut net: Net) -> Net {
+        while self.parent[net] != net {
+            self.parent[net] = self.parent[self.parent[net]];
+            net = self.parent[net];
+        }
+        net
+    }
+
+    /// Join two nets, the constants staying their own representatives.
+    fn union(&mut self, a: Net, b: Net) {
+        let (a, b) = (self.find(a), self.find(b));
+        if a == b {
+            return;
+        }
+        if b <= TRUE {
+            self.parent[a] = b;
+        } else {
+            self.parent[b] = a;
+        }
+    }
+
+    /// Copy the gates of `template` in as the part numbered `part`,
+    /// returning the nets of its pins.
+    fn copy(&mut self, template: &Template, part: usize) -> HashMap<String, Vec<Net>> {
+        let offset = self.parent.len() - (TRUE + 1);
+        self.nets(template.nets - (TRUE + 1));
+        let map = |net: Net| if net <= TRUE { net } else { net + offset };
+        let natives = self.natives.len();
+        for gate in &template.gates {
+            let mut gate = gate.clone();
+            gate.map_nets(map);
+            if let Gate::Native { native, .. } = &mut gate {
+                *native += natives;
+            }
+            self.gates.push(gate);
+            self.origins.push(part);
+        }
+        for native in &template.natives {
+            let mut native = native.clone();
+            native.map_nets(map);
+            self.natives.push(native);
+        }
+        self.dffs.extend(
+            template
+                .dffs
+                .iter()
+                .map(|(input, out)| (map(*input), map(*out))),
+        );
+        template
+            .pins
+            .iter()
+            .map(|(name, nets)| (name.clone(), nets.iter().map(|net| map(*net)).collect()))
+            .collect()
+    }
+
+    /// The nets of `pins` renumbered by `dense`.
+    fn renumber<'p>(
+        &mut self,
+        pins: impl Iterator<Item = (&'p String, &'p Vec<Net>)>,
+        dense: &mut impl FnMut(&mut Self, Net) -> Net,
+    ) -> Vec<(String, Vec<Net>)> {
+        pins.map(|(name, nets)| {
+            let nets = nets.iter().map(|net| dense(self, *net)).collect();
+            (name.clone(), nets)
+        })
+        .collect()
+    }
+
+    /// The template of the wired-up chip, its nets numbered densely, and
+    /// the part each of its gates came from.
+    fn finish(mut self) -> (Template, Vec<usize>) {
+        let mut index = vec![usize::MAX; self.parent.len()];
+        index[FALSE] = FALSE;
+        index[TRUE] = TRUE;
+        let mut nets = TRUE + 1;
+        let mut dense = |wiring: &mut Wiring, net: Net| {
+            let root = wiring.find(net);
+            if index[root] == usize::MAX {
+                index[root] = nets;
+                nets += 1;
+            }
+            index[root]
+        };
+        let mut gates = std::mem::take(&mut self.gates);
+        for gate in &mut gates {
+            gate.map_nets(|net| dense(&mut self, net));
+        }
+        let dffs = std::mem::take(&mut self.dffs)
+            .into_iter()
+            .map(|(input, out)| (dense(&mut self, input), dense(&mut self, out)))
+            .collect();
+        let mut natives = std::mem::take(&mut self.natives);
+        for native in &mut natives {
+            native.map_nets(|net| dense(&mut self, net));
+        }
+        let pins = std::mem::take(&mut self.pins);
+        let pins = self.renumber(pins.iter(), &mut dense).into_iter().collect();
+        let internal = std::mem::take(&mut self.internal);
+        let internal = self
+            .renumber(internal.iter(), &mut dense)
+            .into_iter()
+            .collect();
+        let template = Template {
+            nets,
+            pins,
+            internal,
+            gates,
+            dffs,
+            natives,
+        };
+        (template, self.origins)
+    }
+}
+
+/// What a wire named in a connection is inside the chip.
+enum Scope<'a> {
+    Input(&'a [Net]),
+    Output(&'a [Net]),
+    Internal(&'a [Net]),
+}
+
+struct Builder<'a> {
+    library: &'a mut Library,
+    /// Chips flattened so far, `None` for those with errors.
+    templates: HashMap<String, Option<Rc<Template>>>,
+    errors: Vec<HdlError>,
+    /// Chips being flattened, to catch chips containing themselves.
+    stack: Vec<String>,
+    /// The part each gate of the outermost chip came from.
+    origins: Vec<usize>,
+}
+
+impl Builder<'_> {
+    fn error(&mut self, location: &Location, message: String) {
+        let error = HdlError::new(location, message);
+        if !self.errors.contains(&error) {
+            self.errors.push(error);
+        }
+    }
+
+    fn load(&mut self, name: &str, location: &Location) -> Option<Rc<Chip>> {
+        match self.library.load(name) {
+            Ok(Some(chip)) => Some(chip),
+            Ok(None) => {
+                self.error(location, format!("no chip named {}", name));
+                None
+            }
+            Err(error) => {
+                // a broken file would be reported by every chip using it
+                if !self.errors.contains(&error) {
+                    self.errors.push(error);
+                }
+                None
+            }
+        }
+    }
+
+    /// Bits `(from, to)` of a `width`-bit pin selected by `pin`.
+    fn bits(&mut self, pin: &PinRef, width: usize) -> Option<(usize, usize)> {
+        match pin.range {
+            None => Some((0, width - 1)),
+            Some((from, to)) if to < width => Some((from, to)),
+            Some((from, to)) => {
+                self.error(
+                    &pin.location,
+                    format!(
+                        "{}[{}..{}] is out of range for a {}-bit bus",
+                        pin.name, from, to, width
+                    ),
+                );
+                None
+            }
+        }
+    }
+
+    /// `chip` flattened, or `None` when it or one of its parts has errors.
+    fn template(&mut self, chip: &Rc<Chip>) -> Option<Rc<Template>> {
+        if let Some(template) = self.templates.get(&chip.name) {
+            return template.clone();
+        }
+        let errors = self.errors.len();
+        let template = match &chip.builtin {
+            Some(builtin) => self.builtin(chip, builtin),
+            None => {
+                self.stack.push(chip.name.clone());
+                let template = self.wire(chip);
+                self.stack.pop();
+                template
+            }
+        };
+        let template = template
+            .filter(|_| self.errors.len() == errors)
+            .map(Rc::new);
+        self.templates.insert(chip.name.clone(), template.clone());
+        template
+    }
+
+    /// Flatten a chip made of parts.
+    fn wire(&mut self, chip: &Chip) -> Option<Template> {
+        let parts: Vec<Option<Rc<Chip>>> = chip
+            .parts
+            .iter()
+            .map(|part| self.load(&part.chip, &part.location))
+            .collect();
+
+        let mut wiring = Wiring {
+            chip,
+            parent: vec![FALSE, TRUE],
+            pins: HashMap::new(),
+            internal: BTreeMap::new(),
+            driven_outputs: chip
+                .outputs
+                .iter()
+                .map(|pin| (pin.name.as_str(), vec![false; pin.width]))
+                .collect(),
+            unresolved: HashSet::new(),
+            gates: vec![],
+            origins: vec![],
+            dffs: vec![],
+            natives: vec![],
+        };
+        for pin in chip.inputs.iter().chain(&chip.outputs) {
+            let nets = wiring.nets(pin.width);
+            wiring.pins.insert(pin.name.clone(), nets);
+        }
+        // internal wires are named by the part outputs driving them
+        for (part, sub) in chip.parts.iter().zip(&parts) {
+            let Some(sub) = sub else {
+                // its wires would only repeat that the part is missing
+                for connection in &part.connections {
+                    if let Wire::Pin(wire) = &connection.wire {
+                        wiring.unresolved.insert(&wire.name);
+                    }
+                }
+                continue;
+            };
+            for connection in &part.connections {
+                let (Some(pin), Wire::Pin(wire)) =
+                    (sub.output(&connection.pin.name), &connection.wire)
+                else {
+                    continue;
+                };
+                if chip.input(&wire.name).is_some() || chip.output(&wire.name).is_some() {
+                    continue;
+                }
+                if wiring.internal.contains_key(&wire.name) {
+                    self.error(
+                        &wire.location,
+                        format!("{} is driven by more than one part", wire.name),
+                    );
+                    continue;
+                }
+                let width = match connection.pin.range {
+                    Some((from, to)) if to < pin.width => to - from + 1,
+                    _ => pin.width,
+                };
+                let nets = wiring.nets(width);
+                wiring.internal.insert(wire.name.clone(), nets);
+            }
+        }
+
+        for (index, (part, sub)) in chip.parts.iter().zip(&parts).enumerate() {
+            let Some(sub) = sub else { continue };
+            let template = if self.stack.contains(&sub.name) {
+                self.error(&part.location, format!("chip {} contains itself", sub.name));
+                None
+            } else {
+                self.template(sub)
+            };
+            let sub_pins = match template {
+                Some(template) => wiring.copy(&template, index),
+                // still wired up, to find this chip's own mistakes
+                None => sub
+                    .inputs
+                    .iter()
+                    .chain(&sub.outputs)
+                    .map(|pin| (pin.name.clone(), wiring.nets(pin.width)))
+                    .collect(),
+            };
+            let mut connected: HashMap<&str, Vec<bool>> = sub
+                .inputs
+                .iter()
+                .map(|pin| (pin.name.as_str(), vec![false; pin.width]))
+                .collect();
+            for connection in &part.connections {
+                self.connect(&mut wiring, sub, connection, &sub_pins, &mut connected);
+            }
+            for pin in &sub.inputs {
+                let bits = &connected[pin.name.as_str()];
+                if bits.iter().all(|bit| !bit) {
+                    self.error(
+                        &part.location,
+                        format!("input pin {} of {} is not connected", pin.name, sub.name),
+                    );
+                }
+                // inputs connected in part, like `b[0]=true`, are false elsewhere
+                for (bit, connected) in bits.iter().enumerate() {
+                    if !connected {
+                        wiring.union(sub_pins[&pin.name][bit], FALSE);
+                    }
+                }
+            }
+        }
+
+        for pin in &chip.outputs {
+            let driven = &wiring.driven_outputs[pin.name.as_str()];
+            if wiring.unresolved.contains(pin.name.as_str()) {
+                continue;
+            }
+            if driven.iter().all(|bit| !bit) {
+                self.error(
+                    &pin.location,
+                    format!("output pin {} is not connected to any part", pin.name),
+                );
+            } else if let Some(bit) = driven.iter().position(|bit| !bit) {
+                self.error(
+                    &pin.location,
+                    format!("bit {} of output pin {} is not connected", bit, pin.name),
+                );
+            }
+        }
+
+        let (mut template, origins) = wiring.finish();
+        match order(&template.gates, &template.natives, template.nets) {
+            Ok(order) => {
+                let mut gates: Vec<Option<Gate>> = template.gates.into_iter().map(Some).collect();
+                template.gates = order
+                    .iter()
+                    .map(|index| gates[*index].take().unwrap())
+                    .collect();
+                if self.stack.len() == 1 {
+                    self.origins = order.iter().map(|index| origins[*index]).collect();
+                }
+                Some(template)
+            }
+            Err(cycle) => {
+                // the innermost chip holding the loop reports it first
+                let mut parts: Vec<usize> = cycle.iter().map(|gate| origins[*gate]).collect();
+                parts.sort();
+                parts.dedup();
+                let names: Vec<String> = parts
+                    .iter()
+                    .map(|part| {
+                        let part = &chip.parts[*part];
+                        format!("{} (line {})", part.chip, part.location.line)
+                    })
+                    .collect();
+                self.error(
+                    &chip.parts[parts[0]].location,
+                    format!(
+                        "combinational loop through {} without a DFF",
+                        names.join(", ")
+                    ),
+                );
+                None
+            }
+        }
+    }
+
+    /// Join the nets of one `pin=wire` connection of a `sub` part. Input
+    /// pins count as connected even when the connection is wrong, so one
+    /// mistake is reported once.
+    fn connect<'c>(
+        &mut self,
+        wiring: &mut Wiring<'c>,
+        sub: &'c Chip,
+        connection: &Connection,
+        sub_pins: &HashMap<String, Vec<Net>>,
+        connected: &mut HashMap<&'c str, Vec<bool>>,
+    ) {
+        let pin = &connection.pin;
+        let (declared, is_input) = match (sub.input(&pin.name), sub.output(&pin.name)) {
+            (Some(declared), _) => (declared, true),
+            (None, Some(declared)) => (declared, false),
+            (None, None) => {
+                self.error(
+                    &pin.location,
+                    format!("{} has no pin named {}", sub.name, pin.name),
+                );
+                return;
+            }
+        };
+        let Some((from, to)) = self.bits(pin, declared.width) else {
+            return;
+        };
+        if is_input {
+            let bits = connected.get_mut(declared.name.as_str()).unwrap();
+            if bits[from..=to].iter().any(|bit| *bit) {
+                self.error(
+                    &pin.location,
+                    format!("input pin {} is connected more than once", pin.name),
+                );
+            }
+            bits[from..=to].fill(true);
+        }
+        let pin_nets = sub_pins[&pin.name][from..=to].to_vec();
+
+        let wire = match &connection.wire {
+            Wire::Const(value) if is_input => {
+                let net = if *value { TRUE } else { FALSE };
+                for pin_net in pin_nets {
+                    wiring.union(pin_net, net);
+                }
+                return;
+            }
+            Wire::Const(_) => {
+                self.error(
+                    &connection.location,
+                    format!("output pin {} can't be connected to a constant", pin.name),
+                );
+                return;
+            }
+            Wire::Pin(wire) => wire,
+        };
+        let chip = wiring.chip;
+        let scope = if chip.input(&wire.name).is_some() {
+            Scope::Input(&wiring.pins[&wire.name])
+        } else if chip.output(&wire.name).is_some() {
+            Scope::Output(&wiring.pins[&wire.name])
+        } else if let Some(nets) = wiring.internal.get(&wire.name) {
+            Scope::Internal(nets)
+        } else {
+            // outputs declared their wires, or failed to and said why
+            if is_input && !wiring.unresolved.contains(wire.name.as_str()) {
+                self.error(
+                    &wire.location,
+                    format!("{} is not connected to any part output", wire.name),
+                );
+            }
+            return;
+        };
+        let nets = match scope {
+            Scope::Input(_) if !is_input => {
+                self.error(
+                    &wire.location,
+                    format!("input pin {} can't be driven by a part", wire.name),
+                );
+                return;
+            }
+            Scope::Output(_) if is_input => {
+                self.error(
+                    &wire.location,
+                    format!("output pin {} can't be used as an input", wire.name),
+                );
+                return;
+            }
+            Scope::Internal(_) if wire.range.is_some() => {
+                self.error(
+                    &wire.location,
+                    format!("internal wire {} can't be sub-bused", wire.name),
+                );
+                return;
+            }
+            Scope::Input(nets) | Scope::Output(nets) | Scope::Internal(nets) => nets,
+        };
+        let Some((wire_from, wire_to)) = self.bits(wire, nets.len()) else {
+            return;
+        };
+        let wire_nets = nets[wire_from..=wire_to].to_vec();
+        if let (Scope::Output(_), Some(driven)) =
+            (&scope, wiring.driven_outputs.get_mut(wire.name.as_str()))
+        {
+            if driven[wire_from..=wire_to].iter().any(|bit| *bit) {
+                self.error(
+                    &wire.location,
+                    format!("{} is driven by more than one part", wire.name),
+                );
+                return;
+            }
+            driven[wire_from..=wire_to].fill(true);
+        }
+        if wire_nets.len() != pin_nets.len() {
+            self.error(
+                &connection.location,
+                format!(
+                    "{} is {} wide but {} is {}",
+                    describe(pin),
+                    bits(pin_nets.len()),
+                    describe(wire),
+                    bits(wire_nets.len())
+                ),
+            );
+            return;
+        }
+        for (pin_net, wire_net) in pin_nets.into_iter().zip(wire_nets) {
+            wiring.union(pin_net, wire_net);
+        }
+    }
+
+    fn builtin(&mut self, chip: &Chip, builtin: &str) -> Option<Template> {
+        let widths: Vec<(&str, usize)> = chip
+            .inputs
+            .iter()
+            .chain(&chip.outputs)
+            .map(|pin| (pin.name.as_str(), pin.width))
+            .collect();
+        match (builtin, widths.as_slice()) {
+            ("Nand", [("a", 1), ("b", 1), ("out", 1)]) => Some(Template::builtin(
+                &widths,
+                vec![Gate::Nand { a: 2, b: 3, out: 4 }],
+                vec![],
+                vec![],
+            )),
+            ("DFF", [("in", 1), ("out", 1)]) => {
+                Some(Template::builtin(&widths, vec![], vec![(2, 3)], vec![]))
+            }
+            _ => match hdl_builtin::find(builtin).filter(|native| same_pins(chip, native)) {
+                Some(native) => Some(Self::native(chip, native, &widths)),
+                None => {
+                    self.error(
+                        &chip.location,
+                        format!("no built-in implementation of {}", builtin),
+                    );
+                    None
+                }
+            },
+        }
+    }
+
+    /// The template of a chip simulated natively.
+    fn native(chip: &Chip, native: &'static Builtin, widths: &[(&str, usize)]) -> Template {
+        let mut template = Template::builtin(widths, vec![], vec![], vec![]);
+        let nets = |pins: &[hdl::Pin]| -> Vec<Vec<Net>> {
+            pins.iter()
+                .map(|pin| template.pins[&pin.name].clone())
+                .collect()
+        };
+        let (inputs, outputs) = (nets(&chip.inputs), nets(&chip.outputs));
+        let index = |pins: &[hdl::Pin], name: &str| -> usize {
+            pins.iter().position(|pin| pin.name == name).unwrap()
+        };
+        let groups: Vec<(Vec<usize>, Vec<usize>)> = if native.groups.is_empty() {
+            vec![((0..inputs.len()).collect(), (0..outputs.len()).collect())]
+        } else {
+            native
+                .groups
+                .iter()
+                .map(|group| {
+                    let inputs = group.inputs.iter().map(|pin| index(&chip.inputs, pin));
+                    let outputs = group.outputs.iter().map(|pin| index(&chip.outputs, pin));
+                    (inputs.collect(), outputs.collect())
+                })
+                .collect()
+        };
+        template.gates = (0..groups.len())
+            .map(|group| Gate::Native { native: 0, group })
+            .collect();
+        template.natives.push(Native {
+            chip: native,
+            inputs,
+            outputs,
+            groups,
+            state: vec![],
+            pending: vec![],
+        });
+        template
+    }
+}
+
+/// Whether `chip` declares the pins of the built-in chip `native`.
+fn same_pins(chip: &Chip, native: &Builtin) -> bool {
+    let declared = hdl::parse(native.name, &native.hdl()).unwrap();
+    let pins = |chip: &Chip| -> Vec<(String, usize)> {
+        chip.inputs
+            .iter()
+            .chain(&chip.outputs)
+            .map(|pin| (pin.name.clone(), pin.width))
+            .collect()
+    };
+    pins(chip) == pins(&declared) && chip.inputs.len() == declared.inputs.len()
+}
+
+fn bits(n: usize) -> String {
+    match n {
+        1 => "1 bit".to_string(),
+        n => format!("{} bits", n),
+    }
+}
+
+/// `name` or `name[i..j]`, as written.
+fn describe(pin: &PinRef) -> String {
+    match pin.range {
+        None => pin.name.clone(),
+        Some((from, to)) if from == to => format!("{}[{}]", pin.name, from),
+        Some((from, to)) => format!("{}[{}..{}]", pin.name, from, to),
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Dff {
+    input: Net,
+    out: Net,
+    /// The input latched by the last `tick`.
+    latched: bool,
+}
+
+/// A flattened chip and the value of every net.
+pub struct Simulator {
+    pub chip: Rc<Chip>,
+    gates: Vec<Gate>,
+    dffs: Vec<Dff>,
+    natives: Vec<Native>,
+    values: Vec<bool>,
+    /// Nets of the chip's pins and internal wires, least significant bit
+    /// first.
+    pins: BTreeMap<String, Vec<Net>>,
+    /// Completed clock cycles.
+    time: usize,
+    /// Between a `tick` and its `tock`.
+    high: bool,
+}
+
+impl Simulator {
+    /// Build the chip `name` from `library`.
+    pub fn build(library: &mut Library, name: &str) -> Result<Simulator, Vec<HdlError>> {
+        let (chip, _, template) = flatten_template(library, name)?;
+        let mut values = vec![false; template.nets];
+        values[TRUE] = true;
+        let mut simulator = Simulator {
+            chip,
+            gates: template.gates.clone(),
+            dffs: template
+                .dffs
+                .iter()
+                .map(|(input, out)| Dff {
+                    input: *input,
+                    out: *out,
+                    latched: false,
+                })
+                .collect(),
+            natives: template
+                .natives
+                .iter()
+                .map(|native| Native {
+                    state: vec![0; native.chip.words],
+                    ..native.clone()
+                })
+                .collect(),
+            values,
+            pins: template
+                .pins
+                .iter()
+                .chain(&template.internal)
+                .map(|(name, nets)| (name.clone(), nets.clone()))
+                .collect(),
+            time: 0,
+            high: false,
+        };
+        simulator.eval();
+        Ok(simulator)
+    }
+
+    pub fn width(&self, pin: &str) -> Option<usize> {
+        match self.pins.get(pin) {
+            Some(nets) => Some(nets.len()),
+            None => self.word(pin).map(|_| 16),
+        }
+    }
+
+    /// Names and widths of the chip's pins, then of its internal wires.
+    pub fn signals(&self) -> Vec<(String, usize)> {
+        let chip = &self.chip;
+        let pins: Vec<&String> = chip
+            .inputs
+            .iter()
+            .chain(&chip.outputs)
+            .map(|pin| &pin.name)
+            .collect();
+        let internal = self.pins.keys().filter(|name| !pins.contains(name));
+        pins.iter()
+            .copied()
+            .chain(internal)
+            .map(|name| (name.clone(), self.pins[name].len()))
+            .collect()
+    }
+
+    /// The native chip and word of state named like `RAM16K[3]` or
+    /// `DRegister[]`, in the first native chip that has it.
+    fn word(&self, name: &str) -> Option<(usize, usize)> {
+        let (name, index) = name.strip_suffix(']')?.split_once('[')?;
+        let index = match index {
+            "" => None,
+            index => Some(index.parse().ok()?),
+        };
+        self.natives
+            .iter()
+            .enumerate()
+            .find_map(|(native, chip)| Some((native, chip.chip.word(name, index)?)))
+    }
+
+    /// Set an input pin or a word of a native chip's state. Takes effect on
+    /// the next `eval`.
+    pub fn set(&mut self, pin: &str, value: i64) -> Result<(), String> {
+        if let Some((native, word)) = self.word(pin) {
+            let native = &mut self.natives[native];
+            native.state[word] = value as u16;
+            native.pending.retain(|(pending, _)| *pending != word);
+            return Ok(());
+        }
+        if self.chip.input(pin).is_none() {
+            return Err(format!("{} has no input pin named {}", self.chip.name, pin));
+        }
+        for (bit, net) in self.pins[pin].iter().enumerate() {
+            self.values[*net] = (value >> bit) & 1 == 1;
+        }
+        Ok(())
+    }
+
+    /// The value of a pin, an internal wire or a word of a native chip's
+    /// state.
+    pub fn get(&self, pin: &str) -> Option<i64> {
+        let Some(nets) = self.pins.get(pin) else {
+            // like the reference simulator, a register shows what it
+            // stored on the tick, though its output waits for the tock
+            let (native, word) = self.word(pin)?;
+            let native = &self.natives[native];
+            let pending = native
+                .pending
+                .iter()
+                .rev()
+                .find(|(pending, _)| *pending == word);
+            let value = pending.map_or(native.state[word], |(_, value)| *value);
+            return Some(value as i64);
+        };
+        Some(bus(&self.values, nets) as i64)
+    }
+
+    /// Fill the state named `chip` of the first native chip that has it
+    /// with `words`, like `ROM32K load Add.hack`.
+    pub fn load_words(&mut self, chip: &str, words: &[u16]) -> Result<(), String> {
+        let (state, (first, size)) = self
+            .natives
+            .iter_mut()
+            .find_map(|native| Some((&mut native.state, native.chip.exposed(chip)?)))
+            .ok_or_else(|| format!("{} has no built-in {}", self.chip.name, chip))?;
+        if words.len() > size {
+            return Err(format!("{} words don't fit in {}", words.len(), chip));
+        }
+        state[first..first + words.len()].copy_from_slice(words);
+        state[first + words.len()..first + size].fill(0);
+        Ok(())
+    }
+
+    /// The state words the last `tick` is about to write, as the names and
+    /// indices scripts know them by, like `("RAM16K", 5)`.
+    pub fn pending_writes(&self) -> Vec<(&'static str, usize)> {
+        self.natives
+            .iter()
+            .flat_map(|native| {
+                native.pending.iter().filter_map(|(word, _)| {
+                    native
+                        .chip
+                        .exposes
+                        .iter()
+                        .find(|(_, first, words)| (*first..first + words).contains(word))
+                        .map(|(name, first, _)| (*name, word - first))
+                })
+            })
+            .collect()
+    }
+
+    /// Propagate the inputs and DFF outputs through every gate.
+    pub fn eval(&mut self) {
+        for gate in &self.gates {
+            match gate {
+                Gate::Nand { a, b, out } => {
+                    self.values[*out] = !(self.values[*a] && self.values[*b]);
+                }
+                Gate::Native { native, group } => {
+                    let native = &self.natives[*native];
+                    let inputs: Vec<u64> = native
+                        .inputs
+                        .iter()
+                        .map(|nets| bus(&self.values, nets))
+                        .collect();
+                    let outputs = (native.chip.eval)(&native.state, &inputs);
+                    for pin in &native.groups[*group].1 {
+                        for (bit, net) in native.outputs[*pin].iter().enumerate() {
+                            self.values[*net] = (outputs[*pin] >> bit) & 1 == 1;
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// The rising edge: every DFF latches its input, and every native chip
+    /// works out what it will store.
+    pub fn tick(&mut self) {
+        self.eval();
+        for dff in &mut self.dffs {
+            dff.latched = self.values[dff.input];
+        }
+        for native in &mut self.natives {
+            if let Some(tick) = native.chip.tick {
+                let inputs: Vec<u64> = native
+                    .inputs
+                    .iter()
+                    .map(|nets| bus(&self.values, nets))
+                    .collect();
+                native.pending = tick(&native.state, &inputs);
+            }
+        }
+        self.eval();
+        self.high = true;
+    }
+
+    /// The falling edge: every DFF outputs what it latched, and every
+    /// native chip stores it.
+    pub fn tock(&mut self) {
+        for dff in &self.dffs {
+            self.values[dff.out] = dff.latched;
+        }
+        for native in &mut self.natives {
+            for (word, value) in std::mem::take(&mut native.pending) {
+                native.state[word] = value;
+            }
+        }
+        self.eval();
+        self.time += 1;
+        self.high = false;
+    }
+
+    /// The clock as the `time` column shows it: `3` after three cycles,
+    /// `3+` after the next `tick`.
+    pub fn time(&self) -> String {
+        format!("{}{}", self.time, if self.high { "+" } else { "" })
+    }
+}
+
+/// Flatten the chip `name` from `library`, returning the builder that did
+/// it along with the chip and its template.
+fn flatten_template<'a>(
+    library: &'a mut Library,
+    name: &str,
+) -> Result<(Rc<Chip>, Builder<'a>, Rc<Template>), Vec<HdlError>> {
+    library.top.get_or_insert_with(|| name.to_string());
+    let chip = match library.load(name) {
+        Ok(Some(chip)) => chip,
+        Ok(None) => {
+            return Err(vec![HdlError::new(
+                &Location::default(),
+                format!("no chip named {}", name),
+            )])
+        }
+        Err(error) => return Err(vec![error]),
+    };
+    let mut builder = Builder {
+        library,
+        templates: HashMap::new(),
+        errors: vec![],
+        stack: vec![],
+        origins: vec![],
+    };
+    match builder.template(&chip) {
+        Some(template) => Ok((chip, builder, template)),
+        None => Err(builder.errors),
+    }
+}
+
+/// What a flattened chip or part is made of.
+#[derive(Debug, Default, Clone, PartialEq, Serialize)]
+pub struct Count {
+    pub nands: usize,
+    pub dffs: usize,
+    /// Parts simulated natively, by chip.
+    pub builtins: BTreeMap<String, usize>,
+}
+
+impl Template {
+    fn count(&self) -> Count {
+        let mut builtins = BTreeMap::new();
+        for native in &self.natives {
+            *builtins.entry(native.chip.name.to_string()).or_default() += 1;
+        }
+        Count {
+            nands: self
+                .gates
+                .iter()
+                .filter(|gate| matches!(gate, Gate::Nand { .. }))
+                .count(),
+            dffs: self.dffs.len(),
+            builtins,
+        }
+    }
+}
+
+/// A gate of a flattened chip, with the part of the chip it came from.
+#[derive(Debug, PartialEq)]
+pub struct FlatGate {
+    /// Gate delays: 1 for a Nand, 0 for a native chip.
+    pub delay: usize,
+    /// The native chip it is part of, None for a Nand.
+    pub builtin: Option<&'static str>,
+    pub inputs: Vec<Net>,
+    pub outputs: Vec<Net>,
+    pub part: Option<usize>,
+}
+
+/// A chip flattened to be measured rather than run.
+pub struct Flattened {
+    pub chip: Rc<Chip>,
+    pub total: Count,
+    /// What each part of the chip is made of, in order.
+    pub parts: Vec<Count>,
+    /// Gates in evaluation order.
+    pub gates: Vec<FlatGate>,
+    /// `(in, out)` of every DFF.
+    pub dffs: Vec<(Net, Net)>,
+    /// Nets of the chip's pins and internal wires.
+    pub names: BTreeMap<String, Vec<Net>>,
+}
+
+/// Flatten the chip `name` from `library` for `hdl_report`.
+pub fn flatten(library: &mut Library, name: &str) -> Result<Flattened, Vec<HdlError>> {
+    let (chip, mut builder, template) = flatten_template(library, name)?;
+    let parts = chip
+        .parts
+        .iter()
+        .map(|part| {
+            let template = builder.templates[&part.chip].as_ref().unwrap();
+            template.count()
+        })
+        .collect();
+    let origins = std::mem::take(&mut builder.origins);
+    let gates = template
+        .gates
+        .iter()
+        .enumerate()
+        .map(|(index, gate)| FlatGate {
+            delay: matches!(gate, Gate::Nand { .. }) as usize,
+            builtin: match gate {
+                Gate::Nand { .. } => None,
+                Gate::Native { native, .. } => Some(template.natives[*native].chip.name),
+            },
+            inputs: gate.inputs(&template.natives),
+            outputs: gate.outputs(&template.natives),
+            part: origins.get(index).copied(),
+        })
+        .collect();
+    Ok(Flattened {
+        total: template.count(),
+        parts,
+        gates,
+        dffs: template.dffs.clone(),
+        names: template
+            .pins
+            .iter()
+            .chain(&template.internal)
+            .map(|(name, nets)| (name.clone(), nets.clone()))
+            .collect(),
+        chip,
+    })
+}
+
+/// The value of a bus, least significant bit first.
+fn bus(values: &[bool], nets: &[Net]) -> u64 {
+    nets.iter()
+        .enumerate()
+        .map(|(bit, net)| (values[*net] as u64) << bit)
+        .sum()
+}
+
+/// Indices of `gates` in an order where each comes after the gates
+/// driving its inputs, or the gates of a loop when there is no such order.
+fn order(gates: &[Gate], natives: &[Native], nets: usize) -> Result<Vec<usize>, Vec<usize>> {
+    let mut driver = vec![None; nets];
+    for (index, gate) in gates.iter().enumerate() {
+        for net in gate.outputs(natives) {
+            driver[net] = Some(index);
+        }
+    }
+    // 0 unvisited, 1 on the stack, 2 placed
+    let mut state = vec![0u8; gates.len()];
+    let mut sorted = Vec::with_capacity(gates.len());
+    for start in 0..gates.len() {
+        if state[start] != 0 {
+            continue;
+        }
+        let mut stack = vec![(start, 0)];
+        state[start] = 1;
+        while let Some((gate, input)) = stack.pop() {
+            let inputs = gates[gate].inputs(natives);
+            let Some(net) = inputs.get(input) else {
+                state[gate] = 2;
+                sorted.push(gate);
+                continue;
+            };
+            stack.push((gate, input + 1));
+            match driver[*net] {
+                Some(next) if state[next] == 0 => {
+                    state[next] = 1;
+                    stack.push((next, 0));
+                }
+                Some(next) if state[next] == 1 => {
+                    let from = stack.iter().position(|(gate, _)| *gate == next).unwrap();
+                    return Err(stack[from..].iter().map(|(gate, _)| *gate).collect());
+                }
+                _ => {}
+            }
+        }
+    }
+    Ok(sorted)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A library of the chips in `sources` and the built-in chips.
+    fn library(sources: &[&str]) -> Library {
+        let chips = sources.iter().map(|source| {
+            let name = source.split_whitespace().nth(1).unwrap();
+            let chip = hdl::parse(&format!("{}.hdl", name), source).unwrap();
+            (name.to_string(), Rc::new(chip))
+        });
+        Library {
+            dir: None,
+            chips: chips.collect(),
+            builtins: Builtins::Never,
+            top: None,
+        }
+    }
+
+    const GATES: [&str; 3] = [
+        "CHIP Not { IN in; OUT out; PARTS: Nand(a=in, b=in, out=out); }",
+        "CHIP And { IN a, b; OUT out; PARTS: Nand(a=a, b=b, out=x); Not(in=x, out=out); }",
+        "CHIP Xor { IN a, b; OUT out; PARTS:
+            Nand(a=a, b=b, out=n); Nand(a=a, b=n, out=x); Nand(a=n, b=b, out=y);
+            Nand(a=x, b=y, out=out); }",
+    ];
+
+    fn errors(sources: &[&str], name: &str) -> Vec<String> {
+        match Simulator::build(&mut library(sources), name) {
+            Ok(_) => vec![],
+            Err(errors) => errors.iter().map(HdlError::to_string).collect(),
+        }
+    }
+
+    #[test]
+    fn evaluates_buses_and_constants() {
+        let mut sources = GATES.to_vec();
+        // parts listed before the parts driving their inputs
+        sources.push(
+            "CHIP Swap { IN in[4]; OUT out[4], hi, one, any;
+             PARTS:
+             Not(in=notlow, out=out[3]);
+             Not(in=in[0], out=notlow);
+             And(a=in[1], b=in[2], out=out[0..0], out=any);
+             Xor(a=in[3], b=false, out=out[1], out=hi);
+             And(a=true, b=true, out=one);
+             Not(in=true, out=out[2]); }",
+        );
+        let mut simulator = Simulator::build(&mut library(&sources), "Swap").unwrap();
+        for (input, expected) in [(0b0000, 0b0000), (0b0111, 0b1001), (0b1110, 0b0011)] {
+            simulator.set("in", input).unwrap();
+            simulator.eval();
+            assert_eq!(simulator.get("out"), Some(expected), "in = {:04b}", input);
+        }
+        assert_eq!(simulator.get("hi"), Some(1));
+        assert_eq!(simulator.get("one"), Some(1));
+        assert_eq!(simulator.get("notlow"), Some(1));
+        assert_eq!(simulator.width("in"), Some(4));
+        assert!(simulator.set("out", 1).is_err());
+    }
+
+    #[test]
+    fn reports_wiring_mistakes_where_they_are() {
+        let mut sources = GATES.to_vec();
+        sources.push(
+            "CHIP Bad { IN a, b[2]; OUT out, wide[2], lost, half[2];
+             PARTS:
+             Mux(a=a, b=a, sel=a, out=out);
+             Not(in=b, out=wide);
+             Not(in=a, x=a, out=wide[3]);
+             And(a=a, out=half[0]);
+             Not(in=ghost, out=a);
+             Not(in=out, out=internal[0]); }",
+        );
+        assert_eq!(
+            errors(&sources, "Bad"),
+            vec![
+                "Bad.hdl:3:14: no chip named Mux",
+                "Bad.hdl:4:18: in is 1 bit wide but b is 2 bits",
+                "Bad.hdl:4:24: out is 1 bit wide but wide is 2 bits",
+                "Bad.hdl:5:24: Not has no pin named x",
+                "Bad.hdl:5:33: wide[3..3] is out of range for a 2-bit bus",
+                "Bad.hdl:6:14: input pin b of And is not connected",
+                "Bad.hdl:7:21: ghost is not connected to any part output",
+                "Bad.hdl:7:32: input pin a can't be driven by a part",
+                "Bad.hdl:8:21: output pin out can't be used as an input",
+                "Bad.hdl:8:30: internal wire internal can't be sub-bused",
+                "Bad.hdl:1:42: output pin lost is not connected to any part",
+                "Bad.hdl:1:48: bit 1 of output pin half is not connected",
+            ]
+            .into_iter()
+            .map(String::from)
+            .collect::<Vec<_>>()
+        );
+        assert_eq!(
+            errors(
+                &["CHIP Loop { IN a; OUT out; PARTS: Loop(a=a, out=out); }"],
+                "Loop"
+            ),
+            vec!["Loop.hdl:1:35: chip Loop contains itself"]
+        );
+    }
+
+    #[test]
+    fn clocks_dffs_in_two_phases() {
+        let mut sources = GATES.to_vec();
+        sources.push(
+            "CHIP Mux { IN a, b, sel; OUT out; PARTS:
+             Not(in=sel, out=nsel); Nand(a=a, b=nsel, out=x); Nand(a=b, b=sel, out=y);
+             Nand(a=x, b=y, out=out); }",
+        );
+        sources.push(
+            "CHIP Bit { IN in, load; OUT out; PARTS:
+             Mux(a=out2, b=in, sel=load, out=next); DFF(in=next, out=out, out=out2); }",
+        );
+        let mut bit = Simulator::build(&mut library(&sources), "Bit").unwrap();
+        assert_eq!(bit.time(), "0");
+        bit.set("in", 1).unwrap();
+        bit.set("load", 1).unwrap();
+        bit.tick();
+        assert_eq!((bit.time().as_str(), bit.get("out")), ("0+", Some(0)));
+        // the input latched on the tick is what comes out on the tock
+        bit.set("in", 0).unwrap();
+        bit.eval();
+        bit.tock();
+        assert_eq!((bit.time().as_str(), bit.get("out")), ("1", Some(1)));
+        bit.set("load", 0).unwrap();
+        bit.tick();
+        bit.tock();
+        assert_eq!((bit.time().as_str(), bit.get("out")), ("2", Some(1)));
+    }
+
+    #[test]
+    fn reports_loops_without_a_dff_in_the_innermost_chip() {
+        let mut sources = GATES.to_vec();
+        sources.push(
+            "CHIP Latch { IN set; OUT out; PARTS:
+             Nand(a=set, b=back, out=q, out=out);
+             Not(in=q, out=nq);
+             And(a=nq, b=true, out=back); }",
+        );
+        sources.push("CHIP Top { IN in; OUT out; PARTS: Latch(set=in, out=out); }");
+        assert_eq!(
+            errors(&sources, "Top"),
+            vec!["Latch.hdl:2:14: combinational loop through Nand (line 2), Not (line 3), And (line 4) without a DFF"]
+        );
+        assert_eq!(
+            errors(
+                &["CHIP Ring { IN in; OUT out; PARTS: Nand(a=in, b=out2, out=out, out=out2); }"],
+                "Ring"
+            ),
+            vec!["Ring.hdl:1:36: combinational loop through Nand (line 1) without a DFF"]
+        );
+    }
+
+    #[test]
+    fn picks_builtin_or_user_chips() {
+        let dir = std::env::temp_dir().join(format!("hdl-sim-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        // a broken Not, always 1
+        fs::write(
+            dir.join("Not.hdl"),
+            "CHIP Not { IN in; OUT out; PARTS: Nand(a=in, b=false, out=out); }",
+        )
+        .unwrap();
+        fs::write(
+            dir.join("Top.hdl"),
+            "CHIP Top { IN in; OUT out; PARTS: Not(in=in, out=x); And(a=x, b=true, out=out); }",
+        )
+        .unwrap();
+        let run = |builtins: Option<&str>, name: &str| {
+            let mut library = Library::new(&dir, builtins)?;
+            let mut simulator =
+                Simulator::build(&mut library, name).map_err(|errors| errors[0].message.clone())?;
+            simulator.set("in", 1)?;
+            simulator.eval();
+            Ok::<_, String>(simulator.get("out").unwrap())
+        };
+        assert_eq!(run(None, "Top"), Ok(1));
+        assert_eq!(run(Some("Not"), "Top"), Ok(0));
+        assert_eq!(run(Some("all"), "Top"), Ok(0));
+        assert_eq!(run(Some("all"), "Not"), Ok(1));
+        assert_eq!(run(Some("Not"), "Not"), Ok(0));
+        assert_eq!(
+            run(Some("none"), "Top"),
+            Err("no chip named And".to_string())
+        );
+        assert_eq!(
+            run(Some("Not,Nand"), "Top"),
+            Err("Nand has no built-in version".to_string())
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn clocks_native_chips_and_names_their_state() {
+        let mut library = library(&[
+            "CHIP Top { IN in[16], load, address[3]; OUT out[16], reg[16]; PARTS:
+             RAM8(in=in, load=load, address=address, out=out);
+             Register(in=in, load=load, out=reg); }",
+        ]);
+        library.builtins = Builtins::Missing;
+        let mut top = Simulator::build(&mut library, "Top").unwrap();
+        top.set("in", 7).unwrap();
+        top.set("load", 1).unwrap();
+        top.set("address", 3).unwrap();
+        top.tick();
+        // stored on the tick, output on the tock
+        assert_eq!(
+            (top.get("RAM8[3]"), top.get("Register[]")),
+            (Some(7), Some(7))
+        );
+        assert_eq!((top.get("out"), top.get("reg")), (Some(0), Some(0)));
+        top.tock();
+        assert_eq!((top.get("out"), top.get("reg")), (Some(7), Some(7)));
+        top.set("RAM8[2]", 9).unwrap();
+        top.set("address", 2).unwrap();
+        top.eval();
+        assert_eq!(top.get("out"), Some(9));
+        assert_eq!(top.width("RAM8[2]"), Some(16));
+        assert_eq!(top.get("RAM8[8]"), None);
+        assert!(top.load_words("RAM8", &[1, 2, 3]).is_ok());
+        assert_eq!(top.get("RAM8[2]"), Some(3));
+        assert!(top.load_words("ROM32K", &[1]).is_err());
+    }
+}
@@ -0,0 +1,426 @@
+// HDL test scripts, run on the gate simulator
+//
+// `hdl Mux16.tst` builds the chip named by the script's `load` command from
+// the `.hdl` files next to the script, runs its `set`, `eval`, `tick`,
+// `tock` and `output` commands, writes what it recorded to the
+// `output-file` and compares that with the `compare-to` file, row by row.
+// A `time` column shows the clock. With built-in parts, `RAM16K[2]` names a
+// word of their state and `ROM32K load Add.hack` fills a memory. The pins
+// and internal wires can also be dumped as a waveform, one time unit per
+// `eval`, `tick` or `tock`. A `while` loop waiting for a key, as in
+// Memory.tst, is fed by a keyboard script whose cycles are those time units.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::cpu_emulator;
+use crate::hdl::HdlError;
+use crate::hdl_sim::{Library, Simulator};
+use crate::keyboard::{KeyPlayer, KeyScript};
+use crate::tst::{self, Column, Statement};
+use crate::vcd::Vcd;
+
+/// How to run a script.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Options<'a> {
+    /// Which chips are built in, see `Library::new`.
+    pub builtins: Option<&'a str>,
+    /// Where to write a waveform of the run.
+    pub vcd: Option<&'a Path>,
+    /// Patterns of the signals the waveform records, see `Vcd::new`.
+    pub signals: Option<&'a str>,
+    /// Keys to set the built-in Keyboard to, see `KeyScript`.
+    pub keys: Option<&'a KeyScript>,
+}
+
+/// Rounds a `while` may run before it is taken to wait forever.
+const MAX_ROUNDS: u64 = 1_000_000;
+
+/// What a script records and where it goes.
+#[derive(Debug, Default)]
+struct Recording {
+    lines: Vec<String>,
+    output_file: Option<String>,
+    compare_to: Option<String>,
+    columns: Vec<Column>,
+    waves: Option<Waves>,
+    keys: KeyPlayer,
+    /// Evals, ticks and tocks so far, the cycles of `keys`.
+    time: u64,
+}
+
+/// The waveform being recorded.
+#[derive(Debug, Default)]
+struct Waves {
+    vcd: Option<Vcd>,
+    /// Names of the recorded signals and their indices in `vcd`.
+    signals: Vec<(String, usize)>,
+    time: u64,
+}
+
+impl Waves {
+    /// Start over on a newly loaded chip.
+    fn start(&mut self, simulator: &Simulator, filter: Option<&str>) {
+        let mut vcd = Vcd::new(&simulator.chip.name, filter);
+        self.signals = simulator
+            .signals()
+            .into_iter()
+            .filter_map(|(name, width)| Some((name.clone(), vcd.add(&name, width)?)))
+            .collect();
+        self.vcd = Some(vcd);
+        self.time = 0;
+        self.sample(simulator);
+    }
+
+    fn sample(&mut self, simulator: &Simulator) {
+        let Some(vcd) = &mut self.vcd else { return };
+        for (name, signal) in &self.signals {
+            vcd.change(self.time, *signal, simulator.get(name).unwrap() as u64);
+        }
+    }
+
+    /// Move on a time unit and sample.
+    fn step(&mut self, simulator: &Simulator) {
+        self.time += 1;
+        self.sample(simulator);
+    }
+}
+
+/// The errors of a chip that failed to build, one per line.
+pub fn build_errors(errors: &[HdlError]) -> String {
+    errors
+        .iter()
+        .map(HdlError::to_string)
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+fn load(
+    dir: &Path,
+    file: &str,
+    options: &Options,
+    recording: &mut Recording,
+) -> Result<Simulator, String> {
+    let name = file.strip_suffix(".hdl").unwrap_or(file);
+    let mut library = Library::new(dir, options.builtins)?;
+    let simulator = Simulator::build(&mut library, name).map_err(|errors| build_errors(&errors))?;
+    if let Some(waves) = &mut recording.waves {
+        waves.start(&simulator, options.signals);
+    }
+    Ok(simulator)
+}
+
+fn read_hack(path: &Path) -> Result<Vec<u16>, String> {
+    let text =
+        fs::read_to_string(path).map_err(|error| format!("{}: {}", path.display(), error))?;
+    cpu_emulator::parse_hack(&text).map_err(|error| format!("{}: {}", path.display(), error))
+}
+
+/// The value of a pin, 16-bit buses being signed like the Hack words they
+/// usually hold.
+fn value(simulator: &Simulator, name: &str) -> Result<i64, String> {
+    let value = simulator
+        .get(name)
+        .ok_or_else(|| format!("{} has no pin named {}", simulator.chip.name, name))?;
+    Ok(match simulator.width(name).unwrap() {
+        16 => value as i16 as i64,
+        _ => value,
+    })
+}
+
+/// A cell of the `time` column or of a pin.
+fn cell(simulator: &Simulator, column: &Column) -> Result<String, String> {
+    if column.name == "time" {
+        return Ok(column.text_cell(&simulator.time()));
+    }
+    let value = value(simulator, &column.name)?;
+    Ok(column.cell(value, simulator.width(&column.name).unwrap()))
+}
+
+/// Run `statements`, building the chip on `load` or, without one, the
+/// chip named `default` before the first command that needs it.
+fn run_statements(
+    statements: &[Statement],
+    dir: &Path,
+    default: &str,
+    options: &Options,
+    simulator: &mut Option<Simulator>,
+    recording: &mut Recording,
+) -> Result<(), String> {
+    for statement in statements {
+        match statement {
+            Statement::Repeat { count, body } => {
+                let count = count.ok_or("repeat without a count never ends")?;
+                for _ in 0..count {
+                    run_statements(body, dir, default, options, simulator, recording)?;
+                }
+            }
+            Statement::While { condition, body } => {
+                for round in 0.. {
+                    if simulator.is_none() {
+                        *simulator = Some(load(dir, default, options, recording)?);
+                    }
+                    let chip = simulator.as_ref().unwrap();
+                    if !condition.holds(|name| value(chip, name))? {
+                        break;
+                    }
+                    if round == MAX_ROUNDS {
+                        return Err(format!(
+                            "while {} still holds after {} rounds, does it wait for keys?",
+                            condition, MAX_ROUNDS
+                        ));
+                    }
+                    run_statements(body, dir, default, options, simulator, recording)?;
+                }
+            }
+            Statement::Command { words, line } => {
+                let error = |message: String| format!("line {}: {}", line, message);
+                match words[0].as_str() {
+                    "load" => {
+                        let file = words.get(1).map_or(default, String::as_str);
+                        *simulator = Some(load(dir, file, options, recording).map_err(error)?);
+                        continue;
+                    }
+                    "output-file" => {
+                        recording.output_file = words.get(1).cloned();
+                        continue;
+                    }
+                    "compare-to" => {
+                        recording.compare_to = words.get(1).cloned();
+                        continue;
+                    }
+                    "echo" | "clear-echo" => continue,
+                    _ => {}
+                }
+                if simulator.is_none() {
+                    *simulator = Some(load(dir, default, options, recording).map_err(error)?);
+                }
+                let simulator = simulator.as_mut().unwrap();
+                if let ("eval" | "tick" | "tock", Some(key)) =
+                    (words[0].as_str(), recording.keys.poll(recording.time))
+                {
+                    simulator.set("Keyboard[]", key as i64).map_err(error)?;
+                }
+                match (words[0].as_str(), &words[1..]) {
+                    ("output-list", specs) => {
+                        recording.columns = specs
+                            .iter()
+                            .map(|spec| Column::parse(spec))
+                            .collect::<Result<_, _>>()
+                            .map_err(error)?;
+                        recording.lines.push(tst::header_line(&recording.columns));
+                    }
+                    ("set", [pin, value]) => {
+                        let value = tst::parse_value(value).map_err(error)?;
+                        simulator.set(pin, value).map_err(error)?;
+                    }
+                    (chip, [load, file]) if load == "load" => {
+                        let words = read_hack(&dir.join(file)).map_err(error)?;
+                        simulator.load_words(chip, &words).map_err(error)?;
+                    }
+                    ("eval", []) => simulator.eval(),
+                    ("tick", []) => simulator.tick(),
+                    ("tock", []) => simulator.tock(),
+                    ("output", []) => {
+                        let cells = recording
+                            .columns
+                            .iter()
+                            .map(|column| cell(simulator, column))
+                            .collect::<Result<Vec<_>, String>>()
+                            .map_err(error)?;
+                        recording.lines.push(tst::row_line(&cells));
+                    }
+                    (other, _) => return Err(error(format!("unsupported command {}", other))),
+                }
+                if let "eval" | "tick" | "tock" = words[0].as_str() {
+                    recording.time += 1;
+                    if let Some(waves) = &mut recording.waves {
+                        waves.step(simulator);
+                    }
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+fn output_path(script: &Path, name: Option<&String>, extension: &str) -> PathBuf {
+    match name {
+        Some(name) => script.with_file_name(name),
+        None => script.with_extension(extension),
+    }
+}
+
+/// Run the `.tst` file `script`, write its output file and compare it with
+/// the `.cmp` file, printing a line per row. True when every row passed.
+pub fn run_script(script: &Path, options: &Options) -> Result<bool, String> {
+    let text =
+        fs::read_to_string(script).map_err(|error| format!("{}: {}", script.display(), error))?;
+    let statements =
+        tst::parse(&text).map_err(|error| format!("{}: {}", script.display(), error))?;
+    let dir = script.parent().unwrap_or(Path::new(""));
+    let default = script.file_stem().unwrap().to_string_lossy().to_string();
+    let mut recording = Recording {
+        waves: options.vcd.map(|_| Waves::default()),
+        keys: KeyPlayer::new(options.keys.cloned().unwrap_or_default()),
+        ..Recording::default()
+    };
+    run_statements(
+        &statements,
+        dir,
+        &default,
+        options,
+        &mut None,
+        &mut recording,
+    )
+    .map_err(|error| format!("{}: {}", script.display(), error))?;
+    if let (Some(path), Some(vcd)) = (options.vcd, recording.waves.and_then(|waves| waves.vcd)) {
+        fs::write(path, vcd.finish()).map_err(|error| format!("{}: {}", path.display(), error))?;
+    }
+    let out = output_path(script, recording.output_file.as_ref(), "out");
+    let mut text = recording.lines.join("\n");
+    text.push('\n');
+    fs::write(&out, text).map_err(|error| format!("{}: {}", out.display(), error))?;
+    let cmp = output_path(script, recording.compare_to.as_ref(), "cmp");
+    let expected =
+        fs::read_to_string(&cmp).map_err(|error| format!("{}: {}", cmp.display(), error))?;
+    Ok(tst::report(&tst::compare(&recording.lines, &expected)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn runs_scripts_and_writes_the_output_file() {
+        let dir = std::env::temp_dir().join(format!("hdl-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let parts: String = (0..16)
+            .map(|bit| format!("Not(in=in[{0}], out=out[{0}]);", bit))
+            .collect();
+        fs::write(
+            dir.join("Not16.hdl"),
+            format!("CHIP Not16 {{ IN in[16]; OUT out[16]; PARTS: {} }}", parts),
+        )
+        .unwrap();
+        fs::write(
+            dir.join("Not.hdl"),
+            "CHIP Not { IN in; OUT out; PARTS: Nand(a=in, b=true, out=out); }",
+        )
+        .unwrap();
+        fs::write(
+            dir.join("Not16.tst"),
+            "load Not16.hdl, output-file Not16.out, compare-to Not16.cmp,
+             output-list in%B1.16.1 out%D1.6.1;
+             set in %B0000000000000000, eval, output;
+             set in -2, eval, output;",
+        )
+        .unwrap();
+        let expected = "|        in        |  out   |\n\
+                        | 0000000000000000 |     -1 |\n\
+                        | 1111111111111110 |      1 |\n";
+        fs::write(dir.join("Not16.cmp"), expected).unwrap();
+        assert!(run_script(&dir.join("Not16.tst"), &Options::default()).unwrap());
+        assert_eq!(fs::read_to_string(dir.join("Not16.out")).unwrap(), expected);
+        let options = Options {
+            vcd: Some(&dir.join("Not16.vcd")),
+            signals: Some("out"),
+            ..Options::default()
+        };
+        assert!(run_script(&dir.join("Not16.tst"), &options).unwrap());
+        assert_eq!(
+            fs::read_to_string(dir.join("Not16.vcd")).unwrap(),
+            "$timescale 1 ns $end\n$scope module Not16 $end\n$var wire 16 ! out $end\n\
+             $upscope $end\n$enddefinitions $end\n#0\nb1111111111111111 !\n#2\nb1 !\n"
+        );
+
+        fs::write(
+            dir.join("Not.hdl"),
+            "CHIP Not { IN in; OUT out; PARTS:\n  Nand(a=in, out=out); }",
+        )
+        .unwrap();
+        assert_eq!(
+            run_script(&dir.join("Not16.tst"), &Options::default()).unwrap_err(),
+            format!(
+                "{}: line 1: {}:2:3: input pin b of Nand is not connected",
+                dir.join("Not16.tst").display(),
+                dir.join("Not.hdl").display()
+            )
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn runs_programs_on_builtin_parts() {
+        let dir = std::env::temp_dir().join(format!("hdl-computer-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        // the CPU's addressM doesn't follow inM, so this is no loop
+        fs::write(
+            dir.join("Computer.hdl"),
+            "CHIP Computer { IN reset; PARTS:
+             ROM32K(address=pc, out=instruction);
+             CPU(inM=inM, instruction=instruction, reset=reset, outM=outM, writeM=writeM,
+                 addressM[0..13]=address, pc=pc);
+             RAM16K(in=outM, load=writeM, address=address, out=inM); }",
+        )
+        .unwrap();
+        // RAM[0] = 2 + 3
+        let add = "0000000000000010\n1110110000010000\n0000000000000011\n\
+                   1110000010010000\n0000000000000000\n1110001100001000\n";
+        fs::write(dir.join("Add.hack"), add).unwrap();
+        fs::write(
+            dir.join("Computer.tst"),
+            "load Computer.hdl, echo \"Adds, then stores\",
+             output-list time%S1.4.1 ARegister[]%D1.6.1 PC[]%D1.2.1 RAM16K[0]%D1.6.1;
+             ROM32K load Add.hack, output;
+             repeat 6 { tick, tock; } output;",
+        )
+        .unwrap();
+        let expected = "| time |ARegiste|PC[]|RAM16K[0|\n\
+                        | 0    |      0 |  0 |      0 |\n\
+                        | 6    |      0 |  6 |      5 |\n";
+        fs::write(dir.join("Computer.cmp"), expected).unwrap();
+        assert!(run_script(&dir.join("Computer.tst"), &Options::default()).unwrap());
+        assert_eq!(
+            fs::read_to_string(dir.join("Computer.out")).unwrap(),
+            expected
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn runs_while_loops_waiting_for_keys() {
+        let dir = std::env::temp_dir().join(format!("hdl-while-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(
+            dir.join("Key.hdl"),
+            "CHIP Key { OUT out[16]; PARTS: Keyboard(out=out); }",
+        )
+        .unwrap();
+        fs::write(
+            dir.join("Key.tst"),
+            "output-list time%S1.4.1 out%D1.3.1;
+             echo \"Press K\", while out <> 75 { eval, } clear-echo, output;",
+        )
+        .unwrap();
+        let expected = "| time | out |\n| 0    |  75 |\n";
+        fs::write(dir.join("Key.cmp"), expected).unwrap();
+        let keys = KeyScript::parse("press K at 3").unwrap();
+        let options = Options {
+            keys: Some(&keys),
+            ..Options::default()
+        };
+        assert!(run_script(&dir.join("Key.tst"), &options).unwrap());
+        // the key is pressed before the fourth eval
+        assert_eq!(fs::read_to_string(dir.join("Key.out")).unwrap(), expected);
+        fs::write(dir.join("Key.tst"), "while out <> 75 { eval, }").unwrap();
+        assert_eq!(
+            run_script(&dir.join("Key.tst"), &Options::default()).unwrap_err(),
+            format!(
+                "{}: while out <> 75 still holds after 1000000 rounds, does it wait for keys?",
+                dir.join("Key.tst").display()
+            )
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
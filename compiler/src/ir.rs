@@ -0,0 +1,506 @@
+// three-address intermediate representation sitting between the parse tree and VM code
+//
+// Each subroutine becomes a `Function` made of basic blocks. Values computed
+// inside a block live in typed temporaries (`%n`) that are assigned exactly
+// once; Jack variables are only reached through explicit `load`/`store` of a
+// `Place`. Block 0 is the entry block.
+
+use std::fmt::{self, Display};
+
+use serde::{Deserialize, Serialize};
+
+use crate::parser::structures::{SubroutineType, VarTypeEnum};
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Ty {
+    Int,
+    Char,
+    Boolean,
+    Class(String),
+    /// A 16-bit value whose Jack type isn't known, e.g. an array element.
+    Word,
+}
+
+impl From<&VarTypeEnum> for Ty {
+    fn from(var_type: &VarTypeEnum) -> Self {
+        match var_type {
+            VarTypeEnum::INT => Ty::Int,
+            VarTypeEnum::CHAR => Ty::Char,
+            VarTypeEnum::BOOLEAN => Ty::Boolean,
+            VarTypeEnum::CLASSNAME(name) => Ty::Class(name.0.clone()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct Temp(pub usize);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct BlockId(pub usize);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Operand {
+    Const(i16),
+    Temp(Temp),
+}
+
+/// Storage a Jack variable lives in, mirrors the VM memory segments.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Place {
+    Local(u16),
+    Argument(u16),
+    Field(u16),
+    Static(u16),
+    /// The current object, `pointer 0` in the VM.
+    This,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum BinOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    And,
+    Or,
+    Lt,
+    Gt,
+    Eq,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum UnOp {
+    Neg,
+    Not,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum Instr {
+    Load {
+        dst: Temp,
+        place: Place,
+    },
+    Store {
+        place: Place,
+        src: Operand,
+    },
+    Binary {
+        dst: Temp,
+        op: BinOp,
+        lhs: Operand,
+        rhs: Operand,
+    },
+    Unary {
+        dst: Temp,
+        op: UnOp,
+        src: Operand,
+    },
+    /// Read `RAM[addr]`, used for array elements.
+    LoadIndirect {
+        dst: Temp,
+        addr: Operand,
+    },
+    /// Write `RAM[addr]`, used for array elements.
+    StoreIndirect {
+        addr: Operand,
+        src: Operand,
+    },
+    /// Every Jack subroutine returns a value, `dst` receives it.
+    Call {
+        dst: Temp,
+        function: String,
+        args: Vec<Operand>,
+    },
+    /// A fresh `String` object holding `value`.
+    StringConst {
+        dst: Temp,
+        value: String,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum Terminator {
+    Jump(BlockId),
+    Branch {
+        cond: Operand,
+        then_block: BlockId,
+        else_block: BlockId,
+    },
+    Return(Operand),
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BasicBlock {
+    pub instrs: Vec<Instr>,
+    pub terminator: Terminator,
+    /// Jack source line of each instruction, 0 where unknown.
+    pub lines: Vec<u32>,
+    pub terminator_line: u32,
+}
+
+impl BasicBlock {
+    pub fn new(instrs: Vec<Instr>, terminator: Terminator) -> Self {
+        let lines = vec![0; instrs.len()];
+        BasicBlock {
+            instrs,
+            terminator,
+            lines,
+            terminator_line: 0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum FunctionKind {
+    Constructor,
+    Function,
+    Method,
+}
+
+impl From<&SubroutineType> for FunctionKind {
+    fn from(subroutine_type: &SubroutineType) -> Self {
+        match subroutine_type {
+            SubroutineType::CONSTRUCTOR => FunctionKind::Constructor,
+            SubroutineType::FUNCTION => FunctionKind::Function,
+            SubroutineType::METHOD => FunctionKind::Method,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Function {
+    /// Full VM name, `Class.subroutine`.
+    pub name: String,
+    pub kind: FunctionKind,
+    /// Argument count including the implicit `this` of methods.
+    pub args: u16,
+    pub locals: u16,
+    /// Size of the class' field and static segments, used for verification.
+    pub fields: u16,
+    pub statics: u16,
+    pub temps: Vec<Ty>,
+    pub blocks: Vec<BasicBlock>,
+    /// Jack source line of the subroutine declaration, 0 where unknown.
+    pub line: u32,
+}
+
+impl Instr {
+    pub fn dst(&self) -> Option<Temp> {
+        match self {
+            Instr::Load { dst, .. }
+            | Instr::Binary { dst, .. }
+            | Instr::Unary { dst, .. }
+            | Instr::LoadIndirect { dst, .. }
+            | Instr::Call { dst, .. }
+            | Instr::StringConst { dst, .. } => Some(*dst),
+            Instr::Store { .. } | Instr::StoreIndirect { .. } => None,
+        }
+    }
+
+    /// Operands in the order they are evaluated.
+    pub fn operands(&self) -> Vec<Operand> {
+        match self {
+            Instr::Load { .. } | Instr::StringConst { .. } => vec![],
+            Instr::Store { src, .. } => vec![*src],
+            Instr::Binary { lhs, rhs, .. } => vec![*lhs, *rhs],
+            Instr::Unary { src, .. } => vec![*src],
+            Instr::LoadIndirect { addr, .. } => vec![*addr],
+            Instr::StoreIndirect { addr, src } => vec![*addr, *src],
+            Instr::Call { args, .. } => args.clone(),
+        }
+    }
+}
+
+impl Terminator {
+    pub fn operands(&self) -> Vec<Operand> {
+        match self {
+            Terminator::Jump(_) => vec![],
+            Terminator::Branch { cond, .. } => vec![*cond],
+            Terminator::Return(value) => vec![*value],
+        }
+    }
+
+    pub fn successors(&self) -> Vec<BlockId> {
+        match self {
+            Terminator::Jump(target) => vec![*target],
+            Terminator::Branch {
+                then_block,
+                else_block,
+                ..
+            } => vec![*then_block, *else_block],
+            Terminator::Return(_) => vec![],
+        }
+    }
+}
+
+impl BinOp {
+    /// `None` for `and`/`or`, which are bitwise on ints and logical on booleans.
+    pub fn result_type(&self) -> Option<Ty> {
+        match self {
+            BinOp::Lt | BinOp::Gt | BinOp::Eq => Some(Ty::Boolean),
+            BinOp::And | BinOp::Or => None,
+            BinOp::Add | BinOp::Sub | BinOp::Mul | BinOp::Div => Some(Ty::Int),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            BinOp::Add => "add",
+            BinOp::Sub => "sub",
+            BinOp::Mul => "mul",
+            BinOp::Div => "div",
+            BinOp::And => "and",
+            BinOp::Or => "or",
+            BinOp::Lt => "lt",
+            BinOp::Gt => "gt",
+            BinOp::Eq => "eq",
+        }
+    }
+}
+
+impl UnOp {
+    pub fn name(&self) -> &'static str {
+        match self {
+            UnOp::Neg => "neg",
+            UnOp::Not => "not",
+        }
+    }
+}
+
+impl Display for Ty {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Ty::Int => f.write_str("int"),
+            Ty::Char => f.write_str("char"),
+            Ty::Boolean => f.write_str("boolean"),
+            Ty::Class(name) => f.write_str(name),
+            Ty::Word => f.write_str("word"),
+        }
+    }
+}
+
+impl Display for Temp {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "%{}", self.0)
+    }
+}
+
+impl Display for BlockId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "b{}", self.0)
+    }
+}
+
+impl Display for Operand {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Operand::Const(value) => write!(f, "{}", value),
+            Operand::Temp(temp) => write!(f, "{}", temp),
+        }
+    }
+}
+
+impl Display for Place {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Place::Local(index) => write!(f, "local {}", index),
+            Place::Argument(index) => write!(f, "argument {}", index),
+            Place::Field(index) => write!(f, "field {}", index),
+            Place::Static(index) => write!(f, "static {}", index),
+            Place::This => f.write_str("this"),
+        }
+    }
+}
+
+fn join(operands: &[Operand]) -> String {
+    let items: Vec<String> = operands.iter().map(|o| o.to_string()).collect();
+    items.join(", ")
+}
+
+/// End a dumped instruction, noting its Jack line when known.
+fn fmt_line(f: &mut fmt::Formatter, line: u32) -> fmt::Result {
+    match line {
+        0 => writeln!(f),
+        line => writeln!(f, "  ; line {}", line),
+    }
+}
+
+impl Function {
+    fn fmt_instr(&self, f: &mut fmt::Formatter, instr: &Instr) -> fmt::Result {
+        if let Some(dst) = instr.dst() {
+            write!(f, "{}: {} = ", dst, self.temps[dst.0])?;
+        }
+        match instr {
+            Instr::Load { place, .. } => write!(f, "load {}", place),
+            Instr::Store { place, src } => write!(f, "store {}, {}", place, src),
+            Instr::Binary { op, lhs, rhs, .. } => write!(f, "{} {}, {}", op.name(), lhs, rhs),
+            Instr::Unary { op, src, .. } => write!(f, "{} {}", op.name(), src),
+            Instr::LoadIndirect { addr, .. } => write!(f, "load [{}]", addr),
+            Instr::StoreIndirect { addr, src } => write!(f, "store [{}], {}", addr, src),
+            Instr::Call { function, args, .. } => write!(f, "call {}({})", function, join(args)),
+            Instr::StringConst { value, .. } => write!(f, "string {:?}", value),
+        }
+    }
+}
+
+impl Display for Function {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let kind = match self.kind {
+            FunctionKind::Constructor => "constructor",
+            FunctionKind::Function => "function",
+            FunctionKind::Method => "method",
+        };
+        writeln!(
+            f,
+            "{} {} args {} locals {}",
+            kind, self.name, self.args, self.locals
+        )?;
+        for (id, block) in self.blocks.iter().enumerate() {
+            writeln!(f, "  {}:", BlockId(id))?;
+            for (i, instr) in block.instrs.iter().enumerate() {
+                f.write_str("    ")?;
+                self.fmt_instr(f, instr)?;
+                fmt_line(f, block.lines.get(i).copied().unwrap_or(0))?;
+            }
+            match &block.terminator {
+                Terminator::Jump(target) => write!(f, "    jump {}", target)?,
+                Terminator::Branch {
+                    cond,
+                    then_block,
+                    else_block,
+                } => write!(f, "    branch {}, {}, {}", cond, then_block, else_block)?,
+                Terminator::Return(value) => write!(f, "    return {}", value)?,
+            }
+            fmt_line(f, block.terminator_line)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct VerifyError {
+    pub function: String,
+    pub block: Option<BlockId>,
+    pub message: String,
+}
+
+impl Display for VerifyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.block {
+            Some(block) => write!(f, "{} {}: {}", self.function, block, self.message),
+            None => write!(f, "{}: {}", self.function, self.message),
+        }
+    }
+}
+
+/// Check the structural rules every pass relies on: jump targets exist,
+/// temporaries are typed, defined once and only used later in the block that
+/// defines them, and places stay inside their segments.
+pub fn verify(function: &Function) -> Result<(), Vec<VerifyError>> {
+    let mut problems: Vec<(Option<BlockId>, String)> = vec![];
+    if function.blocks.is_empty() {
+        problems.push((None, "function has no entry block".to_string()));
+    }
+    let mut defined_in: Vec<Option<BlockId>> = vec![None; function.temps.len()];
+    for (id, block) in function.blocks.iter().enumerate() {
+        let id = BlockId(id);
+        let mut live = vec![false; function.temps.len()];
+        if block.lines.len() != block.instrs.len() {
+            let message = format!(
+                "{} line numbers for {} instructions",
+                block.lines.len(),
+                block.instrs.len()
+            );
+            problems.push((Some(id), message));
+        }
+        for instr in &block.instrs {
+            check_uses(&instr.operands(), &live, id, &mut problems);
+            if let Instr::Load { place, .. } | Instr::Store { place, .. } = instr {
+                if let Some(message) = check_place(function, place) {
+                    problems.push((Some(id), message));
+                }
+            }
+            if let Instr::Binary { dst, op, .. } = instr {
+                let expected = op.result_type().unwrap_or(Ty::Int);
+                let found = function.temps.get(dst.0);
+                let boolean_logic = op.result_type().is_none() && found == Some(&Ty::Boolean);
+                if !boolean_logic && found.is_some_and(|ty| *ty != expected) {
+                    let message = format!("{} {} must produce {}", dst, op.name(), expected);
+                    problems.push((Some(id), message));
+                }
+            }
+            let Some(dst) = instr.dst() else {
+                continue;
+            };
+            if dst.0 >= function.temps.len() {
+                problems.push((Some(id), format!("{} has no type", dst)));
+                continue;
+            }
+            if let Some(other) = defined_in[dst.0] {
+                problems.push((Some(id), format!("{} already defined in {}", dst, other)));
+            }
+            defined_in[dst.0] = Some(id);
+            live[dst.0] = true;
+        }
+        check_uses(&block.terminator.operands(), &live, id, &mut problems);
+        for target in block.terminator.successors() {
+            if target.0 >= function.blocks.len() {
+                problems.push((Some(id), format!("jump to missing block {}", target)));
+            }
+        }
+    }
+    if problems.is_empty() {
+        return Ok(());
+    }
+    Err(problems
+        .into_iter()
+        .map(|(block, message)| VerifyError {
+            function: function.name.clone(),
+            block,
+            message,
+        })
+        .collect())
+}
+
+fn check_uses(
+    operands: &[Operand],
+    live: &[bool],
+    block: BlockId,
+    problems: &mut Vec<(Option<BlockId>, String)>,
+) {
+    for operand in operands {
+        let Operand::Temp(temp) = operand else {
+            continue;
+        };
+        if temp.0 >= live.len() {
+            problems.push((Some(block), format!("{} has no type", temp)));
+        } else if !live[temp.0] {
+            problems.push((
+                Some(block),
+                format!("{} used before it is defined in this block", temp),
+            ));
+        }
+    }
+}
+
+fn check_place(function: &Function, place: &Place) -> Option<String> {
+    let (index, size, segment) = match place {
+        Place::Local(index) => (*index, function.locals, "local"),
+        Place::Argument(index) => (*index, function.args, "argument"),
+        Place::Static(index) => (*index, function.statics, "static"),
+        Place::Field(index) => {
+            if function.kind == FunctionKind::Function {
+                return Some(format!("function accesses field {}", index));
+            }
+            (*index, function.fields, "field")
+        }
+        Place::This => return None,
+    };
+    if index >= size {
+        return Some(format!(
+            "{} {} out of range, segment has {}",
+            segment, index, size
+        ));
+    }
+    None
+}
@@ -0,0 +1,116 @@
+// json backend for Jack language
+
+use serde_json::Value;
+
+use crate::{
+    backend::{resolve_span, span_from_value, Backend},
+    parser::Node,
+    tokenizer::TokenSpan,
+};
+
+/// Dumps the tree through serde. With `spans` set, every `span` field is
+/// turned into source locations, otherwise spans are left out.
+pub struct Json<'a> {
+    pub spans: Option<&'a [TokenSpan]>,
+}
+
+impl Backend for Json<'_> {
+    fn convert_node(&self, node: &Node) -> String {
+        let mut value = serde_json::to_value(node).unwrap();
+        self.fix_spans(&mut value);
+        serde_json::to_string_pretty(&value).unwrap()
+    }
+}
+
+impl Json<'_> {
+    fn fix_spans(&self, value: &mut Value) {
+        match value {
+            Value::Object(map) => {
+                if let Some(span) = map.remove("span") {
+                    let located = self
+                        .spans
+                        .zip(span_from_value(&span))
+                        .and_then(|(spans, span)| resolve_span(spans, &span));
+                    if let Some(located) = located {
+                        map.insert("span".to_string(), serde_json::to_value(located).unwrap());
+                    }
+                }
+                map.values_mut().for_each(|v| self.fix_spans(v));
+            }
+            Value::Array(items) => items.iter_mut().for_each(|v| self.fix_spans(v)),
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        backend::Backend,
+        json::Json,
+        parser::{structures, Node, Parsable, TokenReader},
+        tokenizer::tokenize_str_spanned,
+    };
+
+    fn parse(source: &str) -> (Node, Vec<crate::tokenizer::TokenSpan>) {
+        let (tokens, spans) = tokenize_str_spanned(source);
+        let class = structures::Class::try_parse(&TokenReader { tokens }, 0).unwrap();
+        (Node::Class(class.0), spans)
+    }
+
+    #[test]
+    fn class_without_spans() {
+        let (node, _) = parse("class Main { static int a; }");
+        let value: serde_json::Value =
+            serde_json::from_str(&Json { spans: None }.convert_node(&node)).unwrap();
+        let class = &value["Class"];
+        assert_eq!(class["class_name"], "Main");
+        assert_eq!(class["class_var_dec"][0]["var_dec_type"], "STATIC");
+        assert!(class.get("span").is_none());
+    }
+
+    #[test]
+    fn class_with_spans() {
+        let (node, spans) = parse("class Main {\n  static int a;\n}");
+        let value: serde_json::Value = serde_json::from_str(
+            &Json {
+                spans: Some(&spans),
+            }
+            .convert_node(&node),
+        )
+        .unwrap();
+        let var_dec = &value["Class"]["class_var_dec"][0]["span"];
+        assert_eq!(var_dec["start"]["line"], 2);
+        assert_eq!(var_dec["start"]["column"], 3);
+        assert_eq!(var_dec["end"]["column"], 16);
+    }
+
+    #[test]
+    fn terms_with_spans() {
+        let (tokens, spans) = tokenize_str_spanned("1 + (2 * -x)");
+        let expression =
+            crate::parser::expressions::Expression::try_parse(&TokenReader { tokens }, 0).unwrap();
+        let output = Json {
+            spans: Some(&spans),
+        }
+        .convert_node(&Node::Expression(expression.0));
+        let value: serde_json::Value = serde_json::from_str(&output).unwrap();
+        assert_eq!(value["Expression"]["span"]["end"]["column"], 13);
+        let wrapped = &value["Expression"]["op_term"][0][1]["WrappedExpression"];
+        assert_eq!(wrapped["span"]["start"]["column"], 6);
+        let unary = &wrapped["op_term"][0][1]["UnaryTerm"]["span"];
+        assert_eq!(unary["start"]["column"], 10);
+        assert_eq!(unary["end"]["column"], 12);
+    }
+
+    #[test]
+    fn single_expression() {
+        let (tokens, _) = tokenize_str_spanned("1 + x");
+        let expression =
+            crate::parser::expressions::Expression::try_parse(&TokenReader { tokens }, 0).unwrap();
+        let output = Json { spans: None }.convert_node(&Node::Expression(expression.0));
+        let value: serde_json::Value = serde_json::from_str(&output).unwrap();
+        assert_eq!(value["Expression"]["term"]["IntegerConstant"], 1);
+        assert_eq!(value["Expression"]["op_term"][0][1]["VarName"], "x");
+    }
+}
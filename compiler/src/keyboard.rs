@@ -0,0 +1,245 @@
+// timed keyboard scripts for emulated programs
+//
+// The Hack keyboard is the single word at RAM[24576], holding the code of the
+// key currently pressed or 0. A script sets it at fixed cycles, so a run fed
+// the same script always sees the same input. One event per line:
+//
+//     press LEFT at 10000
+//     release at 20000
+//     type "hello\n" at 30000 every 2000
+//
+// `type` presses every character for `every` cycles (5000 by default) and
+// releases it for as long again, so programs waiting for a key to come up
+// see each one separately. Keys are a printable character, a name from
+// `KEY_NAMES` or a raw code. `#` outside quotes starts a comment.
+
+pub const KEYBOARD: usize = 24576;
+
+const DEFAULT_EVERY: u64 = 5000;
+
+/// Codes of the non-printable keys, as listed in the Jack OS documentation.
+pub const KEY_NAMES: [(&str, i16); 15] = [
+    ("NEWLINE", 128),
+    ("BACKSPACE", 129),
+    ("LEFT", 130),
+    ("UP", 131),
+    ("RIGHT", 132),
+    ("DOWN", 133),
+    ("HOME", 134),
+    ("END", 135),
+    ("PAGEUP", 136),
+    ("PAGEDOWN", 137),
+    ("INSERT", 138),
+    ("DELETE", 139),
+    ("ESC", 140),
+    ("SPACE", 32),
+    ("ENTER", 128),
+];
+
+fn key_code(name: &str) -> Option<i16> {
+    let upper = name.to_ascii_uppercase();
+    if let Some(&(_, code)) = KEY_NAMES.iter().find(|(key, _)| *key == upper) {
+        return Some(code);
+    }
+    // F1..F12 are 141..152
+    if let Some(n) = upper.strip_prefix('F').and_then(|n| n.parse::<i16>().ok()) {
+        return (1..=12).contains(&n).then_some(140 + n);
+    }
+    let mut chars = name.chars();
+    match (chars.next(), chars.next()) {
+        (Some(c @ ' '..='~'), None) => Some(c as i16),
+        _ => name.parse().ok().filter(|code| *code >= 0),
+    }
+}
+
+/// Codes of the characters of a `type` string, with `\n`, `\b`, `\\` and
+/// `\"` escapes.
+fn typed_codes(text: &str) -> Result<Vec<i16>, String> {
+    let mut codes = vec![];
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        let code = match c {
+            '\\' => match chars.next() {
+                Some('n') => 128,
+                Some('b') => 129,
+                Some(c @ ('\\' | '"')) => c as i16,
+                other => return Err(format!("unknown escape \\{}", other.unwrap_or(' '))),
+            },
+            ' '..='~' => c as i16,
+            _ => return Err(format!("{:?} has no Hack key code", c)),
+        };
+        codes.push(code);
+    }
+    Ok(codes)
+}
+
+fn strip_comment(line: &str) -> &str {
+    let mut quoted = false;
+    for (i, c) in line.char_indices() {
+        match c {
+            '"' => quoted = !quoted,
+            '#' if !quoted => return &line[..i],
+            _ => {}
+        }
+    }
+    line
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct KeyScript {
+    /// `(cycle, key)` in cycle order, 0 meaning no key.
+    pub events: Vec<(u64, i16)>,
+}
+
+impl KeyScript {
+    pub fn parse(text: &str) -> Result<KeyScript, String> {
+        let mut events = vec![];
+        for (number, line) in text.lines().enumerate() {
+            let error = |message: String| format!("line {}: {}", number + 1, message);
+            let line = strip_comment(line).trim();
+            if line.is_empty() {
+                continue;
+            }
+            // a quoted string may hold spaces, so take it out first
+            let (line, text) = match (line.find('"'), line.rfind('"')) {
+                (Some(open), Some(close)) if open < close => (
+                    format!("{} \"\" {}", &line[..open], &line[close + 1..]),
+                    Some(&line[open + 1..close]),
+                ),
+                _ => (line.to_string(), None),
+            };
+            let words: Vec<&str> = line.split_whitespace().collect();
+            let cycle = |word: &str| {
+                word.parse::<u64>()
+                    .map_err(|_| error(format!("bad cycle {:?}", word)))
+            };
+            match words.as_slice() {
+                ["press", key, "at", at] => {
+                    let code =
+                        key_code(key).ok_or_else(|| error(format!("unknown key {}", key)))?;
+                    events.push((cycle(at)?, code));
+                }
+                ["release", "at", at] => events.push((cycle(at)?, 0)),
+                ["type", "\"\"", "at", at, rest @ ..] => {
+                    let every = match rest {
+                        [] => DEFAULT_EVERY,
+                        ["every", every] => cycle(every)?.max(1),
+                        _ => return Err(error(format!("unexpected {:?}", rest.join(" ")))),
+                    };
+                    let codes = typed_codes(text.unwrap_or("")).map_err(error)?;
+                    let mut at = cycle(at)?;
+                    for code in codes {
+                        events.push((at, code));
+                        events.push((at + every, 0));
+                        at += 2 * every;
+                    }
+                }
+                _ => return Err(error(format!("can't read {:?}", line.trim()))),
+            }
+        }
+        // stable, so events at the same cycle keep their script order
+        events.sort_by_key(|(at, _)| *at);
+        Ok(KeyScript { events })
+    }
+}
+
+/// Walks a script forward as an emulator runs.
+#[derive(Debug, Clone, Default)]
+pub struct KeyPlayer {
+    script: KeyScript,
+    next: usize,
+}
+
+impl KeyPlayer {
+    pub fn new(script: KeyScript) -> KeyPlayer {
+        KeyPlayer { script, next: 0 }
+    }
+
+    /// The new key when one or more events are due by `cycle`. Cycles must
+    /// not go backwards.
+    pub fn poll(&mut self, cycle: u64) -> Option<i16> {
+        let events = &self.script.events;
+        let mut key = None;
+        while self.next < events.len() && events[self.next].0 <= cycle {
+            key = Some(events[self.next].1);
+            self.next += 1;
+        }
+        key
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vm_emulator::{Program, VmEmulator, STATIC};
+
+    #[test]
+    fn parses_scripts() {
+        let script = KeyScript::parse(
+            "press LEFT at 10000\nrelease at 20000  # let go\n\ntype \"h i\\n\" at 30000 every 10\npress F3 at 5\npress q at 7\n",
+        )
+        .unwrap();
+        assert_eq!(
+            script.events,
+            vec![
+                (5, 143),
+                (7, 113),
+                (10000, 130),
+                (20000, 0),
+                (30000, 104),
+                (30010, 0),
+                (30020, 32),
+                (30030, 0),
+                (30040, 105),
+                (30050, 0),
+                (30060, 128),
+                (30070, 0),
+            ]
+        );
+        let mut player = KeyPlayer::new(script);
+        assert_eq!(player.poll(0), None);
+        assert_eq!(player.poll(15000), Some(130));
+        assert_eq!(player.poll(15001), None);
+        assert_eq!(player.poll(30065), Some(128));
+        let script = KeyScript::parse("type \"#\" at 1 # a hash").unwrap();
+        assert_eq!(script.events, vec![(1, 35), (5001, 0)]);
+    }
+
+    #[test]
+    fn reports_bad_lines() {
+        assert_eq!(
+            KeyScript::parse("press LEFT at 1\npress WHAT at 2").unwrap_err(),
+            "line 2: unknown key WHAT"
+        );
+        assert_eq!(
+            KeyScript::parse("type \"\\x\" at 1").unwrap_err(),
+            "line 1: unknown escape \\x"
+        );
+        assert!(KeyScript::parse("release 10").is_err());
+    }
+
+    #[test]
+    fn feeds_the_vm_emulator() {
+        // wait for a key and store its code in static 0
+        let source = "function Main.main 0
+            push constant 24576
+            pop pointer 1
+            label WAIT
+            push that 0
+            push constant 0
+            eq
+            if-goto WAIT
+            push that 0
+            pop static 0
+            push constant 0
+            return";
+        let program = Program::load(&[("Main".to_string(), source.to_string())]).unwrap();
+        for _ in 0..2 {
+            let mut emulator = VmEmulator::new(program.clone()).unwrap();
+            emulator.keys = KeyPlayer::new(KeyScript::parse("press DOWN at 500").unwrap());
+            emulator.run(10_000).unwrap();
+            assert_eq!(emulator.read(STATIC), 133);
+            assert_eq!(emulator.steps, 512);
+        }
+    }
+}
@@ -0,0 +1,567 @@
+// lowering from the parse tree to the three-address IR
+
+use std::fmt::{self, Display};
+
+use crate::{
+    ir::{
+        BasicBlock, BinOp, BlockId, Function, FunctionKind, Instr, Operand, Place, Temp,
+        Terminator, Ty, UnOp,
+    },
+    parser::Span,
+    parser::{
+        expressions::{Expression, KeywordConstant, SubroutineCall, Term},
+        statements::{LetLHS, Statement, Statements},
+        structures::{Class, ReturnType, SubroutineDec, SubroutineType},
+    },
+    symbol_table::{Kind, SymbolTable},
+    tokenizer::TokenSpan,
+};
+
+#[derive(Debug, PartialEq)]
+pub struct LowerError {
+    pub function: String,
+    pub message: String,
+}
+
+impl Display for LowerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.function, self.message)
+    }
+}
+
+/// Lower every subroutine of `class`, in declaration order. `token_spans`
+/// locate the class' tokens and give every instruction its Jack line; pass
+/// an empty slice when they aren't known.
+pub fn lower_class(
+    class: &Class,
+    token_spans: &[TokenSpan],
+) -> Result<Vec<Function>, Vec<LowerError>> {
+    let mut symbols = SymbolTable::for_class(class);
+    let mut functions = vec![];
+    let mut errors = vec![];
+    for subroutine_dec in &class.subroutine_dec {
+        symbols.start_subroutine(&class.class_name.0, subroutine_dec);
+        let mut builder = Builder::new(class, subroutine_dec, &symbols, token_spans);
+        builder.lower_body(subroutine_dec);
+        errors.append(&mut builder.errors);
+        functions.push(builder.function);
+    }
+    if errors.is_empty() {
+        Ok(functions)
+    } else {
+        Err(errors)
+    }
+}
+
+struct Builder<'a> {
+    class: &'a Class,
+    symbols: &'a SymbolTable,
+    token_spans: &'a [TokenSpan],
+    /// Jack line of the statement being lowered.
+    line: u32,
+    function: Function,
+    /// Block new instructions go to, `None` right after a `return`.
+    current: Option<BlockId>,
+    errors: Vec<LowerError>,
+}
+
+impl<'a> Builder<'a> {
+    fn new(
+        class: &'a Class,
+        subroutine_dec: &SubroutineDec,
+        symbols: &'a SymbolTable,
+        token_spans: &'a [TokenSpan],
+    ) -> Self {
+        let line = line_of(token_spans, &subroutine_dec.span);
+        let name = format!(
+            "{}.{}",
+            class.class_name.0, subroutine_dec.subroutine_name.0
+        );
+        let function = Function {
+            name,
+            kind: FunctionKind::from(&subroutine_dec.subroutine_type),
+            args: symbols.count(Kind::Argument),
+            locals: symbols.count(Kind::Local),
+            fields: symbols.count(Kind::Field),
+            statics: symbols.count(Kind::Static),
+            temps: vec![],
+            blocks: vec![],
+            line,
+        };
+        let mut builder = Builder {
+            class,
+            symbols,
+            token_spans,
+            line,
+            function,
+            current: None,
+            errors: vec![],
+        };
+        let entry = builder.new_block();
+        builder.current = Some(entry);
+        builder
+    }
+
+    fn error(&mut self, message: String) {
+        self.errors.push(LowerError {
+            function: self.function.name.clone(),
+            message,
+        });
+    }
+
+    fn new_block(&mut self) -> BlockId {
+        self.function.blocks.push(BasicBlock::new(
+            vec![],
+            Terminator::Return(Operand::Const(0)),
+        ));
+        BlockId(self.function.blocks.len() - 1)
+    }
+
+    fn new_temp(&mut self, ty: Ty) -> Temp {
+        self.function.temps.push(ty);
+        Temp(self.function.temps.len() - 1)
+    }
+
+    /// The block to append to; code following a `return` goes to a fresh,
+    /// unreachable block so nothing is lost before optimisation.
+    fn block(&mut self) -> BlockId {
+        match self.current {
+            Some(block) => block,
+            None => {
+                let block = self.new_block();
+                self.current = Some(block);
+                block
+            }
+        }
+    }
+
+    fn emit(&mut self, instr: Instr) {
+        let block = self.block();
+        let block = &mut self.function.blocks[block.0];
+        block.instrs.push(instr);
+        block.lines.push(self.line);
+    }
+
+    fn terminate(&mut self, terminator: Terminator) {
+        let block = self.block();
+        let block = &mut self.function.blocks[block.0];
+        block.terminator = terminator;
+        block.terminator_line = self.line;
+        self.current = None;
+    }
+
+    fn operand_type(&self, operand: Operand) -> Ty {
+        match operand {
+            Operand::Const(_) => Ty::Int,
+            Operand::Temp(temp) => self.function.temps[temp.0].clone(),
+        }
+    }
+
+    fn lower_body(&mut self, subroutine_dec: &SubroutineDec) {
+        match subroutine_dec.subroutine_type {
+            SubroutineType::CONSTRUCTOR => {
+                let size = Operand::Const(self.function.fields as i16);
+                let object = self.call("Memory.alloc".to_string(), vec![size], Ty::Word);
+                self.emit(Instr::Store {
+                    place: Place::This,
+                    src: Operand::Temp(object),
+                });
+            }
+            SubroutineType::METHOD => {
+                let object = self.load(
+                    Place::Argument(0),
+                    Ty::Class(self.class.class_name.0.clone()),
+                );
+                self.emit(Instr::Store {
+                    place: Place::This,
+                    src: Operand::Temp(object),
+                });
+            }
+            SubroutineType::FUNCTION => {}
+        }
+        self.statements(&subroutine_dec.subroutine_body.statements);
+        if self.current.is_some() {
+            self.terminate(Terminator::Return(Operand::Const(0)));
+        }
+    }
+
+    fn statements(&mut self, statements: &Statements) {
+        for statement in &statements.0 {
+            self.statement(statement);
+        }
+    }
+
+    fn statement(&mut self, statement: &Statement) {
+        let span = match statement {
+            Statement::LetStatement(s) => &s.span,
+            Statement::IfStatement(s) => &s.span,
+            Statement::WhileStatement(s) => &s.span,
+            Statement::DoStatement(s) => &s.span,
+            Statement::ReturnStatement(s) => &s.span,
+        };
+        self.line = line_of(self.token_spans, span);
+        match statement {
+            Statement::LetStatement(let_statement) => match &let_statement.let_lhs {
+                LetLHS::VarName(var_name) => {
+                    let value = self.expression(&let_statement.let_rhs);
+                    if let Some(place) = self.variable(&var_name.0).map(|(place, _)| place) {
+                        self.emit(Instr::Store { place, src: value });
+                    }
+                }
+                LetLHS::ArrayTerm(array_term) => {
+                    let addr = self.element_address(&array_term.var_name.0, &array_term.expression);
+                    let value = self.expression(&let_statement.let_rhs);
+                    self.emit(Instr::StoreIndirect { addr, src: value });
+                }
+            },
+            Statement::IfStatement(if_statement) => {
+                let cond = self.expression(&if_statement.condition);
+                let then_block = self.new_block();
+                let else_block = if_statement
+                    .false_statements
+                    .as_ref()
+                    .map(|_| self.new_block());
+                let join = self.new_block();
+                self.terminate(Terminator::Branch {
+                    cond,
+                    then_block,
+                    else_block: else_block.unwrap_or(join),
+                });
+                self.current = Some(then_block);
+                self.statements(&if_statement.true_statements);
+                if self.current.is_some() {
+                    self.terminate(Terminator::Jump(join));
+                }
+                if let (Some(else_block), Some(false_statements)) =
+                    (else_block, &if_statement.false_statements)
+                {
+                    self.current = Some(else_block);
+                    self.statements(false_statements);
+                    if self.current.is_some() {
+                        self.terminate(Terminator::Jump(join));
+                    }
+                }
+                self.current = Some(join);
+            }
+            Statement::WhileStatement(while_statement) => {
+                let header = self.new_block();
+                self.terminate(Terminator::Jump(header));
+                self.current = Some(header);
+                let cond = self.expression(&while_statement.condition);
+                let body = self.new_block();
+                let exit = self.new_block();
+                self.terminate(Terminator::Branch {
+                    cond,
+                    then_block: body,
+                    else_block: exit,
+                });
+                self.current = Some(body);
+                self.statements(&while_statement.statements);
+                if self.current.is_some() {
+                    self.terminate(Terminator::Jump(header));
+                }
+                self.current = Some(exit);
+            }
+            Statement::DoStatement(do_statement) => {
+                self.subroutine_call(&do_statement.subroutine_call);
+            }
+            Statement::ReturnStatement(return_statement) => {
+                let value = match &return_statement.expression {
+                    Some(expression) => self.expression(expression),
+                    None => Operand::Const(0),
+                };
+                self.terminate(Terminator::Return(value));
+            }
+        }
+    }
+
+    fn load(&mut self, place: Place, ty: Ty) -> Temp {
+        let dst = self.new_temp(ty);
+        self.emit(Instr::Load { dst, place });
+        dst
+    }
+
+    fn call(&mut self, function: String, args: Vec<Operand>, ty: Ty) -> Temp {
+        let dst = self.new_temp(ty);
+        self.emit(Instr::Call {
+            dst,
+            function,
+            args,
+        });
+        dst
+    }
+
+    fn variable(&mut self, name: &str) -> Option<(Place, Ty)> {
+        match self.symbols.lookup(name) {
+            Some(symbol) => Some((symbol.place(), symbol.var_type.clone())),
+            None => {
+                self.error(format!("undeclared variable {}", name));
+                None
+            }
+        }
+    }
+
+    fn element_address(&mut self, var_name: &str, index: &Expression) -> Operand {
+        let base = match self.variable(var_name) {
+            Some((place, ty)) => Operand::Temp(self.load(place, ty)),
+            None => Operand::Const(0),
+        };
+        let index = self.expression(index);
+        let dst = self.new_temp(Ty::Int);
+        self.emit(Instr::Binary {
+            dst,
+            op: BinOp::Add,
+            lhs: base,
+            rhs: index,
+        });
+        Operand::Temp(dst)
+    }
+
+    fn expression(&mut self, expression: &Expression) -> Operand {
+        let mut value = self.term(&expression.term);
+        for (op, term) in &expression.op_term {
+            let rhs = self.term(term);
+            let op = match op.0 .0 {
+                '+' => BinOp::Add,
+                '-' => BinOp::Sub,
+                '*' => BinOp::Mul,
+                '/' => BinOp::Div,
+                '&' => BinOp::And,
+                '|' => BinOp::Or,
+                '<' => BinOp::Lt,
+                '>' => BinOp::Gt,
+                _ => BinOp::Eq,
+            };
+            let both_boolean =
+                self.operand_type(value) == Ty::Boolean && self.operand_type(rhs) == Ty::Boolean;
+            let ty = match op.result_type() {
+                Some(ty) => ty,
+                None if both_boolean => Ty::Boolean,
+                None => Ty::Int,
+            };
+            let dst = self.new_temp(ty);
+            self.emit(Instr::Binary {
+                dst,
+                op,
+                lhs: value,
+                rhs,
+            });
+            value = Operand::Temp(dst);
+        }
+        value
+    }
+
+    fn term(&mut self, term: &Term) -> Operand {
+        match term {
+            // Jack integer constants are 0..32767, wider values wrap like the VM would
+            Term::IntegerConstant(constant) => Operand::Const(constant.0 as i16),
+            Term::StringConstant(constant) => {
+                let dst = self.new_temp(Ty::Class("String".to_string()));
+                self.emit(Instr::StringConst {
+                    dst,
+                    value: constant.0.clone(),
+                });
+                Operand::Temp(dst)
+            }
+            Term::KeywordConstant(constant) => match constant {
+                KeywordConstant::TRUE => Operand::Const(-1),
+                KeywordConstant::FALSE | KeywordConstant::NULL => Operand::Const(0),
+                KeywordConstant::THIS => {
+                    let ty = Ty::Class(self.class.class_name.0.clone());
+                    Operand::Temp(self.load(Place::This, ty))
+                }
+            },
+            Term::VarName(var_name) => match self.variable(&var_name.0) {
+                Some((place, ty)) => Operand::Temp(self.load(place, ty)),
+                None => Operand::Const(0),
+            },
+            Term::ArrayTerm(array_term) => {
+                let addr = self.element_address(&array_term.var_name.0, &array_term.expression);
+                let dst = self.new_temp(Ty::Word);
+                self.emit(Instr::LoadIndirect { dst, addr });
+                Operand::Temp(dst)
+            }
+            Term::UnaryTerm(unary_term) => {
+                let src = self.term(&unary_term.term);
+                let (op, ty) = match unary_term.unary_op.0 .0 {
+                    '-' => (UnOp::Neg, Ty::Int),
+                    _ if self.operand_type(src) == Ty::Boolean => (UnOp::Not, Ty::Boolean),
+                    _ => (UnOp::Not, Ty::Int),
+                };
+                let dst = self.new_temp(ty);
+                self.emit(Instr::Unary { dst, op, src });
+                Operand::Temp(dst)
+            }
+            Term::WrappedExpression(wrapped) => self.expression(&wrapped.0),
+            Term::SubroutineCall(call) => Operand::Temp(self.subroutine_call(call)),
+        }
+    }
+
+    /// Resolve the callee the usual Jack way: `foo()` is a method on `this`
+    /// unless the class declares `foo` as a function or constructor, `v.foo()`
+    /// calls the method of `v`'s class, anything else is `Class.foo`.
+    fn subroutine_call(&mut self, call: &SubroutineCall) -> Temp {
+        let subroutine_name = &call.subroutine_name.0;
+        let own_class = &self.class.class_name.0;
+        let mut args = vec![];
+        let class_name = match &call.bind_this {
+            None => {
+                let is_method = self
+                    .declared(subroutine_name)
+                    .is_none_or(|dec| matches!(dec.subroutine_type, SubroutineType::METHOD));
+                if is_method {
+                    let ty = Ty::Class(own_class.clone());
+                    args.push(Operand::Temp(self.load(Place::This, ty)));
+                }
+                own_class.clone()
+            }
+            Some(name) => match self.symbols.lookup(&name.0) {
+                Some(symbol) => {
+                    let (place, ty) = (symbol.place(), symbol.var_type.clone());
+                    let class_name = match &ty {
+                        Ty::Class(class_name) => class_name.clone(),
+                        other => {
+                            self.error(format!("{} of type {} has no methods", name.0, other));
+                            other.to_string()
+                        }
+                    };
+                    args.push(Operand::Temp(self.load(place, ty)));
+                    class_name
+                }
+                None => name.0.clone(),
+            },
+        };
+        for expression in &call.expression_list.0 {
+            let arg = self.expression(expression);
+            args.push(arg);
+        }
+        let ty = match self.declared(subroutine_name) {
+            Some(dec) if class_name == *own_class => match &dec.return_type {
+                ReturnType::VOID => Ty::Int,
+                ReturnType::VARTYPE(var_type) => Ty::from(&var_type.0),
+            },
+            _ => Ty::Word,
+        };
+        self.call(format!("{}.{}", class_name, subroutine_name), args, ty)
+    }
+
+    fn declared(&self, subroutine_name: &str) -> Option<&'a SubroutineDec> {
+        self.class
+            .subroutine_dec
+            .iter()
+            .find(|dec| dec.subroutine_name.0 == subroutine_name)
+    }
+}
+
+/// Line of the first token of `span`, 0 without token spans.
+fn line_of(token_spans: &[TokenSpan], span: &Span) -> u32 {
+    token_spans
+        .get(span.start)
+        .map_or(0, |token_span| token_span.start.line as u32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ir::verify;
+    use crate::parser::{Parsable, TokenReader};
+    use crate::tokenizer::{tokenize_str, tokenize_str_spanned};
+
+    fn lower(source: &str) -> Vec<Function> {
+        let reader = TokenReader {
+            tokens: tokenize_str(source),
+        };
+        let (class, _) = Class::try_parse(&reader, 0).unwrap();
+        lower_class(&class, &[]).unwrap()
+    }
+
+    #[test]
+    fn lowers_method_with_loop() {
+        let functions = lower(
+            "class Counter {
+                field int count;
+                method int countTo(int limit) {
+                    while (count < limit) { let count = count + 1; }
+                    return count;
+                }
+            }",
+        );
+        let expected = "\
+method Counter.countTo args 2 locals 0
+  b0:
+    %0: Counter = load argument 0
+    store this, %0
+    jump b1
+  b1:
+    %1: int = load field 0
+    %2: int = load argument 1
+    %3: boolean = lt %1, %2
+    branch %3, b2, b3
+  b2:
+    %4: int = load field 0
+    %5: int = add %4, 1
+    store field 0, %5
+    jump b1
+  b3:
+    %6: int = load field 0
+    return %6
+";
+        assert_eq!(functions[0].to_string(), expected);
+        assert_eq!(verify(&functions[0]), Ok(()));
+    }
+
+    #[test]
+    fn lowers_calls_and_arrays() {
+        let functions = lower(
+            "class Main {
+                function void main() {
+                    var Array a;
+                    var Main m;
+                    let m = Main.new();
+                    let a[2] = m.size(\"hi\");
+                    do run();
+                    return;
+                }
+                constructor Main new() { return this; }
+                method int size(String s) { return s.length(); }
+                function void run() { return; }
+            }",
+        );
+        let main = functions[0].to_string();
+        assert!(main.contains("%0: Main = call Main.new()"));
+        assert!(main.contains("%5: int = call Main.size(%3, %4)"));
+        assert!(main.contains("call Main.run()"));
+        assert!(functions[1].to_string().contains("call Memory.alloc(0)"));
+        assert!(functions[2].to_string().contains("call String.length("));
+        for function in &functions {
+            assert_eq!(verify(function), Ok(()));
+        }
+    }
+
+    #[test]
+    fn records_jack_lines() {
+        let source = "class Main {\n  function int f() {\n    var int x;\n    let x = 1;\n    return x;\n  }\n}";
+        let (tokens, token_spans) = tokenize_str_spanned(source);
+        let (class, _) = Class::try_parse(&TokenReader { tokens }, 0).unwrap();
+        let functions = lower_class(&class, &token_spans).unwrap();
+        let expected = "\
+function Main.f args 0 locals 1
+  b0:
+    store local 0, 1  ; line 4
+    %0: int = load local 0  ; line 5
+    return %0  ; line 5
+";
+        assert_eq!(functions[0].line, 2);
+        assert_eq!(functions[0].to_string(), expected);
+    }
+
+    #[test]
+    fn reports_undeclared_variables() {
+        let reader = TokenReader {
+            tokens: tokenize_str("class A { function int f() { return x; } }"),
+        };
+        let (class, _) = Class::try_parse(&reader, 0).unwrap();
+        let errors = lower_class(&class, &[]).unwrap_err();
+        assert_eq!(errors[0].to_string(), "A.f: undeclared variable x");
+    }
+}
@@ -1,26 +1,1113 @@
-use clap::Parser;
+use std::fs;
+use std::io::IsTerminal;
+use std::path::{Path, PathBuf};
 
+use clap::{Parser, Subcommand, ValueEnum};
+
+use crate::backend::Backend;
 use crate::parser::{Parsable, TokenReader};
+mod asm_macros;
+mod assembler;
+mod backend;
+mod build_id;
+mod cache;
+mod cpu_emulator;
+mod debug;
+mod disassembler;
+mod dot;
+mod hdl;
+mod hdl_builtin;
+mod hdl_diff;
+mod hdl_report;
+mod hdl_sim;
+mod hdl_test;
+mod ir;
+mod json;
+mod keyboard;
+mod lower;
+mod opt;
+mod os;
+mod os_test;
+mod parallel;
 mod tokenizer;
 mod parser;
+mod screen;
+mod sexp;
+mod sourcemap;
+mod symbol_table;
+mod tst;
+mod vcd;
+mod vm;
+mod vm_emulator;
+mod watch;
 mod xml;
 
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+enum Emit {
+    Xml,
+    Json,
+    Sexp,
+    /// Graphviz files for the parse tree, every subroutine's control flow
+    /// and the call graph, written next to the sources
+    Dot,
+    /// The three-address IR of every subroutine
+    Ir,
+    /// VM code, written to a .vm file next to each source
+    Vm,
+}
+
 /// Simple program to greet a person
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
+#[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
 struct Args {
-    path: String
+    #[command(subcommand)]
+    command: Option<Command>,
+    /// A .jack file, or a directory of them
+    #[arg(required = true)]
+    path: Option<String>,
+    /// Format the parse tree is written in
+    #[arg(long, value_enum, default_value_t = Emit::Xml)]
+    emit: Emit,
+    /// Attach source locations to the nodes (json and sexp only)
+    #[arg(long)]
+    spans: bool,
+    /// Also write a .vm.map next to each .vm file, mapping VM lines back
+    /// to Jack lines (vm only)
+    #[arg(long)]
+    source_map: bool,
+    /// Optimise the IR and the generated VM code, and report instruction
+    /// counts before and after (ir and vm only)
+    #[arg(short = 'O')]
+    optimize: bool,
+    /// Keep every compiled class in a .jack-cache directory next to the
+    /// sources and only compile classes that changed (vm only)
+    #[arg(long)]
+    cache: bool,
+    /// Say which classes were compiled and which were reused from the cache
+    #[arg(long)]
+    verbose: bool,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Run a program on the VM emulator under an interactive debugger that
+    /// reads its commands from stdin
+    Debug {
+        /// A .jack file or directory, compiled in memory, or a directory of
+        /// .vm files (with .vm.map files for Jack locations)
+        path: String,
+        /// Commands a single continue or step may run before it stops
+        #[arg(long, default_value_t = 100_000_000)]
+        max_steps: u64,
+        /// Keyboard script to feed the program, see `run --keys`
+        #[arg(long)]
+        keys: Option<PathBuf>,
+        /// OS classes to run natively, see `run --native`
+        #[arg(long)]
+        native: Option<String>,
+    },
+    /// Run a program on the VM emulator until it halts or runs out of steps,
+    /// then show what it drew on the screen
+    Run {
+        /// A .jack file or directory, or a directory of .vm files
+        path: String,
+        /// VM commands to run at most
+        #[arg(long, default_value_t = 10_000_000)]
+        max_steps: u64,
+        /// Keyboard script with lines like `press LEFT at 10000`,
+        /// `release at 20000` or `type "hello\n" at 30000`, cycles being
+        /// VM commands run
+        #[arg(long)]
+        keys: Option<PathBuf>,
+        /// OS classes to run natively: `all`, `none` or a list such as
+        /// `Math,Memory`. By default every OS class the program doesn't
+        /// define itself is native
+        #[arg(long)]
+        native: Option<String>,
+        /// Write the final screen to this PNG file
+        #[arg(long)]
+        png: Option<PathBuf>,
+        /// Print the final screen with block characters
+        #[arg(long)]
+        preview: bool,
+        /// Pixels per preview quadrant, 1 shows the full 512x256 screen
+        #[arg(long, default_value_t = 2)]
+        scale: usize,
+        /// Compare the final screen with this golden PNG, exiting with an
+        /// error when they differ. A missing golden image is created
+        #[arg(long)]
+        snapshot: Option<PathBuf>,
+        /// Overwrite the golden image instead of comparing
+        #[arg(long, requires = "snapshot")]
+        update_snapshot: bool,
+    },
+    /// Recompile a directory whenever its .jack files change, printing the
+    /// diagnostics, and optionally run the program or a .tst script after
+    /// every successful build
+    Watch {
+        /// Directory of .jack files
+        dir: PathBuf,
+        /// Milliseconds between checks for changes
+        #[arg(long, default_value_t = 500)]
+        interval: u64,
+        /// Run the program on the VM emulator after each build
+        #[arg(long)]
+        run: bool,
+        /// Run this .tst script (vmstep and output commands) after each
+        /// build and compare with its .cmp file
+        #[arg(long)]
+        tst: Option<PathBuf>,
+        /// VM commands a run may take at most
+        #[arg(long, default_value_t = 10_000_000)]
+        max_steps: u64,
+        /// Keyboard script for runs, see `run --keys`
+        #[arg(long)]
+        keys: Option<PathBuf>,
+        /// OS classes to run natively, see `run --native`
+        #[arg(long)]
+        native: Option<String>,
+    },
+    /// Run a .tst script on the HDL simulator, writing its output file and
+    /// comparing it with the .cmp file, or check that a .hdl chip builds
+    Hdl {
+        /// A .tst script, or a .hdl file to check
+        path: PathBuf,
+        /// Chips to simulate with their built-in versions: `all` but the
+        /// chip under test, `none` or a list such as `CPU,RAM16K`. By
+        /// default, and for chips not listed, only chips without a .hdl
+        /// file are built in
+        #[arg(long)]
+        builtin: Option<String>,
+        /// Write the chip's pins and internal wires to this VCD file, a
+        /// time unit per eval, tick or tock
+        #[arg(long)]
+        vcd: Option<PathBuf>,
+        /// Signals the VCD file records, such as `out,load,*carry*`
+        #[arg(long, requires = "vcd")]
+        signals: Option<String>,
+        /// Keyboard script for `while` loops waiting on a key, see `run
+        /// --keys`, cycles being evals, ticks and tocks
+        #[arg(long)]
+        keys: Option<PathBuf>,
+    },
+    /// Run a .hack program on the CPU emulator until it halts or runs out of
+    /// cycles, then show what it drew on the screen
+    Cpu {
+        /// A .hack file, or a .asm file to assemble first
+        path: PathBuf,
+        /// Instructions to run at most
+        #[arg(long, default_value_t = 10_000_000)]
+        max_steps: u64,
+        /// Keyboard script, see `run --keys`, cycles being instructions run
+        #[arg(long)]
+        keys: Option<PathBuf>,
+        /// Write the final screen to this PNG file
+        #[arg(long)]
+        png: Option<PathBuf>,
+        /// Print the final screen with block characters
+        #[arg(long)]
+        preview: bool,
+        /// Pixels per preview quadrant, 1 shows the full 512x256 screen
+        #[arg(long, default_value_t = 2)]
+        scale: usize,
+        /// Write PC, A, D, the instruction, memory writes and every word
+        /// written (as RAM_n) to this VCD file, a time unit per instruction
+        #[arg(long)]
+        vcd: Option<PathBuf>,
+        /// Signals the VCD file records, such as `pc,A,D,RAM_1*`
+        #[arg(long, requires = "vcd")]
+        signals: Option<String>,
+    },
+    /// Time the CPU emulator running a program a step at a time, an
+    /// instruction at a time predecoded and a basic block at a time
+    CpuBench {
+        /// A .hack or .asm file
+        path: PathBuf,
+        /// Instructions to run in each mode
+        #[arg(long, default_value_t = 200_000_000)]
+        steps: u64,
+    },
+    /// Assemble a .asm file into a .hack file next to it
+    Asm {
+        /// A .asm file
+        path: PathBuf,
+        /// Expand `.define`, `.macro`, `.include` and the built-in PUSH_D,
+        /// POP_D and GOTO macros first
+        #[arg(long)]
+        extended: bool,
+        /// Also write a .lst file listing each line's address and word, and
+        /// the symbols
+        #[arg(long)]
+        listing: bool,
+    },
+    /// Print a .hack program as assembly, warning about words that aren't
+    /// legal Hack instructions and failing when there are any
+    Disasm {
+        /// A .hack file
+        path: PathBuf,
+        /// Label the targets of jumps, so the assembly reassembles to the
+        /// same program
+        #[arg(long)]
+        labels: bool,
+    },
+    /// Count the Nands of a chip once flattened, in total and per part, and
+    /// find its longest combinational path in gate delays
+    HdlReport {
+        /// The .hdl file of the chip
+        path: PathBuf,
+        /// Write the report as JSON
+        #[arg(long)]
+        json: bool,
+        /// Chips to take the built-in versions of, see `hdl --builtin`
+        #[arg(long)]
+        builtin: Option<String>,
+    },
+    /// Run a .hack program on an HDL Computer chip and on the CPU emulator in
+    /// lockstep, and report the first cycle where PC, A, D or a written
+    /// memory word differ
+    HdlDiff {
+        /// The .hdl file of the Computer chip
+        path: PathBuf,
+        /// A .hack file
+        program: PathBuf,
+        /// Cycles to run at most
+        #[arg(long, default_value_t = 1_000_000)]
+        max_steps: u64,
+        /// Start a memory word at a value on both sides, such as `0=3`
+        #[arg(long)]
+        ram: Vec<String>,
+        /// Chips to take the built-in versions of, see `hdl --builtin`
+        #[arg(long)]
+        builtin: Option<String>,
+    },
+    /// Test one of our OS classes with its project 12 test, the other OS
+    /// classes running natively, and report pass or fail per .cmp row or
+    /// against a golden screenshot
+    TestOs {
+        /// The OS class, such as Memory
+        class: String,
+        /// Directory holding <Class>.jack and the <Class>Test directories
+        #[arg(long, default_value = "12")]
+        dir: PathBuf,
+        /// VM commands a screen test may run at most
+        #[arg(long, default_value_t = 10_000_000)]
+        max_steps: u64,
+        /// Remake the golden screenshot from the native OS
+        #[arg(long)]
+        update_golden: bool,
+    },
+}
+
+/// The .jack files named by `path`, sorted so output order is stable.
+fn source_files(path: &Path) -> Vec<PathBuf> {
+    if !path.is_dir() {
+        return vec![path.to_path_buf()];
+    }
+    let mut files: Vec<PathBuf> = fs::read_dir(path)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|file| file.extension().is_some_and(|ext| ext == "jack"))
+        .collect();
+    files.sort();
+    files
+}
+
+type Parsed = (parser::structures::Class, Vec<tokenizer::TokenSpan>);
+
+fn try_parse_file(path: &Path) -> Result<Parsed, Vec<String>> {
+    let (tokens, token_spans) = tokenizer::tokenize_spanned(path.to_str().unwrap());
+    parse_tokens(path, tokens, token_spans)
+}
+
+/// Parse `source`, already read from `path`.
+fn try_parse_source(path: &Path, source: &str) -> Result<Parsed, Vec<String>> {
+    let (tokens, token_spans) = tokenizer::tokenize_str_spanned(source);
+    parse_tokens(path, tokens, token_spans)
+}
+
+fn parse_tokens(
+    path: &Path,
+    tokens: Vec<tokenizer::Token>,
+    token_spans: Vec<tokenizer::TokenSpan>,
+) -> Result<Parsed, Vec<String>> {
+    match parser::structures::Class::try_parse(&TokenReader { tokens }, 0) {
+        Some((class, _)) => Ok((class, token_spans)),
+        None => Err(vec![format!("{}: not a valid class", path.display())]),
+    }
+}
+
+fn parse_file(path: &Path) -> Parsed {
+    try_parse_file(path).unwrap_or_else(|diagnostics| exit_with(&diagnostics))
+}
+
+fn exit_with(diagnostics: &[String]) -> ! {
+    for diagnostic in diagnostics {
+        eprintln!("{}", diagnostic);
+    }
+    std::process::exit(1);
+}
+
+fn write_output(path: &Path, content: &str) {
+    fs::write(path, content).unwrap();
+    println!("{}", path.display());
+}
+
+fn emit_dot(path: &Path, files: &[PathBuf]) {
+    let out_dir = if path.is_dir() {
+        path
+    } else {
+        path.parent().unwrap_or(Path::new("."))
+    };
+    let mut classes = vec![];
+    for file in files {
+        let (class, _) = parse_file(file);
+        let name = &class.class_name.0;
+        write_output(&out_dir.join(format!("{}.ast.dot", name)), &dot::class_tree(&class));
+        for subroutine_dec in &class.subroutine_dec {
+            let file_name = format!("{}.{}.cfg.dot", name, subroutine_dec.subroutine_name.0);
+            write_output(&out_dir.join(file_name), &dot::subroutine_cfg(name, subroutine_dec));
+        }
+        classes.push(class);
+    }
+    write_output(&out_dir.join("callgraph.dot"), &dot::call_graph(&classes));
+}
+
+/// Lower `class` and check every function, or the diagnostics of a bad
+/// class.
+fn check_file(
+    file: &Path,
+    class: &parser::structures::Class,
+    token_spans: &[tokenizer::TokenSpan],
+    optimize: bool,
+) -> Result<Vec<ir::Function>, Vec<String>> {
+    let mut functions = lower::lower_class(class, token_spans).map_err(|errors| {
+        errors
+            .iter()
+            .map(|error| format!("{}: {}", file.display(), error))
+            .collect::<Vec<_>>()
+    })?;
+    for function in &mut functions {
+        if optimize {
+            opt::optimize(function);
+        }
+        ir::verify(function).map_err(|errors| {
+            errors
+                .iter()
+                .map(|error| format!("{}: invalid IR: {}", file.display(), error))
+                .collect::<Vec<_>>()
+        })?;
+    }
+    Ok(functions)
+}
+
+/// Like `check_file`, exiting on a bad class.
+fn lower_file(
+    file: &Path,
+    class: &parser::structures::Class,
+    token_spans: &[tokenizer::TokenSpan],
+    optimize: bool,
+) -> Vec<ir::Function> {
+    check_file(file, class, token_spans, optimize)
+        .unwrap_or_else(|diagnostics| exit_with(&diagnostics))
+}
+
+/// VM code of `class` with Jack lines, peephole optimised under `-O`, and
+/// its instruction count before optimisation.
+fn vm_commands(
+    file: &Path,
+    class: &parser::structures::Class,
+    token_spans: &[tokenizer::TokenSpan],
+    optimize: bool,
+) -> Result<(Vec<vm::MappedCommand>, usize), Vec<String>> {
+    let commands = vm::class_mapped(&check_file(file, class, token_spans, optimize)?);
+    if !optimize {
+        let count = opt::instruction_count(&commands);
+        return Ok((commands, count));
+    }
+    let before = vm::class_mapped(&check_file(file, class, &[], false)?);
+    let commands = opt::peephole(&class.class_name.0, commands);
+    Ok((commands, opt::instruction_count(&before)))
+}
+
+/// Tokenize, parse and generate VM code for the text `source` of one file,
+/// as run on each core.
+fn compile_file(
+    file: &Path,
+    source: &str,
+    optimize: bool,
+) -> Result<(Parsed, Vec<vm::MappedCommand>, usize), Vec<String>> {
+    let (class, token_spans) = try_parse_source(file, source)?;
+    let (commands, unoptimized) = vm_commands(file, &class, &token_spans, optimize)?;
+    Ok(((class, token_spans), commands, unoptimized))
+}
+
+fn write_vm(file: &Path, commands: &[vm::MappedCommand], source_map: bool) {
+    let texts: Vec<String> = commands.iter().map(|(text, _)| text.clone()).collect();
+    write_output(&file.with_extension("vm"), &vm::join_lines(&texts));
+    if source_map {
+        let source_file = file.file_name().unwrap().to_string_lossy();
+        let map = sourcemap::SourceMap::from_commands(&source_file, commands);
+        write_output(&file.with_extension("vm.map"), &map.to_string());
+    }
+}
+
+/// Compile `files` through the cache, reusing the classes that didn't
+/// change.
+fn compile_cached(
+    files: &[PathBuf],
+    optimize: bool,
+    verbose: bool,
+) -> Result<Vec<(PathBuf, cache::Entry)>, Vec<String>> {
+    let cache = cache::Cache::new(optimize);
+    let results = parallel::map(files, |file| {
+        let source = fs::read_to_string(file).unwrap();
+        if let Some(entry) = cache.load(file, &source) {
+            return Ok((entry, true));
+        }
+        // the very text hashed, which `watch` may see change meanwhile
+        let ((class, token_spans), commands, unoptimized) =
+            compile_file(file, &source, optimize)?;
+        let entry = cache::Entry::new(&source, optimize, class, token_spans, commands, unoptimized);
+        Ok((entry, false))
+    });
+    let mut compiled = vec![];
+    let mut diagnostics = vec![];
+    for (file, result) in files.iter().zip(results) {
+        match result {
+            Ok((entry, reused)) => compiled.push((file.clone(), entry, reused)),
+            Err(mut errors) => diagnostics.append(&mut errors),
+        }
+    }
+    if !diagnostics.is_empty() {
+        return Err(diagnostics);
+    }
+    let mut entries = vec![];
+    for (file, entry, reused) in compiled {
+        let name = file.file_name().unwrap().to_string_lossy().to_string();
+        if reused {
+            if verbose {
+                println!("reused {}", name);
+            }
+        } else {
+            if verbose {
+                println!("compiled {}", name);
+            }
+            cache.store(&file, &entry);
+        }
+        entries.push((file, entry));
+    }
+    Ok(entries)
+}
+
+/// Instruction counts of every optimised file, before and after `-O`.
+fn print_report(report: &[(PathBuf, usize, usize)]) {
+    let change = |before: usize, after: usize| {
+        (after as f64 - before as f64) * 100.0 / before.max(1) as f64
+    };
+    println!("{:<24} {:>8} {:>8} {:>8}", "file", "before", "after", "change");
+    for (file, before, after) in report {
+        let name = file.with_extension("vm");
+        let name = name.file_name().unwrap().to_string_lossy();
+        println!("{:<24} {:>8} {:>8} {:>7.1}%", name, before, after, change(*before, *after));
+    }
+    let before: usize = report.iter().map(|(_, before, _)| before).sum();
+    let after: usize = report.iter().map(|(_, _, after)| after).sum();
+    println!("{:<24} {:>8} {:>8} {:>7.1}%", "total", before, after, change(before, after));
+}
+
+/// The .vm files in `dir` with their source maps, when there are no .jack
+/// files to compile.
+fn vm_files(dir: &Path) -> Vec<(String, String, Option<sourcemap::SourceMap>)> {
+    let mut paths: Vec<PathBuf> = fs::read_dir(dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|file| file.extension().is_some_and(|ext| ext == "vm"))
+        .collect();
+    paths.sort();
+    paths
+        .iter()
+        .map(|path| {
+            let name = path.file_stem().unwrap().to_string_lossy().to_string();
+            let map = fs::read_to_string(path.with_extension("vm.map")).ok().map(|text| {
+                sourcemap::SourceMap::parse(&text).unwrap_or_else(|error| {
+                    eprintln!("{}.vm.map: {}", name, error);
+                    std::process::exit(1);
+                })
+            });
+            (name, fs::read_to_string(path).unwrap(), map)
+        })
+        .collect()
+}
+
+fn read_keys(path: &Path) -> keyboard::KeyScript {
+    let text = fs::read_to_string(path).unwrap();
+    keyboard::KeyScript::parse(&text).unwrap_or_else(|error| {
+        eprintln!("{}: {}", path.display(), error);
+        std::process::exit(1);
+    })
+}
+
+fn debug(path: &Path, max_steps: u64, keys: Option<&Path>, native: Option<&str>) {
+    let files = source_files(path);
+    let debugger = if files.is_empty() && path.is_dir() {
+        debug::Debugger::from_vm(&vm_files(path), native)
+    } else {
+        let parsed: Vec<_> = files.iter().map(|file| parse_file(file)).collect();
+        let classes: Vec<_> = files
+            .iter()
+            .zip(&parsed)
+            .map(|(file, (class, token_spans))| {
+                let file_name = file.file_name().unwrap().to_string_lossy().to_string();
+                (file_name, class, lower_file(file, class, token_spans, false))
+            })
+            .collect();
+        debug::Debugger::from_classes(&classes, native)
+    };
+    let mut debugger = debugger.unwrap_or_else(|error| {
+        eprintln!("{}", error);
+        std::process::exit(1);
+    });
+    debugger.max_steps = max_steps;
+    if let Some(keys) = keys {
+        debugger.set_keys(read_keys(keys));
+    }
+    let stdin = std::io::stdin();
+    let prompt = stdin.is_terminal();
+    debugger.repl(stdin.lock(), &mut std::io::stdout(), prompt);
+}
+
+/// VM code of the program at `path` as `(file stem, text)`, compiling
+/// .jack files when there are any.
+fn vm_sources(path: &Path) -> Vec<(String, String)> {
+    let files = source_files(path);
+    if files.is_empty() && path.is_dir() {
+        return vm_files(path)
+            .into_iter()
+            .map(|(name, text, _)| (name, text))
+            .collect();
+    }
+    files
+        .iter()
+        .map(|file| {
+            let (class, token_spans) = parse_file(file);
+            let functions = lower_file(file, &class, &token_spans, false);
+            let texts: Vec<String> = vm::class_mapped(&functions)
+                .into_iter()
+                .map(|(text, _)| text)
+                .collect();
+            (class.class_name.0, vm::join_lines(&texts))
+        })
+        .collect()
+}
+
+fn load_emulator(
+    sources: &[(String, String)],
+    native: Option<&str>,
+) -> Result<vm_emulator::VmEmulator, String> {
+    let program = vm_emulator::Program::load(sources).map_err(|error| error.to_string())?;
+    let os = os::NativeOs::for_program(&program, native)?;
+    vm_emulator::VmEmulator::with_os(program, os).map_err(|error| error.to_string())
+}
+
+fn run_sources(
+    sources: &[(String, String)],
+    max_steps: u64,
+    keys: Option<&Path>,
+    native: Option<&str>,
+) -> Result<vm_emulator::VmEmulator, String> {
+    let mut emulator = load_emulator(sources, native)?;
+    if let Some(keys) = keys {
+        emulator.keys = keyboard::KeyPlayer::new(read_keys(keys));
+    }
+    match emulator.run(max_steps).map_err(|error| error.to_string())? {
+        vm_emulator::State::Halted => println!("halted after {} steps", emulator.steps),
+        vm_emulator::State::Running => println!("stopped after {} steps", emulator.steps),
+    }
+    Ok(emulator)
+}
+
+fn run(
+    path: &Path,
+    max_steps: u64,
+    keys: Option<&Path>,
+    native: Option<&str>,
+) -> vm_emulator::VmEmulator {
+    run_sources(&vm_sources(path), max_steps, keys, native)
+        .unwrap_or_else(|error| exit_with(&[error]))
+}
+
+/// Build `dir` whenever one of its .jack files changes, reusing the cache
+/// for the classes that didn't, then run the program or a test script.
+fn watch(dir: &Path, interval: u64, then: &WatchThen) -> ! {
+    let mut watcher = watch::Watcher::new(dir, "jack");
+    loop {
+        let changed = watcher.poll();
+        if !changed.is_empty() {
+            for file in &changed {
+                println!("changed {}", file.display());
+            }
+            match compile_cached(&source_files(dir), false, true) {
+                Ok(entries) => {
+                    let mut sources = vec![];
+                    for (file, entry) in entries {
+                        write_vm(&file, &entry.commands, false);
+                        let texts: Vec<String> =
+                            entry.commands.into_iter().map(|(text, _)| text).collect();
+                        sources.push((entry.class.class_name.0, vm::join_lines(&texts)));
+                    }
+                    println!("build succeeded");
+                    if let Err(error) = after_build(&sources, then) {
+                        eprintln!("{}", error);
+                    }
+                }
+                Err(diagnostics) => {
+                    for diagnostic in diagnostics {
+                        eprintln!("{}", diagnostic);
+                    }
+                    println!("build failed");
+                }
+            }
+            println!("watching {} for changes", dir.display());
+        }
+        std::thread::sleep(std::time::Duration::from_millis(interval));
+    }
+}
+
+/// What `watch` does after a successful build.
+struct WatchThen<'a> {
+    run: bool,
+    tst: Option<&'a Path>,
+    max_steps: u64,
+    keys: Option<&'a Path>,
+    native: Option<&'a str>,
+}
+
+fn after_build(sources: &[(String, String)], then: &WatchThen) -> Result<(), String> {
+    if then.run {
+        run_sources(sources, then.max_steps, then.keys, then.native)?;
+    }
+    if let Some(script) = then.tst {
+        let mut emulator = load_emulator(sources, then.native)?;
+        let passed = os_test::run_script(&mut emulator, script)?;
+        println!("{}", if passed { "test passed" } else { "test failed" });
+    }
+    Ok(())
+}
+
+fn hdl(path: &Path, options: &hdl_test::Options) {
+    if path.extension().is_some_and(|ext| ext == "hdl") {
+        let dir = path.parent().unwrap_or(Path::new(""));
+        let name = path.file_stem().unwrap().to_string_lossy();
+        let mut library = hdl_sim::Library::new(dir, options.builtins)
+            .unwrap_or_else(|error| exit_with(&[error]));
+        match hdl_sim::Simulator::build(&mut library, &name) {
+            Ok(_) => println!("{}: ok", path.display()),
+            Err(errors) => {
+                eprintln!("{}", hdl_test::build_errors(&errors));
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+    match hdl_test::run_script(path, options) {
+        Ok(true) => println!("test passed"),
+        Ok(false) => {
+            println!("test failed");
+            std::process::exit(1);
+        }
+        Err(error) => {
+            eprintln!("{}", error);
+            std::process::exit(1);
+        }
+    }
+}
+
+fn show_screen(ram: &[i16], png: Option<&Path>, preview: bool, scale: usize) -> screen::Screen {
+    let screen = screen::Screen::from_ram(ram);
+    if let Some(png) = png {
+        fs::write(png, screen.to_png()).unwrap();
+        println!("{}", png.display());
+    }
+    if preview {
+        print!("{}", screen.to_terminal(scale));
+    }
+    screen
+}
+
+struct CpuRun<'a> {
+    max_steps: u64,
+    keys: Option<&'a Path>,
+    vcd: Option<&'a Path>,
+    signals: Option<&'a str>,
+}
+
+fn read_hack(path: &Path) -> Vec<u16> {
+    fs::read_to_string(path)
+        .map_err(|error| error.to_string())
+        .and_then(|text| cpu_emulator::parse_hack(&text))
+        .unwrap_or_else(|error| exit_with(&[format!("{}: {}", path.display(), error)]))
+}
+
+/// A .hack file, or a .asm file assembled.
+fn read_program(path: &Path) -> Vec<u16> {
+    if path.extension().is_none_or(|ext| ext != "asm") {
+        return read_hack(path);
+    }
+    assembler::read(path, false)
+        .and_then(|lines| assembler::assemble(&lines))
+        .map(|assembly| assembly.words)
+        .unwrap_or_else(|errors| {
+            exit_with(&errors.iter().map(|error| error.to_string()).collect::<Vec<_>>())
+        })
+}
+
+fn cpu(path: &Path, run: &CpuRun) -> cpu_emulator::CpuEmulator {
+    let mut emulator = cpu_emulator::CpuEmulator::new(&read_program(path));
+    if let Some(keys) = run.keys {
+        emulator.keys = keyboard::KeyPlayer::new(read_keys(keys));
+    }
+    if let Some(vcd) = run.vcd {
+        let mut trace = cpu_emulator::Trace::new(&emulator, run.signals);
+        while emulator.state == vm_emulator::State::Running && emulator.cycles < run.max_steps {
+            let step = emulator.step();
+            trace.record(&emulator, &step);
+        }
+        fs::write(vcd, trace.finish()).unwrap();
+    } else {
+        emulator.run(run.max_steps);
+    }
+    match emulator.state {
+        vm_emulator::State::Halted => println!("halted after {} cycles", emulator.cycles),
+        vm_emulator::State::Running => println!("stopped after {} cycles", emulator.cycles),
+    }
+    emulator
+}
+
+fn hdl_report(path: &Path, json: bool, builtins: Option<&str>) {
+    let dir = path.parent().unwrap_or(Path::new(""));
+    let name = path.file_stem().unwrap().to_string_lossy();
+    let mut library =
+        hdl_sim::Library::new(dir, builtins).unwrap_or_else(|error| exit_with(&[error]));
+    let flattened = hdl_sim::flatten(&mut library, &name).unwrap_or_else(|errors| {
+        eprintln!("{}", hdl_test::build_errors(&errors));
+        std::process::exit(1);
+    });
+    let report = hdl_report::Report::new(&flattened);
+    println!("{}", if json { report.json() } else { report.text() });
+}
+
+fn cpu_bench(path: &Path, steps: u64) {
+    let program = read_program(path);
+    let modes = [
+        ("step", cpu_emulator::Mode::Step),
+        ("decoded", cpu_emulator::Mode::Decoded),
+        ("blocks", cpu_emulator::Mode::Blocks),
+    ];
+    let mut ends = vec![];
+    for (name, mode) in modes {
+        let mut emulator = cpu_emulator::CpuEmulator::new(&program);
+        let start = std::time::Instant::now();
+        emulator.run_mode(mode, steps);
+        let seconds = start.elapsed().as_secs_f64();
+        println!(
+            "{:8} {} instructions in {:.2} s, {:.1}M a second",
+            name,
+            emulator.cycles,
+            seconds,
+            emulator.cycles as f64 / seconds / 1e6
+        );
+        ends.push((emulator.a, emulator.d, emulator.pc, emulator.cycles, emulator.ram));
+    }
+    if ends.windows(2).any(|pair| pair[0] != pair[1]) {
+        exit_with(&["the modes ended in different states".to_string()]);
+    }
+}
+
+struct HdlDiff<'a> {
+    max_steps: u64,
+    ram: &'a [String],
+    builtins: Option<&'a str>,
+}
+
+fn hdl_diff(path: &Path, program: &Path, diff: &HdlDiff) {
+    let dir = path.parent().unwrap_or(Path::new(""));
+    let name = path.file_stem().unwrap().to_string_lossy();
+    let mut library =
+        hdl_sim::Library::new(dir, diff.builtins).unwrap_or_else(|error| exit_with(&[error]));
+    let mut simulator = hdl_sim::Simulator::build(&mut library, &name).unwrap_or_else(|errors| {
+        eprintln!("{}", hdl_test::build_errors(&errors));
+        std::process::exit(1);
+    });
+    let mut emulator = cpu_emulator::CpuEmulator::new(&read_program(program));
+    for setting in diff.ram {
+        let word = setting
+            .split_once('=')
+            .and_then(|(address, value)| Some((address.parse().ok()?, value.parse().ok()?)))
+            .filter(|(address, _)| *address < vm_emulator::RAM_SIZE);
+        let Some((address, value)) = word else {
+            exit_with(&[format!("--ram {}: expected address=value", setting)]);
+        };
+        hdl_diff::poke(&mut simulator, &mut emulator, address, value)
+            .unwrap_or_else(|error| exit_with(&[error]));
+    }
+    let outcome = hdl_diff::lockstep(&mut simulator, &mut emulator, diff.max_steps)
+        .unwrap_or_else(|error| exit_with(&[error]));
+    match outcome {
+        hdl_diff::Outcome::Agreed { cycles, state } => println!(
+            "{} and the emulator agree for {} cycles{}",
+            name,
+            cycles,
+            match state {
+                vm_emulator::State::Halted => ", until the program halts",
+                vm_emulator::State::Running => "",
+            }
+        ),
+        hdl_diff::Outcome::Mismatch(mismatch) => exit_with(&[mismatch.text()]),
+    }
 }
 
 fn main() {
     let args = Args::parse();
+    match &args.command {
+        Some(Command::Debug {
+            path,
+            max_steps,
+            keys,
+            native,
+        }) => {
+            debug(Path::new(path), *max_steps, keys.as_deref(), native.as_deref());
+            return;
+        }
+        Some(Command::Run {
+            path,
+            max_steps,
+            keys,
+            native,
+            png,
+            preview,
+            scale,
+            snapshot,
+            update_snapshot,
+        }) => {
+            let emulator = run(Path::new(path), *max_steps, keys.as_deref(), native.as_deref());
+            let screen = show_screen(&emulator.ram, png.as_deref(), *preview, *scale);
+            if let Some(golden) = snapshot {
+                match screen::check_snapshot(&screen, golden, *update_snapshot) {
+                    Ok(true) => println!("wrote {}", golden.display()),
+                    Ok(false) => println!("screen matches {}", golden.display()),
+                    Err(error) => {
+                        eprintln!("{}: {}", golden.display(), error);
+                        std::process::exit(1);
+                    }
+                }
+            }
+            return;
+        }
+        Some(Command::TestOs {
+            class,
+            dir,
+            max_steps,
+            update_golden,
+        }) => {
+            let test_dir = dir.join(format!("{}Test", class));
+            let mut sources = vm_sources(&dir.join(format!("{}.jack", class)));
+            sources.extend(vm_sources(&test_dir));
+            match os_test::test_os(&sources, &test_dir, class, *max_steps, *update_golden) {
+                Ok(true) => println!("{}Test passed", class),
+                Ok(false) => {
+                    println!("{}Test failed", class);
+                    std::process::exit(1);
+                }
+                Err(error) => {
+                    eprintln!("{}", error);
+                    std::process::exit(1);
+                }
+            }
+            return;
+        }
+        Some(Command::Hdl {
+            path,
+            builtin,
+            vcd,
+            signals,
+            keys,
+        }) => {
+            let keys = keys.as_deref().map(read_keys);
+            let options = hdl_test::Options {
+                builtins: builtin.as_deref(),
+                vcd: vcd.as_deref(),
+                signals: signals.as_deref(),
+                keys: keys.as_ref(),
+            };
+            hdl(path, &options);
+            return;
+        }
+        Some(Command::Cpu {
+            path,
+            max_steps,
+            keys,
+            png,
+            preview,
+            scale,
+            vcd,
+            signals,
+        }) => {
+            let run = CpuRun {
+                max_steps: *max_steps,
+                keys: keys.as_deref(),
+                vcd: vcd.as_deref(),
+                signals: signals.as_deref(),
+            };
+            let emulator = cpu(path, &run);
+            show_screen(&emulator.ram, png.as_deref(), *preview, *scale);
+            return;
+        }
+        Some(Command::CpuBench { path, steps }) => {
+            cpu_bench(path, *steps);
+            return;
+        }
+        Some(Command::Asm {
+            path,
+            extended,
+            listing,
+        }) => {
+            let (lines, assembly) = assembler::read(path, *extended)
+                .and_then(|lines| Ok((lines.clone(), assembler::assemble(&lines)?)))
+                .unwrap_or_else(|errors| {
+                    exit_with(&errors.iter().map(|error| error.to_string()).collect::<Vec<_>>())
+                });
+            for warning in &assembly.warnings {
+                eprintln!("warning: {}", warning);
+            }
+            write_output(&path.with_extension("hack"), &assembler::to_hack(&assembly.words));
+            if *listing {
+                write_output(&path.with_extension("lst"), &assembly.listing(&lines));
+            }
+            return;
+        }
+        Some(Command::Disasm { path, labels }) => {
+            let disassembly = disassembler::disassemble(&read_hack(path), *labels);
+            print!("{}", disassembly.text);
+            for (address, problem) in &disassembly.problems {
+                eprintln!("{}: word {}: {}", path.display(), address, problem);
+            }
+            // the text no longer reassembles to the same words
+            if !disassembly.problems.is_empty() {
+                std::process::exit(1);
+            }
+            return;
+        }
+        Some(Command::HdlReport {
+            path,
+            json,
+            builtin,
+        }) => {
+            hdl_report(path, *json, builtin.as_deref());
+            return;
+        }
+        Some(Command::HdlDiff {
+            path,
+            program,
+            max_steps,
+            ram,
+            builtin,
+        }) => {
+            let diff = HdlDiff {
+                max_steps: *max_steps,
+                ram,
+                builtins: builtin.as_deref(),
+            };
+            hdl_diff(path, program, &diff);
+            return;
+        }
+        Some(Command::Watch {
+            dir,
+            interval,
+            run,
+            tst,
+            max_steps,
+            keys,
+            native,
+        }) => {
+            let then = WatchThen {
+                run: *run,
+                tst: tst.as_deref(),
+                max_steps: *max_steps,
+                keys: keys.as_deref(),
+                native: native.as_deref(),
+            };
+            watch(dir, *interval, &then);
+        }
+        None => {}
+    }
     // read file content from path
-    let path = args.path;
-    let tokens = tokenizer::tokenize(&path);
-    println!("{:?}",tokens);
-    let parsed_class = parser::structures::Class::try_parse(&TokenReader{tokens}, 0).unwrap();
-    println!("{:?}",parsed_class);
-    let node = parser::Node::Class(parsed_class.0);
-    let output = xml::convert_node(node);
-    println!("{}",output);
+    let path = Path::new(args.path.as_deref().unwrap());
+    let files = source_files(path);
+    if args.emit == Emit::Dot {
+        emit_dot(path, &files);
+        return;
+    }
+    let mut report = vec![];
+    if args.emit == Emit::Vm && args.cache {
+        let entries = compile_cached(&files, args.optimize, args.verbose)
+            .unwrap_or_else(|diagnostics| exit_with(&diagnostics));
+        for (file, entry) in entries {
+            if args.optimize {
+                let after = opt::instruction_count(&entry.commands);
+                report.push((file.clone(), entry.unoptimized, after));
+            }
+            write_vm(&file, &entry.commands, args.source_map);
+        }
+        if !report.is_empty() {
+            print_report(&report);
+        }
+        return;
+    }
+    if args.emit == Emit::Vm {
+        // outputs of good classes are still written when others fail
+        let results = parallel::map(&files, |file| {
+            let source = fs::read_to_string(file).unwrap();
+            compile_file(file, &source, args.optimize)
+        });
+        let mut diagnostics = vec![];
+        for (file, result) in files.iter().zip(results) {
+            match result {
+                Ok((_, commands, unoptimized)) => {
+                    if args.optimize {
+                        let after = opt::instruction_count(&commands);
+                        report.push((file.clone(), unoptimized, after));
+                    }
+                    write_vm(file, &commands, args.source_map);
+                }
+                Err(mut errors) => diagnostics.append(&mut errors),
+            }
+        }
+        if !report.is_empty() {
+            print_report(&report);
+        }
+        if !diagnostics.is_empty() {
+            exit_with(&diagnostics);
+        }
+        return;
+    }
+    let parsed = parallel::map(&files, |file| try_parse_file(file));
+    for (file, parsed) in files.iter().zip(parsed) {
+        let (class, token_spans) = parsed.unwrap_or_else(|diagnostics| exit_with(&diagnostics));
+        if args.emit == Emit::Ir {
+            for function in &lower_file(file, &class, &token_spans, args.optimize) {
+                print!("{}", function);
+            }
+            continue;
+        }
+        let node = parser::Node::Class(class);
+        let spans = args.spans.then_some(token_spans.as_slice());
+        let backend: Box<dyn Backend> = match args.emit {
+            Emit::Xml => Box::new(xml::Xml),
+            Emit::Json => Box::new(json::Json { spans }),
+            Emit::Sexp => Box::new(sexp::Sexp { spans }),
+            Emit::Dot | Emit::Ir | Emit::Vm => unreachable!(),
+        };
+        println!("{}", backend.convert_node(&node));
+    }
 }
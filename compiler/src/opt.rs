@@ -0,0 +1,770 @@
+// optimisation passes behind `-O`
+//
+// Most of the work happens on the IR: constant folding, branch and jump
+// simplification and dead code removal. What can only be expressed in VM
+// code (strength reduction, redundant stack traffic) is done by `peephole`
+// on the generated commands.
+
+use std::collections::HashMap;
+
+use crate::{
+    ir::{BasicBlock, BinOp, BlockId, Function, Instr, Operand, Temp, Terminator, UnOp},
+    vm::MappedCommand,
+};
+
+/// Run every IR pass until none of them changes anything.
+pub fn optimize(function: &mut Function) {
+    loop {
+        let mut changed = fold_constants(function);
+        changed |= simplify_branches(function);
+        changed |= thread_jumps(function);
+        changed |= remove_unreachable(function);
+        changed |= merge_blocks(function);
+        changed |= remove_dead_instrs(function);
+        if !changed {
+            break;
+        }
+    }
+}
+
+fn fold_binary(op: BinOp, lhs: i16, rhs: i16) -> Option<i16> {
+    let truth = |b: bool| if b { -1 } else { 0 };
+    Some(match op {
+        BinOp::Add => lhs.wrapping_add(rhs),
+        BinOp::Sub => lhs.wrapping_sub(rhs),
+        BinOp::Mul => lhs.wrapping_mul(rhs),
+        // leave division by zero to Math.divide, it reports the error at run time
+        BinOp::Div => lhs.checked_div(rhs)?,
+        BinOp::And => lhs & rhs,
+        BinOp::Or => lhs | rhs,
+        BinOp::Lt => truth(lhs < rhs),
+        BinOp::Gt => truth(lhs > rhs),
+        BinOp::Eq => truth(lhs == rhs),
+    })
+}
+
+/// `x op c` that is just `x`.
+fn is_identity(op: BinOp, rhs: i16) -> bool {
+    matches!(
+        (op, rhs),
+        (BinOp::Add | BinOp::Sub | BinOp::Or, 0) | (BinOp::Mul | BinOp::Div, 1) | (BinOp::And, -1)
+    )
+}
+
+/// Operands of `instr` in evaluation order, mutably.
+fn operands_mut(instr: &mut Instr) -> Vec<&mut Operand> {
+    match instr {
+        Instr::Load { .. } | Instr::StringConst { .. } => vec![],
+        Instr::Store { src, .. } | Instr::Unary { src, .. } => vec![src],
+        Instr::Binary { lhs, rhs, .. } => vec![lhs, rhs],
+        Instr::LoadIndirect { addr, .. } => vec![addr],
+        Instr::StoreIndirect { addr, src } => vec![addr, src],
+        Instr::Call { args, .. } => args.iter_mut().collect(),
+    }
+}
+
+fn terminator_operands_mut(terminator: &mut Terminator) -> Vec<&mut Operand> {
+    match terminator {
+        Terminator::Jump(_) => vec![],
+        Terminator::Branch { cond, .. } => vec![cond],
+        Terminator::Return(value) => vec![value],
+    }
+}
+
+/// Evaluate arithmetic on constants, drop identities such as `x + 0`, and
+/// move constants to the right of commutative operators so later passes
+/// only have to look in one place.
+fn fold_constants(function: &mut Function) -> bool {
+    let mut replaced: HashMap<Temp, Operand> = HashMap::new();
+    let mut changed = false;
+    for block in &mut function.blocks {
+        let mut kept = vec![];
+        let mut kept_lines = vec![];
+        let lines = std::mem::take(&mut block.lines);
+        for (mut instr, line) in block.instrs.drain(..).zip(lines) {
+            for operand in operands_mut(&mut instr) {
+                if let Operand::Temp(temp) = operand {
+                    if let Some(value) = replaced.get(temp) {
+                        *operand = *value;
+                    }
+                }
+            }
+            match &mut instr {
+                Instr::Binary { dst, op, lhs, rhs } => {
+                    if let (Operand::Const(l), Operand::Temp(_)) = (*lhs, *rhs) {
+                        let swapped = match op {
+                            BinOp::Add | BinOp::Mul | BinOp::And | BinOp::Or | BinOp::Eq => {
+                                Some(*op)
+                            }
+                            BinOp::Lt => Some(BinOp::Gt),
+                            BinOp::Gt => Some(BinOp::Lt),
+                            BinOp::Sub | BinOp::Div => None,
+                        };
+                        if let Some(swapped) = swapped {
+                            *op = swapped;
+                            *lhs = *rhs;
+                            *rhs = Operand::Const(l);
+                            changed = true;
+                        }
+                    }
+                    match (*lhs, *rhs) {
+                        (Operand::Const(l), Operand::Const(r)) => {
+                            if let Some(value) = fold_binary(*op, l, r) {
+                                replaced.insert(*dst, Operand::Const(value));
+                                changed = true;
+                                continue;
+                            }
+                        }
+                        (value, Operand::Const(r)) if is_identity(*op, r) => {
+                            replaced.insert(*dst, value);
+                            changed = true;
+                            continue;
+                        }
+                        _ => {}
+                    }
+                }
+                Instr::Unary {
+                    dst,
+                    op,
+                    src: Operand::Const(value),
+                } => {
+                    let value = match op {
+                        UnOp::Neg => value.wrapping_neg(),
+                        UnOp::Not => !*value,
+                    };
+                    replaced.insert(*dst, Operand::Const(value));
+                    changed = true;
+                    continue;
+                }
+                _ => {}
+            }
+            kept.push(instr);
+            kept_lines.push(line);
+        }
+        block.instrs = kept;
+        block.lines = kept_lines;
+        for operand in terminator_operands_mut(&mut block.terminator) {
+            if let Operand::Temp(temp) = operand {
+                if let Some(value) = replaced.get(temp) {
+                    *operand = *value;
+                }
+            }
+        }
+    }
+    changed
+}
+
+/// Branches on constants, like `if (false)` or `while (true)`, and branches
+/// whose targets agree become plain jumps.
+fn simplify_branches(function: &mut Function) -> bool {
+    let mut changed = false;
+    for block in &mut function.blocks {
+        if let Terminator::Branch {
+            cond,
+            then_block,
+            else_block,
+        } = block.terminator
+        {
+            let target = match cond {
+                Operand::Const(0) => else_block,
+                Operand::Const(_) => then_block,
+                Operand::Temp(_) if then_block == else_block => then_block,
+                Operand::Temp(_) => continue,
+            };
+            block.terminator = Terminator::Jump(target);
+            changed = true;
+        }
+    }
+    changed
+}
+
+/// Follow chains of empty blocks that only jump elsewhere.
+fn thread_jumps(function: &mut Function) -> bool {
+    let forward = |mut target: BlockId| {
+        let mut seen = vec![target];
+        while let Some(BasicBlock {
+            instrs,
+            terminator: Terminator::Jump(next),
+            ..
+        }) = function.blocks.get(target.0)
+        {
+            if !instrs.is_empty() || seen.contains(next) {
+                break;
+            }
+            target = *next;
+            seen.push(target);
+        }
+        target
+    };
+    let threaded: Vec<Terminator> = function
+        .blocks
+        .iter()
+        .map(|block| match &block.terminator {
+            Terminator::Jump(target) => Terminator::Jump(forward(*target)),
+            Terminator::Branch {
+                cond,
+                then_block,
+                else_block,
+            } => Terminator::Branch {
+                cond: *cond,
+                then_block: forward(*then_block),
+                else_block: forward(*else_block),
+            },
+            Terminator::Return(value) => Terminator::Return(*value),
+        })
+        .collect();
+    let mut changed = false;
+    for (block, terminator) in function.blocks.iter_mut().zip(threaded) {
+        if block.terminator != terminator {
+            block.terminator = terminator;
+            changed = true;
+        }
+    }
+    changed
+}
+
+fn renumber(terminator: &mut Terminator, new_ids: &[Option<BlockId>]) {
+    match terminator {
+        Terminator::Jump(target) => *target = new_ids[target.0].unwrap(),
+        Terminator::Branch {
+            then_block,
+            else_block,
+            ..
+        } => {
+            *then_block = new_ids[then_block.0].unwrap();
+            *else_block = new_ids[else_block.0].unwrap();
+        }
+        Terminator::Return(_) => {}
+    }
+}
+
+/// Drop blocks the entry can't reach, such as code after `return`.
+fn remove_unreachable(function: &mut Function) -> bool {
+    let mut reachable = vec![false; function.blocks.len()];
+    let mut work = vec![BlockId(0)];
+    while let Some(id) = work.pop() {
+        if reachable[id.0] {
+            continue;
+        }
+        reachable[id.0] = true;
+        work.extend(function.blocks[id.0].terminator.successors());
+    }
+    if reachable.iter().all(|r| *r) {
+        return false;
+    }
+    let mut new_ids = vec![None; function.blocks.len()];
+    let mut next = 0;
+    for (id, keep) in reachable.iter().enumerate() {
+        if *keep {
+            new_ids[id] = Some(BlockId(next));
+            next += 1;
+        }
+    }
+    let blocks = std::mem::take(&mut function.blocks);
+    function.blocks = blocks
+        .into_iter()
+        .zip(reachable)
+        .filter_map(|(block, keep)| keep.then_some(block))
+        .collect();
+    for block in &mut function.blocks {
+        renumber(&mut block.terminator, &new_ids);
+    }
+    true
+}
+
+/// Append a block to its only predecessor when that predecessor jumps to it
+/// unconditionally. The emptied block becomes unreachable.
+fn merge_blocks(function: &mut Function) -> bool {
+    let mut predecessors = vec![0; function.blocks.len()];
+    for block in &function.blocks {
+        for target in block.terminator.successors() {
+            predecessors[target.0] += 1;
+        }
+    }
+    let mut changed = false;
+    for id in 0..function.blocks.len() {
+        while let Terminator::Jump(target) = function.blocks[id].terminator {
+            if target.0 == id || target.0 == 0 || predecessors[target.0] != 1 {
+                break;
+            }
+            let mut merged = std::mem::replace(
+                &mut function.blocks[target.0],
+                BasicBlock::new(vec![], Terminator::Jump(target)),
+            );
+            predecessors[target.0] = 0;
+            let block = &mut function.blocks[id];
+            block.instrs.append(&mut merged.instrs);
+            block.lines.append(&mut merged.lines);
+            block.terminator = merged.terminator;
+            block.terminator_line = merged.terminator_line;
+            changed = true;
+        }
+    }
+    changed
+}
+
+/// Remove instructions whose value is never used and that have no side
+/// effects. Calls stay, the callee may print or write memory.
+fn remove_dead_instrs(function: &mut Function) -> bool {
+    let mut changed = false;
+    loop {
+        let mut used = vec![false; function.temps.len()];
+        for block in &function.blocks {
+            let operands = block
+                .instrs
+                .iter()
+                .flat_map(|instr| instr.operands())
+                .chain(block.terminator.operands());
+            for operand in operands {
+                if let Operand::Temp(temp) = operand {
+                    used[temp.0] = true;
+                }
+            }
+        }
+        let mut removed = false;
+        for block in &mut function.blocks {
+            let instrs = std::mem::take(&mut block.instrs);
+            let lines = std::mem::take(&mut block.lines);
+            for (instr, line) in instrs.into_iter().zip(lines) {
+                let dead = match &instr {
+                    Instr::Load { dst, .. }
+                    | Instr::Binary { dst, .. }
+                    | Instr::Unary { dst, .. }
+                    | Instr::LoadIndirect { dst, .. }
+                    | Instr::StringConst { dst, .. } => !used[dst.0],
+                    Instr::Store { .. } | Instr::StoreIndirect { .. } | Instr::Call { .. } => false,
+                };
+                removed |= dead;
+                if !dead {
+                    block.instrs.push(instr);
+                    block.lines.push(line);
+                }
+            }
+        }
+        if !removed {
+            return changed;
+        }
+        changed = true;
+    }
+}
+
+fn power_of_two(command: &str) -> Option<u32> {
+    let value: u16 = command.strip_prefix("push constant ")?.parse().ok()?;
+    value.is_power_of_two().then(|| value.trailing_zeros())
+}
+
+/// Name of the helper `peephole` calls for division by a power of two.
+pub fn div_helper_name(class_name: &str) -> String {
+    format!("{}.div$pow2", class_name)
+}
+
+/// VM code of `class_name`'s division helper: `x / 2^k` truncating toward
+/// zero like `Math.divide`, by collecting the bits of `|x|` above `2^k`.
+pub fn div_helper(class_name: &str) -> Vec<String> {
+    let name = div_helper_name(class_name);
+    let code = [
+        format!("function {} 3", name),
+        // local 0 = bit being tested, local 1 = its weight in the quotient,
+        // local 2 = quotient, argument 1 = whether x was negative
+        "push argument 1".to_string(),
+        "pop local 0".to_string(),
+        "push constant 1".to_string(),
+        "pop local 1".to_string(),
+        "push argument 0".to_string(),
+        "push constant 0".to_string(),
+        "lt".to_string(),
+        "pop argument 1".to_string(),
+        "push argument 1".to_string(),
+        "if-goto ABS".to_string(),
+        "goto LOOP".to_string(),
+        "label ABS".to_string(),
+        "push argument 0".to_string(),
+        "neg".to_string(),
+        "pop argument 0".to_string(),
+        "label LOOP".to_string(),
+        "push local 0".to_string(),
+        "if-goto BODY".to_string(),
+        "goto DONE".to_string(),
+        "label BODY".to_string(),
+        "push argument 0".to_string(),
+        "push local 0".to_string(),
+        "and".to_string(),
+        "if-goto SET".to_string(),
+        "goto NEXT".to_string(),
+        "label SET".to_string(),
+        "push local 2".to_string(),
+        "push local 1".to_string(),
+        "add".to_string(),
+        "pop local 2".to_string(),
+        "label NEXT".to_string(),
+        "push local 0".to_string(),
+        "push local 0".to_string(),
+        "add".to_string(),
+        "pop local 0".to_string(),
+        "push local 1".to_string(),
+        "push local 1".to_string(),
+        "add".to_string(),
+        "pop local 1".to_string(),
+        "goto LOOP".to_string(),
+        "label DONE".to_string(),
+        "push local 2".to_string(),
+        "push argument 1".to_string(),
+        "if-goto NEGATE".to_string(),
+        "return".to_string(),
+        "label NEGATE".to_string(),
+        "neg".to_string(),
+        "return".to_string(),
+    ];
+    code.to_vec()
+}
+
+/// How many values a VM command pops and pushes, for the commands an
+/// expression is made of.
+fn stack_effect(command: &str) -> Option<(usize, usize)> {
+    match command {
+        "add" | "sub" | "and" | "or" | "eq" | "gt" | "lt" => Some((2, 1)),
+        "neg" | "not" => Some((1, 1)),
+        _ if command.starts_with("push ") => Some((0, 1)),
+        _ => {
+            let args = command.strip_prefix("call ")?.rsplit_once(' ')?.1;
+            Some((args.parse().ok()?, 1))
+        }
+    }
+}
+
+/// Where the expression whose value `commands[..end]` leaves on top of the
+/// stack starts.
+fn operand_start(commands: &[MappedCommand], end: usize) -> Option<usize> {
+    let mut needed = 1;
+    for start in (0..end).rev() {
+        let (pops, pushes) = stack_effect(&commands[start].0)?;
+        needed = needed - pushes + pops;
+        if needed == 0 {
+            return Some(start);
+        }
+    }
+    None
+}
+
+/// Whether the value `commands[..end]` leaves on top of the stack is known
+/// to be 0 or -1, so that `not` negates it as a condition too.
+fn is_boolean(commands: &[MappedCommand], end: usize) -> bool {
+    let Some(last) = end.checked_sub(1) else {
+        return false;
+    };
+    match commands[last].0.as_str() {
+        "eq" | "gt" | "lt" | "push constant 0" => true,
+        "not" => is_boolean(commands, last),
+        "and" | "or" => {
+            is_boolean(commands, last)
+                && operand_start(commands, last).is_some_and(|start| is_boolean(commands, start))
+        }
+        _ => false,
+    }
+}
+
+/// Rewrite the VM code of one class: multiplication by `2^k` becomes `k`
+/// doublings, division by `2^k` calls the class' shift helper (appended when
+/// used), `push x` directly followed by `pop x` disappears, and
+/// `if-goto A; goto B; label A` becomes `not; if-goto B; label A` so that
+/// double negations cancel out. That last one only applies to conditions
+/// known to be 0 or -1, as `not 5` is as true as 5.
+pub fn peephole(class_name: &str, commands: Vec<MappedCommand>) -> Vec<MappedCommand> {
+    let mut commands = commands;
+    for i in 2..commands.len() {
+        let (Some(then_label), Some(else_label), Some(next_label)) = (
+            commands[i - 2].0.strip_prefix("if-goto "),
+            commands[i - 1].0.strip_prefix("goto "),
+            commands[i].0.strip_prefix("label "),
+        ) else {
+            continue;
+        };
+        if then_label == next_label && is_boolean(&commands, i - 2) {
+            let else_label = else_label.to_string();
+            commands[i - 2].0 = "not".to_string();
+            commands[i - 1].0 = format!("if-goto {}", else_label);
+        }
+    }
+    let mut out: Vec<MappedCommand> = vec![];
+    let mut uses_helper = false;
+    for (command, line) in commands {
+        let previous = out.last().map(|(previous, _)| previous.clone());
+        if let Some(previous) = previous {
+            let k = power_of_two(&previous);
+            match (command.as_str(), k) {
+                ("call Math.multiply 2", Some(k)) => {
+                    out.pop();
+                    let mut k = k;
+                    // a multiplicand that is a plain push can be pushed again
+                    let repeated = out
+                        .last()
+                        .filter(|(last, _)| last.starts_with("push "))
+                        .cloned();
+                    if let (Some((push, _)), true) = (repeated, k > 0) {
+                        out.push((push, line));
+                        out.push(("add".to_string(), line));
+                        k -= 1;
+                    }
+                    for _ in 0..k {
+                        for text in ["pop temp 1", "push temp 1", "push temp 1", "add"] {
+                            out.push((text.to_string(), line));
+                        }
+                    }
+                    continue;
+                }
+                ("call Math.divide 2", Some(0)) => {
+                    out.pop();
+                    continue;
+                }
+                ("call Math.divide 2", Some(_)) => {
+                    uses_helper = true;
+                    out.push((format!("call {} 2", div_helper_name(class_name)), line));
+                    continue;
+                }
+                _ => {}
+            }
+            if previous == "not" && command == "not" {
+                out.pop();
+                continue;
+            }
+            if let (Some(pushed), Some(popped)) =
+                (previous.strip_prefix("push "), command.strip_prefix("pop "))
+            {
+                if pushed == popped {
+                    out.pop();
+                    continue;
+                }
+            }
+        }
+        out.push((command, line));
+    }
+    if uses_helper {
+        out.extend(
+            div_helper(class_name)
+                .into_iter()
+                .map(|command| (command, 0)),
+        );
+    }
+    out
+}
+
+/// Number of VM commands that execute, labels don't count.
+pub fn instruction_count(commands: &[MappedCommand]) -> usize {
+    commands
+        .iter()
+        .filter(|(command, _)| !command.starts_with("label ") && !command.starts_with("function "))
+        .count()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ir::{verify, FunctionKind, Place, Ty};
+    use crate::vm::{function_to_vm, join_lines};
+    use crate::vm_emulator::{Program, VmEmulator};
+
+    fn function(blocks: Vec<BasicBlock>, temps: Vec<Ty>) -> Function {
+        Function {
+            name: "Main.f".to_string(),
+            kind: FunctionKind::Function,
+            args: 1,
+            locals: 1,
+            fields: 0,
+            statics: 0,
+            temps,
+            blocks,
+            line: 0,
+        }
+    }
+
+    #[test]
+    fn folds_constants_and_dead_branches() {
+        // if (false) { let x = 1; } return (2 * 3) + x;
+        let mut f = function(
+            vec![
+                BasicBlock::new(
+                    vec![],
+                    Terminator::Branch {
+                        cond: Operand::Const(0),
+                        then_block: BlockId(1),
+                        else_block: BlockId(2),
+                    },
+                ),
+                BasicBlock::new(
+                    vec![Instr::Store {
+                        place: Place::Local(0),
+                        src: Operand::Const(1),
+                    }],
+                    Terminator::Jump(BlockId(2)),
+                ),
+                BasicBlock::new(
+                    vec![
+                        Instr::Binary {
+                            dst: Temp(0),
+                            op: BinOp::Mul,
+                            lhs: Operand::Const(2),
+                            rhs: Operand::Const(3),
+                        },
+                        Instr::Load {
+                            dst: Temp(1),
+                            place: Place::Local(0),
+                        },
+                        Instr::Binary {
+                            dst: Temp(2),
+                            op: BinOp::Add,
+                            lhs: Operand::Temp(Temp(0)),
+                            rhs: Operand::Temp(Temp(1)),
+                        },
+                    ],
+                    Terminator::Return(Operand::Temp(Temp(2))),
+                ),
+            ],
+            vec![Ty::Int, Ty::Int, Ty::Int],
+        );
+        optimize(&mut f);
+        assert_eq!(verify(&f), Ok(()));
+        assert_eq!(
+            f.to_string(),
+            "\
+function Main.f args 1 locals 1
+  b0:
+    %1: int = load local 0
+    %2: int = add %1, 6
+    return %2
+"
+        );
+    }
+
+    #[test]
+    fn threads_jumps_through_empty_blocks() {
+        let mut f = function(
+            vec![
+                BasicBlock::new(
+                    vec![Instr::Load {
+                        dst: Temp(0),
+                        place: Place::Argument(0),
+                    }],
+                    Terminator::Branch {
+                        cond: Operand::Temp(Temp(0)),
+                        then_block: BlockId(1),
+                        else_block: BlockId(2),
+                    },
+                ),
+                BasicBlock::new(vec![], Terminator::Jump(BlockId(3))),
+                BasicBlock::new(
+                    vec![Instr::Store {
+                        place: Place::Local(0),
+                        src: Operand::Const(1),
+                    }],
+                    Terminator::Jump(BlockId(3)),
+                ),
+                BasicBlock::new(vec![], Terminator::Return(Operand::Const(0))),
+            ],
+            vec![Ty::Boolean],
+        );
+        optimize(&mut f);
+        assert_eq!(verify(&f), Ok(()));
+        assert_eq!(
+            function_to_vm(&f),
+            vec![
+                "function Main.f 1",
+                "push argument 0",
+                "if-goto b2",
+                "label b1",
+                "push constant 1",
+                "pop local 0",
+                "label b2",
+                "push constant 0",
+                "return"
+            ]
+        );
+    }
+
+    #[test]
+    fn strength_reduces_powers_of_two() {
+        let lines = [
+            "function Main.f 0",
+            "push argument 0",
+            "push constant 4",
+            "call Math.multiply 2",
+            "push constant 8",
+            "call Math.divide 2",
+            "push local 0",
+            "pop local 0",
+            "return",
+        ]
+        .iter()
+        .enumerate()
+        .map(|(i, command)| (command.to_string(), i as u32 + 1))
+        .collect();
+        let optimized = peephole("Main", lines);
+        // the doublings stay attributed to the multiplication
+        assert_eq!(optimized[3], ("add".to_string(), 4));
+        let optimized: Vec<&str> = optimized
+            .iter()
+            .map(|(command, _)| command.as_str())
+            .collect();
+        let double = ["pop temp 1", "push temp 1", "push temp 1", "add"];
+        let mut expected = vec![
+            "function Main.f 0",
+            "push argument 0",
+            "push argument 0",
+            "add",
+        ];
+        expected.extend(double);
+        expected.extend(["push constant 8", "call Main.div$pow2 2", "return"]);
+        assert_eq!(optimized[..expected.len()], expected);
+        assert_eq!(optimized[expected.len()], "function Main.div$pow2 3");
+    }
+
+    #[test]
+    fn negates_only_boolean_conditions() {
+        let mapped = |code: &str| -> Vec<MappedCommand> {
+            code.lines()
+                .map(|command| (command.trim().to_string(), 0))
+                .collect()
+        };
+        // if (x) { let s = 1; } else { let s = 2; } with x = 5
+        let branch = "if-goto THEN
+            goto ELSE
+            label THEN
+            push constant 1
+            pop static 0
+            goto END
+            label ELSE
+            push constant 2
+            pop static 0
+            label END
+            goto END";
+        let truthy = format!(
+            "function Main.main 1\npush constant 5\npop local 0\npush local 0\n{}",
+            branch
+        );
+        let optimized = peephole("Main", mapped(&truthy));
+        assert_eq!(optimized, mapped(&truthy));
+        let sources = vec![(
+            "Main".to_string(),
+            join_lines(
+                &optimized
+                    .into_iter()
+                    .map(|(command, _)| command)
+                    .collect::<Vec<_>>(),
+            ),
+        )];
+        let mut emulator = VmEmulator::new(Program::load(&sources).unwrap()).unwrap();
+        emulator.run(20).unwrap();
+        assert_eq!(emulator.read(16), 1);
+
+        // ~(x = 0) & (x < 9) is 0 or -1, so the double negation goes
+        let boolean = format!(
+            "function Main.main 1\npush local 0\npush constant 0\neq\nnot\n\
+             push local 0\npush constant 9\nlt\nand\nnot\n{}",
+            branch
+        );
+        let optimized: Vec<String> = peephole("Main", mapped(&boolean))
+            .into_iter()
+            .map(|(command, _)| command)
+            .collect();
+        assert_eq!(optimized[8..11], ["and", "if-goto ELSE", "label THEN"]);
+    }
+}
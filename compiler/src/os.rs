@@ -0,0 +1,1054 @@
+// native implementation of the Jack OS for the VM emulator
+//
+// Each of the eight OS classes can run natively or as VM code, so a class of
+// our own can be tested with the native versions standing in for the rest.
+// Natives only touch memory the way the Jack OS does: the heap is a free list
+// kept in RAM[2048..16384], strings and arrays are heap blocks, and text and
+// shapes are drawn into the screen map. Calls from one class into another
+// (say `Array.new` into `Memory.alloc`) go through `call_function`, so they
+// reach VM code when that class isn't native.
+//
+// Functions that wait, `Keyboard.read*` and `Sys.wait`, block: the emulator
+// runs their `call` again every cycle until they are done, so keyboard
+// scripts and step limits keep working while a program waits.
+
+use crate::keyboard::KEYBOARD;
+use crate::screen::{HEIGHT, SCREEN, WIDTH};
+use crate::vm_emulator::{Program, RunError, State, VmEmulator};
+
+pub const CLASSES: [&str; 8] = [
+    "Math", "Memory", "Screen", "Output", "String", "Array", "Keyboard", "Sys",
+];
+
+/// Every native function and its argument count, `this` included.
+const FUNCTIONS: [(&str, usize); 48] = [
+    ("Math.init", 0),
+    ("Math.abs", 1),
+    ("Math.multiply", 2),
+    ("Math.divide", 2),
+    ("Math.min", 2),
+    ("Math.max", 2),
+    ("Math.sqrt", 1),
+    ("Memory.init", 0),
+    ("Memory.peek", 1),
+    ("Memory.poke", 2),
+    ("Memory.alloc", 1),
+    ("Memory.deAlloc", 1),
+    ("Array.new", 1),
+    ("Array.dispose", 1),
+    ("String.new", 1),
+    ("String.dispose", 1),
+    ("String.length", 1),
+    ("String.charAt", 2),
+    ("String.setCharAt", 3),
+    ("String.appendChar", 2),
+    ("String.eraseLastChar", 1),
+    ("String.intValue", 1),
+    ("String.setInt", 2),
+    ("String.backSpace", 0),
+    ("String.doubleQuote", 0),
+    ("String.newLine", 0),
+    ("Output.init", 0),
+    ("Output.moveCursor", 2),
+    ("Output.printChar", 1),
+    ("Output.printString", 1),
+    ("Output.printInt", 1),
+    ("Output.println", 0),
+    ("Output.backSpace", 0),
+    ("Screen.init", 0),
+    ("Screen.clearScreen", 0),
+    ("Screen.setColor", 1),
+    ("Screen.drawPixel", 2),
+    ("Screen.drawLine", 4),
+    ("Screen.drawRectangle", 4),
+    ("Screen.drawCircle", 3),
+    ("Keyboard.init", 0),
+    ("Keyboard.keyPressed", 0),
+    ("Keyboard.readChar", 0),
+    ("Keyboard.readLine", 1),
+    ("Keyboard.readInt", 1),
+    ("Sys.halt", 0),
+    ("Sys.error", 1),
+    ("Sys.wait", 1),
+];
+
+pub const HEAP_BASE: usize = 2048;
+pub const HEAP_END: usize = SCREEN;
+/// Cycles `Sys.wait` spends per millisecond.
+pub const CYCLES_PER_MS: u64 = 100;
+/// Capacity of the strings `Keyboard.readLine` returns, unless longer.
+const LINE_CAPACITY: usize = 64;
+pub const ROWS: usize = 23;
+pub const COLUMNS: usize = 64;
+const NEWLINE: i16 = 128;
+const BACKSPACE: i16 = 129;
+
+/// Error codes of `Sys.error`, as used by the Jack OS.
+mod code {
+    pub const WAIT_DURATION: i16 = 1;
+    pub const ARRAY_SIZE: i16 = 2;
+    pub const DIVIDE_BY_ZERO: i16 = 3;
+    pub const SQRT_NEGATIVE: i16 = 4;
+    pub const ALLOC_SIZE: i16 = 5;
+    pub const HEAP_OVERFLOW: i16 = 6;
+    pub const PIXEL: i16 = 7;
+    pub const LINE: i16 = 8;
+    pub const RECTANGLE: i16 = 9;
+    pub const CIRCLE_CENTER: i16 = 12;
+    pub const CIRCLE_RADIUS: i16 = 13;
+    pub const STRING_LENGTH: i16 = 14;
+    pub const CHAR_AT: i16 = 15;
+    pub const SET_CHAR_AT: i16 = 16;
+    pub const STRING_FULL: i16 = 17;
+    pub const STRING_EMPTY: i16 = 18;
+    pub const SET_INT: i16 = 19;
+    pub const CURSOR: i16 = 20;
+}
+
+/// What a native call did.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Native {
+    Return(i16),
+    /// Not done yet, call again on the next cycle.
+    Block,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum KeyPhase {
+    /// The cursor isn't drawn yet.
+    Start,
+    WaitPress,
+    WaitRelease(i16),
+}
+
+/// A `Keyboard.read*` in progress.
+#[derive(Debug, Clone, PartialEq)]
+struct Reading {
+    phase: KeyPhase,
+    line: Vec<i16>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct NativeOs {
+    classes: Vec<String>,
+    /// Head of the heap free list, 0 when empty.
+    free_list: usize,
+    row: usize,
+    column: usize,
+    /// Draw in black.
+    color: bool,
+    reading: Option<Reading>,
+    wait_until: Option<u64>,
+    /// Code of the `Sys.error` that stopped the program.
+    pub error: Option<i16>,
+}
+
+impl NativeOs {
+    /// Run `classes` natively; unknown names are ignored.
+    pub fn new(classes: &[&str]) -> NativeOs {
+        NativeOs {
+            classes: classes
+                .iter()
+                .filter(|class| CLASSES.contains(class))
+                .map(|class| class.to_string())
+                .collect(),
+            color: true,
+            ..NativeOs::default()
+        }
+    }
+
+    /// Native classes for `program` from a `--native` value: `all`, `none`
+    /// or a comma separated list. Without one, every OS class the program
+    /// doesn't define itself is native.
+    pub fn for_program(program: &Program, spec: Option<&str>) -> Result<NativeOs, String> {
+        let classes: Vec<&str> = match spec {
+            None => CLASSES
+                .into_iter()
+                .filter(|class| !program.files.iter().any(|file| file.name == *class))
+                .collect(),
+            Some("all") => CLASSES.to_vec(),
+            Some("none") => vec![],
+            Some(list) => {
+                let classes: Vec<&str> = list.split(',').map(str::trim).collect();
+                if let Some(unknown) = classes.iter().find(|class| !CLASSES.contains(class)) {
+                    return Err(format!("{} is not an OS class", unknown));
+                }
+                classes
+            }
+        };
+        Ok(NativeOs::new(&classes))
+    }
+
+    pub fn has_class(&self, class: &str) -> bool {
+        self.classes.iter().any(|native| native == class)
+    }
+
+    pub fn provides(&self, function: &str) -> bool {
+        let class = function.split('.').next().unwrap_or("");
+        self.has_class(class) && FUNCTIONS.iter().any(|(name, _)| *name == function)
+    }
+}
+
+/// 8x11 glyphs of the printable characters, one byte per row with bit 0 the
+/// leftmost pixel, as in `Output.initMap`. Index 0 is the black square shown
+/// for characters without a glyph, 1 onwards are ' ' to '~'.
+#[rustfmt::skip]
+const FONT: [[u8; 11]; 96] = [
+    [63, 63, 63, 63, 63, 63, 63, 63, 63,  0,  0], // black square
+    [ 0,  0,  0,  0,  0,  0,  0,  0,  0,  0,  0], //  
+    [12, 30, 30, 30, 12, 12,  0, 12, 12,  0,  0], // !
+    [54, 54, 20,  0,  0,  0,  0,  0,  0,  0,  0], // "
+    [ 0, 18, 18, 63, 18, 18, 63, 18, 18,  0,  0], // #
+    [12, 30, 51,  3, 30, 48, 51, 30, 12, 12,  0], // $
+    [ 0,  0, 35, 51, 24, 12,  6, 51, 49,  0,  0], // %
+    [12, 30, 30, 12, 54, 27, 27, 27, 54,  0,  0], // &
+    [12, 12,  6,  0,  0,  0,  0,  0,  0,  0,  0], // '
+    [24, 12,  6,  6,  6,  6,  6, 12, 24,  0,  0], // (
+    [ 6, 12, 24, 24, 24, 24, 24, 12,  6,  0,  0], // )
+    [ 0,  0,  0, 51, 30, 63, 30, 51,  0,  0,  0], // *
+    [ 0,  0,  0, 12, 12, 63, 12, 12,  0,  0,  0], // +
+    [ 0,  0,  0,  0,  0,  0,  0, 12, 12,  6,  0], // ,
+    [ 0,  0,  0,  0,  0, 63,  0,  0,  0,  0,  0], // -
+    [ 0,  0,  0,  0,  0,  0,  0, 12, 12,  0,  0], // .
+    [ 0,  0, 32, 48, 24, 12,  6,  3,  1,  0,  0], // /
+    [12, 30, 51, 51, 51, 51, 51, 30, 12,  0,  0], // 0
+    [12, 14, 15, 12, 12, 12, 12, 12, 63,  0,  0], // 1
+    [30, 51, 48, 24, 12,  6,  3, 51, 63,  0,  0], // 2
+    [30, 51, 48, 48, 28, 48, 48, 51, 30,  0,  0], // 3
+    [16, 24, 28, 26, 25, 63, 24, 24, 60,  0,  0], // 4
+    [63,  3,  3, 31, 48, 48, 48, 51, 30,  0,  0], // 5
+    [28,  6,  3,  3, 31, 51, 51, 51, 30,  0,  0], // 6
+    [63, 49, 48, 48, 24, 12, 12, 12, 12,  0,  0], // 7
+    [30, 51, 51, 51, 30, 51, 51, 51, 30,  0,  0], // 8
+    [30, 51, 51, 51, 62, 48, 48, 24, 14,  0,  0], // 9
+    [ 0,  0, 12, 12,  0,  0, 12, 12,  0,  0,  0], // :
+    [ 0,  0, 12, 12,  0,  0, 12, 12,  6,  0,  0], // ;
+    [ 0,  0, 24, 12,  6,  3,  6, 12, 24,  0,  0], // <
+    [ 0,  0,  0, 63,  0,  0, 63,  0,  0,  0,  0], // =
+    [ 0,  0,  3,  6, 12, 24, 12,  6,  3,  0,  0], // >
+    [30, 51, 51, 24, 12, 12,  0, 12, 12,  0,  0], // ?
+    [30, 51, 51, 59, 59, 59, 27,  3, 30,  0,  0], // @
+    [12, 30, 51, 51, 63, 51, 51, 51, 51,  0,  0], // A
+    [31, 51, 51, 51, 31, 51, 51, 51, 31,  0,  0], // B
+    [28, 54, 35,  3,  3,  3, 35, 54, 28,  0,  0], // C
+    [15, 27, 51, 51, 51, 51, 51, 27, 15,  0,  0], // D
+    [63, 51, 35, 11, 15, 11, 35, 51, 63,  0,  0], // E
+    [63, 51, 35, 11, 15, 11,  3,  3,  3,  0,  0], // F
+    [28, 54, 35,  3, 59, 51, 51, 54, 44,  0,  0], // G
+    [51, 51, 51, 51, 63, 51, 51, 51, 51,  0,  0], // H
+    [30, 12, 12, 12, 12, 12, 12, 12, 30,  0,  0], // I
+    [60, 24, 24, 24, 24, 24, 27, 27, 14,  0,  0], // J
+    [51, 51, 51, 27, 15, 27, 51, 51, 51,  0,  0], // K
+    [ 3,  3,  3,  3,  3,  3, 35, 51, 63,  0,  0], // L
+    [33, 51, 63, 63, 51, 51, 51, 51, 51,  0,  0], // M
+    [51, 51, 55, 55, 63, 59, 59, 51, 51,  0,  0], // N
+    [30, 51, 51, 51, 51, 51, 51, 51, 30,  0,  0], // O
+    [31, 51, 51, 51, 31,  3,  3,  3,  3,  0,  0], // P
+    [30, 51, 51, 51, 51, 51, 63, 59, 30, 48,  0], // Q
+    [31, 51, 51, 51, 31, 27, 51, 51, 51,  0,  0], // R
+    [30, 51, 51,  6, 28, 48, 51, 51, 30,  0,  0], // S
+    [63, 63, 45, 12, 12, 12, 12, 12, 30,  0,  0], // T
+    [51, 51, 51, 51, 51, 51, 51, 51, 30,  0,  0], // U
+    [51, 51, 51, 51, 51, 30, 30, 12, 12,  0,  0], // V
+    [51, 51, 51, 51, 51, 63, 63, 63, 18,  0,  0], // W
+    [51, 51, 30, 30, 12, 30, 30, 51, 51,  0,  0], // X
+    [51, 51, 51, 51, 30, 12, 12, 12, 30,  0,  0], // Y
+    [63, 51, 49, 24, 12,  6, 35, 51, 63,  0,  0], // Z
+    [30,  6,  6,  6,  6,  6,  6,  6, 30,  0,  0], // [
+    [ 0,  0,  1,  3,  6, 12, 24, 48, 32,  0,  0], // \
+    [30, 24, 24, 24, 24, 24, 24, 24, 30,  0,  0], // ]
+    [ 8, 28, 54,  0,  0,  0,  0,  0,  0,  0,  0], // ^
+    [ 0,  0,  0,  0,  0,  0,  0,  0,  0, 63,  0], // _
+    [ 6, 12, 24,  0,  0,  0,  0,  0,  0,  0,  0], // `
+    [ 0,  0,  0, 14, 24, 30, 27, 27, 54,  0,  0], // a
+    [ 3,  3,  3, 15, 27, 51, 51, 51, 30,  0,  0], // b
+    [ 0,  0,  0, 30, 51,  3,  3, 51, 30,  0,  0], // c
+    [48, 48, 48, 60, 54, 51, 51, 51, 30,  0,  0], // d
+    [ 0,  0,  0, 30, 51, 63,  3, 51, 30,  0,  0], // e
+    [28, 54, 38,  6, 15,  6,  6,  6, 15,  0,  0], // f
+    [ 0,  0, 30, 51, 51, 51, 62, 48, 51, 30,  0], // g
+    [ 3,  3,  3, 27, 55, 51, 51, 51, 51,  0,  0], // h
+    [12, 12,  0, 14, 12, 12, 12, 12, 30,  0,  0], // i
+    [48, 48,  0, 56, 48, 48, 48, 48, 51, 30,  0], // j
+    [ 3,  3,  3, 51, 27, 15, 15, 27, 51,  0,  0], // k
+    [14, 12, 12, 12, 12, 12, 12, 12, 30,  0,  0], // l
+    [ 0,  0,  0, 29, 63, 43, 43, 43, 43,  0,  0], // m
+    [ 0,  0,  0, 29, 51, 51, 51, 51, 51,  0,  0], // n
+    [ 0,  0,  0, 30, 51, 51, 51, 51, 30,  0,  0], // o
+    [ 0,  0,  0, 30, 51, 51, 51, 31,  3,  3,  0], // p
+    [ 0,  0,  0, 30, 51, 51, 51, 62, 48, 48,  0], // q
+    [ 0,  0,  0, 29, 55, 51,  3,  3,  7,  0,  0], // r
+    [ 0,  0,  0, 30, 51,  6, 24, 51, 30,  0,  0], // s
+    [ 4,  6,  6, 15,  6,  6,  6, 54, 28,  0,  0], // t
+    [ 0,  0,  0, 27, 27, 27, 27, 27, 54,  0,  0], // u
+    [ 0,  0,  0, 51, 51, 51, 51, 30, 12,  0,  0], // v
+    [ 0,  0,  0, 51, 51, 51, 63, 63, 18,  0,  0], // w
+    [ 0,  0,  0, 51, 30, 12, 12, 30, 51,  0,  0], // x
+    [ 0,  0,  0, 51, 51, 51, 62, 48, 24, 15,  0], // y
+    [ 0,  0,  0, 63, 27, 12,  6, 51, 63,  0,  0], // z
+    [56, 12, 12, 12,  7, 12, 12, 12, 56,  0,  0], // {
+    [12, 12, 12, 12, 12, 12, 12, 12, 12,  0,  0], // |
+    [ 7, 12, 12, 12, 56, 12, 12, 12,  7,  0,  0], // }
+    [38, 45, 25,  0,  0,  0,  0,  0,  0,  0,  0], // ~
+];
+
+fn glyph(c: i16) -> &'static [u8; 11] {
+    match c {
+        32..=126 => &FONT[c as usize - 31],
+        _ => &FONT[0],
+    }
+}
+
+/// Largest `y` with `y * y <= x`, for `x >= 0`.
+fn isqrt(x: i32) -> i32 {
+    let mut y = 0;
+    while (y + 1) * (y + 1) <= x {
+        y += 1;
+    }
+    y
+}
+
+impl VmEmulator {
+    /// Set up the state of the native classes, as their `init` would.
+    pub(crate) fn init_native_classes(&mut self) {
+        if self.os.has_class("Memory") {
+            self.memory_init();
+        }
+        self.os.row = 0;
+        self.os.column = 0;
+        self.os.color = true;
+    }
+
+    pub(crate) fn call_native(&mut self, function: &str, args: &[i16]) -> Result<Native, RunError> {
+        let arity = FUNCTIONS
+            .iter()
+            .find(|(name, _)| *name == function)
+            .map(|(_, n)| *n);
+        if arity != Some(args.len()) {
+            return Err(self.error(format!(
+                "{} takes {} arguments, not {}",
+                function,
+                arity.unwrap_or(0),
+                args.len()
+            )));
+        }
+        let arg = |i: usize| args[i];
+        let done = |value: i16| Ok(Native::Return(value));
+        match function {
+            "Math.init" | "Keyboard.init" => done(0),
+            "Math.abs" => done(arg(0).wrapping_abs()),
+            "Math.multiply" => done(arg(0).wrapping_mul(arg(1))),
+            "Math.divide" if arg(1) == 0 => self.sys_error(code::DIVIDE_BY_ZERO),
+            "Math.divide" => done(arg(0).wrapping_div(arg(1))),
+            "Math.min" => done(arg(0).min(arg(1))),
+            "Math.max" => done(arg(0).max(arg(1))),
+            "Math.sqrt" if arg(0) < 0 => self.sys_error(code::SQRT_NEGATIVE),
+            "Math.sqrt" => done(isqrt(arg(0) as i32) as i16),
+
+            "Memory.init" => {
+                self.memory_init();
+                done(0)
+            }
+            "Memory.peek" => done(self.ram[self.address(arg(0) as i32)?]),
+            "Memory.poke" => {
+                let address = self.address(arg(0) as i32)?;
+                self.ram[address] = arg(1);
+                done(0)
+            }
+            "Memory.alloc" => self.alloc(arg(0)),
+            "Memory.deAlloc" => {
+                self.dealloc(arg(0))?;
+                done(0)
+            }
+
+            "Array.new" if arg(0) <= 0 => self.sys_error(code::ARRAY_SIZE),
+            "Array.new" => done(self.call_function("Memory.alloc", &[arg(0)])?),
+            "Array.dispose" => {
+                self.call_function("Memory.deAlloc", &[arg(0)])?;
+                done(0)
+            }
+
+            "String.new" => self.string_new(arg(0)),
+            "String.dispose" => {
+                let chars = self.field(arg(0), 2)?;
+                if chars != 0 {
+                    self.call_function("Memory.deAlloc", &[chars])?;
+                }
+                self.call_function("Memory.deAlloc", &[arg(0)])?;
+                done(0)
+            }
+            "String.length" => done(self.field(arg(0), 1)?),
+            "String.charAt" => match self.char_address(arg(0), arg(1))? {
+                Some(address) => done(self.ram[address]),
+                None => self.sys_error(code::CHAR_AT),
+            },
+            "String.setCharAt" => match self.char_address(arg(0), arg(1))? {
+                Some(address) => {
+                    self.ram[address] = arg(2);
+                    done(0)
+                }
+                None => self.sys_error(code::SET_CHAR_AT),
+            },
+            "String.appendChar" => {
+                let (capacity, length) = (self.field(arg(0), 0)?, self.field(arg(0), 1)?);
+                if length >= capacity {
+                    return self.sys_error(code::STRING_FULL);
+                }
+                let address = self.address(self.field(arg(0), 2)? as i32 + length as i32)?;
+                self.ram[address] = arg(1);
+                self.set_field(arg(0), 1, length + 1)?;
+                done(arg(0))
+            }
+            "String.eraseLastChar" => {
+                let length = self.field(arg(0), 1)?;
+                if length == 0 {
+                    return self.sys_error(code::STRING_EMPTY);
+                }
+                self.set_field(arg(0), 1, length - 1)?;
+                done(0)
+            }
+            "String.intValue" => {
+                let text = self.string_chars(arg(0))?;
+                let (negative, digits) = match text.first() {
+                    Some(&c) if c == b'-' as i16 => (true, &text[1..]),
+                    _ => (false, &text[..]),
+                };
+                let mut value: i16 = 0;
+                for &c in digits
+                    .iter()
+                    .take_while(|c| (b'0' as i16..=b'9' as i16).contains(c))
+                {
+                    value = value.wrapping_mul(10).wrapping_add(c - b'0' as i16);
+                }
+                done(if negative {
+                    value.wrapping_neg()
+                } else {
+                    value
+                })
+            }
+            "String.setInt" => {
+                let text = arg(1).to_string();
+                if text.len() > self.field(arg(0), 0)? as usize {
+                    return self.sys_error(code::SET_INT);
+                }
+                let chars = self.field(arg(0), 2)?;
+                for (i, c) in text.bytes().enumerate() {
+                    let address = self.address(chars as i32 + i as i32)?;
+                    self.ram[address] = c as i16;
+                }
+                self.set_field(arg(0), 1, text.len() as i16)?;
+                done(0)
+            }
+            "String.backSpace" => done(BACKSPACE),
+            "String.doubleQuote" => done(b'"' as i16),
+            "String.newLine" => done(NEWLINE),
+
+            "Output.init" => {
+                self.os.row = 0;
+                self.os.column = 0;
+                done(0)
+            }
+            "Output.moveCursor" => {
+                let (row, column) = (arg(0), arg(1));
+                if !(0..ROWS as i16).contains(&row) || !(0..COLUMNS as i16).contains(&column) {
+                    return self.sys_error(code::CURSOR);
+                }
+                self.os.row = row as usize;
+                self.os.column = column as usize;
+                self.draw_char(b' ' as i16);
+                done(0)
+            }
+            "Output.printChar" => {
+                self.print_char(arg(0));
+                done(0)
+            }
+            "Output.printString" => {
+                let length = self.call_function("String.length", &[arg(0)])?;
+                for i in 0..length {
+                    let c = self.call_function("String.charAt", &[arg(0), i])?;
+                    self.print_char(c);
+                }
+                done(0)
+            }
+            "Output.printInt" => {
+                self.print_text(&arg(0).to_string());
+                done(0)
+            }
+            "Output.println" => {
+                self.println();
+                done(0)
+            }
+            "Output.backSpace" => {
+                self.back_space();
+                done(0)
+            }
+
+            "Screen.init" => {
+                self.os.color = true;
+                done(0)
+            }
+            "Screen.clearScreen" => {
+                self.ram[SCREEN..SCREEN + WIDTH * HEIGHT / 16].fill(0);
+                done(0)
+            }
+            "Screen.setColor" => {
+                self.os.color = arg(0) != 0;
+                done(0)
+            }
+            "Screen.drawPixel" => {
+                let (x, y) = (arg(0) as i32, arg(1) as i32);
+                if !on_screen(x, y) {
+                    return self.sys_error(code::PIXEL);
+                }
+                self.draw_pixel(x, y);
+                done(0)
+            }
+            "Screen.drawLine" => {
+                let [x1, y1, x2, y2] = [0, 1, 2, 3].map(|i| arg(i) as i32);
+                if !on_screen(x1, y1) || !on_screen(x2, y2) {
+                    return self.sys_error(code::LINE);
+                }
+                self.draw_line(x1, y1, x2, y2);
+                done(0)
+            }
+            "Screen.drawRectangle" => {
+                let [x1, y1, x2, y2] = [0, 1, 2, 3].map(|i| arg(i) as i32);
+                if !on_screen(x1, y1) || !on_screen(x2, y2) || x1 > x2 || y1 > y2 {
+                    return self.sys_error(code::RECTANGLE);
+                }
+                for y in y1..=y2 {
+                    self.draw_row(x1, x2, y);
+                }
+                done(0)
+            }
+            "Screen.drawCircle" => {
+                let (x, y, r) = (arg(0) as i32, arg(1) as i32, arg(2) as i32);
+                if !on_screen(x, y) {
+                    return self.sys_error(code::CIRCLE_CENTER);
+                }
+                if !(0..=181).contains(&r) {
+                    return self.sys_error(code::CIRCLE_RADIUS);
+                }
+                for dy in -r..=r {
+                    let half = isqrt(r * r - dy * dy);
+                    if (0..HEIGHT as i32).contains(&(y + dy)) {
+                        self.draw_row((x - half).max(0), (x + half).min(WIDTH as i32 - 1), y + dy);
+                    }
+                }
+                done(0)
+            }
+
+            "Keyboard.keyPressed" => done(self.ram[KEYBOARD]),
+            "Keyboard.readChar" => Ok(match self.read_key()? {
+                Some(key) => {
+                    self.os.reading = None;
+                    self.echo(key);
+                    Native::Return(key)
+                }
+                None => Native::Block,
+            }),
+            "Keyboard.readLine" => self.read_line(arg(0)),
+            "Keyboard.readInt" => match self.read_line(arg(0))? {
+                Native::Return(line) => {
+                    let value = self.call_function("String.intValue", &[line])?;
+                    self.call_function("String.dispose", &[line])?;
+                    done(value)
+                }
+                Native::Block => Ok(Native::Block),
+            },
+
+            "Sys.halt" => {
+                self.state = State::Halted;
+                done(0)
+            }
+            "Sys.error" => self.sys_error(arg(0)),
+            "Sys.wait" if arg(0) < 0 => self.sys_error(code::WAIT_DURATION),
+            "Sys.wait" => {
+                let until = *self
+                    .os
+                    .wait_until
+                    .get_or_insert(self.steps + arg(0) as u64 * CYCLES_PER_MS);
+                if self.steps < until {
+                    return Ok(Native::Block);
+                }
+                self.os.wait_until = None;
+                done(0)
+            }
+            _ => unreachable!("{} is in FUNCTIONS", function),
+        }
+    }
+
+    /// Print `ERR<code>` and stop, as `Sys.error` does.
+    fn sys_error(&mut self, code: i16) -> Result<Native, RunError> {
+        self.print_text(&format!("ERR{}", code));
+        self.os.error = Some(code);
+        self.state = State::Halted;
+        Ok(Native::Return(0))
+    }
+
+    // Memory: a first-fit free list of segments `[length, next]`, where the
+    // length counts the whole segment, kept in address order so that a freed
+    // block merges with the free segments either side of it. Blocks are cut
+    // from the end of a segment and keep their length in the word before them.
+
+    fn memory_init(&mut self) {
+        self.os.free_list = HEAP_BASE;
+        self.ram[HEAP_BASE] = (HEAP_END - HEAP_BASE) as i16;
+        self.ram[HEAP_BASE + 1] = 0;
+    }
+
+    fn alloc(&mut self, size: i16) -> Result<Native, RunError> {
+        if size <= 0 {
+            return self.sys_error(code::ALLOC_SIZE);
+        }
+        let needed = size as usize + 1;
+        let mut previous = 0;
+        let mut segment = self.os.free_list;
+        while segment != 0 {
+            let length = self.ram[segment] as u16 as usize;
+            let next = self.ram[segment + 1] as u16 as usize;
+            // the rest must still hold a segment header
+            if length >= needed + 2 {
+                self.ram[segment] = (length - needed) as i16;
+                let block = segment + length - size as usize;
+                self.ram[block - 1] = needed as i16;
+                return Ok(Native::Return(block as i16));
+            }
+            // else the block gets the whole segment, a word over at most
+            if length >= needed {
+                self.set_next(previous, next);
+                return Ok(Native::Return(segment as i16 + 1));
+            }
+            previous = segment;
+            segment = next;
+        }
+        self.sys_error(code::HEAP_OVERFLOW)
+    }
+
+    fn dealloc(&mut self, block: i16) -> Result<(), RunError> {
+        let segment = self.address(block as i32 - 1)?;
+        if !(HEAP_BASE..HEAP_END).contains(&segment) {
+            return Err(self.error(format!("Memory.deAlloc of {}, outside the heap", block)));
+        }
+        let mut previous = 0;
+        let mut next = self.os.free_list;
+        while next != 0 && next <= segment {
+            if next == segment {
+                return Err(self.error(format!("Memory.deAlloc of {}, already free", block)));
+            }
+            previous = next;
+            next = self.ram[next + 1] as u16 as usize;
+        }
+        let mut length = self.ram[segment] as u16 as usize;
+        if segment + length == next {
+            length += self.ram[next] as u16 as usize;
+            next = self.ram[next + 1] as u16 as usize;
+        }
+        if previous != 0 && previous + self.ram[previous] as u16 as usize == segment {
+            self.ram[previous] += length as i16;
+            self.ram[previous + 1] = next as i16;
+            return Ok(());
+        }
+        self.ram[segment] = length as i16;
+        self.ram[segment + 1] = next as i16;
+        self.set_next(previous, segment);
+        Ok(())
+    }
+
+    /// Link `segment` after `previous`, or first when `previous` is 0.
+    fn set_next(&mut self, previous: usize, segment: usize) {
+        match previous {
+            0 => self.os.free_list = segment,
+            _ => self.ram[previous + 1] = segment as i16,
+        }
+    }
+
+    // String objects are `[capacity, length, chars]`, `chars` being an array
+    // of `capacity` words, or 0 when the capacity is 0.
+
+    fn field(&self, object: i16, index: i32) -> Result<i16, RunError> {
+        Ok(self.ram[self.address(object as i32 + index)?])
+    }
+
+    fn set_field(&mut self, object: i16, index: i32, value: i16) -> Result<(), RunError> {
+        let address = self.address(object as i32 + index)?;
+        self.ram[address] = value;
+        Ok(())
+    }
+
+    fn string_new(&mut self, capacity: i16) -> Result<Native, RunError> {
+        if capacity < 0 {
+            return self.sys_error(code::STRING_LENGTH);
+        }
+        let object = self.call_function("Memory.alloc", &[3])?;
+        let chars = if capacity > 0 {
+            self.call_function("Memory.alloc", &[capacity])?
+        } else {
+            0
+        };
+        if self.state == State::Halted {
+            return Ok(Native::Return(0));
+        }
+        self.set_field(object, 0, capacity)?;
+        self.set_field(object, 1, 0)?;
+        self.set_field(object, 2, chars)?;
+        Ok(Native::Return(object))
+    }
+
+    /// Address of character `index`, `None` past the end of the string.
+    fn char_address(&self, string: i16, index: i16) -> Result<Option<usize>, RunError> {
+        if !(0..self.field(string, 1)?).contains(&index) {
+            return Ok(None);
+        }
+        Ok(Some(
+            self.address(self.field(string, 2)? as i32 + index as i32)?,
+        ))
+    }
+
+    fn string_chars(&self, string: i16) -> Result<Vec<i16>, RunError> {
+        let chars = self.field(string, 2)? as i32;
+        (0..self.field(string, 1)? as i32)
+            .map(|i| Ok(self.ram[self.address(chars + i)?]))
+            .collect()
+    }
+
+    // Output: 23 rows of 64 characters, each 8 pixels wide and 11 high, so
+    // two characters share a screen word.
+
+    /// Draw `c` at the cursor without moving it.
+    fn draw_char(&mut self, c: i16) {
+        let glyph = glyph(c);
+        let (row, column) = (self.os.row, self.os.column);
+        let shift = if column % 2 == 0 { 0 } else { 8 };
+        for (line, bits) in glyph.iter().enumerate() {
+            let address = SCREEN + (row * 11 + line) * (WIDTH / 16) + column / 2;
+            let word = self.ram[address] as u16;
+            let word = word & !(0xFF << shift) | (*bits as u16) << shift;
+            self.ram[address] = word as i16;
+        }
+    }
+
+    fn print_char(&mut self, c: i16) {
+        self.draw_char(c);
+        self.os.column += 1;
+        if self.os.column == COLUMNS {
+            self.println();
+        }
+    }
+
+    fn print_text(&mut self, text: &str) {
+        for c in text.bytes() {
+            self.print_char(c as i16);
+        }
+    }
+
+    fn println(&mut self) {
+        self.os.column = 0;
+        self.os.row = (self.os.row + 1) % ROWS;
+    }
+
+    fn back_space(&mut self) {
+        if self.os.column > 0 {
+            self.os.column -= 1;
+        } else if self.os.row > 0 {
+            self.os.row -= 1;
+            self.os.column = COLUMNS - 1;
+        }
+    }
+
+    // Screen
+
+    fn draw_pixel(&mut self, x: i32, y: i32) {
+        let address = SCREEN + y as usize * (WIDTH / 16) + x as usize / 16;
+        let bit = 1 << (x % 16);
+        if self.os.color {
+            self.ram[address] |= bit;
+        } else {
+            self.ram[address] &= !bit;
+        }
+    }
+
+    fn draw_row(&mut self, x1: i32, x2: i32, y: i32) {
+        for x in x1..=x2 {
+            self.draw_pixel(x, y);
+        }
+    }
+
+    /// The line algorithm of the book: step towards the end point along
+    /// whichever axis keeps `a * dy - b * dx` closest to zero.
+    fn draw_line(&mut self, x1: i32, y1: i32, x2: i32, y2: i32) {
+        let (dx, dy) = ((x2 - x1).abs(), (y2 - y1).abs());
+        let (sx, sy) = ((x2 - x1).signum(), (y2 - y1).signum());
+        if dy == 0 {
+            return self.draw_row(x1.min(x2), x1.max(x2), y1);
+        }
+        if dx == 0 {
+            for y in y1.min(y2)..=y1.max(y2) {
+                self.draw_pixel(x1, y);
+            }
+            return;
+        }
+        let (mut a, mut b, mut diff) = (0, 0, 0);
+        while a <= dx && b <= dy {
+            self.draw_pixel(x1 + sx * a, y1 + sy * b);
+            if diff < 0 {
+                a += 1;
+                diff += dy;
+            } else {
+                b += 1;
+                diff -= dx;
+            }
+        }
+    }
+
+    // Keyboard
+
+    /// Advance the current read by a cycle: show the cursor, wait for a key
+    /// to go down and up again, then return it for the caller to echo.
+    fn read_key(&mut self) -> Result<Option<i16>, RunError> {
+        let reading = self.os.reading.get_or_insert(Reading {
+            phase: KeyPhase::Start,
+            line: vec![],
+        });
+        let phase = reading.phase;
+        let key = self.ram[KEYBOARD];
+        let (next, result) = match phase {
+            KeyPhase::Start => {
+                self.draw_char(0);
+                (KeyPhase::WaitPress, None)
+            }
+            KeyPhase::WaitPress if key != 0 => (KeyPhase::WaitRelease(key), None),
+            KeyPhase::WaitRelease(pressed) if key == 0 => (KeyPhase::Start, Some(pressed)),
+            _ => (phase, None),
+        };
+        if let Some(reading) = &mut self.os.reading {
+            reading.phase = next;
+        }
+        Ok(result)
+    }
+
+    /// Show `key` where the cursor was drawn.
+    fn echo(&mut self, key: i16) {
+        match key {
+            NEWLINE => {
+                self.draw_char(b' ' as i16);
+                self.println();
+            }
+            BACKSPACE => {
+                self.draw_char(b' ' as i16);
+                self.back_space();
+            }
+            _ => self.print_char(key),
+        }
+    }
+
+    /// `Keyboard.readLine`: print `message`, then read keys until a newline,
+    /// handling backspace, and return the line as a new string.
+    fn read_line(&mut self, message: i16) -> Result<Native, RunError> {
+        if self.os.reading.is_none() {
+            self.call_function("Output.printString", &[message])?;
+        }
+        let Some(key) = self.read_key()? else {
+            return Ok(Native::Block);
+        };
+        let line = &mut self.os.reading.as_mut().unwrap().line;
+        match key {
+            NEWLINE => {}
+            BACKSPACE if line.is_empty() => {
+                // nothing to erase, just hide the cursor
+                self.draw_char(b' ' as i16);
+                return Ok(Native::Block);
+            }
+            BACKSPACE => {
+                line.pop();
+                self.echo(key);
+                return Ok(Native::Block);
+            }
+            _ => {
+                line.push(key);
+                self.echo(key);
+                return Ok(Native::Block);
+            }
+        }
+        self.echo(key);
+        let line = self.os.reading.take().unwrap().line;
+        let capacity = line.len().max(LINE_CAPACITY) as i16;
+        let string = self.call_function("String.new", &[capacity])?;
+        for c in line {
+            self.call_function("String.appendChar", &[string, c])?;
+        }
+        Ok(Native::Return(string))
+    }
+}
+
+fn on_screen(x: i32, y: i32) -> bool {
+    (0..WIDTH as i32).contains(&x) && (0..HEIGHT as i32).contains(&y)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vm_emulator::STATIC;
+
+    fn emulator(sources: &[(&str, &str)], native: Option<&str>) -> VmEmulator {
+        let sources: Vec<(String, String)> = sources
+            .iter()
+            .map(|(name, text)| (name.to_string(), text.to_string()))
+            .collect();
+        let program = Program::load(&sources).unwrap();
+        let os = NativeOs::for_program(&program, native).unwrap();
+        VmEmulator::with_os(program, os).unwrap()
+    }
+
+    #[test]
+    fn allocates_from_the_end_of_the_heap() {
+        let mut emulator = emulator(
+            &[("Main", "function Main.main 0\npush constant 0\nreturn")],
+            None,
+        );
+        assert_eq!(emulator.call_function("Memory.alloc", &[3]), Ok(16381));
+        assert_eq!(emulator.read(16380), 4);
+        assert_eq!(emulator.read(HEAP_BASE), 14336 - 4);
+        assert_eq!(emulator.call_function("Array.new", &[10]), Ok(16370));
+        emulator.call_function("Memory.deAlloc", &[16381]).unwrap();
+        // the freed block follows the rest of the heap in the free list
+        assert_eq!(emulator.read(HEAP_BASE + 1), 16380);
+        assert_eq!(emulator.read(16381), 0);
+        assert_eq!(
+            emulator.call_function("Memory.deAlloc", &[16381]),
+            Err(RunError {
+                pc: emulator.pc,
+                message: "Memory.deAlloc of 16381, already free".to_string()
+            })
+        );
+        assert_eq!(emulator.call_function("Memory.alloc", &[2]), Ok(16367));
+        assert_eq!(emulator.call_function("Memory.alloc", &[0]), Ok(0));
+        assert_eq!(emulator.os.error, Some(code::ALLOC_SIZE));
+        assert_eq!(emulator.state, State::Halted);
+    }
+
+    #[test]
+    fn reuses_and_merges_freed_blocks() {
+        let mut emulator = emulator(
+            &[("Main", "function Main.main 0\npush constant 0\nreturn")],
+            None,
+        );
+        // what the real OS does forever
+        for _ in 0..20_000 {
+            let string = emulator.call_function("String.new", &[3]).unwrap();
+            emulator.call_function("String.dispose", &[string]).unwrap();
+        }
+        assert_eq!(emulator.os.error, None);
+        assert_eq!(emulator.read(HEAP_BASE), 14336);
+
+        // freeing the middle block last joins all three to the rest again
+        let blocks: Vec<i16> = [3, 10, 2]
+            .iter()
+            .map(|size| emulator.call_function("Memory.alloc", &[*size]).unwrap())
+            .collect();
+        assert_eq!(blocks, [16381, 16370, 16367]);
+        for block in [16381, 16367, 16370] {
+            emulator.call_function("Memory.deAlloc", &[block]).unwrap();
+        }
+        assert_eq!(emulator.os.free_list, HEAP_BASE);
+        assert_eq!(emulator.read(HEAP_BASE), 14336);
+        assert_eq!(emulator.read(HEAP_BASE + 1), 0);
+
+        // segments a word or two over the size go whole, as no header fits
+        // in what is left
+        for size in [3, 14329] {
+            emulator.call_function("Memory.alloc", &[size]).unwrap();
+        }
+        emulator.call_function("Memory.deAlloc", &[16381]).unwrap();
+        assert_eq!(emulator.call_function("Memory.alloc", &[2]), Ok(16381));
+        assert_eq!(emulator.call_function("Memory.alloc", &[1]), Ok(2049));
+        assert_eq!(emulator.os.free_list, 0);
+        assert_eq!(emulator.call_function("Memory.alloc", &[1]), Ok(0));
+        assert_eq!(emulator.os.error, Some(code::HEAP_OVERFLOW));
+    }
+
+    #[test]
+    fn calls_vm_code_of_non_native_classes() {
+        // a Memory.alloc that hands out a fixed address
+        let memory = "function Memory.alloc 0\npush constant 5000\nreturn";
+        let main = "function Main.main 0
+            push constant 2
+            call String.new 1
+            push constant 72
+            call String.appendChar 2
+            call Output.printString 1
+            pop temp 0
+            push constant 3
+            call Array.new 1
+            pop static 0
+            push constant 0
+            return";
+        let mut emulator = emulator(&[("Memory", memory), ("Main", main)], None);
+        assert!(!emulator.os.has_class("Memory"));
+        assert_eq!(emulator.run(1000), Ok(State::Halted));
+        assert_eq!(emulator.read(STATIC), 5000);
+        // 'H' in the left half of the first word, top row empty
+        assert_eq!(emulator.read(SCREEN), FONT[b'H' as usize - 31][0] as i16);
+        assert_eq!(
+            emulator.read(SCREEN + 32),
+            FONT[b'H' as usize - 31][1] as i16
+        );
+    }
+
+    #[test]
+    fn stops_vm_code_called_natively_at_the_step_limit() {
+        let memory = "function Memory.alloc 0
+            label LOOP
+            push constant 0
+            pop temp 0
+            goto LOOP";
+        let main = "function Main.main 0
+            push constant 3
+            call Array.new 1
+            return";
+        let mut emulator = emulator(&[("Memory", memory), ("Main", main)], None);
+        let error = emulator.run(1000).unwrap_err();
+        assert_eq!(
+            error.message,
+            "ran out of steps in Memory.alloc, called from native code"
+        );
+        assert_eq!(emulator.steps, 1000);
+    }
+
+    #[test]
+    fn waits_in_cycles() {
+        let main = "function Main.main 0
+            push constant 2
+            call Sys.wait 1
+            pop temp 0
+            push constant 0
+            return";
+        let mut emulator = emulator(&[("Main", main)], Some("Sys"));
+        assert_eq!(emulator.run(1000), Ok(State::Halted));
+        assert_eq!(emulator.steps, 2 * CYCLES_PER_MS + 6);
+        assert!(NativeOs::for_program(&emulator.program, Some("Math,Disk")).is_err());
+    }
+
+    #[test]
+    fn draws_shapes() {
+        let mut emulator = emulator(
+            &[("Main", "function Main.main 0\npush constant 0\nreturn")],
+            None,
+        );
+        emulator
+            .call_function("Screen.drawLine", &[0, 0, 3, 1])
+            .unwrap();
+        assert_eq!(emulator.read(SCREEN), 0b0001);
+        assert_eq!(emulator.read(SCREEN + 32), 0b1111);
+        emulator.call_function("Screen.setColor", &[0]).unwrap();
+        emulator
+            .call_function("Screen.drawRectangle", &[1, 0, 2, 1])
+            .unwrap();
+        assert_eq!(emulator.read(SCREEN), 0b0001);
+        assert_eq!(emulator.read(SCREEN + 32), 0b1001);
+        emulator
+            .call_function("Screen.drawPixel", &[512, 0])
+            .unwrap();
+        assert_eq!(emulator.os.error, Some(code::PIXEL));
+    }
+}
@@ -0,0 +1,239 @@
+// the project 12 OS tests, run on the VM emulator
+//
+// `test-os Memory` compiles 12/Memory.jack with 12/MemoryTest and runs it
+// with every other OS class native, so only our own class is under test.
+// Tests with a `.tst` script run it and compare what its `output` commands
+// record with the `.cmp` file, row by row. The others only draw on the
+// screen, so their final frame is compared with a golden PNG next to the
+// test, made by running the same test on the fully native OS. A test that
+// waits for keys reads them from a `<Test>.keys` script.
+//
+// The golden PNGs in 12/ therefore come from our own native OS, not from
+// the reference one. Their only check against the reference is by eye:
+// each was compared with the `<Test>Output.gif` screenshot of the course's
+// VM emulator next to it, which is scaled down and can't be compared pixel
+// for pixel. SysTest has no screenshot; its golden shows the two messages
+// its Main.jack prints. Redo that comparison whenever a golden is updated.
+
+use std::fs;
+use std::path::Path;
+
+use crate::keyboard::{KeyPlayer, KeyScript};
+use crate::os::{NativeOs, CLASSES};
+use crate::screen::{check_snapshot, Screen};
+use crate::tst::{self, Column, Statement};
+use crate::vm_emulator::{Program, State, VmEmulator};
+
+/// What a script records: the header and rows written by `output-list` and
+/// `output`, and the file named by `compare-to`.
+#[derive(Debug, Default)]
+struct Recording {
+    lines: Vec<String>,
+    compare_to: Option<String>,
+    columns: Vec<Column>,
+}
+
+/// The value of `RAM[n]`, the only thing a script here can look at.
+fn ram_value(emulator: &VmEmulator, name: &str) -> Result<i64, String> {
+    name.strip_prefix("RAM[")
+        .and_then(|rest| rest.strip_suffix(']'))
+        .and_then(|address| address.parse::<usize>().ok())
+        .filter(|address| *address < emulator.ram.len())
+        .map(|address| emulator.read(address) as i64)
+        .ok_or_else(|| format!("can't look at {}", name))
+}
+
+fn run_statements(
+    statements: &[Statement],
+    emulator: &mut VmEmulator,
+    recording: &mut Recording,
+) -> Result<(), String> {
+    for statement in statements {
+        match statement {
+            Statement::Repeat { count, body } => {
+                let count = count.ok_or("repeat without a count never ends")?;
+                for _ in 0..count {
+                    run_statements(body, emulator, recording)?;
+                }
+            }
+            Statement::While { condition, body } => {
+                while condition.holds(|name| ram_value(emulator, name))? {
+                    if emulator.state == State::Halted {
+                        return Err(format!(
+                            "while {} still holds, but the program halted",
+                            condition
+                        ));
+                    }
+                    if emulator.steps >= emulator.step_limit {
+                        return Err(format!(
+                            "while {} still holds after {} steps",
+                            condition, emulator.steps
+                        ));
+                    }
+                    run_statements(body, emulator, recording)?;
+                }
+            }
+            Statement::Command { words, line } => {
+                let error = |message: String| format!("line {}: {}", line, message);
+                match words[0].as_str() {
+                    "load" | "output-file" | "echo" | "clear-echo" => {}
+                    "compare-to" => recording.compare_to = words.get(1).cloned(),
+                    "output-list" => {
+                        recording.columns = words[1..]
+                            .iter()
+                            .map(|spec| Column::parse(spec))
+                            .collect::<Result<_, _>>()
+                            .map_err(error)?;
+                        recording.lines.push(tst::header_line(&recording.columns));
+                    }
+                    "vmstep" => {
+                        emulator
+                            .step()
+                            .map_err(|run_error| error(run_error.to_string()))?;
+                    }
+                    "output" => {
+                        let cells = recording
+                            .columns
+                            .iter()
+                            .map(|column| Ok(column.cell(ram_value(emulator, &column.name)?, 16)))
+                            .collect::<Result<Vec<_>, String>>()
+                            .map_err(error)?;
+                        recording.lines.push(tst::row_line(&cells));
+                    }
+                    other => return Err(error(format!("unsupported command {}", other))),
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Run the `.tst` file `script` and compare with its `.cmp` file, printing a
+/// line per row. True when every row passed.
+pub fn run_script(emulator: &mut VmEmulator, script: &Path) -> Result<bool, String> {
+    let text =
+        fs::read_to_string(script).map_err(|error| format!("{}: {}", script.display(), error))?;
+    let statements =
+        tst::parse(&text).map_err(|error| format!("{}: {}", script.display(), error))?;
+    let mut recording = Recording::default();
+    run_statements(&statements, emulator, &mut recording)
+        .map_err(|error| format!("{}: {}", script.display(), error))?;
+    let cmp = match recording.compare_to {
+        Some(name) => script.with_file_name(name),
+        None => script.with_extension("cmp"),
+    };
+    let expected =
+        fs::read_to_string(&cmp).map_err(|error| format!("{}: {}", cmp.display(), error))?;
+    Ok(tst::report(&tst::compare(&recording.lines, &expected)))
+}
+
+/// The final screen of `program` run for at most `max_steps` commands.
+fn final_screen(
+    program: Program,
+    os: NativeOs,
+    keys: &Option<KeyScript>,
+    max_steps: u64,
+) -> Result<(Screen, Option<i16>), String> {
+    let mut emulator = VmEmulator::with_os(program, os).map_err(|error| error.to_string())?;
+    if let Some(keys) = keys {
+        emulator.keys = KeyPlayer::new(keys.clone());
+    }
+    emulator.run(max_steps).map_err(|error| error.to_string())?;
+    Ok((Screen::from_ram(&emulator.ram), emulator.os.error))
+}
+
+/// Test our `class` with the program in `sources` (the class and its test
+/// directory `dir` compiled to VM code). Screen tests are compared with the
+/// golden image, which is made from the native OS when missing or when
+/// `update` is set. True when the test passed.
+pub fn test_os(
+    sources: &[(String, String)],
+    dir: &Path,
+    class: &str,
+    max_steps: u64,
+    update: bool,
+) -> Result<bool, String> {
+    let program = Program::load(sources).map_err(|error| error.to_string())?;
+    let os = NativeOs::for_program(&program, None)?;
+    let test = format!("{}Test", class);
+    let script = dir.join(format!("{}.tst", test));
+    if script.exists() {
+        let mut emulator = VmEmulator::with_os(program, os).map_err(|error| error.to_string())?;
+        emulator.step_limit = max_steps;
+        return run_script(&mut emulator, &script);
+    }
+    let keys_file = dir.join(format!("{}.keys", test));
+    let keys = match fs::read_to_string(&keys_file) {
+        Ok(text) => Some(
+            KeyScript::parse(&text)
+                .map_err(|error| format!("{}: {}", keys_file.display(), error))?,
+        ),
+        Err(_) => None,
+    };
+    let golden = dir.join(format!("{}.png", test));
+    if update || !golden.exists() {
+        let (screen, _) = final_screen(program.clone(), NativeOs::new(&CLASSES), &keys, max_steps)?;
+        check_snapshot(&screen, &golden, true).map_err(|error| error.to_string())?;
+        println!("wrote {} from the native OS", golden.display());
+    }
+    let (screen, error) = final_screen(program, os, &keys, max_steps)?;
+    if let Some(code) = error {
+        println!("Sys.error({})", code);
+    }
+    match check_snapshot(&screen, &golden, false) {
+        Ok(_) => {
+            println!("screen matches {}", golden.display());
+            Ok(true)
+        }
+        Err(error) => {
+            println!("screen: FAIL, {}", error);
+            Ok(false)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn runs_test_scripts() {
+        let source = "function Main.main 0
+            push constant 8000
+            push constant 42
+            call Memory.poke 2
+            pop temp 0
+            push constant 0
+            return";
+        let program = Program::load(&[("Main".to_string(), source.to_string())]).unwrap();
+        let mut emulator = VmEmulator::with_os(program, NativeOs::new(&CLASSES)).unwrap();
+        let statements =
+            tst::parse("load, output-list RAM[8000]%D2.6.1; repeat 10 { vmstep; } output;")
+                .unwrap();
+        let mut recording = Recording::default();
+        run_statements(&statements, &mut emulator, &mut recording).unwrap();
+        assert_eq!(recording.lines, vec!["|RAM[8000]|", "|      42 |"]);
+        let rows = tst::compare(&recording.lines, "|RAM[8000]|\n|      42 |\n");
+        assert_eq!(rows[0].mismatches, vec![]);
+        let mut emulator = VmEmulator::with_os(emulator.program, NativeOs::new(&CLASSES)).unwrap();
+        let statements = tst::parse("while RAM[8000] <> 42 { vmstep; } clear-echo;").unwrap();
+        run_statements(&statements, &mut emulator, &mut recording).unwrap();
+        assert_eq!(emulator.read(8000), 42);
+        let statements = tst::parse("while RAM[8000] = 42 { vmstep; }").unwrap();
+        emulator.step_limit = emulator.steps + 1;
+        assert_eq!(
+            run_statements(&statements, &mut emulator, &mut recording).unwrap_err(),
+            format!("while RAM[8000] = 42 still holds after {} steps", emulator.steps)
+        );
+        emulator.step_limit = u64::MAX;
+        assert_eq!(
+            run_statements(&statements, &mut emulator, &mut recording).unwrap_err(),
+            "while RAM[8000] = 42 still holds, but the program halted"
+        );
+        let statements = tst::parse("tick;").unwrap();
+        assert_eq!(
+            run_statements(&statements, &mut emulator, &mut recording).unwrap_err(),
+            "line 1: unsupported command tick"
+        );
+    }
+}
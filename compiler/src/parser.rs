@@ -1,9 +1,18 @@
+use serde::{Deserialize, Serialize};
+
 use crate::tokenizer::Token;
 
 pub struct TokenReader {
     pub tokens: Vec<Token>,
 }
 
+/// Range of token indices `[start, end)` a node was parsed from.
+#[derive(Debug, PartialEq, Clone, Copy, Default, Serialize, Deserialize)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
 pub trait Parsable {
     fn _try_parse(reader: &TokenReader, idx: usize) -> Option<(Self, usize)>
     where
@@ -20,6 +29,7 @@ pub trait Parsable {
     }
 }
 
+#[derive(Serialize, Deserialize)]
 pub enum Node {
     Keyword(elements::Keyword),
     Symbol(elements::Symbol),
@@ -60,9 +70,12 @@ pub enum Node {
 
 pub mod elements {
 
+    use serde::{Deserialize, Serialize};
+
     use super::{Parsable, TokenReader};
     use crate::tokenizer::{KeywordType, Token};
 
+    #[derive(Serialize, Deserialize)]
     pub struct Keyword(pub KeywordType);
     impl Parsable for Keyword {
         fn _try_parse(reader: &TokenReader, idx: usize) -> Option<(Self, usize)> {
@@ -73,7 +86,7 @@ pub mod elements {
         }
     }
 
-    #[derive(Debug, PartialEq)]
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
     pub struct Symbol(pub char);
     impl Parsable for Symbol {
         fn _try_parse(reader: &TokenReader, idx: usize) -> Option<(Self, usize)> {
@@ -84,7 +97,7 @@ pub mod elements {
         }
     }
 
-    #[derive(Debug, PartialEq)]
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
     pub struct IntegerConstant(pub i64);
     impl Parsable for IntegerConstant {
         fn _try_parse(reader: &TokenReader, idx: usize) -> Option<(Self, usize)> {
@@ -95,7 +108,7 @@ pub mod elements {
         }
     }
 
-    #[derive(Debug, PartialEq)]
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
     pub struct StringConstant(pub String);
     impl Parsable for StringConstant {
         fn _try_parse(reader: &TokenReader, idx: usize) -> Option<(Self, usize)> {
@@ -106,7 +119,7 @@ pub mod elements {
         }
     }
 
-    #[derive(Debug, PartialEq)]
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
     pub struct Identifier(pub String);
     impl Parsable for Identifier {
         fn _try_parse(reader: &TokenReader, idx: usize) -> Option<(Self, usize)> {
@@ -131,18 +144,21 @@ pub mod elements {
 }
 
 pub mod structures {
+    use serde::{Deserialize, Serialize};
+
     use crate::tokenizer::{KeywordType, Token};
 
     use super::{
         elements::{self, try_parse_symbol},
-        statements, Parsable, TokenReader,
+        statements, Parsable, Span, TokenReader,
     };
 
-    #[derive(Debug)]
+    #[derive(Debug, Serialize, Deserialize)]
     pub struct Class {
         pub class_name: ClassName,
         pub class_var_dec: Vec<ClassVarDec>,
         pub subroutine_dec: Vec<SubroutineDec>,
+        pub span: Span,
     }
     impl Parsable for Class {
         fn _try_parse(reader: &TokenReader, idx: usize) -> Option<(Self, usize)>
@@ -184,13 +200,14 @@ pub mod structures {
                     class_name: _class_name.0,
                     class_var_dec: class_var_dec,
                     subroutine_dec: subroutine_dec,
+                    span: Span { start: idx, end: p },
                 },
                 p,
             ))
         }
     }
 
-    #[derive(PartialEq, Debug)]
+    #[derive(PartialEq, Debug, Serialize, Deserialize)]
     pub enum ClassVarDecType {
         STATIC,
         FIELD,
@@ -210,7 +227,7 @@ pub mod structures {
         }
     }
 
-    #[derive(Debug, PartialEq)]
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
     pub enum VarTypeEnum {
         INT,
         CHAR,
@@ -218,7 +235,7 @@ pub mod structures {
         CLASSNAME(ClassName),
     }
 
-    #[derive(Debug, PartialEq)]
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
     pub struct VarType(pub VarTypeEnum);
     impl Parsable for VarType {
         fn _try_parse(reader: &TokenReader, idx: usize) -> Option<(Self, usize)> {
@@ -239,7 +256,7 @@ pub mod structures {
         }
     }
 
-    #[derive(Debug, PartialEq)]
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
     pub struct ClassName(pub String);
     impl Parsable for ClassName {
         fn _try_parse(reader: &TokenReader, idx: usize) -> Option<(Self, usize)> {
@@ -250,7 +267,7 @@ pub mod structures {
         }
     }
 
-    #[derive(Debug, PartialEq)]
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
     pub struct SubroutineName(pub String);
     impl Parsable for SubroutineName {
         fn _try_parse(reader: &TokenReader, idx: usize) -> Option<(Self, usize)> {
@@ -263,7 +280,7 @@ pub mod structures {
         }
     }
 
-    #[derive(Debug, PartialEq)]
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
     pub struct VarName(pub String);
     impl Parsable for VarName {
         fn _try_parse(reader: &TokenReader, idx: usize) -> Option<(Self, usize)> {
@@ -274,10 +291,11 @@ pub mod structures {
         }
     }
 
-    #[derive(Debug, PartialEq)]
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
     pub struct VarDec {
         pub var_type: VarType,
         pub var_names: Vec<VarName>,
+        pub span: Span,
     }
     impl Parsable for VarDec {
         fn _try_parse(reader: &TokenReader, idx: usize) -> Option<(Self, usize)> {
@@ -307,17 +325,19 @@ pub mod structures {
                 VarDec {
                     var_type: _var_type.0,
                     var_names: var_names,
+                    span: Span { start: idx, end: p },
                 },
                 p,
             ))
         }
     }
 
-    #[derive(Debug)]
+    #[derive(Debug, Serialize, Deserialize)]
     pub struct ClassVarDec {
         pub var_dec_type: ClassVarDecType,
         pub var_type: VarType,
         pub var_names: Vec<VarName>,
+        pub span: Span,
     }
     impl Parsable for ClassVarDec {
         fn _try_parse(reader: &TokenReader, idx: usize) -> Option<(Self, usize)> {
@@ -346,13 +366,14 @@ pub mod structures {
                     var_dec_type: _var_dec_type.0,
                     var_type: _var_type.0,
                     var_names: var_names,
+                    span: Span { start: idx, end: p },
                 },
                 p,
             ))
         }
     }
 
-    #[derive(Debug)]
+    #[derive(Debug, Serialize, Deserialize)]
     pub struct ParameterList {
         pub parameters: Vec<(VarType, VarName)>,
     }
@@ -395,7 +416,7 @@ pub mod structures {
         }
     }
 
-    #[derive(Debug)]
+    #[derive(Debug, Serialize, Deserialize)]
     pub struct SubroutineBody {
         pub var_decs: Vec<VarDec>,
         pub statements: statements::Statements,
@@ -427,7 +448,7 @@ pub mod structures {
         }
     }
 
-    #[derive(Debug)]
+    #[derive(Debug, Serialize, Deserialize)]
     pub enum SubroutineType {
         CONSTRUCTOR,
         FUNCTION,
@@ -446,7 +467,7 @@ pub mod structures {
         }
     }
 
-    #[derive(Debug)]
+    #[derive(Debug, Serialize, Deserialize)]
     pub enum ReturnType {
         VOID,
         VARTYPE(VarType),
@@ -472,13 +493,14 @@ pub mod structures {
         }
     }
 
-    #[derive(Debug)]
+    #[derive(Debug, Serialize, Deserialize)]
     pub struct SubroutineDec {
         pub subroutine_type: SubroutineType,
         pub return_type: ReturnType,
         pub subroutine_name: SubroutineName,
         pub parameter_list: ParameterList,
         pub subroutine_body: SubroutineBody,
+        pub span: Span,
     }
     impl Parsable for SubroutineDec {
         fn _try_parse(reader: &TokenReader, idx: usize) -> Option<(Self, usize)> {
@@ -510,6 +532,7 @@ pub mod structures {
                     subroutine_name: _subroutine_name.0,
                     parameter_list: _parameter_list.0,
                     subroutine_body: _subroutine_body.0,
+                    span: Span { start: idx, end: p },
                 },
                 p,
             ))
@@ -518,9 +541,11 @@ pub mod structures {
 }
 
 pub mod statements {
-    use super::{elements::try_parse_symbol, expressions, structures, Parsable};
+    use serde::{Deserialize, Serialize};
+
+    use super::{elements::try_parse_symbol, expressions, structures, Parsable, Span};
 
-    #[derive(Debug, PartialEq)]
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
     pub enum Statement {
         LetStatement(LetStatement),
         IfStatement(IfStatement),
@@ -549,7 +574,7 @@ pub mod statements {
         }
     }
 
-    #[derive(Debug, PartialEq)]
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
     pub struct Statements(pub Vec<Statement>);
     impl Parsable for Statements {
         fn _try_parse(reader: &super::TokenReader, idx: usize) -> Option<(Self, usize)> {
@@ -568,10 +593,11 @@ pub mod statements {
         }
     }
 
-    #[derive(Debug, PartialEq)]
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
     pub struct LetStatement {
         pub let_lhs: LetLHS,
         pub let_rhs: expressions::Expression,
+        pub span: Span,
     }
     impl Parsable for LetStatement {
         fn _try_parse(reader: &super::TokenReader, idx: usize) -> Option<(Self, usize)> {
@@ -597,13 +623,14 @@ pub mod statements {
                 LetStatement {
                     let_lhs: _lhs.0,
                     let_rhs: _rhs.0,
+                    span: Span { start: idx, end: p },
                 },
                 p,
             ))
         }
     }
 
-    #[derive(Debug, PartialEq)]
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
     pub enum LetLHS {
         VarName(structures::VarName),
         ArrayTerm(expressions::ArrayTerm),
@@ -623,11 +650,12 @@ pub mod statements {
         }
     }
 
-    #[derive(Debug, PartialEq)]
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
     pub struct IfStatement {
         pub condition: expressions::Expression,
         pub true_statements: Statements,
         pub false_statements: Option<Statements>,
+        pub span: Span,
     }
     impl Parsable for IfStatement {
         fn _try_parse(reader: &super::TokenReader, idx: usize) -> Option<(Self, usize)> {
@@ -657,6 +685,7 @@ pub mod statements {
                         condition: _condition.0,
                         true_statements: _true_statements.0,
                         false_statements: None,
+                        span: Span { start: idx, end: p },
                     },
                     p,
                 ));
@@ -668,6 +697,7 @@ pub mod statements {
                         condition: _condition.0,
                         true_statements: _true_statements.0,
                         false_statements: None,
+                        span: Span { start: idx, end: p },
                     },
                     p,
                 ));
@@ -684,16 +714,18 @@ pub mod statements {
                     condition: _condition.0,
                     true_statements: _true_statements.0,
                     false_statements: Some(_false_statements.0),
+                    span: Span { start: idx, end: p },
                 },
                 p,
             ))
         }
     }
 
-    #[derive(Debug, PartialEq)]
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
     pub struct WhileStatement {
         pub condition: expressions::Expression,
         pub statements: Statements,
+        pub span: Span,
     }
     impl Parsable for WhileStatement {
         fn _try_parse(reader: &super::TokenReader, idx: usize) -> Option<(Self, usize)> {
@@ -716,15 +748,17 @@ pub mod statements {
                 WhileStatement {
                     condition: _condition.0,
                     statements: _statements.0,
+                    span: Span { start: idx, end: p },
                 },
                 p,
             ))
         }
     }
 
-    #[derive(Debug, PartialEq)]
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
     pub struct DoStatement {
         pub subroutine_call: expressions::SubroutineCall,
+        pub span: Span,
     }
     impl Parsable for DoStatement {
         fn _try_parse(reader: &super::TokenReader, idx: usize) -> Option<(Self, usize)> {
@@ -740,15 +774,17 @@ pub mod statements {
             Some((
                 DoStatement {
                     subroutine_call: _subroutine_call.0,
+                    span: Span { start: idx, end: p },
                 },
                 p,
             ))
         }
     }
 
-    #[derive(Debug, PartialEq)]
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
     pub struct ReturnStatement {
         pub expression: Option<expressions::Expression>,
+        pub span: Span,
     }
     impl Parsable for ReturnStatement {
         fn _try_parse(reader: &super::TokenReader, idx: usize) -> Option<(Self, usize)> {
@@ -761,7 +797,13 @@ pub mod statements {
             let _expression = expressions::Expression::try_parse(reader, p);
             if _expression.is_none() {
                 p = super::elements::try_parse_symbol(reader, p, ';')?;
-                return Some((ReturnStatement { expression: None }, p));
+                return Some((
+                    ReturnStatement {
+                        expression: None,
+                        span: Span { start: idx, end: p },
+                    },
+                    p,
+                ));
             }
             let _expression = _expression.unwrap();
             p = _expression.1;
@@ -769,6 +811,7 @@ pub mod statements {
             Some((
                 ReturnStatement {
                     expression: Some(_expression.0),
+                    span: Span { start: idx, end: p },
                 },
                 p,
             ))
@@ -777,17 +820,20 @@ pub mod statements {
 }
 
 pub mod expressions {
+    use serde::{Deserialize, Serialize};
+
     use crate::tokenizer::KeywordType;
 
     use super::{
         elements::{self, try_parse_symbol},
-        structures, Parsable,
+        structures, Parsable, Span,
     };
 
-    #[derive(Debug, PartialEq)]
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
     pub struct Expression {
         pub term: Box<Term>,
         pub op_term: Vec<(Op, Term)>,
+        pub span: Span,
     }
     impl Parsable for Expression {
         fn _try_parse(reader: &super::TokenReader, idx: usize) -> Option<(Self, usize)>
@@ -816,13 +862,14 @@ pub mod expressions {
                 Expression {
                     term: Box::new(_term.0),
                     op_term: op_term,
+                    span: Span { start: idx, end: p },
                 },
                 p,
             ))
         }
     }
 
-    #[derive(Debug, PartialEq)]
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
     pub enum Term {
         IntegerConstant(elements::IntegerConstant),
         StringConstant(elements::StringConstant),
@@ -866,10 +913,11 @@ pub mod expressions {
         }
     }
 
-    #[derive(Debug, PartialEq)]
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
     pub struct ArrayTerm {
         pub var_name: structures::VarName,
         pub expression: Expression,
+        pub span: Span,
     }
     impl Parsable for ArrayTerm {
         fn _try_parse(reader: &super::TokenReader, idx: usize) -> Option<(Self, usize)>
@@ -891,13 +939,14 @@ pub mod expressions {
                 ArrayTerm {
                     var_name: _var_name.0,
                     expression: _expression.0,
+                    span: Span { start: idx, end: p },
                 },
                 p,
             ))
         }
     }
 
-    #[derive(Debug, PartialEq)]
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
     pub struct WrappedExpression(pub Expression);
     impl Parsable for WrappedExpression {
         fn _try_parse(reader: &super::TokenReader, idx: usize) -> Option<(Self, usize)> {
@@ -914,10 +963,11 @@ pub mod expressions {
         }
     }
 
-    #[derive(Debug, PartialEq)]
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
     pub struct UnaryTerm {
         pub unary_op: UnaryOp,
         pub term: Box<Term>,
+        pub span: Span,
     }
     impl Parsable for UnaryTerm {
         fn _try_parse(reader: &super::TokenReader, idx: usize) -> Option<(Self, usize)>
@@ -930,13 +980,14 @@ pub mod expressions {
                 UnaryTerm {
                     unary_op: _unary_op.0,
                     term: Box::new(_term.0),
+                    span: Span { start: idx, end: _term.1 },
                 },
                 _term.1,
             ))
         }
     }
 
-    #[derive(Debug, PartialEq)]
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
     pub struct ExpressionList(pub Vec<Expression>);
     impl Parsable for ExpressionList {
         fn _try_parse(reader: &super::TokenReader, idx: usize) -> Option<(Self, usize)>
@@ -966,11 +1017,12 @@ pub mod expressions {
         }
     }
 
-    #[derive(Debug, PartialEq)]
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
     pub struct SubroutineCall {
         pub bind_this: Option<structures::VarName>, // TODO: classname or varname
         pub subroutine_name: structures::SubroutineName,
         pub expression_list: ExpressionList,
+        pub span: Span,
     }
     impl Parsable for SubroutineCall {
         fn _try_parse(reader: &super::TokenReader, idx: usize) -> Option<(Self, usize)>
@@ -1003,13 +1055,14 @@ pub mod expressions {
                     bind_this: _bind_this,
                     subroutine_name: _subroutine_name.0,
                     expression_list: _expression_list.0,
+                    span: Span { start: idx, end: p },
                 },
                 p,
             ))
         }
     }
 
-    #[derive(Debug, PartialEq)]
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
     pub struct Op(pub elements::Symbol);
     impl Parsable for Op {
         fn _try_parse(reader: &super::TokenReader, idx: usize) -> Option<(Self, usize)> {
@@ -1023,7 +1076,7 @@ pub mod expressions {
         }
     }
 
-    #[derive(Debug,PartialEq)]
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
     pub struct UnaryOp(pub elements::Symbol);
     impl Parsable for UnaryOp {
         fn _try_parse(reader: &super::TokenReader, idx: usize) -> Option<(Self, usize)> {
@@ -1035,7 +1088,7 @@ pub mod expressions {
         }
     }
 
-    #[derive(Debug, PartialEq)]
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
     pub enum KeywordConstant {
         TRUE,
         FALSE,
@@ -1302,7 +1355,8 @@ pub mod tests {
                 term: Box::new(expressions::Term::KeywordConstant(
                     expressions::KeywordConstant::TRUE
                 )),
-                op_term: vec![]
+                op_term: vec![],
+                span: Span { start: 2, end: 3 }
             }
         );
         assert_eq!(if_statement.true_statements.0.len(), 1);
@@ -1332,7 +1386,8 @@ pub mod tests {
                 term: Box::new(expressions::Term::KeywordConstant(
                     expressions::KeywordConstant::TRUE
                 )),
-                op_term: vec![]
+                op_term: vec![],
+                span: Span { start: 2, end: 3 }
             }
         );
         assert_eq!(while_statement.statements.0.len(), 1);
@@ -1480,7 +1535,8 @@ pub mod tests {
                 term: Box::new(expressions::Term::IntegerConstant(
                     elements::IntegerConstant(123)
                 )),
-                op_term: vec![]
+                op_term: vec![],
+                span: Span { start: 4, end: 5 }
             }
         );
         assert_eq!(new_idx, 8);
@@ -1515,6 +1571,7 @@ pub mod tests {
             expressions::Term::IntegerConstant(elements::IntegerConstant(123))
         );
         assert_eq!(expression.op_term.len(), 2);
+        assert_eq!(expression.span, Span { start: 0, end: 5 });
         assert_eq!(new_idx, 5);
     }
 
@@ -1533,6 +1590,7 @@ pub mod tests {
             *array_term.expression.term,
             expressions::Term::IntegerConstant(elements::IntegerConstant(123))
         );
+        assert_eq!(array_term.span, Span { start: 0, end: 4 });
         assert_eq!(new_idx, 4);
     }
 
@@ -1622,7 +1680,8 @@ pub mod tests {
             expressions::Term::SubroutineCall(expressions::SubroutineCall {
                 bind_this: Some(structures::VarName("def".to_string())),
                 subroutine_name: structures::SubroutineName("ghi".to_string()),
-                expression_list: expressions::ExpressionList(vec![])
+                expression_list: expressions::ExpressionList(vec![]),
+                span: Span { start: 3, end: 8 }
             })
         );
         assert_eq!(new_idx, l);
@@ -1649,7 +1708,8 @@ pub mod tests {
             if_statement.condition,
             expressions::Expression {
                 term: Box::new(expressions::Term::VarName(structures::VarName("x".to_string()))),
-                op_term: vec![]
+                op_term: vec![],
+                span: Span { start: 2, end: 3 }
             }
         );
     }
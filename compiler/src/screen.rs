@@ -0,0 +1,255 @@
+// rendering of the Hack screen memory map
+//
+// The screen is 512x256 black and white pixels stored in 8192 words from
+// RAM[16384], 32 words per row. Bit 0 of a word is its leftmost pixel and a
+// set bit is black. Frames can be written as PNG files, previewed in a
+// terminal with quadrant block characters, and checked against golden PNGs.
+
+use std::fmt::{self, Display};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+pub const SCREEN: usize = 16384;
+pub const WIDTH: usize = 512;
+pub const HEIGHT: usize = 256;
+pub const WORDS: usize = WIDTH * HEIGHT / 16;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Screen {
+    pub words: Vec<i16>,
+}
+
+/// Block characters for a 2x2 cell, indexed by its pixels as bits
+/// (1 top left, 2 top right, 4 bottom left, 8 bottom right).
+const QUADRANTS: [char; 16] = [
+    ' ', '▘', '▝', '▀', '▖', '▌', '▞', '▛', '▗', '▚', '▐', '▜', '▄', '▙', '▟', '█',
+];
+
+impl Screen {
+    /// The screen part of a full 32K RAM.
+    pub fn from_ram(ram: &[i16]) -> Screen {
+        Screen {
+            words: ram[SCREEN..SCREEN + WORDS].to_vec(),
+        }
+    }
+
+    pub fn pixel(&self, x: usize, y: usize) -> bool {
+        self.words[y * WIDTH / 16 + x / 16] & (1 << (x % 16)) != 0
+    }
+
+    fn set_pixel(&mut self, x: usize, y: usize) {
+        self.words[y * WIDTH / 16 + x / 16] |= 1 << (x % 16);
+    }
+
+    /// An 8-bit grayscale PNG, black where pixels are set.
+    pub fn to_png(&self) -> Vec<u8> {
+        let mut data = Vec::with_capacity(WIDTH * HEIGHT);
+        for y in 0..HEIGHT {
+            for x in 0..WIDTH {
+                data.push(if self.pixel(x, y) { 0 } else { 255 });
+            }
+        }
+        let mut png = vec![];
+        let mut encoder = png::Encoder::new(&mut png, WIDTH as u32, HEIGHT as u32);
+        encoder.set_color(png::ColorType::Grayscale);
+        encoder.set_depth(png::BitDepth::Eight);
+        let mut writer = encoder.write_header().unwrap();
+        writer.write_image_data(&data).unwrap();
+        writer.finish().unwrap();
+        png
+    }
+
+    /// Read a 512x256 PNG back, treating dark pixels as set. Any colour type
+    /// works, only the first channel is looked at.
+    pub fn from_png(bytes: &[u8]) -> Result<Screen, String> {
+        let mut decoder = png::Decoder::new(std::io::Cursor::new(bytes));
+        decoder.set_transformations(png::Transformations::EXPAND);
+        let mut reader = decoder.read_info().map_err(|error| error.to_string())?;
+        let mut data = vec![0; reader.output_buffer_size().ok_or("image too large")?];
+        let info = reader
+            .next_frame(&mut data)
+            .map_err(|error| error.to_string())?;
+        if (info.width as usize, info.height as usize) != (WIDTH, HEIGHT) {
+            return Err(format!(
+                "image is {}x{}, not {}x{}",
+                info.width, info.height, WIDTH, HEIGHT
+            ));
+        }
+        let channels = info.color_type.samples() * info.bit_depth as usize / 8;
+        let mut screen = Screen {
+            words: vec![0; WORDS],
+        };
+        for y in 0..HEIGHT {
+            for x in 0..WIDTH {
+                if data[y * info.line_size + x * channels.max(1)] < 128 {
+                    screen.set_pixel(x, y);
+                }
+            }
+        }
+        Ok(screen)
+    }
+
+    /// Text preview where each character covers `scale` x `scale` 2x2 cells,
+    /// a cell quadrant being drawn when any pixel under it is set.
+    pub fn to_terminal(&self, scale: usize) -> String {
+        let scale = scale.max(1);
+        let block = 2 * scale;
+        let mut text = String::new();
+        for row in 0..HEIGHT.div_ceil(block) {
+            for column in 0..WIDTH.div_ceil(block) {
+                let mut bits = 0;
+                for quadrant in 0..4 {
+                    let x0 = column * block + (quadrant % 2) * scale;
+                    let y0 = row * block + (quadrant / 2) * scale;
+                    let set = (y0..(y0 + scale).min(HEIGHT))
+                        .any(|y| (x0..(x0 + scale).min(WIDTH)).any(|x| self.pixel(x, y)));
+                    if set {
+                        bits |= 1 << quadrant;
+                    }
+                }
+                text.push(QUADRANTS[bits]);
+            }
+            text.push('\n');
+        }
+        text
+    }
+
+    /// Pixels that differ from `other`, as `(x, y)` in row order.
+    pub fn diff(&self, other: &Screen) -> Vec<(usize, usize)> {
+        let mut pixels = vec![];
+        for y in 0..HEIGHT {
+            for x in 0..WIDTH {
+                if self.pixel(x, y) != other.pixel(x, y) {
+                    pixels.push((x, y));
+                }
+            }
+        }
+        pixels
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum SnapshotError {
+    /// The golden image couldn't be read or decoded.
+    Unreadable(String),
+    /// The frame differs; it was written to `actual` for inspection.
+    Mismatch {
+        pixels: usize,
+        first: (usize, usize),
+        actual: PathBuf,
+    },
+}
+
+impl Display for SnapshotError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SnapshotError::Unreadable(message) => f.write_str(message),
+            SnapshotError::Mismatch {
+                pixels,
+                first: (x, y),
+                actual,
+            } => write!(
+                f,
+                "{} pixels differ, the first at ({}, {}); frame written to {}",
+                pixels,
+                x,
+                y,
+                actual.display()
+            ),
+        }
+    }
+}
+
+/// Compare `screen` with the golden PNG at `golden`. A missing golden image
+/// is created, as is every one when `update` is set. On a mismatch the frame
+/// is saved next to the golden one as `<name>.actual.png`.
+pub fn check_snapshot(screen: &Screen, golden: &Path, update: bool) -> Result<bool, SnapshotError> {
+    if update || !golden.exists() {
+        fs::write(golden, screen.to_png()).map_err(|error| {
+            SnapshotError::Unreadable(format!("{}: {}", golden.display(), error))
+        })?;
+        return Ok(true);
+    }
+    let bytes = fs::read(golden)
+        .map_err(|error| SnapshotError::Unreadable(format!("{}: {}", golden.display(), error)))?;
+    let expected = Screen::from_png(&bytes)
+        .map_err(|error| SnapshotError::Unreadable(format!("{}: {}", golden.display(), error)))?;
+    let pixels = screen.diff(&expected);
+    if pixels.is_empty() {
+        return Ok(false);
+    }
+    let actual = golden.with_extension("actual.png");
+    fs::write(&actual, screen.to_png())
+        .map_err(|error| SnapshotError::Unreadable(format!("{}: {}", actual.display(), error)))?;
+    Err(SnapshotError::Mismatch {
+        pixels: pixels.len(),
+        first: pixels[0],
+        actual,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn screen_with(pixels: &[(usize, usize)]) -> Screen {
+        let mut screen = Screen {
+            words: vec![0; WORDS],
+        };
+        for &(x, y) in pixels {
+            screen.set_pixel(x, y);
+        }
+        screen
+    }
+
+    #[test]
+    fn reads_pixels_from_ram() {
+        let mut ram = vec![0; 32768];
+        ram[SCREEN] = 1;
+        ram[SCREEN + 33] = i16::MIN;
+        let screen = Screen::from_ram(&ram);
+        assert!(screen.pixel(0, 0));
+        assert!(!screen.pixel(1, 0));
+        assert!(screen.pixel(31, 1));
+        assert_eq!(screen.diff(&screen_with(&[(0, 0), (31, 1)])), vec![]);
+    }
+
+    #[test]
+    fn round_trips_through_png() {
+        let screen = screen_with(&[(0, 0), (511, 255), (100, 37)]);
+        let decoded = Screen::from_png(&screen.to_png()).unwrap();
+        assert_eq!(decoded, screen);
+    }
+
+    #[test]
+    fn previews_with_block_characters() {
+        let screen = screen_with(&[(0, 0), (3, 1), (4, 2), (5, 3)]);
+        let preview = screen.to_terminal(1);
+        let lines: Vec<&str> = preview.lines().collect();
+        assert_eq!(lines.len(), 128);
+        assert_eq!(lines[0].chars().count(), 256);
+        assert!(lines[0].starts_with("▘▗ "));
+        assert!(lines[1].starts_with("  ▚ "));
+        assert!(screen.to_terminal(4).starts_with("▀ "));
+    }
+
+    #[test]
+    fn checks_snapshots() {
+        let dir = std::env::temp_dir().join(format!("screen-snapshot-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let golden = dir.join("frame.png");
+        let screen = screen_with(&[(10, 10)]);
+        assert_eq!(check_snapshot(&screen, &golden, false), Ok(true));
+        assert_eq!(check_snapshot(&screen, &golden, false), Ok(false));
+        let error =
+            check_snapshot(&screen_with(&[(10, 10), (12, 20)]), &golden, false).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            format!(
+                "1 pixels differ, the first at (12, 20); frame written to {}",
+                dir.join("frame.actual.png").display()
+            )
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
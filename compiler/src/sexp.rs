@@ -0,0 +1,467 @@
+// s-expression backend for Jack language
+//
+// Structs become `(Name (field value) ...)`, newtype structs `(Name value)`,
+// sequences and tuples `(a b ...)` and unit variants a bare symbol. Newtype
+// variants are transparent since every variant wraps a node of the same name.
+
+use std::fmt::{self, Display};
+
+use serde::{ser, Serialize};
+
+use crate::{
+    backend::{resolve_span, span_from_value, Backend},
+    parser::Node,
+    tokenizer::TokenSpan,
+};
+
+pub struct Sexp<'a> {
+    pub spans: Option<&'a [TokenSpan]>,
+}
+
+impl Backend for Sexp<'_> {
+    fn convert_node(&self, node: &Node) -> String {
+        let mut serializer = Serializer {
+            output: String::new(),
+            spans: self.spans,
+        };
+        node.serialize(&mut serializer).unwrap();
+        serializer.output
+    }
+}
+
+#[derive(Debug)]
+pub struct Error(String);
+
+impl Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl ser::Error for Error {
+    fn custom<T: Display>(msg: T) -> Self {
+        Error(msg.to_string())
+    }
+}
+
+struct Serializer<'a> {
+    output: String,
+    spans: Option<&'a [TokenSpan]>,
+}
+
+impl<'b> Serializer<'b> {
+    fn open<'a>(&'a mut self, name: &str) -> Compound<'a, 'b> {
+        self.output.push('(');
+        self.output.push_str(name);
+        let first = name.is_empty();
+        Compound { ser: self, first }
+    }
+
+    fn write_string(&mut self, v: &str) {
+        self.output.push('"');
+        for c in v.chars() {
+            match c {
+                '"' => self.output.push_str("\\\""),
+                '\\' => self.output.push_str("\\\\"),
+                '\n' => self.output.push_str("\\n"),
+                '\t' => self.output.push_str("\\t"),
+                '\r' => self.output.push_str("\\r"),
+                _ => self.output.push(c),
+            }
+        }
+        self.output.push('"');
+    }
+}
+
+struct Compound<'a, 'b> {
+    ser: &'a mut Serializer<'b>,
+    first: bool,
+}
+
+impl Compound<'_, '_> {
+    fn separate(&mut self) {
+        if !self.first {
+            self.ser.output.push(' ');
+        }
+        self.first = false;
+    }
+
+    fn element<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<(), Error> {
+        self.separate();
+        value.serialize(&mut *self.ser)
+    }
+
+    fn field<T: ?Sized + Serialize>(&mut self, key: &str, value: &T) -> Result<(), Error> {
+        if key == "span" {
+            return self.span(value);
+        }
+        self.separate();
+        self.ser.output.push('(');
+        self.ser.output.push_str(key);
+        self.ser.output.push(' ');
+        value.serialize(&mut *self.ser)?;
+        self.ser.output.push(')');
+        Ok(())
+    }
+
+    fn span<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<(), Error> {
+        let Some(spans) = self.ser.spans else {
+            return Ok(());
+        };
+        let span = serde_json::to_value(value)
+            .ok()
+            .and_then(|v| span_from_value(&v))
+            .and_then(|span| resolve_span(spans, &span));
+        if let Some(span) = span {
+            self.separate();
+            self.ser.output.push_str(&format!(
+                "(span (start {} {}) (end {} {}))",
+                span.start.line, span.start.column, span.end.line, span.end.column
+            ));
+        }
+        Ok(())
+    }
+
+    fn close(self) -> Result<(), Error> {
+        self.ser.output.push(')');
+        Ok(())
+    }
+}
+
+impl<'a, 'b> ser::Serializer for &'a mut Serializer<'b> {
+    type Ok = ();
+    type Error = Error;
+
+    type SerializeSeq = Compound<'a, 'b>;
+    type SerializeTuple = Compound<'a, 'b>;
+    type SerializeTupleStruct = Compound<'a, 'b>;
+    type SerializeTupleVariant = Compound<'a, 'b>;
+    type SerializeMap = Compound<'a, 'b>;
+    type SerializeStruct = Compound<'a, 'b>;
+    type SerializeStructVariant = Compound<'a, 'b>;
+
+    fn serialize_bool(self, v: bool) -> Result<(), Error> {
+        self.output.push_str(if v { "#t" } else { "#f" });
+        Ok(())
+    }
+
+    fn serialize_i8(self, v: i8) -> Result<(), Error> {
+        self.serialize_i64(v as i64)
+    }
+
+    fn serialize_i16(self, v: i16) -> Result<(), Error> {
+        self.serialize_i64(v as i64)
+    }
+
+    fn serialize_i32(self, v: i32) -> Result<(), Error> {
+        self.serialize_i64(v as i64)
+    }
+
+    fn serialize_i64(self, v: i64) -> Result<(), Error> {
+        self.output.push_str(&v.to_string());
+        Ok(())
+    }
+
+    fn serialize_u8(self, v: u8) -> Result<(), Error> {
+        self.serialize_u64(v as u64)
+    }
+
+    fn serialize_u16(self, v: u16) -> Result<(), Error> {
+        self.serialize_u64(v as u64)
+    }
+
+    fn serialize_u32(self, v: u32) -> Result<(), Error> {
+        self.serialize_u64(v as u64)
+    }
+
+    fn serialize_u64(self, v: u64) -> Result<(), Error> {
+        self.output.push_str(&v.to_string());
+        Ok(())
+    }
+
+    fn serialize_f32(self, v: f32) -> Result<(), Error> {
+        self.serialize_f64(v as f64)
+    }
+
+    fn serialize_f64(self, v: f64) -> Result<(), Error> {
+        self.output.push_str(&v.to_string());
+        Ok(())
+    }
+
+    fn serialize_char(self, v: char) -> Result<(), Error> {
+        self.write_string(&v.to_string());
+        Ok(())
+    }
+
+    fn serialize_str(self, v: &str) -> Result<(), Error> {
+        self.write_string(v);
+        Ok(())
+    }
+
+    fn serialize_bytes(self, v: &[u8]) -> Result<(), Error> {
+        let mut seq = self.open("");
+        for b in v {
+            seq.element(b)?;
+        }
+        seq.close()
+    }
+
+    fn serialize_none(self) -> Result<(), Error> {
+        self.output.push_str("nil");
+        Ok(())
+    }
+
+    fn serialize_some<T: ?Sized + Serialize>(self, value: &T) -> Result<(), Error> {
+        value.serialize(self)
+    }
+
+    fn serialize_unit(self) -> Result<(), Error> {
+        self.output.push_str("nil");
+        Ok(())
+    }
+
+    fn serialize_unit_struct(self, name: &'static str) -> Result<(), Error> {
+        self.output.push_str(name);
+        Ok(())
+    }
+
+    fn serialize_unit_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+    ) -> Result<(), Error> {
+        self.output.push_str(variant);
+        Ok(())
+    }
+
+    fn serialize_newtype_struct<T: ?Sized + Serialize>(
+        self,
+        name: &'static str,
+        value: &T,
+    ) -> Result<(), Error> {
+        let mut compound = self.open(name);
+        compound.element(value)?;
+        compound.close()
+    }
+
+    fn serialize_newtype_variant<T: ?Sized + Serialize>(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        _variant: &'static str,
+        value: &T,
+    ) -> Result<(), Error> {
+        value.serialize(self)
+    }
+
+    fn serialize_seq(self, _len: Option<usize>) -> Result<Self::SerializeSeq, Error> {
+        Ok(self.open(""))
+    }
+
+    fn serialize_tuple(self, _len: usize) -> Result<Self::SerializeTuple, Error> {
+        Ok(self.open(""))
+    }
+
+    fn serialize_tuple_struct(
+        self,
+        name: &'static str,
+        _len: usize,
+    ) -> Result<Self::SerializeTupleStruct, Error> {
+        Ok(self.open(name))
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+        _len: usize,
+    ) -> Result<Self::SerializeTupleVariant, Error> {
+        Ok(self.open(variant))
+    }
+
+    fn serialize_map(self, _len: Option<usize>) -> Result<Self::SerializeMap, Error> {
+        Ok(self.open(""))
+    }
+
+    fn serialize_struct(
+        self,
+        name: &'static str,
+        _len: usize,
+    ) -> Result<Self::SerializeStruct, Error> {
+        Ok(self.open(name))
+    }
+
+    fn serialize_struct_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+        _len: usize,
+    ) -> Result<Self::SerializeStructVariant, Error> {
+        Ok(self.open(variant))
+    }
+}
+
+impl ser::SerializeSeq for Compound<'_, '_> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_element<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<(), Error> {
+        self.element(value)
+    }
+
+    fn end(self) -> Result<(), Error> {
+        self.close()
+    }
+}
+
+impl ser::SerializeTuple for Compound<'_, '_> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_element<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<(), Error> {
+        self.element(value)
+    }
+
+    fn end(self) -> Result<(), Error> {
+        self.close()
+    }
+}
+
+impl ser::SerializeTupleStruct for Compound<'_, '_> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_field<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<(), Error> {
+        self.element(value)
+    }
+
+    fn end(self) -> Result<(), Error> {
+        self.close()
+    }
+}
+
+impl ser::SerializeTupleVariant for Compound<'_, '_> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_field<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<(), Error> {
+        self.element(value)
+    }
+
+    fn end(self) -> Result<(), Error> {
+        self.close()
+    }
+}
+
+impl ser::SerializeMap for Compound<'_, '_> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_key<T: ?Sized + Serialize>(&mut self, key: &T) -> Result<(), Error> {
+        self.separate();
+        self.ser.output.push('(');
+        key.serialize(&mut *self.ser)
+    }
+
+    fn serialize_value<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<(), Error> {
+        self.ser.output.push(' ');
+        value.serialize(&mut *self.ser)?;
+        self.ser.output.push(')');
+        Ok(())
+    }
+
+    fn end(self) -> Result<(), Error> {
+        self.close()
+    }
+}
+
+impl ser::SerializeStruct for Compound<'_, '_> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_field<T: ?Sized + Serialize>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<(), Error> {
+        self.field(key, value)
+    }
+
+    fn end(self) -> Result<(), Error> {
+        self.close()
+    }
+}
+
+impl ser::SerializeStructVariant for Compound<'_, '_> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_field<T: ?Sized + Serialize>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<(), Error> {
+        self.field(key, value)
+    }
+
+    fn end(self) -> Result<(), Error> {
+        self.close()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        backend::Backend,
+        parser::{expressions, structures, Node, Parsable, TokenReader},
+        sexp::Sexp,
+        tokenizer::tokenize_str_spanned,
+    };
+
+    #[test]
+    fn expression() {
+        let (tokens, _) = tokenize_str_spanned("-x + \"a\\\"b\"");
+        let expression = expressions::Expression::try_parse(&TokenReader { tokens }, 0).unwrap();
+        let output = Sexp { spans: None }.convert_node(&Node::Expression(expression.0));
+        assert_eq!(
+            output,
+            r#"(Expression (term (UnaryTerm (unary_op (UnaryOp (Symbol "-"))) (term (VarName "x")))) (op_term (((Op (Symbol "+")) (StringConstant "a\"b")))))"#
+        );
+    }
+
+    #[test]
+    fn class_with_spans() {
+        let (tokens, spans) = tokenize_str_spanned("class A {\n  field int x;\n}");
+        let class = structures::Class::try_parse(&TokenReader { tokens }, 0).unwrap();
+        let node = Node::Class(class.0);
+        let output = Sexp {
+            spans: Some(&spans),
+        }
+        .convert_node(&node);
+        assert_eq!(
+            output,
+            "(Class (class_name (ClassName \"A\")) (class_var_dec ((ClassVarDec (var_dec_type FIELD) (var_type (VarType INT)) (var_names ((VarName \"x\"))) (span (start 2 3) (end 2 15))))) (subroutine_dec ()) (span (start 1 1) (end 3 2)))"
+        );
+        let plain = Sexp { spans: None }.convert_node(&node);
+        assert!(!plain.contains("span"));
+    }
+
+    #[test]
+    fn term_with_spans() {
+        let (tokens, spans) = tokenize_str_spanned("a[-1]");
+        let expression = expressions::Expression::try_parse(&TokenReader { tokens }, 0).unwrap();
+        let output = Sexp {
+            spans: Some(&spans),
+        }
+        .convert_node(&Node::Expression(expression.0));
+        assert_eq!(
+            output,
+            "(Expression (term (ArrayTerm (var_name (VarName \"a\")) (expression (Expression (term (UnaryTerm (unary_op (UnaryOp (Symbol \"-\"))) (term (IntegerConstant 1)) (span (start 1 3) (end 1 5)))) (op_term ()) (span (start 1 3) (end 1 5)))) (span (start 1 1) (end 1 6)))) (op_term ()) (span (start 1 1) (end 1 6)))"
+        );
+    }
+}
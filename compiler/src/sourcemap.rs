@@ -0,0 +1,118 @@
+// source maps from generated code back to Jack lines
+//
+// The text format has one entry per line, `<position> <file> <line>`, or
+// `<position> -` where the origin is unknown. Positions are 1-based VM line
+// numbers in a `.vm.map` and ROM addresses in an `.asm.map` written by the VM
+// translator. An entry holds until the next one, so only changes are listed.
+
+use std::fmt::{self, Display};
+
+use crate::vm::MappedCommand;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Entry {
+    pub position: usize,
+    /// Jack file and line, `None` for generated code such as runtime helpers.
+    pub location: Option<(String, u32)>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SourceMap {
+    pub entries: Vec<Entry>,
+}
+
+impl SourceMap {
+    /// Map the lines of a `.vm` file written from `commands`, one per line,
+    /// to `source_file`.
+    pub fn from_commands(source_file: &str, commands: &[MappedCommand]) -> SourceMap {
+        let mut map = SourceMap::default();
+        for (i, (_, line)) in commands.iter().enumerate() {
+            let location = (*line != 0).then(|| (source_file.to_string(), *line));
+            if map.entries.last().map(|entry| &entry.location) != Some(&location) {
+                map.entries.push(Entry {
+                    position: i + 1,
+                    location,
+                });
+            }
+        }
+        map
+    }
+
+    pub fn parse(text: &str) -> Result<SourceMap, String> {
+        let mut map = SourceMap::default();
+        for (number, line) in text.lines().enumerate() {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let error = || format!("line {}: malformed source map entry {:?}", number + 1, line);
+            let location = match fields.as_slice() {
+                [] => continue,
+                [_, "-"] => None,
+                [_, file, jack_line] => {
+                    Some((file.to_string(), jack_line.parse().map_err(|_| error())?))
+                }
+                _ => return Err(error()),
+            };
+            let position = fields[0].parse().map_err(|_| error())?;
+            map.entries.push(Entry { position, location });
+        }
+        Ok(map)
+    }
+
+    /// Jack file and line of the code at `position`.
+    pub fn lookup(&self, position: usize) -> Option<(&str, u32)> {
+        let index = self
+            .entries
+            .partition_point(|entry| entry.position <= position);
+        let entry = self.entries.get(index.checked_sub(1)?)?;
+        entry
+            .location
+            .as_ref()
+            .map(|(file, line)| (file.as_str(), *line))
+    }
+}
+
+impl Display for SourceMap {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for entry in &self.entries {
+            match &entry.location {
+                Some((file, line)) => writeln!(f, "{} {} {}", entry.position, file, line)?,
+                None => writeln!(f, "{} -", entry.position)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn maps_vm_lines_to_jack_lines() {
+        let commands: Vec<MappedCommand> = [
+            ("function Main.main 0", 2),
+            ("push constant 1", 3),
+            ("pop static 0", 3),
+            ("push constant 0", 4),
+            ("return", 4),
+            ("function Main.div$pow2 3", 0),
+        ]
+        .iter()
+        .map(|(command, line)| (command.to_string(), *line))
+        .collect();
+        let map = SourceMap::from_commands("Main.jack", &commands);
+        let text = map.to_string();
+        assert_eq!(text, "1 Main.jack 2\n2 Main.jack 3\n4 Main.jack 4\n6 -\n");
+        let parsed = SourceMap::parse(&text).unwrap();
+        assert_eq!(parsed, map);
+        assert_eq!(parsed.lookup(3), Some(("Main.jack", 3)));
+        assert_eq!(parsed.lookup(5), Some(("Main.jack", 4)));
+        assert_eq!(parsed.lookup(6), None);
+        assert_eq!(parsed.lookup(0), None);
+    }
+
+    #[test]
+    fn rejects_malformed_entries() {
+        assert!(SourceMap::parse("1 Main.jack x\n").is_err());
+        assert!(SourceMap::parse("1 Main.jack 2 3\n").is_err());
+    }
+}
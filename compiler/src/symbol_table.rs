@@ -0,0 +1,105 @@
+// symbol table mapping Jack variable names to VM segments
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    ir::{Place, Ty},
+    parser::structures::{Class, ClassVarDecType, SubroutineDec, SubroutineType},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Kind {
+    Static,
+    Field,
+    Argument,
+    Local,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Symbol {
+    pub name: String,
+    pub var_type: Ty,
+    pub kind: Kind,
+    pub index: u16,
+}
+
+impl Symbol {
+    pub fn place(&self) -> Place {
+        match self.kind {
+            Kind::Static => Place::Static(self.index),
+            Kind::Field => Place::Field(self.index),
+            Kind::Argument => Place::Argument(self.index),
+            Kind::Local => Place::Local(self.index),
+        }
+    }
+}
+
+/// Class scope plus the scope of the subroutine currently being compiled.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct SymbolTable {
+    pub class: Vec<Symbol>,
+    pub subroutine: Vec<Symbol>,
+}
+
+impl SymbolTable {
+    pub fn for_class(class: &Class) -> SymbolTable {
+        let mut table = SymbolTable::default();
+        for var_dec in &class.class_var_dec {
+            let kind = match var_dec.var_dec_type {
+                ClassVarDecType::STATIC => Kind::Static,
+                ClassVarDecType::FIELD => Kind::Field,
+            };
+            for name in &var_dec.var_names {
+                table.define(&name.0, Ty::from(&var_dec.var_type.0), kind);
+            }
+        }
+        table
+    }
+
+    /// Reset the subroutine scope and fill it with the arguments and locals
+    /// of `subroutine_dec`. Methods get `this` as argument 0.
+    pub fn start_subroutine(&mut self, class_name: &str, subroutine_dec: &SubroutineDec) {
+        self.subroutine.clear();
+        if let SubroutineType::METHOD = subroutine_dec.subroutine_type {
+            self.define("this", Ty::Class(class_name.to_string()), Kind::Argument);
+        }
+        for (var_type, name) in &subroutine_dec.parameter_list.parameters {
+            self.define(&name.0, Ty::from(&var_type.0), Kind::Argument);
+        }
+        for var_dec in &subroutine_dec.subroutine_body.var_decs {
+            for name in &var_dec.var_names {
+                self.define(&name.0, Ty::from(&var_dec.var_type.0), Kind::Local);
+            }
+        }
+    }
+
+    pub fn define(&mut self, name: &str, var_type: Ty, kind: Kind) {
+        let index = self.count(kind);
+        let symbol = Symbol {
+            name: name.to_string(),
+            var_type,
+            kind,
+            index,
+        };
+        match kind {
+            Kind::Static | Kind::Field => self.class.push(symbol),
+            Kind::Argument | Kind::Local => self.subroutine.push(symbol),
+        }
+    }
+
+    pub fn count(&self, kind: Kind) -> u16 {
+        let scope = match kind {
+            Kind::Static | Kind::Field => &self.class,
+            Kind::Argument | Kind::Local => &self.subroutine,
+        };
+        scope.iter().filter(|symbol| symbol.kind == kind).count() as u16
+    }
+
+    /// Subroutine scope shadows class scope.
+    pub fn lookup(&self, name: &str) -> Option<&Symbol> {
+        self.subroutine
+            .iter()
+            .find(|symbol| symbol.name == name)
+            .or_else(|| self.class.iter().find(|symbol| symbol.name == name))
+    }
+}
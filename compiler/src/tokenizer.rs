@@ -5,7 +5,9 @@ use std::{
     mem,
 };
 
-#[derive(Debug, PartialEq, Clone, Copy)]
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, PartialEq, Clone, Copy, Serialize, Deserialize)]
 pub enum KeywordType {
     CLASS,
     METHOD,
@@ -39,6 +41,20 @@ pub enum Token {
     StringConst(String),
 }
 
+/// 1-based line and column of a character in the source file.
+#[derive(Debug, PartialEq, Clone, Copy, Default, Serialize, Deserialize)]
+pub struct Location {
+    pub line: usize,
+    pub column: usize,
+}
+
+/// Source range covered by a single token, end is exclusive.
+#[derive(Debug, PartialEq, Clone, Copy, Default, Serialize, Deserialize)]
+pub struct TokenSpan {
+    pub start: Location,
+    pub end: Location,
+}
+
 #[derive(PartialEq)]
 enum CommentState {
     None,
@@ -55,10 +71,13 @@ enum TokenType {
 
 pub struct Tokenizer<T> {
     token_buffer: VecDeque<Token>,
+    span_buffer: VecDeque<TokenSpan>,
     reader: BufReader<T>,
     comment_state: CommentState,
     token_type: TokenType,
     now_token: String,
+    line: usize,
+    token_start: Location,
 }
 
 fn parse_keyword(k_str: &str) -> Option<KeywordType> {
@@ -136,25 +155,44 @@ where
 {
     pub fn new(path: &str) -> Tokenizer<File> {
         let file = File::open(path).unwrap();
-        let reader = BufReader::new(file);
+        Tokenizer::from_reader(BufReader::new(file))
+    }
+
+    fn from_reader(reader: BufReader<T>) -> Tokenizer<T> {
         Tokenizer {
             token_buffer: VecDeque::new(),
-            reader: reader,
+            span_buffer: VecDeque::new(),
+            reader,
             comment_state: CommentState::None,
             token_type: TokenType::None,
             now_token: String::new(),
+            line: 0,
+            token_start: Location::default(),
         }
     }
 
+    #[allow(dead_code)]
     pub fn advance(&mut self) -> Option<Token> {
+        self.advance_spanned().map(|(token, _)| token)
+    }
+
+    /// Same as `advance`, but also returns where the token sits in the source.
+    pub fn advance_spanned(&mut self) -> Option<(Token, TokenSpan)> {
         // read until there exists some tokens
-        while self.token_buffer.len() == 0 {
+        while self.token_buffer.is_empty() {
             let parsed = self.read_line();
             if parsed == Option::None {
                 return Option::None;
             }
         }
-        self.token_buffer.pop_front()
+        let token = self.token_buffer.pop_front()?;
+        let span = self.span_buffer.pop_front()?;
+        Some((token, span))
+    }
+
+    fn push_token(&mut self, token: Token, start: Location, end: Location) {
+        self.token_buffer.push_back(token);
+        self.span_buffer.push_back(TokenSpan { start, end });
     }
 
     fn read_line(&mut self) -> Option<usize> {
@@ -167,8 +205,18 @@ where
             return Option::None;
         }
         buf.push('\n');
+        self.line += 1;
+        let line = self.line;
         let mut last_char: Option<char> = None;
-        for (idx, c) in buf.char_indices() {
+        for (column, (idx, c)) in buf.char_indices().enumerate() {
+            let here = Location {
+                line,
+                column: column + 1,
+            };
+            let next = Location {
+                line,
+                column: column + 2,
+            };
             'end: loop {
                 match self.comment_state {
                     CommentState::Line => {
@@ -206,8 +254,8 @@ where
                             // string end
                             self.token_type = TokenType::None;
                             token_parsed += 1;
-                            self.token_buffer
-                                .push_back(Token::StringConst(mem::take(&mut self.now_token)));
+                            let token = Token::StringConst(mem::take(&mut self.now_token));
+                            self.push_token(token, self.token_start, next);
                         } else if c == '\\' {
                             // escape, do nothing
                             escaping = true;
@@ -220,28 +268,27 @@ where
                         if c == ' ' || c == '\n' || is_symbol(c){
                             // end of token
                             token_parsed += 1;
-                            self.token_buffer.push_back(
-                                parse_keyword(&self.now_token)
-                                    .map(|x| Token::Keyword(x))
-                                    .or_else(|| {
-                                        // try parse as int
-                                        self.now_token
-                                            .parse::<i64>()
-                                            .ok()
-                                            .map(|x| Token::IntConst(x))
-                                            .or_else(|| {
-                                                Some(Token::Identifier(mem::take(
-                                                    &mut self.now_token,
-                                                )))
-                                            })
-                                    })
-                                    .unwrap(),
-                            );
+                            let token = parse_keyword(&self.now_token)
+                                .map(|x| Token::Keyword(x))
+                                .or_else(|| {
+                                    // try parse as int
+                                    self.now_token
+                                        .parse::<i64>()
+                                        .ok()
+                                        .map(|x| Token::IntConst(x))
+                                        .or_else(|| {
+                                            Some(Token::Identifier(mem::take(
+                                                &mut self.now_token,
+                                            )))
+                                        })
+                                })
+                                .unwrap();
+                            self.push_token(token, self.token_start, here);
                             self.now_token = String::new();
                             self.token_type = TokenType::None;
                             if c != ' ' && c != '\n' {
                                 // special handling for symbols
-                                self.token_buffer.push_back(Token::Symbol(c));
+                                self.push_token(Token::Symbol(c), here, next);
                             }
                         } else {
                             // normal character
@@ -252,6 +299,7 @@ where
                         if c == '"' {
                             // string start
                             self.token_type = TokenType::String;
+                            self.token_start = here;
                         } else if c == ' ' || c == '\n' || c == '\t' {
                             // ignore
                         } else if c == '/' && last_char == Some('/') {
@@ -269,13 +317,14 @@ where
                                     .is_none())
                         {
                             token_parsed += 1;
-                            self.token_buffer.push_back(Token::Symbol(c));
+                            self.push_token(Token::Symbol(c), here, next);
                             self.token_type = TokenType::None;
                         } else if c == '/' {
                             // ignore
                         } else {
                             // normal character
                             self.token_type = TokenType::Others;
+                            self.token_start = here;
                             self.now_token.push(c);
                         }
                     }
@@ -292,35 +341,33 @@ where
     }
 }
 
+#[allow(dead_code)]
 pub fn tokenize(path: &str) -> Vec<Token> {
-    let mut tokenizer: Tokenizer<File> = Tokenizer::<File>::new(path);
-    let mut tokens = Vec::new();
-    loop {
-        let c = tokenizer.advance();
-        if c.is_none() {
-            break tokens;
-        }
-        tokens.push(c.unwrap());
-    }
+    tokenize_spanned(path).0
+}
+
+/// Tokenize a file, keeping the source span of every token next to it.
+pub fn tokenize_spanned(path: &str) -> (Vec<Token>, Vec<TokenSpan>) {
+    collect_tokens(Tokenizer::<File>::new(path))
 }
 
 #[allow(dead_code)]
 pub fn tokenize_str(s: &str) -> Vec<Token> {
-    let mut tokenizer = Tokenizer {
-        token_buffer: VecDeque::new(),
-        reader: BufReader::new(s.as_bytes()),
-        comment_state: CommentState::None,
-        token_type: TokenType::None,
-        now_token: String::new(),
-    };
+    tokenize_str_spanned(s).0
+}
+
+pub fn tokenize_str_spanned(s: &str) -> (Vec<Token>, Vec<TokenSpan>) {
+    collect_tokens(Tokenizer::from_reader(BufReader::new(s.as_bytes())))
+}
+
+fn collect_tokens<T: std::io::Read>(mut tokenizer: Tokenizer<T>) -> (Vec<Token>, Vec<TokenSpan>) {
     let mut tokens = Vec::new();
-    loop {
-        let c = tokenizer.advance();
-        if c.is_none() {
-            break tokens;
-        }
-        tokens.push(c.unwrap());
+    let mut spans = Vec::new();
+    while let Some((token, span)) = tokenizer.advance_spanned() {
+        tokens.push(token);
+        spans.push(span);
     }
+    (tokens, spans)
 }
 
 #[cfg(test)]
@@ -478,4 +525,18 @@ mod tests {
         assert_eq!(ret[4], Token::Identifier("e".to_string()));
         assert_eq!(ret[5], Token::Identifier("f".to_string()));
     }
+
+    #[test]
+    fn token_spans() {
+        let source = "class Main {\n  let s = \"hi\";\n}";
+        let (tokens, spans) = tokenize_str_spanned(source);
+        assert_eq!(tokens.len(), spans.len());
+        assert_eq!(tokens[1], Token::Identifier("Main".to_string()));
+        assert_eq!(spans[1].start, Location { line: 1, column: 7 });
+        assert_eq!(spans[1].end, Location { line: 1, column: 11 });
+        assert_eq!(tokens[6], Token::StringConst("hi".to_string()));
+        assert_eq!(spans[6].start, Location { line: 2, column: 11 });
+        assert_eq!(spans[6].end, Location { line: 2, column: 15 });
+        assert_eq!(spans[8].start, Location { line: 3, column: 1 });
+    }
 }
@@ -0,0 +1,558 @@
+// nand2tetris test scripts and compare files
+//
+// A `.tst` script is a list of commands ended by `,` or `;`, with `repeat n
+// { ... }` and `while out <> 75 { ... }` blocks. Only the structure is
+// parsed here; what each command means, and what a `while` compares, is up
+// to the emulator running the script. Output columns follow the
+// `name%F<left>.<width>.<right>` format of `output-list`, and rows are
+// compared cell by cell with a `.cmp` file, where `*` matches anything.
+
+use std::fmt::{self, Display};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Statement {
+    /// Words of one command, e.g. `["set", "a", "1"]`.
+    Command { words: Vec<String>, line: usize },
+    /// `repeat n { ... }`, or `repeat { ... }` forever when `count` is None.
+    Repeat {
+        count: Option<u64>,
+        body: Vec<Statement>,
+    },
+    /// `while <condition> { ... }`, checked before every round.
+    While {
+        condition: Condition,
+        body: Vec<Statement>,
+    },
+}
+
+/// The comparisons a `while` can make.
+const COMPARISONS: [&str; 6] = ["=", "<>", "<", ">", "<=", ">="];
+
+/// A `while` condition such as `out <> 75`, each side a value or a name for
+/// the emulator to look up.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Condition {
+    pub left: String,
+    pub comparison: String,
+    pub right: String,
+}
+
+impl Condition {
+    /// Whether the condition holds, `lookup` giving the value of a name.
+    pub fn holds(
+        &self,
+        mut lookup: impl FnMut(&str) -> Result<i64, String>,
+    ) -> Result<bool, String> {
+        let mut value = |side: &str| parse_value(side).or_else(|_| lookup(side));
+        let (left, right) = (value(&self.left)?, value(&self.right)?);
+        Ok(match self.comparison.as_str() {
+            "=" => left == right,
+            "<>" => left != right,
+            "<" => left < right,
+            ">" => left > right,
+            "<=" => left <= right,
+            _ => left >= right,
+        })
+    }
+}
+
+impl Display for Condition {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {} {}", self.left, self.comparison, self.right)
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct TstError {
+    pub line: usize,
+    pub message: String,
+}
+
+impl Display for TstError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+/// Split into words and the `,` `;` `{` `}` separators, dropping comments.
+/// A quoted string, as `echo` takes, is one word.
+fn tokenize(text: &str) -> Vec<(String, usize)> {
+    let mut tokens = vec![];
+    let mut in_block_comment = false;
+    for (number, line) in text.lines().enumerate() {
+        let mut rest = line;
+        let mut word = String::new();
+        let end_word = |word: &mut String, tokens: &mut Vec<(String, usize)>| {
+            if !word.is_empty() {
+                tokens.push((std::mem::take(word), number + 1));
+            }
+        };
+        while !rest.is_empty() {
+            if in_block_comment {
+                match rest.find("*/") {
+                    Some(end) => {
+                        rest = &rest[end + 2..];
+                        in_block_comment = false;
+                    }
+                    None => rest = "",
+                }
+            } else if rest.starts_with("//") {
+                rest = "";
+            } else if rest.starts_with("/*") {
+                rest = &rest[2..];
+                in_block_comment = true;
+            } else if rest.starts_with('"') {
+                let end = rest[1..].find('"').map_or(rest.len(), |end| end + 2);
+                word.push_str(&rest[..end]);
+                rest = &rest[end..];
+            } else {
+                let c = rest.chars().next().unwrap();
+                if c.is_whitespace() || ",;{}".contains(c) {
+                    end_word(&mut word, &mut tokens);
+                    if !c.is_whitespace() {
+                        tokens.push((c.to_string(), number + 1));
+                    }
+                } else {
+                    word.push(c);
+                }
+                rest = &rest[c.len_utf8()..];
+            }
+        }
+        end_word(&mut word, &mut tokens);
+    }
+    tokens
+}
+
+pub fn parse(text: &str) -> Result<Vec<Statement>, TstError> {
+    let tokens = tokenize(text);
+    let mut index = 0;
+    let statements = parse_block(&tokens, &mut index, false)?;
+    Ok(statements)
+}
+
+/// What a `{` opens, before its body is parsed.
+enum Block {
+    Repeat(Option<u64>),
+    While(Condition),
+}
+
+fn parse_block(
+    tokens: &[(String, usize)],
+    index: &mut usize,
+    nested: bool,
+) -> Result<Vec<Statement>, TstError> {
+    let mut statements = vec![];
+    let mut words: Vec<String> = vec![];
+    let mut line = 0;
+    while let Some((token, token_line)) = tokens.get(*index) {
+        *index += 1;
+        match token.as_str() {
+            "," | ";" => {
+                if !words.is_empty() {
+                    statements.push(Statement::Command {
+                        words: std::mem::take(&mut words),
+                        line,
+                    });
+                }
+            }
+            "{" => {
+                let error = |message: String| TstError {
+                    line: *token_line,
+                    message,
+                };
+                let block = match words.as_slice() {
+                    [repeat] if repeat == "repeat" => Block::Repeat(None),
+                    [repeat, n] if repeat == "repeat" => Block::Repeat(Some(
+                        n.parse()
+                            .map_err(|_| error(format!("bad repeat count {:?}", n)))?,
+                    )),
+                    [keyword, left, comparison, right] if keyword == "while" => {
+                        if !COMPARISONS.contains(&comparison.as_str()) {
+                            return Err(error(format!("bad comparison {:?}", comparison)));
+                        }
+                        Block::While(Condition {
+                            left: left.clone(),
+                            comparison: comparison.clone(),
+                            right: right.clone(),
+                        })
+                    }
+                    _ => {
+                        return Err(error(format!(
+                            "unexpected block after {:?}",
+                            words.join(" ")
+                        )))
+                    }
+                };
+                words.clear();
+                let body = parse_block(tokens, index, true)?;
+                statements.push(match block {
+                    Block::Repeat(count) => Statement::Repeat { count, body },
+                    Block::While(condition) => Statement::While { condition, body },
+                });
+            }
+            "}" if nested => {
+                if !words.is_empty() {
+                    statements.push(Statement::Command { words, line });
+                }
+                return Ok(statements);
+            }
+            "}" => {
+                return Err(TstError {
+                    line: *token_line,
+                    message: "unmatched }".to_string(),
+                })
+            }
+            _ => {
+                if words.is_empty() {
+                    line = *token_line;
+                }
+                words.push(token.clone());
+            }
+        }
+    }
+    if nested {
+        let line = tokens.last().map_or(0, |(_, line)| *line);
+        return Err(TstError {
+            line,
+            message: "missing }".to_string(),
+        });
+    }
+    if !words.is_empty() {
+        statements.push(Statement::Command { words, line });
+    }
+    Ok(statements)
+}
+
+/// A data row of the `.cmp` file and the cells that didn't match, as
+/// `(column, expected, actual)`.
+#[derive(Debug, PartialEq)]
+pub struct Row {
+    pub number: usize,
+    pub mismatches: Vec<(String, String, String)>,
+}
+
+/// One `output-list` entry such as `RAM[8000]%D2.6.1`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Column {
+    pub name: String,
+    /// `D` decimal, `B` binary, `X` hex or `S` string.
+    pub format: char,
+    pub left: usize,
+    pub width: usize,
+    pub right: usize,
+}
+
+impl Column {
+    pub fn parse(spec: &str) -> Result<Column, String> {
+        let Some((name, format)) = spec.split_once('%') else {
+            return Ok(Column {
+                name: spec.to_string(),
+                format: 'B',
+                left: 1,
+                width: 1,
+                right: 1,
+            });
+        };
+        let error = || format!("bad output format {:?}", spec);
+        let mut chars = format.chars();
+        let kind = chars
+            .next()
+            .filter(|c| "DBXS".contains(*c))
+            .ok_or_else(error)?;
+        let sizes: Vec<usize> = chars
+            .as_str()
+            .split('.')
+            .map(|n| n.parse().map_err(|_| error()))
+            .collect::<Result<_, _>>()?;
+        let [left, width, right] = sizes[..] else {
+            return Err(error());
+        };
+        Ok(Column {
+            name: name.to_string(),
+            format: kind,
+            left,
+            width,
+            right,
+        })
+    }
+
+    fn total(&self) -> usize {
+        self.left + self.width + self.right
+    }
+
+    /// The column name centred in the cell.
+    pub fn header(&self) -> String {
+        let total = self.total();
+        if self.name.len() >= total {
+            return self.name[..total].to_string();
+        }
+        let left = (total - self.name.len()) / 2;
+        format!(
+            "{:left$}{}{:right$}",
+            "",
+            self.name,
+            "",
+            right = total - left - self.name.len()
+        )
+    }
+
+    /// `value` as a cell, `bits` wide for binary and hex.
+    pub fn cell(&self, value: i64, bits: usize) -> String {
+        let text = match self.format {
+            'B' => format!("{:0width$b}", value & ((1 << bits) - 1), width = bits),
+            'X' => format!(
+                "{:0width$X}",
+                value & ((1 << bits) - 1),
+                width = bits.div_ceil(4)
+            ),
+            _ => value.to_string(),
+        };
+        let text = if text.len() > self.width {
+            text[text.len() - self.width..].to_string()
+        } else {
+            text
+        };
+        format!(
+            "{:l$}{:>w$}{:r$}",
+            "",
+            text,
+            "",
+            l = self.left,
+            w = self.width,
+            r = self.right
+        )
+    }
+    /// A `%S` cell, left-aligned: `time%S1.4.1` shows `3+` as ` 3+   `.
+    pub fn text_cell(&self, text: &str) -> String {
+        let text: String = text.chars().take(self.width).collect();
+        format!(
+            "{:l$}{:w$}{:r$}",
+            "",
+            text,
+            "",
+            l = self.left,
+            w = self.width,
+            r = self.right
+        )
+    }
+}
+
+pub fn header_line(columns: &[Column]) -> String {
+    let cells: Vec<String> = columns.iter().map(Column::header).collect();
+    format!("|{}|", cells.join("|"))
+}
+
+pub fn row_line(cells: &[String]) -> String {
+    format!("|{}|", cells.join("|"))
+}
+
+/// Cells of a `|a|b|` line, untrimmed.
+pub fn split_cells(line: &str) -> Vec<&str> {
+    let line = line.trim();
+    let line = line.strip_prefix('|').unwrap_or(line);
+    let line = line.strip_suffix('|').unwrap_or(line);
+    line.split('|').collect()
+}
+
+/// Whether an output cell matches an expected one, `*` matching any
+/// character. Surrounding spaces don't count.
+pub fn cell_matches(actual: &str, expected: &str) -> bool {
+    let matches = |actual: &str, expected: &str| {
+        actual.len() == expected.len()
+            && actual
+                .chars()
+                .zip(expected.chars())
+                .all(|(a, e)| e == '*' || a == e)
+    };
+    // `*******` stands for a whole padded cell, such as `      0`
+    matches(actual, expected) || matches(actual.trim(), expected.trim())
+}
+
+/// A `set` value: decimal, or binary, hex or decimal after `%B`, `%X` or
+/// `%D`.
+pub fn parse_value(text: &str) -> Result<i64, String> {
+    let (radix, digits) = match text.get(..2) {
+        Some("%B") => (2, &text[2..]),
+        Some("%X") => (16, &text[2..]),
+        Some("%D") => (10, &text[2..]),
+        _ => (10, text),
+    };
+    i64::from_str_radix(digits, radix).map_err(|_| format!("bad value {:?}", text))
+}
+
+/// Compare recorded lines with a `.cmp` file, header lines excluded.
+pub fn compare(lines: &[String], expected: &str) -> Vec<Row> {
+    let mut expected_lines = expected.lines().filter(|line| !line.trim().is_empty());
+    let names: Vec<String> = expected_lines
+        .next()
+        .map(|header| {
+            split_cells(header)
+                .iter()
+                .map(|name| name.trim().to_string())
+                .collect()
+        })
+        .unwrap_or_default();
+    expected_lines
+        .enumerate()
+        .map(|(index, expected)| {
+            let actual = lines.get(index + 1).map(|line| split_cells(line));
+            let mismatches = split_cells(expected)
+                .iter()
+                .enumerate()
+                .filter_map(|(column, cell)| {
+                    let got = actual
+                        .as_ref()
+                        .and_then(|cells| cells.get(column))
+                        .map_or("(missing)", |cell| cell);
+                    (!cell_matches(got, cell)).then(|| {
+                        let name = names.get(column).cloned().unwrap_or_default();
+                        (name, cell.trim().to_string(), got.trim().to_string())
+                    })
+                })
+                .collect();
+            Row {
+                number: index + 1,
+                mismatches,
+            }
+        })
+        .collect()
+}
+
+/// Print a line per row, with the mismatched cells of failing ones. True
+/// when every row passed.
+pub fn report(rows: &[Row]) -> bool {
+    for row in rows {
+        if row.mismatches.is_empty() {
+            println!("row {}: pass", row.number);
+            continue;
+        }
+        println!("row {}: FAIL", row.number);
+        for (column, expected, actual) in &row.mismatches {
+            println!("  {}: expected {}, got {}", column, expected, actual);
+        }
+    }
+    rows.iter().all(|row| row.mismatches.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_commands_and_repeats() {
+        let script = "// header\nload,\noutput-list RAM[8000]%D2.6.1;\n/* a\n block */ repeat 3 {\n  vmstep;\n}\noutput;";
+        let statements = parse(script).unwrap();
+        let command = |words: &[&str], line| Statement::Command {
+            words: words.iter().map(|word| word.to_string()).collect(),
+            line,
+        };
+        assert_eq!(
+            statements,
+            vec![
+                command(&["load"], 2),
+                command(&["output-list", "RAM[8000]%D2.6.1"], 3),
+                Statement::Repeat {
+                    count: Some(3),
+                    body: vec![command(&["vmstep"], 6)],
+                },
+                command(&["output"], 8),
+            ]
+        );
+        assert_eq!(
+            parse("echo \"Ready, steady\";").unwrap(),
+            vec![command(&["echo", "\"Ready, steady\""], 1)]
+        );
+        assert_eq!(
+            parse("repeat 2 { tick;").unwrap_err().to_string(),
+            "line 1: missing }"
+        );
+    }
+
+    #[test]
+    fn parses_and_checks_while_conditions() {
+        let statements = parse("while out <> %X4B {\n    eval,\n}\nclear-echo;").unwrap();
+        let condition = Condition {
+            left: "out".to_string(),
+            comparison: "<>".to_string(),
+            right: "%X4B".to_string(),
+        };
+        assert_eq!(
+            statements[0],
+            Statement::While {
+                condition: condition.clone(),
+                body: vec![Statement::Command {
+                    words: vec!["eval".to_string()],
+                    line: 2,
+                }],
+            }
+        );
+        assert_eq!(condition.to_string(), "out <> %X4B");
+        let out = |value| {
+            move |name: &str| match name {
+                "out" => Ok(value),
+                _ => Err(format!("no pin {}", name)),
+            }
+        };
+        assert_eq!(condition.holds(out(0)), Ok(true));
+        assert_eq!(condition.holds(out(75)), Ok(false));
+        let condition = Condition {
+            left: "-1".to_string(),
+            comparison: ">=".to_string(),
+            right: "in".to_string(),
+        };
+        assert_eq!(condition.holds(out(0)), Err("no pin in".to_string()));
+        assert_eq!(
+            parse("while out == 1 { eval; }").unwrap_err().to_string(),
+            "line 1: bad comparison \"==\""
+        );
+        assert_eq!(
+            parse("while out { eval; }").unwrap_err().to_string(),
+            "line 1: unexpected block after \"while out\""
+        );
+    }
+
+    #[test]
+    fn formats_columns_like_the_cmp_files() {
+        let column = Column::parse("RAM[8000]%D2.6.1").unwrap();
+        assert_eq!(column.header(), "RAM[8000]");
+        assert_eq!(column.cell(333, 16), "     333 ");
+        assert_eq!(column.cell(-18000, 16), "  -18000 ");
+        let column = Column::parse("out%B3.16.3").unwrap();
+        assert_eq!(column.header(), "         out          ");
+        assert_eq!(column.cell(-1, 16), "   1111111111111111   ");
+        assert_eq!(Column::parse("a").unwrap().cell(1, 1), " 1 ");
+        let column = Column::parse("time%S1.4.1").unwrap();
+        assert_eq!(column.header(), " time ");
+        assert_eq!(column.text_cell("12+"), " 12+  ");
+        assert!(Column::parse("a%Q1.1.1").is_err());
+        assert_eq!(split_cells("|   a  | 1 |"), vec!["   a  ", " 1 "]);
+        assert!(cell_matches("100", " 1*0 "));
+        assert!(!cell_matches(" 10 ", "100"));
+        assert!(cell_matches("      0", "*******"));
+        assert_eq!(parse_value("%B0001001000110100"), Ok(0x1234));
+        assert_eq!(parse_value("%XFFFF"), Ok(0xffff));
+        assert_eq!(parse_value("-32767"), Ok(-32767));
+        assert!(parse_value("%B12").is_err());
+    }
+
+    #[test]
+    fn compares_rows_with_wildcards() {
+        let lines = vec![
+            "|RAM[0]|RAM[1]|".to_string(),
+            "|   1 |   2 |".to_string(),
+            "|   3 |   5 |".to_string(),
+        ];
+        let rows = compare(
+            &lines,
+            "|RAM[0]|RAM[1]|\n|   1 |   * |\n|   3 |   4 |\n|   7 |   8 |\n",
+        );
+        assert_eq!(rows[0].mismatches, vec![]);
+        assert_eq!(
+            rows[1].mismatches,
+            vec![("RAM[1]".to_string(), "4".to_string(), "5".to_string())]
+        );
+        assert_eq!(rows[2].number, 3);
+        assert_eq!(rows[2].mismatches.len(), 2);
+        assert_eq!(rows[2].mismatches[0].2, "(missing)");
+    }
+}
@@ -0,0 +1,169 @@
+// Value Change Dump waveforms
+//
+// The text format GTKWave and other waveform viewers read: a header
+// declaring every signal with a short identifier, then `#time` stamps each
+// followed by the signals that changed, `1!` for a bit and `b101 "` for a
+// bus. Signals can be added as a run goes, such as a memory word when it is
+// first written, so the changes are kept until `finish` writes the header.
+
+/// A signal, in the order it was added.
+#[derive(Debug)]
+struct Signal {
+    name: String,
+    width: usize,
+    value: Option<u64>,
+}
+
+#[derive(Debug)]
+pub struct Vcd {
+    scope: String,
+    signals: Vec<Signal>,
+    /// Only signals these patterns match are recorded.
+    filter: Option<Vec<String>>,
+    changes: String,
+    time: Option<u64>,
+}
+
+impl Vcd {
+    /// A dump of the signals of `scope`, limited to those matching one of
+    /// the comma separated `filter` patterns, where `*` matches anything.
+    pub fn new(scope: &str, filter: Option<&str>) -> Vcd {
+        Vcd {
+            scope: scope.to_string(),
+            signals: vec![],
+            filter: filter.map(|filter| filter.split(',').map(|p| p.trim().to_string()).collect()),
+            changes: String::new(),
+            time: None,
+        }
+    }
+
+    /// Whether the signal `name` is recorded.
+    pub fn records(&self, name: &str) -> bool {
+        self.filter
+            .as_ref()
+            .is_none_or(|patterns| patterns.iter().any(|pattern| glob(pattern, name)))
+    }
+
+    /// Add a signal, returning its index, or `None` when the filter
+    /// leaves it out.
+    pub fn add(&mut self, name: &str, width: usize) -> Option<usize> {
+        if !self.records(name) {
+            return None;
+        }
+        self.signals.push(Signal {
+            name: name.to_string(),
+            width,
+            value: None,
+        });
+        Some(self.signals.len() - 1)
+    }
+
+    /// Record the value of a signal at `time`, which must not go backwards.
+    pub fn change(&mut self, time: u64, signal: usize, value: u64) {
+        let value = value & mask(self.signals[signal].width);
+        if self.signals[signal].value == Some(value) {
+            return;
+        }
+        if self.time != Some(time) {
+            self.changes += &format!("#{}\n", time);
+            self.time = Some(time);
+        }
+        let signal_ref = &mut self.signals[signal];
+        signal_ref.value = Some(value);
+        let id = identifier(signal);
+        if signal_ref.width == 1 {
+            self.changes += &format!("{}{}\n", value, id);
+        } else {
+            self.changes += &format!("b{:b} {}\n", value, id);
+        }
+    }
+
+    /// The whole dump. Signals added after the start show as unknown until
+    /// their first change.
+    pub fn finish(&self) -> String {
+        let mut text = String::from("$timescale 1 ns $end\n");
+        text += &format!("$scope module {} $end\n", self.scope);
+        for (index, signal) in self.signals.iter().enumerate() {
+            text += &format!(
+                "$var wire {} {} {} $end\n",
+                signal.width,
+                identifier(index),
+                signal.name
+            );
+        }
+        text += "$upscope $end\n$enddefinitions $end\n";
+        text += &self.changes;
+        text
+    }
+}
+
+fn mask(width: usize) -> u64 {
+    if width >= 64 {
+        u64::MAX
+    } else {
+        (1 << width) - 1
+    }
+}
+
+/// The short code of a signal: digits in base 94 of the printable
+/// characters `!` to `~`.
+fn identifier(mut index: usize) -> String {
+    let mut id = String::new();
+    loop {
+        id.push((b'!' + (index % 94) as u8) as char);
+        index /= 94;
+        if index == 0 {
+            return id;
+        }
+        index -= 1;
+    }
+}
+
+/// Whether `name` matches `pattern`, `*` matching any run of characters.
+fn glob(pattern: &str, name: &str) -> bool {
+    match pattern.split_once('*') {
+        None => pattern == name,
+        Some((prefix, rest)) => {
+            let Some(name) = name.strip_prefix(prefix) else {
+                return false;
+            };
+            (0..=name.len())
+                .filter(|start| name.is_char_boundary(*start))
+                .any(|start| glob(rest, &name[start..]))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dumps_changes_only() {
+        let mut vcd = Vcd::new("PC", Some("out,load,RAM*"));
+        let out = vcd.add("out", 16).unwrap();
+        let load = vcd.add("load", 1).unwrap();
+        assert_eq!(vcd.add("inc", 1), None);
+        vcd.change(0, out, 0);
+        vcd.change(0, load, 1);
+        vcd.change(1, out, 0);
+        vcd.change(2, out, 5);
+        let ram = vcd.add("RAM_16", 16).unwrap();
+        vcd.change(2, ram, u64::MAX);
+        assert_eq!(
+            vcd.finish(),
+            "$timescale 1 ns $end\n\
+             $scope module PC $end\n\
+             $var wire 16 ! out $end\n\
+             $var wire 1 \" load $end\n\
+             $var wire 16 # RAM_16 $end\n\
+             $upscope $end\n\
+             $enddefinitions $end\n\
+             #0\nb0 !\n1\"\n\
+             #2\nb101 !\nb1111111111111111 #\n"
+        );
+        assert_eq!(identifier(93), "~");
+        assert_eq!(identifier(94), "!!");
+        assert!(glob("*carry*", "c15carry") && !glob("a*b", "ab c"));
+    }
+}
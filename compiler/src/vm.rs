@@ -0,0 +1,444 @@
+// VM code generation from the three-address IR
+//
+// Temporaries normally never touch memory: when every temporary of a block is
+// used exactly once, in the order the stack would pop it, the defining
+// instructions are emitted right where their value is needed. Blocks that
+// break that discipline (as optimisations may produce) fall back to spilling
+// each temporary into an extra local slot.
+
+use std::collections::HashMap;
+
+use crate::ir::{
+    BasicBlock, BinOp, BlockId, Function, Instr, Operand, Place, Temp, Terminator, UnOp,
+};
+
+/// A VM command and the Jack line it was generated for, 0 where unknown.
+pub type MappedCommand = (String, u32);
+
+/// VM code for all functions of one class, in the order they are written to
+/// `Class.vm`.
+pub fn class_mapped(functions: &[Function]) -> Vec<MappedCommand> {
+    functions.iter().flat_map(function_to_vm_mapped).collect()
+}
+
+pub fn join_lines(lines: &[String]) -> String {
+    let mut code = lines.join("\n");
+    code.push('\n');
+    code
+}
+
+#[allow(dead_code)]
+pub fn function_to_vm(function: &Function) -> Vec<String> {
+    function_to_vm_mapped(function)
+        .into_iter()
+        .map(|(command, _)| command)
+        .collect()
+}
+
+pub fn function_to_vm_mapped(function: &Function) -> Vec<MappedCommand> {
+    let uses = use_counts(function);
+    let targets: Vec<BlockId> = function
+        .blocks
+        .iter()
+        .flat_map(|block| block.terminator.successors())
+        .collect();
+    let mut generator = Generator {
+        function,
+        uses,
+        slots: HashMap::new(),
+        line: function.line,
+        commands: vec![],
+    };
+    for (id, block) in function.blocks.iter().enumerate() {
+        let id = BlockId(id);
+        if targets.contains(&id) {
+            generator.line = block
+                .lines
+                .first()
+                .copied()
+                .unwrap_or(block.terminator_line);
+            generator.push(format!("label {}", id));
+        }
+        match tree_order(block, &generator.uses) {
+            true => generator.block_as_trees(block),
+            false => generator.block_spilled(block),
+        }
+        let next = BlockId(id.0 + 1);
+        generator.line = block.terminator_line;
+        generator.terminator(&block.terminator, next);
+    }
+    let locals = function.locals as usize + generator.slots.len();
+    let header = format!("function {} {}", function.name, locals);
+    let mut commands = vec![(header, function.line)];
+    commands.append(&mut generator.commands);
+    commands
+}
+
+fn use_counts(function: &Function) -> Vec<usize> {
+    let mut uses = vec![0; function.temps.len()];
+    for block in &function.blocks {
+        let operands = block
+            .instrs
+            .iter()
+            .flat_map(|instr| instr.operands())
+            .chain(block.terminator.operands());
+        for operand in operands {
+            if let Operand::Temp(temp) = operand {
+                uses[temp.0] += 1;
+            }
+        }
+    }
+    uses
+}
+
+/// Does emitting each value at its single use reproduce the block's
+/// instruction order exactly?
+fn tree_order(block: &BasicBlock, uses: &[usize]) -> bool {
+    let defs: HashMap<Temp, usize> = block
+        .instrs
+        .iter()
+        .enumerate()
+        .filter_map(|(i, instr)| instr.dst().map(|dst| (dst, i)))
+        .collect();
+    let mut order = vec![];
+    fn visit(
+        operands: &[Operand],
+        block: &BasicBlock,
+        defs: &HashMap<Temp, usize>,
+        uses: &[usize],
+        order: &mut Vec<usize>,
+    ) -> bool {
+        for operand in operands {
+            let Operand::Temp(temp) = operand else {
+                continue;
+            };
+            let Some(&def) = defs.get(temp) else {
+                return false;
+            };
+            if uses[temp.0] != 1 || !visit(&block.instrs[def].operands(), block, defs, uses, order)
+            {
+                return false;
+            }
+            order.push(def);
+        }
+        true
+    }
+    for (i, instr) in block.instrs.iter().enumerate() {
+        let is_root = instr.dst().is_none_or(|dst| uses[dst.0] == 0);
+        if is_root {
+            if !visit(&instr.operands(), block, &defs, uses, &mut order) {
+                return false;
+            }
+            order.push(i);
+        }
+    }
+    if !visit(&block.terminator.operands(), block, &defs, uses, &mut order) {
+        return false;
+    }
+    order.iter().copied().eq(0..block.instrs.len())
+}
+
+struct Generator<'a> {
+    function: &'a Function,
+    uses: Vec<usize>,
+    /// Local slot of every spilled temporary.
+    slots: HashMap<Temp, u16>,
+    /// Jack line of the instruction being generated.
+    line: u32,
+    commands: Vec<MappedCommand>,
+}
+
+impl Generator<'_> {
+    fn push(&mut self, command: impl Into<String>) {
+        self.commands.push((command.into(), self.line));
+    }
+
+    fn block_as_trees(&mut self, block: &BasicBlock) {
+        let defs: HashMap<Temp, (&Instr, u32)> = block
+            .instrs
+            .iter()
+            .zip(&block.lines)
+            .filter_map(|(instr, line)| instr.dst().map(|dst| (dst, (instr, *line))))
+            .collect();
+        for (instr, line) in block.instrs.iter().zip(&block.lines) {
+            if instr.dst().is_none_or(|dst| self.uses[dst.0] == 0) {
+                self.tree(instr, *line, &defs);
+            }
+        }
+        self.line = block.terminator_line;
+        for operand in block.terminator.operands() {
+            self.tree_operand(operand, &defs);
+        }
+    }
+
+    fn tree(&mut self, instr: &Instr, line: u32, defs: &HashMap<Temp, (&Instr, u32)>) {
+        for operand in instr.operands() {
+            self.line = line;
+            self.tree_operand(operand, defs);
+        }
+        self.line = line;
+        self.instr(instr);
+        if instr.dst().is_some_and(|dst| self.uses[dst.0] == 0) {
+            self.push("pop temp 0");
+        }
+    }
+
+    fn tree_operand(&mut self, operand: Operand, defs: &HashMap<Temp, (&Instr, u32)>) {
+        match operand {
+            Operand::Const(value) => self.constant(value),
+            Operand::Temp(temp) => {
+                let (instr, line) = defs[&temp];
+                self.tree(instr, line, defs)
+            }
+        }
+    }
+
+    fn block_spilled(&mut self, block: &BasicBlock) {
+        for (instr, line) in block.instrs.iter().zip(&block.lines) {
+            self.line = *line;
+            for operand in instr.operands() {
+                self.spilled_operand(operand);
+            }
+            self.instr(instr);
+            match instr.dst() {
+                Some(dst) if self.uses[dst.0] > 0 => {
+                    let slot = self.slot(dst);
+                    self.push(format!("pop local {}", slot));
+                }
+                Some(_) => self.push("pop temp 0"),
+                None => {}
+            }
+        }
+        self.line = block.terminator_line;
+        for operand in block.terminator.operands() {
+            self.spilled_operand(operand);
+        }
+    }
+
+    fn spilled_operand(&mut self, operand: Operand) {
+        match operand {
+            Operand::Const(value) => self.constant(value),
+            Operand::Temp(temp) => {
+                let slot = self.slot(temp);
+                self.push(format!("push local {}", slot));
+            }
+        }
+    }
+
+    fn slot(&mut self, temp: Temp) -> u16 {
+        let next = self.function.locals + self.slots.len() as u16;
+        *self.slots.entry(temp).or_insert(next)
+    }
+
+    fn constant(&mut self, value: i16) {
+        if value >= 0 {
+            self.push(format!("push constant {}", value));
+        } else if value == i16::MIN {
+            self.push("push constant 32767".to_string());
+            self.push("not".to_string());
+        } else {
+            self.push(format!("push constant {}", -value));
+            self.push("neg".to_string());
+        }
+    }
+
+    /// The instruction itself, its operands already on the stack.
+    fn instr(&mut self, instr: &Instr) {
+        match instr {
+            Instr::Load { place, .. } => self.push(format!("push {}", segment(place))),
+            Instr::Store { place, .. } => self.push(format!("pop {}", segment(place))),
+            Instr::Binary { op, .. } => self.push(
+                match op {
+                    BinOp::Mul => "call Math.multiply 2",
+                    BinOp::Div => "call Math.divide 2",
+                    op => op.name(),
+                }
+                .to_string(),
+            ),
+            Instr::Unary { op, .. } => self.push(
+                match op {
+                    UnOp::Neg => "neg",
+                    UnOp::Not => "not",
+                }
+                .to_string(),
+            ),
+            Instr::LoadIndirect { .. } => {
+                self.push("pop pointer 1".to_string());
+                self.push("push that 0".to_string());
+            }
+            Instr::StoreIndirect { .. } => {
+                self.push("pop temp 0".to_string());
+                self.push("pop pointer 1".to_string());
+                self.push("push temp 0".to_string());
+                self.push("pop that 0".to_string());
+            }
+            Instr::Call { function, args, .. } => {
+                self.push(format!("call {} {}", function, args.len()))
+            }
+            Instr::StringConst { value, .. } => {
+                self.push(format!("push constant {}", value.chars().count()));
+                self.push("call String.new 1".to_string());
+                for c in value.chars() {
+                    self.push(format!("push constant {}", c as u32));
+                    self.push("call String.appendChar 2".to_string());
+                }
+            }
+        }
+    }
+
+    fn terminator(&mut self, terminator: &Terminator, next: BlockId) {
+        match terminator {
+            Terminator::Jump(target) => {
+                if *target != next {
+                    self.push(format!("goto {}", target));
+                }
+            }
+            Terminator::Branch {
+                then_block,
+                else_block,
+                ..
+            } => {
+                self.push(format!("if-goto {}", then_block));
+                if *else_block != next {
+                    self.push(format!("goto {}", else_block));
+                }
+            }
+            Terminator::Return(_) => self.push("return".to_string()),
+        }
+    }
+}
+
+fn segment(place: &Place) -> String {
+    match place {
+        Place::Local(index) => format!("local {}", index),
+        Place::Argument(index) => format!("argument {}", index),
+        Place::Field(index) => format!("this {}", index),
+        Place::Static(index) => format!("static {}", index),
+        Place::This => "pointer 0".to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ir::{FunctionKind, Ty};
+
+    fn function(blocks: Vec<BasicBlock>, temps: Vec<Ty>) -> Function {
+        Function {
+            name: "Main.f".to_string(),
+            kind: FunctionKind::Function,
+            args: 1,
+            locals: 1,
+            fields: 0,
+            statics: 0,
+            temps,
+            blocks,
+            line: 0,
+        }
+    }
+
+    #[test]
+    fn emits_expression_trees() {
+        // return 1 - x
+        let f = function(
+            vec![BasicBlock::new(
+                vec![
+                    Instr::Load {
+                        dst: Temp(0),
+                        place: Place::Argument(0),
+                    },
+                    Instr::Binary {
+                        dst: Temp(1),
+                        op: BinOp::Sub,
+                        lhs: Operand::Const(1),
+                        rhs: Operand::Temp(Temp(0)),
+                    },
+                ],
+                Terminator::Return(Operand::Temp(Temp(1))),
+            )],
+            vec![Ty::Int, Ty::Int],
+        );
+        assert_eq!(
+            function_to_vm(&f),
+            vec![
+                "function Main.f 1",
+                "push constant 1",
+                "push argument 0",
+                "sub",
+                "return"
+            ]
+        );
+    }
+
+    #[test]
+    fn spills_reused_temps() {
+        // %0 = load argument 0; return %0 * %0
+        let f = function(
+            vec![BasicBlock::new(
+                vec![
+                    Instr::Load {
+                        dst: Temp(0),
+                        place: Place::Argument(0),
+                    },
+                    Instr::Binary {
+                        dst: Temp(1),
+                        op: BinOp::Mul,
+                        lhs: Operand::Temp(Temp(0)),
+                        rhs: Operand::Temp(Temp(0)),
+                    },
+                ],
+                Terminator::Return(Operand::Temp(Temp(1))),
+            )],
+            vec![Ty::Int, Ty::Int],
+        );
+        assert_eq!(
+            function_to_vm(&f),
+            vec![
+                "function Main.f 3",
+                "push argument 0",
+                "pop local 1",
+                "push local 1",
+                "push local 1",
+                "call Math.multiply 2",
+                "pop local 2",
+                "push local 2",
+                "return"
+            ]
+        );
+    }
+
+    #[test]
+    fn branches_fall_through() {
+        let f = function(
+            vec![
+                BasicBlock::new(
+                    vec![],
+                    Terminator::Branch {
+                        cond: Operand::Const(-1),
+                        then_block: BlockId(2),
+                        else_block: BlockId(1),
+                    },
+                ),
+                BasicBlock::new(vec![], Terminator::Jump(BlockId(0))),
+                BasicBlock::new(vec![], Terminator::Return(Operand::Const(i16::MIN))),
+            ],
+            vec![],
+        );
+        assert_eq!(
+            function_to_vm(&f),
+            vec![
+                "function Main.f 1",
+                "label b0",
+                "push constant 1",
+                "neg",
+                "if-goto b2",
+                "label b1",
+                "goto b0",
+                "label b2",
+                "push constant 32767",
+                "not",
+                "return"
+            ]
+        );
+    }
+}
@@ -0,0 +1,671 @@
+// interpreter for Jack VM code
+//
+// Runs `.vm` commands directly on a Hack-sized RAM using the standard memory
+// layout (SP, LCL, ARG, THIS, THAT in RAM[0..5], temp at 5, statics from 16,
+// stack from 256, screen at 16384, keyboard at 24576). Call frames are laid
+// out in RAM exactly like the VM translator does, so programs observe the
+// same memory as on the Hack CPU; a shadow call stack is kept for debugging.
+
+use std::collections::HashMap;
+use std::fmt::{self, Display};
+
+use crate::keyboard::{KeyPlayer, KEYBOARD};
+use crate::os::{Native, NativeOs};
+
+pub const RAM_SIZE: usize = 32768;
+pub const SP: usize = 0;
+pub const LCL: usize = 1;
+pub const ARG: usize = 2;
+pub const THIS: usize = 3;
+pub const THAT: usize = 4;
+pub const TEMP: usize = 5;
+pub const STATIC: usize = 16;
+pub const STACK: usize = 256;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Segment {
+    Constant,
+    Local,
+    Argument,
+    This,
+    That,
+    Pointer,
+    Temp,
+    Static,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArithOp {
+    Add,
+    Sub,
+    Neg,
+    Eq,
+    Gt,
+    Lt,
+    And,
+    Or,
+    Not,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Command {
+    Push(Segment, u16),
+    Pop(Segment, u16),
+    Arith(ArithOp),
+    Label,
+    Goto(usize),
+    IfGoto(usize),
+    Function { name: String, locals: u16 },
+    Call { function: String, args: u16 },
+    Return,
+}
+
+/// Where a command came from: index into `Program::files` and its 1-based
+/// line in that `.vm` file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Origin {
+    pub file: usize,
+    pub line: usize,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct VmFile {
+    /// File stem, which is also the prefix of its static variables.
+    pub name: String,
+    pub static_base: usize,
+    pub static_count: usize,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Program {
+    pub commands: Vec<Command>,
+    pub origins: Vec<Origin>,
+    pub files: Vec<VmFile>,
+    /// Index of the `function` command of every function.
+    pub functions: HashMap<String, usize>,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct LoadError {
+    pub file: String,
+    pub line: usize,
+    pub message: String,
+}
+
+impl Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}.vm:{}: {}", self.file, self.line, self.message)
+    }
+}
+
+fn parse_segment(name: &str) -> Option<Segment> {
+    Some(match name {
+        "constant" => Segment::Constant,
+        "local" => Segment::Local,
+        "argument" => Segment::Argument,
+        "this" => Segment::This,
+        "that" => Segment::That,
+        "pointer" => Segment::Pointer,
+        "temp" => Segment::Temp,
+        "static" => Segment::Static,
+        _ => return None,
+    })
+}
+
+fn parse_arith(name: &str) -> Option<ArithOp> {
+    Some(match name {
+        "add" => ArithOp::Add,
+        "sub" => ArithOp::Sub,
+        "neg" => ArithOp::Neg,
+        "eq" => ArithOp::Eq,
+        "gt" => ArithOp::Gt,
+        "lt" => ArithOp::Lt,
+        "and" => ArithOp::And,
+        "or" => ArithOp::Or,
+        "not" => ArithOp::Not,
+        _ => return None,
+    })
+}
+
+impl Program {
+    /// Parse and link `.vm` sources given as `(file stem, text)`. Statics are
+    /// allocated per file in the given order.
+    pub fn load(sources: &[(String, String)]) -> Result<Program, LoadError> {
+        let mut program = Program::default();
+        // branch targets are resolved once the function's labels are known
+        let mut pending: Vec<(usize, String, String)> = vec![];
+        let mut labels: HashMap<(String, String), usize> = HashMap::new();
+        let mut next_static = STATIC;
+        for (name, text) in sources {
+            let file = program.files.len();
+            let error = |line: usize, message: String| LoadError {
+                file: name.clone(),
+                line,
+                message,
+            };
+            let mut function = String::new();
+            let mut static_count = 0;
+            for (number, line) in text.lines().enumerate() {
+                let number = number + 1;
+                let code = line.split("//").next().unwrap_or("");
+                let words: Vec<&str> = code.split_whitespace().collect();
+                if words.is_empty() {
+                    continue;
+                }
+                let index = |word: &str| {
+                    word.parse::<u16>()
+                        .map_err(|_| error(number, format!("bad index {:?}", word)))
+                };
+                let command = match words.as_slice() {
+                    [op @ ("push" | "pop"), segment, i] => {
+                        let segment = parse_segment(segment)
+                            .ok_or_else(|| error(number, format!("unknown segment {}", segment)))?;
+                        let i = index(i)?;
+                        let limit = match segment {
+                            Segment::Pointer => 2,
+                            Segment::Temp => 8,
+                            Segment::Constant => 32768,
+                            _ => u16::MAX as u32 + 1,
+                        };
+                        if i as u32 >= limit {
+                            return Err(error(number, format!("index {} out of range", i)));
+                        }
+                        if segment == Segment::Static {
+                            static_count = static_count.max(i as usize + 1);
+                        }
+                        if *op == "push" {
+                            Command::Push(segment, i)
+                        } else if segment == Segment::Constant {
+                            return Err(error(number, "cannot pop into constant".to_string()));
+                        } else {
+                            Command::Pop(segment, i)
+                        }
+                    }
+                    [op] if parse_arith(op).is_some() => Command::Arith(parse_arith(op).unwrap()),
+                    ["label", label] => {
+                        let key = (function.clone(), label.to_string());
+                        if labels.insert(key, program.commands.len()).is_some() {
+                            return Err(error(number, format!("label {} defined twice", label)));
+                        }
+                        Command::Label
+                    }
+                    [op @ ("goto" | "if-goto"), label] => {
+                        pending.push((program.commands.len(), function.clone(), label.to_string()));
+                        if *op == "goto" {
+                            Command::Goto(0)
+                        } else {
+                            Command::IfGoto(0)
+                        }
+                    }
+                    ["function", name, locals] => {
+                        function = name.to_string();
+                        let previous = program
+                            .functions
+                            .insert(function.clone(), program.commands.len());
+                        if previous.is_some() {
+                            return Err(error(number, format!("function {} defined twice", name)));
+                        }
+                        Command::Function {
+                            name: function.clone(),
+                            locals: index(locals)?,
+                        }
+                    }
+                    ["call", name, args] => Command::Call {
+                        function: name.to_string(),
+                        args: index(args)?,
+                    },
+                    ["return"] => Command::Return,
+                    _ => return Err(error(number, format!("unknown command {:?}", code.trim()))),
+                };
+                program.commands.push(command);
+                program.origins.push(Origin { file, line: number });
+            }
+            program.files.push(VmFile {
+                name: name.clone(),
+                static_base: next_static,
+                static_count,
+            });
+            next_static += static_count;
+        }
+        for (at, function, label) in pending {
+            let Some(&target) = labels.get(&(function.clone(), label.clone())) else {
+                let origin = program.origins[at];
+                return Err(LoadError {
+                    file: program.files[origin.file].name.clone(),
+                    line: origin.line,
+                    message: format!("unknown label {} in {}", label, function),
+                });
+            };
+            match &mut program.commands[at] {
+                Command::Goto(to) | Command::IfGoto(to) => *to = target,
+                _ => unreachable!(),
+            }
+        }
+        Ok(program)
+    }
+
+    /// Name of the function containing command `pc`.
+    pub fn function_at(&self, pc: usize) -> Option<&str> {
+        self.commands[..=pc.min(self.commands.len().checked_sub(1)?)]
+            .iter()
+            .rev()
+            .find_map(|command| match command {
+                Command::Function { name, .. } => Some(name.as_str()),
+                _ => None,
+            })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Frame {
+    pub function: String,
+    /// Index of the `call` command that created the frame.
+    pub call_pc: usize,
+    pub arg: usize,
+    pub lcl: usize,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct RunError {
+    pub pc: usize,
+    pub message: String,
+}
+
+impl Display for RunError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "at command {}: {}", self.pc, self.message)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum State {
+    Running,
+    /// The entry function returned or the program spins on `goto` to itself.
+    Halted,
+}
+
+pub struct VmEmulator {
+    pub program: Program,
+    pub ram: Vec<i16>,
+    pub pc: usize,
+    pub frames: Vec<Frame>,
+    pub steps: u64,
+    pub state: State,
+    /// Drives the keyboard register, counting cycles in VM commands.
+    pub keys: KeyPlayer,
+    /// OS classes run natively instead of as VM code.
+    pub os: NativeOs,
+    /// The `steps` count VM code that native code calls must finish by.
+    pub step_limit: u64,
+}
+
+impl VmEmulator {
+    /// Start at `Sys.init` when the program has one, else at `Main.main`.
+    #[allow(dead_code)]
+    pub fn new(program: Program) -> Result<VmEmulator, RunError> {
+        VmEmulator::with_os(program, NativeOs::default())
+    }
+
+    /// Like `new`, with the classes of `os` running natively. A native `Sys`
+    /// initialises the OS classes the way `Sys.init` does and then starts at
+    /// `Main.main`.
+    pub fn with_os(program: Program, os: NativeOs) -> Result<VmEmulator, RunError> {
+        let native_sys = os.has_class("Sys");
+        let entry = ["Sys.init", "Main.main"]
+            .into_iter()
+            .filter(|name| !(native_sys && *name == "Sys.init"))
+            .find(|name| program.functions.contains_key(*name))
+            .ok_or_else(|| RunError {
+                pc: 0,
+                message: "no Sys.init or Main.main to start from".to_string(),
+            })?;
+        let mut emulator = VmEmulator {
+            pc: program.functions[entry],
+            program,
+            ram: vec![0; RAM_SIZE],
+            frames: vec![],
+            steps: 0,
+            state: State::Running,
+            keys: KeyPlayer::default(),
+            os,
+            step_limit: u64::MAX,
+        };
+        emulator.ram[SP] = STACK as i16;
+        emulator.ram[LCL] = STACK as i16;
+        emulator.ram[ARG] = STACK as i16;
+        emulator.frames.push(Frame {
+            function: entry.to_string(),
+            call_pc: usize::MAX,
+            arg: STACK,
+            lcl: STACK,
+        });
+        emulator.init_native_classes();
+        if native_sys {
+            for class in ["Memory", "Math", "Screen", "Output", "Keyboard"] {
+                let init = format!("{}.init", class);
+                if emulator.os.provides(&init) || emulator.program.functions.contains_key(&init) {
+                    emulator.call_function(&init, &[])?;
+                }
+            }
+        }
+        Ok(emulator)
+    }
+
+    pub(crate) fn error(&self, message: String) -> RunError {
+        RunError {
+            pc: self.pc,
+            message,
+        }
+    }
+
+    pub(crate) fn address(&self, value: i32) -> Result<usize, RunError> {
+        if (0..RAM_SIZE as i32).contains(&value) {
+            Ok(value as usize)
+        } else {
+            Err(self.error(format!("address {} out of range", value)))
+        }
+    }
+
+    pub fn read(&self, address: usize) -> i16 {
+        self.ram[address]
+    }
+
+    pub(crate) fn push(&mut self, value: i16) -> Result<(), RunError> {
+        let sp = self.address(self.ram[SP] as i32)?;
+        self.ram[sp] = value;
+        self.ram[SP] = self.ram[SP].wrapping_add(1);
+        Ok(())
+    }
+
+    pub(crate) fn pop(&mut self) -> Result<i16, RunError> {
+        let sp = self.address(self.ram[SP] as i32 - 1)?;
+        if sp < STACK {
+            return Err(self.error("stack underflow".to_string()));
+        }
+        self.ram[SP] = sp as i16;
+        Ok(self.ram[sp])
+    }
+
+    fn segment_address(&self, segment: Segment, index: u16) -> Result<usize, RunError> {
+        let index = index as i32;
+        let base = |pointer: usize| self.ram[pointer] as u16 as i32;
+        match segment {
+            Segment::Local => self.address(base(LCL) + index),
+            Segment::Argument => self.address(base(ARG) + index),
+            Segment::This => self.address(base(THIS) + index),
+            Segment::That => self.address(base(THAT) + index),
+            Segment::Pointer => Ok(THIS + index as usize),
+            Segment::Temp => Ok(TEMP + index as usize),
+            Segment::Static => {
+                let file = &self.program.files[self.program.origins[self.pc].file];
+                Ok(file.static_base + index as usize)
+            }
+            Segment::Constant => unreachable!(),
+        }
+    }
+
+    /// Execute one command.
+    pub fn step(&mut self) -> Result<State, RunError> {
+        if self.state == State::Halted {
+            return Ok(State::Halted);
+        }
+        let Some(command) = self.program.commands.get(self.pc).cloned() else {
+            return Err(self.error("ran past the last command".to_string()));
+        };
+        if let Some(key) = self.keys.poll(self.steps) {
+            self.ram[KEYBOARD] = key;
+        }
+        self.steps += 1;
+        let mut next = self.pc + 1;
+        match command {
+            Command::Push(Segment::Constant, value) => self.push(value as i16)?,
+            Command::Push(segment, index) => {
+                let address = self.segment_address(segment, index)?;
+                self.push(self.ram[address])?;
+            }
+            Command::Pop(segment, index) => {
+                let address = self.segment_address(segment, index)?;
+                let value = self.pop()?;
+                self.ram[address] = value;
+            }
+            Command::Arith(op) => {
+                let y = self.pop()?;
+                let truth = |b: bool| if b { -1 } else { 0 };
+                let value = match op {
+                    ArithOp::Neg => y.wrapping_neg(),
+                    ArithOp::Not => !y,
+                    _ => {
+                        let x = self.pop()?;
+                        match op {
+                            ArithOp::Add => x.wrapping_add(y),
+                            ArithOp::Sub => x.wrapping_sub(y),
+                            ArithOp::Eq => truth(x == y),
+                            ArithOp::Gt => truth(x > y),
+                            ArithOp::Lt => truth(x < y),
+                            ArithOp::And => x & y,
+                            ArithOp::Or => x | y,
+                            ArithOp::Neg | ArithOp::Not => unreachable!(),
+                        }
+                    }
+                };
+                self.push(value)?;
+            }
+            Command::Label => {}
+            Command::Goto(target) => {
+                if target == self.pc {
+                    self.state = State::Halted;
+                }
+                next = target;
+            }
+            Command::IfGoto(target) => {
+                if self.pop()? != 0 {
+                    next = target;
+                }
+            }
+            Command::Function { locals, .. } => {
+                for _ in 0..locals {
+                    self.push(0)?;
+                }
+            }
+            Command::Call { function, args } if self.os.provides(&function) => {
+                let sp = self.ram[SP] as u16 as usize;
+                let base = sp
+                    .checked_sub(args as usize)
+                    .filter(|base| *base >= STACK)
+                    .ok_or_else(|| self.error("stack underflow".to_string()))?;
+                let values = self.ram[base..sp].to_vec();
+                match self.call_native(&function, &values)? {
+                    Native::Return(value) => {
+                        self.ram[SP] = base as i16;
+                        self.push(value)?;
+                    }
+                    // run the call again next cycle
+                    Native::Block => next = self.pc,
+                }
+                if self.state == State::Halted {
+                    return Ok(State::Halted);
+                }
+            }
+            Command::Call { function, args } => next = self.enter(function, args, next)?,
+            Command::Return => {
+                let frame = self.ram[LCL] as u16 as usize;
+                let value = self.pop()?;
+                let return_address = self.ram[self.address(frame as i32 - 5)?];
+                let arg = self.ram[ARG] as u16 as usize;
+                let result = self.address(arg as i32)?;
+                self.ram[result] = value;
+                self.ram[SP] = (arg + 1) as i16;
+                for (offset, pointer) in [(1, THAT), (2, THIS), (3, ARG), (4, LCL)] {
+                    self.ram[pointer] = self.ram[self.address(frame as i32 - offset)?];
+                }
+                self.frames.pop();
+                if self.frames.is_empty() {
+                    self.state = State::Halted;
+                    return Ok(State::Halted);
+                }
+                next = return_address as u16 as usize;
+            }
+        }
+        self.pc = next;
+        Ok(self.state)
+    }
+
+    /// Push a frame for a call to `function` whose `args` are on the stack
+    /// and return the command it starts at.
+    fn enter(
+        &mut self,
+        function: String,
+        args: u16,
+        return_address: usize,
+    ) -> Result<usize, RunError> {
+        let Some(&target) = self.program.functions.get(&function) else {
+            return Err(self.error(format!("call to unknown function {}", function)));
+        };
+        let sp = self.ram[SP] as u16 as usize;
+        let arg = sp
+            .checked_sub(args as usize)
+            .ok_or_else(|| self.error("stack underflow".to_string()))?;
+        self.push(return_address as i16)?;
+        for pointer in [LCL, ARG, THIS, THAT] {
+            self.push(self.ram[pointer])?;
+        }
+        self.ram[ARG] = arg as i16;
+        self.ram[LCL] = self.ram[SP];
+        self.frames.push(Frame {
+            function,
+            call_pc: self.pc,
+            arg,
+            lcl: self.ram[SP] as u16 as usize,
+        });
+        Ok(target)
+    }
+
+    /// Call `function` to completion from native code and return its
+    /// result. The call runs as part of the current command.
+    pub fn call_function(&mut self, function: &str, args: &[i16]) -> Result<i16, RunError> {
+        if self.os.provides(function) {
+            return match self.call_native(function, args)? {
+                Native::Return(value) => Ok(value),
+                Native::Block => {
+                    Err(self.error(format!("{} can't wait inside a native call", function)))
+                }
+            };
+        }
+        for &arg in args {
+            self.push(arg)?;
+        }
+        let pc = self.pc;
+        let depth = self.frames.len();
+        self.pc = self.enter(function.to_string(), args.len() as u16, pc)?;
+        while self.frames.len() > depth {
+            if self.steps >= self.step_limit {
+                return Err(self.error(format!(
+                    "ran out of steps in {}, called from native code",
+                    function
+                )));
+            }
+            if self.step()? == State::Halted {
+                return Ok(0);
+            }
+        }
+        self.pc = pc;
+        self.pop()
+    }
+
+    /// Run until the program halts or `max_steps` commands have executed,
+    /// those of VM code that native code calls included.
+    pub fn run(&mut self, max_steps: u64) -> Result<State, RunError> {
+        self.step_limit = self.steps.saturating_add(max_steps);
+        while self.steps < self.step_limit {
+            if self.step()? == State::Halted {
+                return Ok(State::Halted);
+            }
+        }
+        Ok(self.state)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn load(sources: &[(&str, &str)]) -> Program {
+        let sources: Vec<(String, String)> = sources
+            .iter()
+            .map(|(name, text)| (name.to_string(), text.to_string()))
+            .collect();
+        Program::load(&sources).unwrap()
+    }
+
+    #[test]
+    fn runs_calls_and_loops() {
+        // Main.main stores sum(1..=4) in static 0 through a helper
+        let program = load(&[(
+            "Main",
+            "function Main.main 0
+            push constant 4
+            call Main.sum 1
+            pop static 0
+            push constant 0
+            return
+            function Main.sum 1
+            label LOOP
+            push argument 0
+            if-goto BODY
+            push local 0
+            return
+            label BODY
+            push local 0
+            push argument 0
+            add
+            pop local 0
+            push argument 0
+            push constant 1
+            sub
+            pop argument 0
+            goto LOOP",
+        )]);
+        let mut emulator = VmEmulator::new(program).unwrap();
+        assert_eq!(emulator.run(10_000), Ok(State::Halted));
+        assert_eq!(emulator.read(STATIC), 10);
+        assert!(emulator.frames.is_empty());
+    }
+
+    #[test]
+    fn gives_each_file_its_statics() {
+        let program = load(&[
+            (
+                "A",
+                "function A.f 0\npush constant 1\npop static 0\npush constant 0\nreturn",
+            ),
+            (
+                "Main",
+                "function Main.main 0\npush constant 2\npop static 0\ncall A.f 0\nreturn",
+            ),
+        ]);
+        let mut emulator = VmEmulator::new(program).unwrap();
+        emulator.run(100).unwrap();
+        assert_eq!(emulator.read(STATIC), 1);
+        assert_eq!(emulator.read(STATIC + 1), 2);
+    }
+
+    #[test]
+    fn reports_errors() {
+        let sources = vec![(
+            "Main".to_string(),
+            "function Main.main 0\ngoto END".to_string(),
+        )];
+        let error = Program::load(&sources).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Main.vm:2: unknown label END in Main.main"
+        );
+        let program = load(&[("Main", "function Main.main 0\ncall Foo.bar 0")]);
+        let mut emulator = VmEmulator::new(program).unwrap();
+        let error = emulator.run(10).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "at command 1: call to unknown function Foo.bar"
+        );
+    }
+}
@@ -0,0 +1,92 @@
+// polling file watcher
+//
+// Compares the modification time and size of the files with one extension
+// in a directory against the previous poll. Polling needs nothing from the
+// OS, so it works on any machine and over network mounts.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+type Stamp = (Option<SystemTime>, u64);
+
+pub struct Watcher {
+    dir: PathBuf,
+    extension: String,
+    /// Files seen on the last poll, `None` before the first.
+    seen: Option<BTreeMap<PathBuf, Stamp>>,
+}
+
+impl Watcher {
+    pub fn new(dir: &Path, extension: &str) -> Watcher {
+        Watcher {
+            dir: dir.to_path_buf(),
+            extension: extension.to_string(),
+            seen: None,
+        }
+    }
+
+    fn scan(&self) -> BTreeMap<PathBuf, Stamp> {
+        let Ok(entries) = fs::read_dir(&self.dir) else {
+            return BTreeMap::new();
+        };
+        entries
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.path())
+            .filter(|path| path.extension().is_some_and(|ext| *ext == *self.extension))
+            .filter_map(|path| {
+                let metadata = fs::metadata(&path).ok()?;
+                Some((path, (metadata.modified().ok(), metadata.len())))
+            })
+            .collect()
+    }
+
+    /// Files added, changed or removed since the last poll, sorted. The
+    /// first poll reports every file.
+    pub fn poll(&mut self) -> Vec<PathBuf> {
+        let current = self.scan();
+        let changed = match &self.seen {
+            None => current.keys().cloned().collect(),
+            Some(seen) => {
+                let mut changed: Vec<PathBuf> = current
+                    .iter()
+                    .filter(|(path, stamp)| seen.get(*path) != Some(stamp))
+                    .map(|(path, _)| path.clone())
+                    .chain(
+                        seen.keys()
+                            .filter(|path| !current.contains_key(*path))
+                            .cloned(),
+                    )
+                    .collect();
+                changed.sort();
+                changed
+            }
+        };
+        self.seen = Some(current);
+        changed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reports_added_changed_and_removed_files() {
+        let dir = std::env::temp_dir().join(format!("watch-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("A.jack"), "class A {}").unwrap();
+        fs::write(dir.join("notes.txt"), "").unwrap();
+        let mut watcher = Watcher::new(&dir, "jack");
+        assert_eq!(watcher.poll(), vec![dir.join("A.jack")]);
+        assert!(watcher.poll().is_empty());
+        fs::write(dir.join("A.jack"), "class A { }").unwrap();
+        fs::write(dir.join("B.jack"), "class B {}").unwrap();
+        fs::write(dir.join("notes.txt"), "ignored").unwrap();
+        assert_eq!(watcher.poll(), vec![dir.join("A.jack"), dir.join("B.jack")]);
+        fs::remove_file(dir.join("B.jack")).unwrap();
+        assert_eq!(watcher.poll(), vec![dir.join("B.jack")]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
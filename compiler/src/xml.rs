@@ -1,12 +1,76 @@
 // xml backend for Jack language
 
-use crate::parser::{self, expressions, structures::{ReturnType, VarTypeEnum}, Node};
+use crate::{
+    backend::Backend,
+    parser::{self, elements, expressions, structures::{ReturnType, VarTypeEnum}, Node},
+    tokenizer::KeywordType,
+};
 use xml::writer::{EventWriter, XmlEvent};
 
-pub fn convert_node(node: Node) -> String {
+pub struct Xml;
+
+impl Backend for Xml {
+    fn convert_node(&self, node: &Node) -> String {
+        convert_node(node)
+    }
+}
+
+pub fn convert_node(node: &Node) -> String {
     let res = match node {
+        Node::Keyword(keyword) => wrap_xml("keyword", keyword_name(keyword.0)),
+        Node::Symbol(symbol) => convert_symbol(symbol),
+        Node::IntegerConstant(int) => {
+            wrap_xml("integerConstant", int.0.to_string().leak())
+        }
+        Node::StringConstant(string) => wrap_xml("stringConstant", &string.0),
+        Node::Identifier(identifier) => wrap_xml("identifier", &identifier.0),
         Node::Class(ref class) => convert_class(class),
-        _ => unimplemented!(),
+        Node::ClassVarDec(class_var_dec) => convert_class_var_dec(class_var_dec),
+        Node::VarDec(var_dec) => convert_var_dec(var_dec),
+        Node::SubroutineDec(subroutine_dec) => convert_subroutine_dec(subroutine_dec),
+        Node::ParameterList(param_list) => convert_parameter_list(param_list),
+        Node::SubroutineBody(body) => convert_subroutine_body(body),
+        Node::Statements(statements) => convert_statements(statements),
+        Node::LetStatement(let_statement) => convert_let_statement(let_statement),
+        Node::IfStatement(if_statement) => convert_if_statement(if_statement),
+        Node::WhileStatement(while_statement) => convert_while_statement(while_statement),
+        Node::DoStatement(do_statement) => convert_do_statement(do_statement),
+        Node::ReturnStatement(return_statement) => convert_return_statement(return_statement),
+        Node::Expression(exp) => convert_expression(exp),
+        Node::Term(term) => convert_term(term),
+        Node::ArrayTerm(array_term) => convert_array_term(array_term),
+        Node::WrappedExpression(exp) => {
+            let mut res = wrap_xml("symbol", "(");
+            res.extend(convert_expression(&exp.0));
+            res.extend(wrap_xml("symbol", ")"));
+            res
+        }
+        Node::SubroutineCall(call) => convert_subroutine_call(call),
+        Node::Op(op) => convert_symbol(&op.0),
+        Node::UnaryOp(op) => convert_symbol(&op.0),
+        Node::KeywordConstant(keyword) => convert_keyword_constant(keyword),
+        Node::VarName(name) => wrap_xml("identifier", &name.0),
+        Node::ClassName(name) => wrap_xml("identifier", &name.0),
+        Node::SubroutineName(name) => wrap_xml("identifier", &name.0),
+        Node::VarType(var_type) => convert_vartype(&var_type.0),
+        Node::ClassVarDecType(var_dec_type) => wrap_xml(
+            "keyword",
+            match var_dec_type {
+                parser::structures::ClassVarDecType::STATIC => "static",
+                parser::structures::ClassVarDecType::FIELD => "field",
+            },
+        ),
+        Node::SubroutineType(subroutine_type) => {
+            wrap_xml("keyword", subroutine_type_name(subroutine_type))
+        }
+        Node::ReturnType(return_type) => convert_return_type(return_type),
+        Node::LetLHS(lhs) => convert_let_lhs(lhs),
+        Node::UnaryTerm(uterm) => {
+            let mut res = convert_symbol(&uterm.unary_op.0);
+            res.extend(convert_term(&uterm.term));
+            res
+        }
+        Node::ExpressionList(exp_list) => convert_expression_list(exp_list),
     };
     let mut output: Vec<u8> = Vec::new();
     let mut writer =
@@ -18,6 +82,79 @@ pub fn convert_node(node: Node) -> String {
     String::from_utf8(output).unwrap()
 }
 
+fn keyword_name(keyword: KeywordType) -> &'static str {
+    match keyword {
+        KeywordType::CLASS => "class",
+        KeywordType::METHOD => "method",
+        KeywordType::FUNCTION => "function",
+        KeywordType::CONSTRUCTOR => "constructor",
+        KeywordType::INT => "int",
+        KeywordType::BOOLEAN => "boolean",
+        KeywordType::CHAR => "char",
+        KeywordType::VOID => "void",
+        KeywordType::VAR => "var",
+        KeywordType::STATIC => "static",
+        KeywordType::FIELD => "field",
+        KeywordType::LET => "let",
+        KeywordType::DO => "do",
+        KeywordType::IF => "if",
+        KeywordType::ELSE => "else",
+        KeywordType::WHILE => "while",
+        KeywordType::RETURN => "return",
+        KeywordType::TRUE => "true",
+        KeywordType::FALSE => "false",
+        KeywordType::NULL => "null",
+        KeywordType::THIS => "this",
+    }
+}
+
+fn subroutine_type_name(subroutine_type: &parser::structures::SubroutineType) -> &'static str {
+    match subroutine_type {
+        parser::structures::SubroutineType::CONSTRUCTOR => "constructor",
+        parser::structures::SubroutineType::FUNCTION => "function",
+        parser::structures::SubroutineType::METHOD => "method",
+    }
+}
+
+fn convert_symbol(symbol: &elements::Symbol) -> Vec<XmlEvent<'_>> {
+    wrap_xml("symbol", symbol.0.to_string().leak())
+}
+
+fn convert_keyword_constant(keyword: &expressions::KeywordConstant) -> Vec<XmlEvent<'_>> {
+    wrap_xml(
+        "keyword",
+        match keyword {
+            parser::expressions::KeywordConstant::TRUE => "true",
+            parser::expressions::KeywordConstant::FALSE => "false",
+            parser::expressions::KeywordConstant::NULL => "null",
+            parser::expressions::KeywordConstant::THIS => "this",
+        },
+    )
+}
+
+fn convert_array_term(array_term: &expressions::ArrayTerm) -> Vec<XmlEvent<'_>> {
+    let mut res = vec![];
+    res.extend(wrap_xml("identifier", &array_term.var_name.0));
+    res.extend(wrap_xml("symbol", "["));
+    res.extend(convert_expression(&array_term.expression));
+    res.extend(wrap_xml("symbol", "]"));
+    res
+}
+
+fn convert_return_type(return_type: &ReturnType) -> Vec<XmlEvent<'_>> {
+    match return_type {
+        ReturnType::VOID => wrap_xml("keyword", "void"),
+        ReturnType::VARTYPE(vartype) => convert_vartype(&vartype.0),
+    }
+}
+
+fn convert_let_lhs(lhs: &parser::statements::LetLHS) -> Vec<XmlEvent<'_>> {
+    match lhs {
+        parser::statements::LetLHS::VarName(name) => wrap_xml("identifier", &name.0),
+        parser::statements::LetLHS::ArrayTerm(term) => convert_array_term(term),
+    }
+}
+
 fn wrap_xml<'a>(tag: &'a str, content: &'a str) -> Vec<XmlEvent<'a>> {
     let mut res = vec![];
     res.push(XmlEvent::start_element(tag).into());
@@ -93,20 +230,9 @@ fn convert_subroutine_dec(subroutine_dec: &parser::structures::SubroutineDec) ->
     res.push(XmlEvent::start_element("subroutineDec").into());
     res.extend(wrap_xml(
         "keyword",
-        match subroutine_dec.subroutine_type {
-            parser::structures::SubroutineType::CONSTRUCTOR => "constructor",
-            parser::structures::SubroutineType::FUNCTION => "function",
-            parser::structures::SubroutineType::METHOD => "method",
-        },
+        subroutine_type_name(&subroutine_dec.subroutine_type),
     ));
-    match &subroutine_dec.return_type {
-        ReturnType::VOID => {
-            res.extend(wrap_xml("keyword", "void"));
-        }
-        ReturnType::VARTYPE(vartype) => {
-            res.extend(convert_vartype(&vartype.0));
-        }
-    }
+    res.extend(convert_return_type(&subroutine_dec.return_type));
     res.extend(wrap_xml("identifier", &subroutine_dec.subroutine_name.0));
     res.extend(wrap_xml("symbol", "("));
     res.extend(convert_parameter_list(&subroutine_dec.parameter_list));
@@ -149,26 +275,13 @@ fn convert_term<'a>(term: &'a expressions::Term) -> Vec<XmlEvent<'a>> {
             res.extend(wrap_xml("stringConstant", &string.0));
         }
         expressions::Term::KeywordConstant(keyword) => {
-            res.extend(wrap_xml(
-                "keyword",
-                match keyword {
-                    parser::expressions::KeywordConstant::TRUE => "true",
-                    parser::expressions::KeywordConstant::FALSE => "false",
-                    parser::expressions::KeywordConstant::NULL => "null",
-                    parser::expressions::KeywordConstant::THIS => "this",
-                },
-            ));
+            res.extend(convert_keyword_constant(keyword));
         }
         expressions::Term::VarName(name) => {
             res.extend(wrap_xml("identifier", &name.0));
         }
         expressions::Term::ArrayTerm(ref array_term) => {
-            let name = &array_term.var_name;
-            let exp = &array_term.expression;
-            res.extend(wrap_xml("identifier", &name.0));
-            res.extend(wrap_xml("symbol", "["));
-            res.extend(convert_expression(exp));
-            res.extend(wrap_xml("symbol", "]"));
+            res.extend(convert_array_term(array_term));
         }
         expressions::Term::SubroutineCall(subroutine_call) => {
             res.extend(convert_subroutine_call(subroutine_call));
@@ -306,17 +419,7 @@ fn convert_let_statement(let_statement: &parser::statements::LetStatement) -> Ve
     let mut res = vec![];
     res.push(XmlEvent::start_element("letStatement").into());
     res.extend(wrap_xml("keyword", "let"));
-    match &let_statement.let_lhs {
-        parser::statements::LetLHS::VarName(name) => {
-            res.extend(wrap_xml("identifier", &name.0));
-        }
-        parser::statements::LetLHS::ArrayTerm(term) => {
-            res.extend(wrap_xml("identifier", &term.var_name.0));
-            res.extend(wrap_xml("symbol", "["));
-            res.extend(convert_expression(&term.expression));
-            res.extend(wrap_xml("symbol", "]"));
-        }
-    }
+    res.extend(convert_let_lhs(&let_statement.let_lhs));
     res.extend(wrap_xml("symbol", "="));
     res.extend(convert_expression(&let_statement.let_rhs));
     res.extend(wrap_xml("symbol", ";"));
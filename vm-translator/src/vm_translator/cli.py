@@ -5,7 +5,42 @@ from vm_translator import compiler
 
 @click.command()
 @click.argument("path", type=click.Path(exists=True))
-def main(path: str):
+@click.option(
+    "--optimize-size",
+    is_flag=True,
+    help="Share call/return/comparison code and run the peephole optimiser.",
+)
+@click.option("--size-report", is_flag=True, help="Print ROM usage per VM function.")
+@click.option(
+    "--link",
+    "link_output",
+    type=click.Path(dir_okay=False),
+    help="Link PATH and the OS into this single .vm or .asm file, dropping "
+    "unreachable functions, with a .map of function sizes next to it.",
+)
+@click.option(
+    "--os",
+    "os_path",
+    type=click.Path(exists=True),
+    help="Directory or .vm file of OS classes to link against.",
+)
+def main(
+    path: str,
+    optimize_size: bool,
+    size_report: bool,
+    link_output: str | None,
+    os_path: str | None,
+):
+    if link_output is not None:
+        click.echo(f"Linking {path}")
+        compiler.link(
+            Path(path),
+            Path(os_path) if os_path is not None else None,
+            Path(link_output),
+            optimize_size=optimize_size,
+        )
+        click.echo("Done")
+        return
     click.echo(f"Translating {path}")
-    compiler.compile(Path(path))
+    compiler.compile(Path(path), optimize_size=optimize_size, size_report=size_report)
     click.echo("Done")
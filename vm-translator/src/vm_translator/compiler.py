@@ -1,33 +1,152 @@
 import click
-from vm_translator import hack, parser
+from vm_translator import hack, linker, parser, peephole, sourcemap
 from vm_translator import model
 from pathlib import Path
 
+# translated assembly and the Jack location it came from
+Chunk = tuple[str, model.Origin]
 
-def compile(input_path: Path):
-    ctx = model.Context()
+
+def compile(input_path: Path, optimize_size: bool = False, size_report: bool = False):
+    ctx = model.Context(shared_routines=optimize_size)
     translator = hack.Translator(ctx)
-    output = translator.bootstrap()
+    # generated code grouped by the VM function it belongs to, in output order
+    sections: list[tuple[str, list[Chunk]]] = [
+        ("(bootstrap)", [(translator.bootstrap(), None)])
+    ]
+    if optimize_size:
+        sections.append(("(runtime)", [(translator.runtime(), None)]))
     if input_path.is_dir():
         # find all files under such dir
-        files = input_path.glob("*.vm")
-        for file in files:
-            output += compileFile(ctx, file, translator)
-        with open(input_path / f"all.asm", "w") as f:
-            f.write(output)
+        files = sorted(input_path.glob("*.vm"))
+        output_path = input_path / f"all.asm"
     else:
-        output += compileFile(ctx, input_path, translator)
-        with open(input_path.with_suffix(".asm"), "w") as f:
-            f.write(output)
+        files = [input_path]
+        output_path = input_path.with_suffix(".asm")
+    for file in files:
+        sections += compileFile(ctx, file, translator)
+    if optimize_size:
+        sections = [
+            (name, [(line + "\n", origin) for line, origin in peephole.optimize(chunks)])
+            for name, chunks in sections
+        ]
+    with open(output_path, "w") as f:
+        f.write("".join(asm for _, chunks in sections for asm, _ in chunks))
+    if any(file.with_suffix(".vm.map").exists() for file in files):
+        write_rom_map(output_path.with_suffix(".asm.map"), sections)
+    if size_report:
+        print_size_report(sections)
 
 
 def compileFile(ctx: model.Context, input_path: Path, translator):
+    map_path = input_path.with_suffix(".vm.map")
+    vm_map = sourcemap.read(map_path) if map_path.exists() else []
     with open(input_path, "r") as f:
         ctx.filename = input_path.stem
         cmds = parser.parse(ctx, f.read())
-        output = ""
+        sections: list[tuple[str, list[Chunk]]] = []
         for cmd in cmds:
             if cmd.getName() == "function":
                 ctx.function_name = cmd.function_name
-            output += translator.translate(cmd)
-        return output
+                sections.append((cmd.function_name, []))
+            if not sections:
+                sections.append((f"({ctx.filename})", []))
+            origin = sourcemap.lookup(vm_map, cmd.source_line)
+            sections[-1][1].append((translator.translate(cmd), origin))
+        return sections
+
+
+def link(
+    input_path: Path,
+    os_path: Path | None,
+    output_path: Path,
+    optimize_size: bool = False,
+):
+    """Link the program and the OS into `output_path`, a .vm or .asm file,
+    and list the surviving functions and their sizes in a .map next to it."""
+
+    def vm_files(path: Path) -> list[Path]:
+        return sorted(path.glob("*.vm")) if path.is_dir() else [path]
+
+    os_files = vm_files(os_path) if os_path is not None else []
+    try:
+        functions = linker.link(vm_files(input_path), os_files)
+        if output_path.suffix == ".asm":
+            sections = translate_functions(functions, optimize_size)
+            with open(output_path, "w") as f:
+                f.write("".join(asm for _, chunks in sections for asm, _ in chunks))
+            sizes = [
+                (name, sum(peephole.count_instructions(asm) for asm, _ in chunks))
+                for name, chunks in sections
+            ]
+        else:
+            text, sizes = linker.bundle(functions)
+            with open(output_path, "w") as f:
+                f.write(text)
+    except linker.LinkError as error:
+        for message in error.messages:
+            click.echo(message, err=True)
+        raise SystemExit(1)
+    linker.write_map(output_path.with_suffix(".map"), sizes)
+    click.echo(f"Linked {len(functions)} functions into {output_path}")
+
+
+def translate_functions(
+    functions: list[linker.Function], optimize_size: bool
+) -> list[tuple[str, list[Chunk]]]:
+    """Assembly for linked functions after the bootstrap code. Each keeps
+    the name of its own file, so statics stay apart as in `compile`."""
+    if not any(function.name == "Sys.init" for function in functions):
+        raise linker.LinkError(["the bootstrap code needs a Sys.init"])
+    ctx = model.Context(shared_routines=optimize_size)
+    translator = hack.Translator(ctx)
+    sections: list[tuple[str, list[Chunk]]] = [
+        ("(bootstrap)", [(translator.bootstrap(), None)])
+    ]
+    if optimize_size:
+        sections.append(("(runtime)", [(translator.runtime(), None)]))
+    for function in functions:
+        ctx.filename = function.file
+        source = "\n".join(text for _, text in function.commands)
+        chunks: list[Chunk] = []
+        for cmd in parser.parse(ctx, source):
+            if cmd.getName() == "function":
+                ctx.function_name = cmd.function_name
+            chunks.append((translator.translate(cmd), None))
+        sections.append((function.name, chunks))
+    if optimize_size:
+        sections = [
+            (name, [(line + "\n", origin) for line, origin in peephole.optimize(chunks)])
+            for name, chunks in sections
+        ]
+    return sections
+
+
+def write_rom_map(path: Path, sections: list[tuple[str, list[Chunk]]]):
+    """Carry the VM source maps forward to the ROM address of every chunk."""
+    entries: list[tuple[int, model.Origin]] = []
+    address = 0
+    for _, chunks in sections:
+        for asm, origin in chunks:
+            size = peephole.count_instructions(asm)
+            if size == 0:
+                continue
+            if len(entries) == 0 or entries[-1][1] != origin:
+                entries.append((address, origin))
+            address += size
+    sourcemap.write(path, entries)
+
+
+def print_size_report(sections: list[tuple[str, list[Chunk]]]):
+    """ROM words used by each VM function, largest first."""
+    sizes = [
+        (name, sum(peephole.count_instructions(asm) for asm, _ in chunks))
+        for name, chunks in sections
+    ]
+    total = sum(size for _, size in sizes)
+    click.echo(f"{'function':<40} {'words':>7} {'share':>7}")
+    for name, size in sorted(sizes, key=lambda item: (-item[1], item[0])):
+        click.echo(f"{name:<40} {size:>7} {size * 100 / max(total, 1):>6.1f}%")
+    click.echo(f"{'total':<40} {total:>7} {'':>7}")
+    if total > 32768:
+        click.echo(f"does not fit in the 32K ROM by {total - 32768} words")
@@ -60,6 +60,8 @@ M=D;
 """
 
     def opt_logical(self, comp: str):
+        if self.ctx.shared_routines:
+            return self.jump_to_routine(f"__vm_{comp[1:].lower()}")
         label_end = self.gen_label()
         label_true = self.gen_label()
         return self.opt_two_args(
@@ -76,6 +78,17 @@ D=-1;
         )
 
 
+    def jump_to_routine(self, routine: str) -> str:
+        """Jump to a shared routine with the return address in D."""
+        label_ret = self.gen_label()
+        return f"""@{label_ret}
+D=A
+@{routine}
+0;JMP
+({label_ret})
+"""
+
+
 class Translator:
     def __init__(self, ctx: model.Context):
         self.ctx = ctx
@@ -142,9 +155,85 @@ M=D
             output += f"{self.helper.stpush()}\n"
         return output
 
+    def runtime(self) -> str:
+        """Shared routines the calls, returns and comparisons jump to."""
+        output = f"""(__vm_call)
+{self.push_frame()}
+@R13
+D=M
+@SP
+D=M-D
+@ARG
+M=D
+@SP
+D=M
+@LCL
+M=D
+@R14
+A=M
+0;JMP
+(__vm_return)
+{self.frame_teardown()}
+"""
+        for comp in ["JEQ", "JGT", "JLT"]:
+            routine = f"__vm_{comp[1:].lower()}"
+            output += f"""({routine})
+@R15
+M=D
+@SP
+AM=M-1
+D=M
+A=A-1
+D=M-D
+M=0
+@{routine}_true
+D;{comp}
+@R15
+A=M
+0;JMP
+({routine}_true)
+@SP
+A=M-1
+M=-1
+@R15
+A=M
+0;JMP
+"""
+        return output
+
+    def push_frame(self) -> str:
+        """Push the return address held in D and the caller's segment pointers."""
+        return f"""{self.helper.stpush()}
+@LCL
+D=M
+{self.helper.stpush()}
+@ARG
+D=M
+{self.helper.stpush()}
+@THIS
+D=M
+{self.helper.stpush()}
+@THAT
+D=M
+{self.helper.stpush()}"""
+
     def translate_call(self, cmd: model.C_CALL) -> str:
         ret_id = self.ctx.function_ret_counter[cmd.function_name]
         self.ctx.function_ret_counter[cmd.function_name] = ret_id + 1
+        if self.ctx.shared_routines:
+            return f"""{self.helper.constval(5 + cmd.num_args)}
+@R13
+M=D
+@{cmd.function_name}
+D=A
+@R14
+M=D
+@{cmd.function_name}$ret.{ret_id}
+D=A
+@__vm_call
+0;JMP
+({cmd.function_name}$ret.{ret_id})
+"""
         return f"""@{cmd.function_name}$ret.{ret_id}
 D=A
 {self.helper.stpush()}
@@ -175,6 +264,11 @@ M=D
 """
 
     def translate_return(self, cmd: model.C_RETURN) -> str:
+        if self.ctx.shared_routines:
+            return "@__vm_return\n0;JMP\n"
+        return self.frame_teardown()
+
+    def frame_teardown(self) -> str:
         return f"""
 {self.helper.stpop()}
 @R15
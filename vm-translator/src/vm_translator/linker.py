@@ -0,0 +1,124 @@
+"""Link the .vm files of a program and its OS into one program: every call
+is resolved, functions unreachable from the entry points are dropped, and the
+survivors are written as a single .vm file or translated to .asm. Classes of
+the program replace OS classes of the same name."""
+
+from collections import deque
+from dataclasses import dataclass, field
+from pathlib import Path
+
+ENTRY_POINTS = ["Sys.init", "Main.main"]
+
+
+@dataclass
+class Function:
+    name: str
+    # stem of the .vm file the function came from
+    file: str
+    # (line in the .vm file, command with comments stripped)
+    commands: list[tuple[int, str]] = field(default_factory=list)
+
+    def calls(self) -> list[tuple[int, str]]:
+        return [
+            (line, words[1])
+            for line, words in ((line, text.split()) for line, text in self.commands)
+            if words[0] == "call" and len(words) > 1
+        ]
+
+
+class LinkError(Exception):
+    def __init__(self, messages: list[str]):
+        super().__init__("\n".join(messages))
+        self.messages = messages
+
+
+def read_functions(path: Path) -> list[Function]:
+    functions: list[Function] = []
+    with open(path, "r") as f:
+        for number, line in enumerate(f, start=1):
+            text = line.split("//")[0].strip()
+            if not text:
+                continue
+            words = text.split()
+            if words[0] == "function" and len(words) > 1:
+                functions.append(Function(words[1], path.stem))
+            elif not functions:
+                raise LinkError([f"{path.name}:{number}: code outside a function"])
+            functions[-1].commands.append((number, text))
+    return functions
+
+
+def link(files: list[Path], os_files: list[Path]) -> list[Function]:
+    """Functions reachable from Sys.init and Main.main, in input order.
+    Raises a LinkError listing every unresolved call and duplicate."""
+    own = {file.stem for file in files}
+    inputs = files + [file for file in os_files if file.stem not in own]
+    errors: list[str] = []
+    functions: dict[str, Function] = {}
+    for file in inputs:
+        for function in read_functions(file):
+            if function.name in functions:
+                first = functions[function.name].file
+                errors.append(
+                    f"{file.name}: {function.name} is already defined in {first}.vm"
+                )
+                continue
+            functions[function.name] = function
+    for function in functions.values():
+        for line, target in function.calls():
+            if target not in functions:
+                errors.append(f"{function.file}.vm:{line}: call to undefined {target}")
+    entries = [name for name in ENTRY_POINTS if name in functions]
+    if not entries:
+        errors.append("no Sys.init or Main.main to start from")
+    if errors:
+        raise LinkError(errors)
+    reachable = set(entries)
+    queue = deque(entries)
+    while queue:
+        for _, target in functions[queue.popleft()].calls():
+            if target not in reachable:
+                reachable.add(target)
+                queue.append(target)
+    return [function for function in functions.values() if function.name in reachable]
+
+
+def bundle(functions: list[Function]) -> tuple[str, list[tuple[str, int]]]:
+    """One .vm text for the linked functions and the size of each in VM
+    commands. A single file has a single static segment, so each class's
+    statics are moved past those of the classes before it."""
+    bases: dict[str, int] = {}
+    next_base = 0
+    for function in functions:
+        if function.file not in bases:
+            bases[function.file] = next_base
+            next_base += static_count(functions, function.file)
+    if next_base > 240:
+        raise LinkError([f"{next_base} statics do not fit in RAM[16..255]"])
+    lines: list[str] = []
+    sizes: list[tuple[str, int]] = []
+    for function in functions:
+        for _, text in function.commands:
+            words = text.split()
+            if words[0] in ("push", "pop") and words[1] == "static":
+                words[2] = str(bases[function.file] + int(words[2]))
+            lines.append(" ".join(words))
+        sizes.append((function.name, len(function.commands)))
+    return "".join(line + "\n" for line in lines), sizes
+
+
+def static_count(functions: list[Function], file: str) -> int:
+    indices = [
+        int(words[2])
+        for function in functions
+        if function.file == file
+        for words in (text.split() for _, text in function.commands)
+        if words[0] in ("push", "pop") and words[1] == "static"
+    ]
+    return max(indices, default=-1) + 1
+
+
+def write_map(path: Path, sizes: list[tuple[str, int]]):
+    with open(path, "w") as f:
+        for name, size in sizes:
+            f.write(f"{name} {size}\n")
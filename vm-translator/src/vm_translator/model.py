@@ -6,6 +6,10 @@ import pathlib
 from typing import ClassVar, Type
 
 
+# Jack file and line a piece of code was generated from, if known
+Origin = tuple[str, int] | None
+
+
 @dataclass
 class Context:
     filename: str | None = None
@@ -14,10 +18,14 @@ class Context:
     function_ret_counter: dict[str, int] = field(
         default_factory=lambda: defaultdict(int)
     )
+    # jump to shared call/return/comparison routines instead of inlining them
+    shared_routines: bool = False
 
 
 class ICommand:
     name: ClassVar[str]
+    # line of the command in its .vm file, 0 when not parsed from one
+    source_line: int = 0
 
     def __init__(self, ctx: Context):
         self.ctx = ctx
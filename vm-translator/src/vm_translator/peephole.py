@@ -0,0 +1,80 @@
+import re
+from vm_translator import model
+
+# a push of D immediately followed by a pop into D
+PUSH_POP = ["@SP", "A=M", "M=D", "@SP", "M=M+1", "@SP", "M=M-1", "A=M", "D=M"]
+NUMBER = re.compile(r"@(\d+)$")
+
+
+def normalize(asm: str) -> list[str]:
+    """One instruction or label per line, without blanks or stray `;`."""
+    lines = []
+    for line in asm.splitlines():
+        comment_start = line.find("//")
+        if comment_start != -1:
+            line = line[:comment_start]
+        line = line.replace(" ", "").strip().rstrip(";")
+        if line:
+            lines.append(line)
+    return lines
+
+
+def is_instruction(line: str) -> bool:
+    return not line.startswith("(")
+
+
+def count_instructions(asm: str) -> int:
+    return sum(1 for line in normalize(asm) if is_instruction(line))
+
+
+# an instruction and the Jack location it came from
+Line = tuple[str, model.Origin]
+
+
+def step(lines: list[Line]) -> list[Line]:
+    out: list[Line] = []
+
+    def last(n: int) -> list[str]:
+        return [text for text, _ in out[-n:]]
+
+    for line, origin in lines:
+        out.append((line, origin))
+        # push D; pop D leaves D as it was
+        if last(len(PUSH_POP)) == PUSH_POP:
+            del out[-len(PUSH_POP) :]
+            continue
+        # @a; D=A; @b; D=D+A with constant a and b is @(a+b); D=A
+        if len(out) >= 4 and last(4)[1] == "D=A" and line == "D=D+A":
+            a, b = NUMBER.match(out[-4][0]), NUMBER.match(out[-2][0])
+            if a and b:
+                first = out[-4][1]
+                out[-4:] = [(f"@{int(a.group(1)) + int(b.group(1))}", first), ("D=A", first)]
+                continue
+        # A already equals D
+        if len(out) >= 2 and last(2) == ["D=A", "A=D"]:
+            out.pop()
+            continue
+        # the first of two address loads has no effect
+        if len(out) >= 2 and out[-2][0].startswith("@") and line.startswith("@"):
+            del out[-2]
+            continue
+        # jump to the very next instruction
+        if (
+            line.startswith("(")
+            and len(out) >= 3
+            and last(3)[:2] == [f"@{line[1:-1]}", "0;JMP"]
+        ):
+            del out[-3:-1]
+    return out
+
+
+def optimize(chunks: list[tuple[str, model.Origin]]) -> list[Line]:
+    """Repeat the peephole rules until the code stops shrinking. Takes
+    translated chunks of assembly and returns single instructions, each
+    keeping the origin of the chunk it came from."""
+    lines = [(line, origin) for asm, origin in chunks for line in normalize(asm)]
+    while True:
+        optimized = step(lines)
+        if optimized == lines:
+            return lines
+        lines = optimized
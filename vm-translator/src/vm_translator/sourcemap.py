@@ -0,0 +1,37 @@
+"""Source maps in the compiler's format: one `<position> <file> <line>` or
+`<position> -` entry per line, each holding until the next one. Positions are
+VM line numbers in a `.vm.map` and ROM addresses in an `.asm.map`."""
+
+from bisect import bisect_right
+from pathlib import Path
+from vm_translator import model
+
+
+def read(path: Path) -> list[tuple[int, model.Origin]]:
+    entries: list[tuple[int, model.Origin]] = []
+    with open(path, "r") as f:
+        for number, line in enumerate(f, start=1):
+            fields = line.split()
+            if len(fields) == 0:
+                continue
+            if len(fields) == 2 and fields[1] == "-":
+                entries.append((int(fields[0]), None))
+            elif len(fields) == 3:
+                entries.append((int(fields[0]), (fields[1], int(fields[2]))))
+            else:
+                raise ValueError(f"{path}:{number}: malformed source map entry")
+    return entries
+
+
+def lookup(entries: list[tuple[int, model.Origin]], position: int) -> model.Origin:
+    index = bisect_right(entries, position, key=lambda entry: entry[0])
+    return entries[index - 1][1] if index > 0 else None
+
+
+def write(path: Path, entries: list[tuple[int, model.Origin]]):
+    with open(path, "w") as f:
+        for position, origin in entries:
+            if origin is None:
+                f.write(f"{position} -\n")
+            else:
+                f.write(f"{position} {origin[0]} {origin[1]}\n")
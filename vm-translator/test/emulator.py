@@ -0,0 +1,169 @@
+"""Just enough of the Hack CPU emulator to run the fixtures' .tst scripts:
+`set RAM[n] v`, `repeat n { ticktock; }`, `output-list` and `output`, with
+the rows compared against the .cmp file next to the script."""
+
+import re
+from pathlib import Path
+
+SYMBOLS = {
+    "SP": 0,
+    "LCL": 1,
+    "ARG": 2,
+    "THIS": 3,
+    "THAT": 4,
+    "SCREEN": 16384,
+    "KBD": 24576,
+    **{f"R{n}": n for n in range(16)},
+}
+
+COMPS = {
+    "0": lambda a, d, m: 0,
+    "1": lambda a, d, m: 1,
+    "-1": lambda a, d, m: -1,
+    "D": lambda a, d, m: d,
+    "A": lambda a, d, m: a,
+    "M": lambda a, d, m: m,
+    "!D": lambda a, d, m: ~d,
+    "!A": lambda a, d, m: ~a,
+    "!M": lambda a, d, m: ~m,
+    "-D": lambda a, d, m: -d,
+    "-A": lambda a, d, m: -a,
+    "-M": lambda a, d, m: -m,
+    "D+1": lambda a, d, m: d + 1,
+    "A+1": lambda a, d, m: a + 1,
+    "M+1": lambda a, d, m: m + 1,
+    "D-1": lambda a, d, m: d - 1,
+    "A-1": lambda a, d, m: a - 1,
+    "M-1": lambda a, d, m: m - 1,
+    "D+A": lambda a, d, m: d + a,
+    "D+M": lambda a, d, m: d + m,
+    "D-A": lambda a, d, m: d - a,
+    "D-M": lambda a, d, m: d - m,
+    "A-D": lambda a, d, m: a - d,
+    "M-D": lambda a, d, m: m - d,
+    "D&A": lambda a, d, m: d & a,
+    "D&M": lambda a, d, m: d & m,
+    "D|A": lambda a, d, m: d | a,
+    "D|M": lambda a, d, m: d | m,
+}
+
+JUMPS = {
+    "": lambda out: False,
+    "JGT": lambda out: out > 0,
+    "JEQ": lambda out: out == 0,
+    "JGE": lambda out: out >= 0,
+    "JLT": lambda out: out < 0,
+    "JNE": lambda out: out != 0,
+    "JLE": lambda out: out <= 0,
+    "JMP": lambda out: True,
+}
+
+
+def signed(value: int) -> int:
+    value &= 0xFFFF
+    return value - 0x10000 if value & 0x8000 else value
+
+
+def source_lines(asm: str) -> list[str]:
+    lines = []
+    for line in asm.splitlines():
+        line = line.split("//")[0].replace(" ", "").strip().rstrip(";")
+        if line:
+            lines.append(line)
+    return lines
+
+
+def labels(asm: str) -> dict[str, int]:
+    """The ROM address of every label."""
+    addresses = {}
+    address = 0
+    for line in source_lines(asm):
+        if line.startswith("("):
+            addresses[line[1:-1]] = address
+        else:
+            address += 1
+    return addresses
+
+
+def assemble(asm: str) -> list[tuple]:
+    """`("A", value)` and `("C", dest, comp, jump)` per instruction."""
+    lines = source_lines(asm)
+    symbols = SYMBOLS | labels(asm)
+    variables = 16
+    program: list[tuple] = []
+    for line in lines:
+        if line.startswith("("):
+            continue
+        if line.startswith("@"):
+            value = line[1:]
+            if not value.isdigit():
+                if value not in symbols:
+                    symbols[value] = variables
+                    variables += 1
+                value = symbols[value]
+            program.append(("A", int(value)))
+            continue
+        dest, _, rest = line.rpartition("=")
+        comp, _, jump = rest.partition(";")
+        if comp not in COMPS:
+            # D+M written as M+D
+            comp = re.sub(r"^(\w)([+&|])(\w)$", r"\3\2\1", comp)
+        program.append(("C", dest, COMPS[comp], JUMPS[jump]))
+    return program
+
+
+class Cpu:
+    def __init__(self, program: list[tuple]):
+        self.program = program
+        self.ram = [0] * 32768
+        self.a = self.d = self.pc = 0
+
+    def run(self, cycles: int):
+        ram = self.ram
+        for _ in range(cycles):
+            if self.pc >= len(self.program):
+                break
+            instruction = self.program[self.pc]
+            self.pc += 1
+            if instruction[0] == "A":
+                self.a = instruction[1]
+                continue
+            _, dest, comp, jump = instruction
+            address = self.a & 0x7FFF
+            out = signed(comp(signed(self.a), signed(self.d), signed(ram[address])))
+            target = self.a
+            if "M" in dest:
+                ram[address] = out & 0xFFFF
+            if "A" in dest:
+                self.a = out & 0xFFFF
+            if "D" in dest:
+                self.d = out & 0xFFFF
+            if jump(out):
+                self.pc = target
+
+
+def run_test(tst: Path, asm: Path) -> tuple[list[list[int]], list[list[int]]]:
+    """The rows `tst` outputs running `asm`, and the rows of its .cmp."""
+    script = re.sub(r"//.*|[{}]", "", tst.read_text())
+    cpu = Cpu(assemble(asm.read_text()))
+    columns: list[int] = []
+    rows: list[list[int]] = []
+    for command in re.split(r"[,;]", script):
+        words = command.split()
+        if not words:
+            continue
+        if words[0] == "set":
+            cpu.ram[int(words[1][4:-1])] = int(words[2]) & 0xFFFF
+        elif words[0] == "repeat":
+            cpu.run(int(words[1]))
+        elif words[0] == "output-list":
+            columns = [int(word[4 : word.index("]")]) for word in words[1:]]
+        elif words[0] == "output":
+            rows.append([signed(cpu.ram[column]) for column in columns])
+    # the .cmp repeats the header of each output-list
+    expected = [
+        [int(field) for field in line.split("|")[1:-1]]
+        for line in tst.with_suffix(".cmp").read_text().splitlines()
+        if line.strip() and "RAM" not in line
+    ]
+    return rows, expected
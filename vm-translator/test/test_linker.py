@@ -0,0 +1,108 @@
+import contextlib
+import io
+import shutil
+import tempfile
+import unittest
+from pathlib import Path
+
+from emulator import run_test
+from vm_translator import compiler, linker
+
+STATICS_TEST = Path(__file__).parent / "FunctionCalls" / "StaticsTest"
+
+# an OS whose Class2 the program replaces and whose Math is never called
+OS = {
+    "Class2.vm": "function Class2.set 0\npush constant 0\nreturn\n",
+    "Math.vm": "function Math.abs 0\npush argument 0\npop static 0\n"
+    "push static 0\nreturn\n",
+}
+
+
+class LinkerTest(unittest.TestCase):
+    def setUp(self):
+        self.temp = tempfile.TemporaryDirectory()
+        self.directory = Path(self.temp.name)
+        self.program = self.directory / "StaticsTest"
+        shutil.copytree(STATICS_TEST, self.program)
+        self.os = self.directory / "os"
+        self.os.mkdir()
+        for name, text in OS.items():
+            (self.os / name).write_text(text)
+
+    def tearDown(self):
+        self.temp.cleanup()
+
+    def link(self, output: str) -> Path:
+        path = self.directory / output
+        with contextlib.redirect_stdout(io.StringIO()):
+            compiler.link(self.program, self.os, path)
+        return path
+
+    def test_keeps_reachable_functions_and_prefers_program_classes(self):
+        files = sorted(self.program.glob("*.vm"))
+        functions = linker.link(files, sorted(self.os.glob("*.vm")))
+        self.assertEqual(
+            [(function.name, function.file) for function in functions],
+            [
+                ("Class1.set", "Class1"),
+                ("Class1.get", "Class1"),
+                ("Class2.set", "Class2"),
+                ("Class2.get", "Class2"),
+                ("Sys.init", "Sys"),
+            ],
+        )
+
+    def test_gives_each_class_its_own_statics(self):
+        linked = self.link("StaticsTest.vm")
+        statics = [line for line in linked.read_text().splitlines() if "static" in line]
+        self.assertEqual(
+            statics,
+            [
+                "pop static 0",
+                "pop static 1",
+                "push static 0",
+                "push static 1",
+                "pop static 2",
+                "pop static 3",
+                "push static 2",
+                "push static 3",
+            ],
+        )
+        self.assertEqual(
+            linked.with_suffix(".map").read_text(),
+            "Class1.set 7\nClass1.get 5\nClass2.set 7\nClass2.get 5\nSys.init 13\n",
+        )
+        # one file still runs as the three did
+        compiler.compile(linked)
+        tst = self.program / "StaticsTest.tst"
+        rows, expected = run_test(tst, linked.with_suffix(".asm"))
+        self.assertEqual(rows, expected)
+
+    def test_links_straight_to_assembly(self):
+        asm = self.link("StaticsTest.asm")
+        rows, expected = run_test(self.program / "StaticsTest.tst", asm)
+        self.assertEqual(rows, expected)
+
+    def test_reports_every_problem(self):
+        (self.program / "Class3.vm").write_text(
+            "function Class3.f 0\ncall Class4.g 0\nreturn\n"
+            "function Class1.get 0\npush constant 0\nreturn\n"
+        )
+        with self.assertRaises(linker.LinkError) as raised:
+            linker.link(sorted(self.program.glob("*.vm")), [])
+        self.assertEqual(
+            raised.exception.messages,
+            [
+                "Class3.vm: Class1.get is already defined in Class1.vm",
+                "Class3.vm:2: call to undefined Class4.g",
+            ],
+        )
+        with self.assertRaises(linker.LinkError) as raised:
+            linker.link([self.program / "Class1.vm"], [])
+        self.assertEqual(
+            raised.exception.messages, ["no Sys.init or Main.main to start from"]
+        )
+
+
+if __name__ == "__main__":
+    unittest.main()
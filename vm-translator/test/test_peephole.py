@@ -0,0 +1,72 @@
+import shutil
+import tempfile
+import unittest
+from pathlib import Path
+
+from emulator import run_test
+from vm_translator import compiler, peephole
+
+FIXTURES = Path(__file__).parent
+
+
+def lines(*texts: str) -> list[peephole.Line]:
+    return [(text, ("Main.jack", number)) for number, text in enumerate(texts, 1)]
+
+
+def texts(lines: list[peephole.Line]) -> list[str]:
+    return [text for text, _ in lines]
+
+
+class PeepholeTest(unittest.TestCase):
+    def test_normalizes_assembly(self):
+        self.assertEqual(
+            peephole.normalize("@SP // top\nA = M;\n\n(LOOP)\n0;JMP\n"),
+            ["@SP", "A=M", "(LOOP)", "0;JMP"],
+        )
+        self.assertEqual(peephole.count_instructions("(LOOP)\n@LOOP\n0;JMP\n"), 2)
+
+    def test_drops_a_push_of_d_popped_straight_back(self):
+        code = lines("D=M", *peephole.PUSH_POP, "M=D")
+        self.assertEqual(texts(peephole.step(code)), ["D=M", "M=D"])
+
+    def test_folds_constant_additions(self):
+        code = lines("@3", "D=A", "@4", "D=D+A", "@5", "D=D+A")
+        chunks = [(text + "\n", origin) for text, origin in code]
+        # the sum keeps the origin of its first constant
+        self.assertEqual(
+            peephole.optimize(chunks),
+            [("@12", ("Main.jack", 1)), ("D=A", ("Main.jack", 1))],
+        )
+
+    def test_drops_redundant_loads_and_jumps(self):
+        self.assertEqual(
+            texts(peephole.step(lines("D=A", "A=D", "M=D"))), ["D=A", "M=D"]
+        )
+        self.assertEqual(
+            texts(peephole.step(lines("@R13", "@SP", "M=D"))), ["@SP", "M=D"]
+        )
+        self.assertEqual(
+            texts(peephole.step(lines("@NEXT", "0;JMP", "(NEXT)", "D=0"))),
+            ["(NEXT)", "D=0"],
+        )
+        # a jump elsewhere and a conditional jump stay
+        for code in [("@END", "0;JMP", "(NEXT)"), ("@NEXT", "D;JEQ", "(NEXT)")]:
+            self.assertEqual(texts(peephole.step(lines(*code))), list(code))
+
+    def test_optimized_programs_still_pass_their_tests(self):
+        for name in ["FibonacciElement", "NestedCall"]:
+            with self.subTest(name), tempfile.TemporaryDirectory() as temp:
+                directory = Path(temp) / name
+                shutil.copytree(FIXTURES / "FunctionCalls" / name, directory)
+                sizes = []
+                for optimize_size in [False, True]:
+                    compiler.compile(directory, optimize_size=optimize_size)
+                    asm = directory / "all.asm"
+                    rows, expected = run_test(directory / f"{name}.tst", asm)
+                    self.assertEqual(rows, expected)
+                    sizes.append(peephole.count_instructions(asm.read_text()))
+                self.assertLess(sizes[1], sizes[0])
+
+
+if __name__ == "__main__":
+    unittest.main()
@@ -0,0 +1,71 @@
+import shutil
+import tempfile
+import unittest
+from pathlib import Path
+
+from emulator import labels, run_test
+from vm_translator import compiler, sourcemap
+
+FIXTURES = Path(__file__).parent
+
+
+class SourceMapTest(unittest.TestCase):
+    def test_reads_what_it_writes(self):
+        entries = [(0, None), (3, ("Main.jack", 1)), (9, ("Main.jack", 4))]
+        with tempfile.TemporaryDirectory() as temp:
+            path = Path(temp) / "Main.vm.map"
+            sourcemap.write(path, entries)
+            self.assertEqual(path.read_text(), "0 -\n3 Main.jack 1\n9 Main.jack 4\n")
+            self.assertEqual(sourcemap.read(path), entries)
+            path.write_text("3 Main.jack 1\n4 Main.jack\n")
+            with self.assertRaisesRegex(ValueError, "Main.vm.map:2: malformed"):
+                sourcemap.read(path)
+
+    def test_looks_up_the_entry_in_force(self):
+        entries = [(3, ("Main.jack", 1)), (7, None), (9, ("Main.jack", 4))]
+        for position, origin in [
+            (0, None),
+            (3, ("Main.jack", 1)),
+            (6, ("Main.jack", 1)),
+            (7, None),
+            (8, None),
+            (9, ("Main.jack", 4)),
+            (500, ("Main.jack", 4)),
+        ]:
+            self.assertEqual(sourcemap.lookup(entries, position), origin)
+        self.assertIsNone(sourcemap.lookup([], 0))
+
+    def test_maps_rom_addresses_back_to_jack_lines(self):
+        for optimize_size in [False, True]:
+            with self.subTest(optimize_size=optimize_size):
+                with tempfile.TemporaryDirectory() as temp:
+                    directory = Path(temp) / "FibonacciElement"
+                    shutil.copytree(
+                        FIXTURES / "FunctionCalls" / "FibonacciElement", directory
+                    )
+                    # Main.fibonacci, its n < 2 branch and its n >= 2 branch
+                    (directory / "Main.vm.map").write_text(
+                        "11 Main.jack 4\n17 Main.jack 5\n20 Main.jack 6\n"
+                    )
+                    compiler.compile(directory, optimize_size=optimize_size)
+                    asm = directory / "all.asm"
+                    rows, expected = run_test(directory / "FibonacciElement.tst", asm)
+                    self.assertEqual(rows, expected)
+                    rom_map = sourcemap.read(directory / "all.asm.map")
+                    addresses = labels(asm.read_text())
+                    start = addresses["main.fibonacci"]
+                    below_2 = addresses["main.fibonacci$n_lt_2"]
+                    for address, origin in [
+                        (0, None),
+                        (start - 1, None),
+                        (start, ("Main.jack", 4)),
+                        (below_2 - 1, ("Main.jack", 4)),
+                        (below_2, ("Main.jack", 5)),
+                        (addresses["main.fibonacci$n_ge_2"], ("Main.jack", 6)),
+                        (addresses["sys.init"], None),
+                    ]:
+                        self.assertEqual(sourcemap.lookup(rom_map, address), origin)
+
+
+if __name__ == "__main__":
+    unittest.main()